@@ -0,0 +1,164 @@
+//! Runs captures for a long time while cycling pause/resume and recreating them,
+//! and fails if the number of open fds or the resident memory keeps growing.
+//!
+//! Usage: cargo run --release --example soak -- [seconds]
+//!
+//! Uses wlr-export-dmabuf and wlr-screencopy on Wayland, XShm on X11.
+
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+use wlx_capture::{
+    wayland::{OutputChangeEvent, WlxClient},
+    wlr_dmabuf::WlrDmabufCapture,
+    wlr_screencopy::WlrScreencopyCapture,
+    xshm::XshmCapture,
+    WlxCapture,
+};
+
+/// Time allowed for buffer pools to fill up before the baseline is taken.
+const WARMUP: Duration = Duration::from_secs(30);
+const PAUSE_INTERVAL: Duration = Duration::from_secs(10);
+const RECREATE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Growth over the baseline that is still considered bounded.
+const MAX_EXTRA_FDS: usize = 32;
+const MAX_EXTRA_RSS_KB: usize = 256 * 1024;
+
+fn main() {
+    let seconds = std::env::args()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(3600);
+    let duration = Duration::from_secs(seconds);
+
+    let wayland = std::env::var("WAYLAND_DISPLAY").is_ok();
+    let mut watcher = if wayland { WlxClient::new() } else { None };
+
+    let mut captures = create_captures(wayland);
+    assert!(!captures.is_empty(), "no outputs to capture");
+
+    let start = Instant::now();
+    let mut last_pause = start;
+    let mut last_recreate = start;
+    let mut paused = false;
+    let mut baseline = None;
+    let mut frames = 0u64;
+
+    while start.elapsed() < duration {
+        if let Some(wl) = watcher.as_mut() {
+            wl.dispatch_pending();
+            for event in wl.iter_events() {
+                if let OutputChangeEvent::Create(_) | OutputChangeEvent::Destroy(_) = event {
+                    println!("output hotplug, recreating captures");
+                    last_recreate = Instant::now() - RECREATE_INTERVAL;
+                }
+            }
+        }
+
+        if last_recreate.elapsed() >= RECREATE_INTERVAL {
+            captures.clear();
+            captures = create_captures(wayland);
+            last_recreate = Instant::now();
+            paused = false;
+        }
+
+        if last_pause.elapsed() >= PAUSE_INTERVAL {
+            for capture in captures.iter_mut() {
+                if paused {
+                    capture.resume();
+                } else {
+                    capture.pause();
+                }
+            }
+            paused = !paused;
+            last_pause = Instant::now();
+        }
+
+        if !paused {
+            for capture in captures.iter_mut() {
+                if capture.receive().is_some() {
+                    frames += 1;
+                }
+                capture.request_new_frame();
+            }
+        }
+
+        if start.elapsed() >= WARMUP {
+            let usage = (open_fds(), rss_kb());
+            let (base_fds, base_rss) = *baseline.get_or_insert(usage);
+            assert!(
+                usage.0 <= base_fds + MAX_EXTRA_FDS,
+                "fd leak: {} open, baseline {}",
+                usage.0,
+                base_fds
+            );
+            assert!(
+                usage.1 <= base_rss + MAX_EXTRA_RSS_KB,
+                "memory growth: {} kB resident, baseline {} kB",
+                usage.1,
+                base_rss
+            );
+        }
+
+        std::thread::sleep(Duration::from_millis(16));
+    }
+
+    println!(
+        "ok: {} frames in {}s, {} fds, {} kB resident",
+        frames,
+        seconds,
+        open_fds(),
+        rss_kb()
+    );
+}
+
+fn create_captures(wayland: bool) -> Vec<Box<dyn WlxCapture>> {
+    let mut captures: Vec<Box<dyn WlxCapture>> = Vec::new();
+
+    if wayland {
+        let Some(wl) = WlxClient::new() else {
+            return captures;
+        };
+        let output_ids: Vec<u32> = wl.outputs.values().map(|o| o.id).collect();
+        for id in output_ids {
+            if let Some(wl) = WlxClient::new() {
+                captures.push(Box::new(WlrDmabufCapture::new(wl, id)));
+            }
+            if let Some(wl) = WlxClient::new() {
+                captures.push(Box::new(WlrScreencopyCapture::new(wl, id)));
+            }
+        }
+    } else if let Ok(screens) = XshmCapture::get_monitors() {
+        for screen in screens {
+            captures.push(Box::new(XshmCapture::new(screen)));
+        }
+    }
+
+    for capture in captures.iter_mut() {
+        capture.init(&[]);
+        capture.request_new_frame();
+    }
+    captures
+}
+
+fn open_fds() -> usize {
+    fs::read_dir("/proc/self/fd")
+        .map(|dir| dir.count())
+        .unwrap_or(0)
+}
+
+fn rss_kb() -> usize {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find(|l| l.starts_with("VmRSS:"))
+                .and_then(|l| l.split_whitespace().nth(1))
+                .and_then(|kb| kb.parse().ok())
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,144 @@
+//! Delivery paced by the consumer: a frame is only handed over once the previous one
+//! has been acknowledged, so that a consumer that stutters is not flooded with imports
+//! it cannot keep up with. Frames that arrive in the meantime are dropped by the backend
+//! as usual, and the consumer gets the newest one after its acknowledgement.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    callback::{forward_capture, wrap_frame_callback},
+    frame::{monotonic_ns, WlxFrame},
+    FrameCallback, WlxCapture,
+};
+
+/// How long to wait for an acknowledgement before delivering the next frame anyway.
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Acknowledges frames of an `AckCapture`, from any thread.
+#[derive(Clone)]
+pub struct FrameAck {
+    /// When the unacknowledged frame was delivered, in `monotonic_ns`, or 0.
+    pending: Arc<AtomicU64>,
+}
+
+impl FrameAck {
+    /// The last delivered frame is done with, e.g. imported or uploaded.
+    pub fn ack(&self) {
+        self.pending.store(0, Ordering::Release);
+    }
+
+    /// Whether the next frame may be delivered, because the last one was acknowledged
+    /// or `timeout` passed since it was delivered.
+    fn ready(&self, timeout: Duration) -> bool {
+        let pending = self.pending.load(Ordering::Acquire);
+        if pending == 0 {
+            return true;
+        }
+        if monotonic_ns().saturating_sub(pending) < timeout.as_nanos() as u64 {
+            return false;
+        }
+        // give up on the frame once, so that this is only logged once
+        if self
+            .pending
+            .compare_exchange(pending, 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            log::debug!("Frame was not acknowledged within {:?}", timeout);
+        }
+        true
+    }
+
+    fn delivered(&self) {
+        self.pending.store(monotonic_ns().max(1), Ordering::Release);
+    }
+}
+
+/// Wraps a capture to deliver a frame only after the previous one was acknowledged
+/// through `FrameAck::ack`, or `timeout` passed. No new frames are requested from the
+/// backend in the meantime. Cursor-only frames are always delivered.
+pub struct AckCapture {
+    capture: Box<dyn WlxCapture>,
+    ack: FrameAck,
+    timeout: Duration,
+}
+
+impl AckCapture {
+    pub fn new(capture: Box<dyn WlxCapture>) -> Self {
+        Self {
+            capture,
+            ack: FrameAck {
+                pending: Arc::new(AtomicU64::new(0)),
+            },
+            timeout: DEFAULT_ACK_TIMEOUT,
+        }
+    }
+
+    /// How long to wait for an acknowledgement before delivering the next frame anyway.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// A handle to acknowledge frames with, e.g. from an import thread.
+    pub fn frame_ack(&self) -> FrameAck {
+        self.ack.clone()
+    }
+
+    /// Acknowledge the last delivered frame.
+    pub fn ack(&self) {
+        self.ack.ack();
+    }
+
+    pub fn capture(&self) -> &dyn WlxCapture {
+        self.capture.as_ref()
+    }
+}
+
+impl WlxCapture for AckCapture {
+    forward_capture!(capture: init, is_ready, supports_dmbuf, caps, source_kind, pause);
+    forward_capture!(capture: last_activity, next_frame_eta);
+    forward_capture!(capture: set_embedded_cursor, freeze, unfreeze);
+    fn receive(&mut self) -> Option<WlxFrame> {
+        // frames stay with the backend, which keeps only the newest
+        if !self.ack.ready(self.timeout) {
+            return None;
+        }
+        let frame = self.capture.receive()?;
+        if !matches!(frame, WlxFrame::Mouse(_)) {
+            self.ack.delivered();
+        }
+        Some(frame)
+    }
+    fn resume(&mut self) {
+        // frames from before the pause are not coming back to be acknowledged
+        self.ack.ack();
+        self.capture.resume();
+    }
+    fn request_new_frame(&mut self) {
+        if self.ack.ready(self.timeout) {
+            self.capture.request_new_frame();
+        }
+    }
+    /// Frames that arrive before the previous one was acknowledged are dropped on the
+    /// capture thread.
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        let ack = self.ack.clone();
+        let timeout = self.timeout;
+        wrap_frame_callback(self.capture.as_mut(), callback, move |frame, next| {
+            if !matches!(frame, WlxFrame::Mouse(_)) {
+                if !ack.ready(timeout) {
+                    log::trace!("Dropping frame that arrived before the last one was acknowledged");
+                    return;
+                }
+                ack.delivered();
+            }
+            next(frame);
+        })
+    }
+}
@@ -0,0 +1,228 @@
+//! Average colors of frames, overall and along the edges, for bias lighting and VR
+//! ambiance without consumers touching pixels. See `AmbientAnalyzer`.
+
+use std::sync::mpsc;
+
+use crate::{
+    convert::to_rgba,
+    frame::{monotonic_ns, DmabufFrame, MemFdFrame, WlxFrame, DRM_FORMAT_MOD_LINEAR},
+    hook::HookResult,
+};
+
+/// Frames are sampled on a grid of at most this many pixels in either direction,
+/// which keeps the cost the same for any resolution.
+const MAX_SAMPLES: u32 = 128;
+
+/// Average colors of one frame, as RGB8.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AmbientColors {
+    /// `monotonic_ns` when the frame was analyzed.
+    pub timestamp: u64,
+    pub average: [u8; 3],
+    /// Zones along the top edge, from left to right.
+    pub top: Vec<[u8; 3]>,
+    /// Zones along the bottom edge, from left to right.
+    pub bottom: Vec<[u8; 3]>,
+    /// Zones along the left edge, from top to bottom.
+    pub left: Vec<[u8; 3]>,
+    /// Zones along the right edge, from top to bottom.
+    pub right: Vec<[u8; 3]>,
+}
+
+/// Sums of sampled colors.
+#[derive(Clone, Copy, Default)]
+struct Sum {
+    rgb: [u64; 3],
+    count: u64,
+}
+
+impl Sum {
+    fn add(&mut self, rgba: [u8; 4]) {
+        self.rgb[0] += rgba[0] as u64;
+        self.rgb[1] += rgba[1] as u64;
+        self.rgb[2] += rgba[2] as u64;
+        self.count += 1;
+    }
+
+    fn average(&self) -> [u8; 3] {
+        let count = self.count.max(1);
+        self.rgb.map(|c| (c / count) as u8)
+    }
+}
+
+/// Computes `AmbientColors` of CPU frames and linear single-plane DMA-bufs, in the
+/// formats that `convert` supports. Zones are in the orientation of the buffer, within
+/// the frame's crop rectangle; apply `format.transform` to find the edges on screen.
+#[derive(Debug, Clone)]
+pub struct AmbientAnalyzer {
+    horizontal_zones: usize,
+    vertical_zones: usize,
+    /// How far the edge zones reach into the image, as a fraction of its size.
+    depth: f32,
+}
+
+impl Default for AmbientAnalyzer {
+    fn default() -> Self {
+        Self {
+            horizontal_zones: 8,
+            vertical_zones: 4,
+            depth: 0.1,
+        }
+    }
+}
+
+impl AmbientAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of zones along the top and bottom edges, and along the left and right edges.
+    /// Zero leaves out those edges.
+    pub fn with_zones(mut self, horizontal: usize, vertical: usize) -> Self {
+        self.horizontal_zones = horizontal;
+        self.vertical_zones = vertical;
+        self
+    }
+
+    /// How far the edge zones reach into the image, as a fraction of its width or height.
+    pub fn with_depth(mut self, depth: f32) -> Self {
+        self.depth = depth.clamp(0.0, 0.5);
+        self
+    }
+
+    /// Analyze a frame. None for cursor updates, tiled or multi-plane DMA-bufs, and
+    /// unsupported formats.
+    pub fn analyze(&self, frame: &WlxFrame) -> Option<AmbientColors> {
+        match frame {
+            WlxFrame::MemPtr(memptr) => {
+                if memptr.ptr == 0 || memptr.format.height == 0 {
+                    return None;
+                }
+                let bytes =
+                    unsafe { std::slice::from_raw_parts(memptr.ptr as *const u8, memptr.size) };
+                let stride = memptr.stride();
+                self.analyze_bytes(bytes, stride, frame)
+            }
+            WlxFrame::MemFd(memfd) => self.analyze_memfd(memfd, frame),
+            WlxFrame::Dmabuf(dmabuf) => self.analyze_dmabuf(dmabuf, frame),
+            WlxFrame::Mouse(_) => None,
+        }
+    }
+
+    /// A hook for `hook::HookCapture::set_frame_hook` that analyzes every frame, and the
+    /// receiver the results arrive on. Results are dropped while 4 of them wait.
+    pub fn into_hook(
+        self,
+    ) -> (
+        impl FnMut(&WlxFrame) -> HookResult + Send + 'static,
+        mpsc::Receiver<AmbientColors>,
+    ) {
+        let (sender, receiver) = mpsc::sync_channel(4);
+        let hook = move |frame: &WlxFrame| {
+            if let Some(colors) = self.analyze(frame) {
+                if let Err(mpsc::TrySendError::Full(_)) = sender.try_send(colors) {
+                    log::trace!("Dropping ambient colors, the receiver is behind");
+                }
+            }
+            HookResult::Continue
+        };
+        (hook, receiver)
+    }
+
+    fn analyze_memfd(&self, memfd: &MemFdFrame, frame: &WlxFrame) -> Option<AmbientColors> {
+        let stride = memfd.stride();
+        memfd
+            .with_mapped(|bytes| self.analyze_bytes(bytes, stride, frame))
+            .flatten()
+    }
+
+    fn analyze_dmabuf(&self, dmabuf: &DmabufFrame, frame: &WlxFrame) -> Option<AmbientColors> {
+        if dmabuf.format.modifier != DRM_FORMAT_MOD_LINEAR || dmabuf.num_planes != 1 {
+            return None;
+        }
+        let memfd = MemFdFrame {
+            format: dmabuf.format,
+            meta: dmabuf.meta,
+            plane: dmabuf.planes[0],
+        };
+        self.analyze_memfd(&memfd, frame)
+    }
+
+    /// Sample the image in `bytes`, which starts at the first row.
+    fn analyze_bytes(
+        &self,
+        bytes: &[u8],
+        stride: usize,
+        frame: &WlxFrame,
+    ) -> Option<AmbientColors> {
+        let (format, meta) = (frame.format()?, frame.meta()?);
+        let fourcc = format.fourcc;
+        to_rgba([0; 4], fourcc)?;
+
+        let (x0, y0, width, height) = match meta.crop {
+            Some(crop) => (crop.x, crop.y, crop.width, crop.height),
+            None => (0, 0, format.width, format.height),
+        };
+        let width = width.min(format.width.saturating_sub(x0));
+        let height = height.min(format.height.saturating_sub(y0));
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        let step_x = width.div_ceil(MAX_SAMPLES).max(1);
+        let step_y = height.div_ceil(MAX_SAMPLES).max(1);
+        let depth_x = ((width as f32 * self.depth) as u32).max(1);
+        let depth_y = ((height as f32 * self.depth) as u32).max(1);
+
+        let mut average = Sum::default();
+        let mut top = vec![Sum::default(); self.horizontal_zones];
+        let mut bottom = vec![Sum::default(); self.horizontal_zones];
+        let mut left = vec![Sum::default(); self.vertical_zones];
+        let mut right = vec![Sum::default(); self.vertical_zones];
+
+        for y in (0..height).step_by(step_y as usize) {
+            let row = (y0 + y) as usize * stride;
+            let zone_y = (y as usize * self.vertical_zones) / height as usize;
+            for x in (0..width).step_by(step_x as usize) {
+                let i = row + (x0 + x) as usize * 4;
+                let Some(px) = bytes.get(i..i + 4) else {
+                    continue;
+                };
+                let rgba = to_rgba([px[0], px[1], px[2], px[3]], fourcc)?;
+                average.add(rgba);
+
+                let zone_x = (x as usize * self.horizontal_zones) / width as usize;
+                if y < depth_y {
+                    if let Some(zone) = top.get_mut(zone_x) {
+                        zone.add(rgba);
+                    }
+                }
+                if y >= height - depth_y {
+                    if let Some(zone) = bottom.get_mut(zone_x) {
+                        zone.add(rgba);
+                    }
+                }
+                if x < depth_x {
+                    if let Some(zone) = left.get_mut(zone_y) {
+                        zone.add(rgba);
+                    }
+                }
+                if x >= width - depth_x {
+                    if let Some(zone) = right.get_mut(zone_y) {
+                        zone.add(rgba);
+                    }
+                }
+            }
+        }
+
+        let averages = |sums: Vec<Sum>| sums.iter().map(Sum::average).collect();
+        Some(AmbientColors {
+            timestamp: monotonic_ns(),
+            average: average.average(),
+            top: averages(top),
+            bottom: averages(bottom),
+            left: averages(left),
+            right: averages(right),
+        })
+    }
+}
@@ -0,0 +1,110 @@
+//! A log of capture session lifecycle events, for applications that want to keep it
+//! around and show it to users asking why their screen stopped mirroring overnight.
+//! Nothing is recorded until a callback is set with `set_audit_log`.
+
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use crate::BackendKind;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// A session was started after the user selected what to capture, or a capture
+    /// of a source that needs no selection was set up.
+    Created,
+    /// A session was started from a saved restore token. The portal may still have asked
+    /// the user, if it no longer accepted the token.
+    Restored,
+    /// The compositor or portal took a running session away, e.g. because the user
+    /// stopped sharing or the source was closed.
+    Revoked,
+    /// A session could not be started, or broke down on its own.
+    Failed,
+}
+
+impl AuditEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEvent::Created => "created",
+            AuditEvent::Restored => "restored",
+            AuditEvent::Revoked => "revoked",
+            AuditEvent::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    /// Wall clock time, to compare with when the user noticed.
+    pub time: SystemTime,
+    pub event: AuditEvent,
+    pub backend: BackendKind,
+    /// What is captured, e.g. an output name or a PipeWire node. Empty if not known yet.
+    pub identity: String,
+    /// The reason given by the compositor or portal, if any.
+    pub detail: Option<String>,
+}
+
+/// One line per record: seconds since the epoch, event, backend, identity and detail.
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_epoch = self
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+        write!(
+            f,
+            "{}.{:03} {} {}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_millis(),
+            self.event.as_str(),
+            self.backend.as_str()
+        )?;
+        if !self.identity.is_empty() {
+            write!(f, " {}", self.identity)?;
+        }
+        if let Some(detail) = self.detail.as_ref() {
+            write!(f, ": {}", detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives every record, on whichever thread the event was noticed.
+/// Should return quickly, e.g. by appending a line to a file.
+pub type AuditCallback = Box<dyn FnMut(&AuditRecord) + Send>;
+
+static AUDIT_LOG: Mutex<Option<AuditCallback>> = Mutex::new(None);
+
+/// Start passing records to `callback`, across all captures, or stop with None.
+pub fn set_audit_log(callback: Option<AuditCallback>) {
+    if let Ok(mut log) = AUDIT_LOG.lock() {
+        *log = callback;
+    }
+}
+
+pub(crate) fn record(
+    event: AuditEvent,
+    backend: BackendKind,
+    identity: impl Into<String>,
+    detail: Option<String>,
+) {
+    let Ok(mut log) = AUDIT_LOG.lock() else {
+        return;
+    };
+    let Some(callback) = log.as_mut() else {
+        return;
+    };
+    let record = AuditRecord {
+        time: SystemTime::now(),
+        event,
+        backend,
+        identity: identity.into(),
+        detail,
+    };
+    log::debug!("Audit: {}", record);
+    callback(&record);
+}
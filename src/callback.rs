@@ -0,0 +1,221 @@
+use std::sync::{mpsc, Arc, Mutex};
+
+use crate::{
+    convert::convert_frame,
+    frame::{DrmFormat, FourCC, FrameFormat, WlxFrame},
+    FrameCallback, WlxCapture,
+};
+
+/// Returned by a callback that cannot handle the format of the frame it was given.
+#[derive(Debug, Clone, Copy)]
+pub struct UnsupportedFormat;
+
+/// Runs a consumer callback on the capture thread and hands its result back,
+/// e.g. to import or upload a frame there and pass a texture handle to the render thread.
+pub struct CallbackCapture<U> {
+    capture: Box<dyn WlxCapture>,
+    receiver: mpsc::Receiver<U>,
+    /// Set if the backend has no capture thread to run the callback on,
+    /// in which case it runs inside `receive` instead.
+    fallback: Option<FrameCallback>,
+}
+
+impl<U: Send + 'static> CallbackCapture<U> {
+    /// `capture` must not be initialized yet.
+    /// Results are dropped if the consumer falls more than 2 behind.
+    pub fn new<F>(mut capture: Box<dyn WlxCapture>, mut callback: F) -> Self
+    where
+        F: FnMut(WlxFrame) -> Option<U> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(2);
+        let callback: FrameCallback = Box::new(move |frame| {
+            if let Some(result) = callback(frame) {
+                let _ = sender.try_send(result);
+            }
+        });
+        let fallback = capture.set_frame_callback(callback).err();
+
+        Self {
+            capture,
+            receiver,
+            fallback,
+        }
+    }
+
+    /// Like `new`, but if `callback` reports `UnsupportedFormat`, the frame is converted
+    /// on the CPU to `fallback` and handed to `callback` once more as a `WlxFrame::MemPtr`.
+    /// DMA-buf frames cannot be converted and are dropped.
+    pub fn with_conversion<F>(
+        capture: Box<dyn WlxCapture>,
+        fallback: FourCC,
+        mut callback: F,
+    ) -> Self
+    where
+        F: FnMut(WlxFrame) -> Result<Option<U>, UnsupportedFormat> + Send + 'static,
+    {
+        Self::new(capture, move |frame| match callback(frame.clone()) {
+            Ok(result) => result,
+            Err(UnsupportedFormat) => {
+                let Some((converted, _pixels)) = convert_frame(&frame, fallback) else {
+                    log::debug!("Dropping frame that could not be converted to {}", fallback);
+                    return None;
+                };
+                callback(WlxFrame::MemPtr(converted)).ok().flatten()
+            }
+        })
+    }
+
+    /// Like `new`, but also calls `on_format` on the capture thread whenever the format
+    /// of incoming frames changes, before `callback` sees the first frame in the new format.
+    /// Textures can be reallocated there once, instead of comparing formats on every frame.
+    pub fn with_format_callback<G, F>(
+        capture: Box<dyn WlxCapture>,
+        mut on_format: G,
+        mut callback: F,
+    ) -> Self
+    where
+        G: FnMut(&FrameFormat) + Send + 'static,
+        F: FnMut(WlxFrame) -> Option<U> + Send + 'static,
+    {
+        let mut last_format = None;
+        Self::new(capture, move |frame| {
+            if let Some(format) = frame.format() {
+                if last_format.as_ref() != Some(format) {
+                    on_format(format);
+                    last_format = Some(*format);
+                }
+            }
+            callback(frame)
+        })
+    }
+
+    pub fn capture(&self) -> &dyn WlxCapture {
+        self.capture.as_ref()
+    }
+
+    /// Access to the wrapped capture for pausing, freezing and the like.
+    /// Its `receive` only yields `WlxFrame::Mouse` updates while the callback is in use.
+    pub fn capture_mut(&mut self) -> &mut dyn WlxCapture {
+        self.capture.as_mut()
+    }
+
+    pub fn init(&mut self, dmabuf_formats: &[DrmFormat]) {
+        self.capture.init(dmabuf_formats);
+    }
+
+    /// The result of the callback for the newest frame, if any arrived since the last call.
+    pub fn receive(&mut self) -> Option<U> {
+        if let Some(callback) = self.fallback.as_mut() {
+            if let Some(frame) = self.capture.receive() {
+                callback(frame);
+            }
+        }
+        self.receiver.try_iter().last()
+    }
+}
+
+/// Set a frame callback on `inner` that hands each frame to `wrapper` together with
+/// `callback`, for wrappers that work on frames before the consumer sees them.
+/// Returns `callback` unchanged if `inner` does not support frame callbacks.
+pub(crate) fn wrap_frame_callback<W>(
+    inner: &mut dyn WlxCapture,
+    callback: FrameCallback,
+    mut wrapper: W,
+) -> Result<(), FrameCallback>
+where
+    W: FnMut(WlxFrame, &mut FrameCallback) + Send + 'static,
+{
+    // kept reachable from here, to hand it back if the backend refuses it
+    let callback = Arc::new(Mutex::new(Some(callback)));
+    let wrapped: FrameCallback = Box::new({
+        let callback = callback.clone();
+        move |frame| {
+            if let Some(next) = callback.lock().ok().as_mut().and_then(|c| c.as_mut()) {
+                wrapper(frame, next);
+            }
+        }
+    });
+    inner.set_frame_callback(wrapped).map_err(|_| {
+        let callback = callback.lock().ok().and_then(|mut c| c.take());
+        callback.unwrap_or_else(|| Box::new(|_| {}))
+    })
+}
+
+/// Implement the listed `WlxCapture` methods of a wrapper by calling the same method of
+/// the capture in `self.$field`, e.g. `forward_capture!(capture: init, pause, resume);`.
+macro_rules! forward_capture {
+    ($field:ident: $($method:ident),+ $(,)?) => {
+        $($crate::callback::forward_capture!(@$method $field);)+
+    };
+    (@init $field:ident) => {
+        fn init(&mut self, dmabuf_formats: &[$crate::frame::DrmFormat]) {
+            self.$field.init(dmabuf_formats);
+        }
+    };
+    (@is_ready $field:ident) => {
+        fn is_ready(&self) -> bool {
+            self.$field.is_ready()
+        }
+    };
+    (@supports_dmbuf $field:ident) => {
+        fn supports_dmbuf(&self) -> bool {
+            self.$field.supports_dmbuf()
+        }
+    };
+    (@caps $field:ident) => {
+        fn caps(&self) -> $crate::CaptureCaps {
+            self.$field.caps()
+        }
+    };
+    (@source_kind $field:ident) => {
+        fn source_kind(&self) -> $crate::frame::SourceKind {
+            self.$field.source_kind()
+        }
+    };
+    (@receive $field:ident) => {
+        fn receive(&mut self) -> Option<$crate::frame::WlxFrame> {
+            self.$field.receive()
+        }
+    };
+    (@pause $field:ident) => {
+        fn pause(&mut self) {
+            self.$field.pause();
+        }
+    };
+    (@resume $field:ident) => {
+        fn resume(&mut self) {
+            self.$field.resume();
+        }
+    };
+    (@request_new_frame $field:ident) => {
+        fn request_new_frame(&mut self) {
+            self.$field.request_new_frame();
+        }
+    };
+    (@last_activity $field:ident) => {
+        fn last_activity(&self) -> Option<u64> {
+            self.$field.last_activity()
+        }
+    };
+    (@next_frame_eta $field:ident) => {
+        fn next_frame_eta(&self) -> Option<u64> {
+            self.$field.next_frame_eta()
+        }
+    };
+    (@set_embedded_cursor $field:ident) => {
+        fn set_embedded_cursor(&mut self, embedded: bool) -> bool {
+            self.$field.set_embedded_cursor(embedded)
+        }
+    };
+    (@freeze $field:ident) => {
+        fn freeze(&mut self) {
+            self.$field.freeze();
+        }
+    };
+    (@unfreeze $field:ident) => {
+        fn unfreeze(&mut self) {
+            self.$field.unfreeze();
+        }
+    };
+}
+pub(crate) use forward_capture;
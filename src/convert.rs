@@ -0,0 +1,363 @@
+use crate::{
+    callback::{forward_capture, wrap_frame_callback},
+    frame::{
+        FourCC, FrameFormat, MemPtrFrame, WlxFrame, DRM_FORMAT_ABGR2101010, DRM_FORMAT_ABGR8888,
+        DRM_FORMAT_ARGB2101010, DRM_FORMAT_ARGB8888, DRM_FORMAT_BGRA8888, DRM_FORMAT_BGRX8888,
+        DRM_FORMAT_RGBA8888, DRM_FORMAT_RGBX8888, DRM_FORMAT_XBGR2101010, DRM_FORMAT_XBGR8888,
+        DRM_FORMAT_XRGB2101010, DRM_FORMAT_XRGB8888,
+    },
+    CaptureCaps, CopyCost, FrameCallback, WlxCapture,
+};
+
+/// Whether a 2101010 format stores red in the low bits, and whether it has alpha.
+fn layout_2101010(fourcc: u32) -> (bool, bool) {
+    let red_low = matches!(fourcc, DRM_FORMAT_ABGR2101010 | DRM_FORMAT_XBGR2101010);
+    let alpha = matches!(fourcc, DRM_FORMAT_ABGR2101010 | DRM_FORMAT_ARGB2101010);
+    (red_low, alpha)
+}
+
+/// Convert one pixel to RGBA8. Returns None if the fourcc is not supported.
+pub fn to_rgba(px: [u8; 4], fourcc: FourCC) -> Option<[u8; 4]> {
+    let rgba = match fourcc.value {
+        DRM_FORMAT_ARGB8888 => [px[2], px[1], px[0], px[3]],
+        DRM_FORMAT_XRGB8888 => [px[2], px[1], px[0], 0xff],
+        DRM_FORMAT_ABGR8888 => px,
+        DRM_FORMAT_XBGR8888 => [px[0], px[1], px[2], 0xff],
+        DRM_FORMAT_RGBA8888 => [px[3], px[2], px[1], px[0]],
+        DRM_FORMAT_RGBX8888 => [px[3], px[2], px[1], 0xff],
+        DRM_FORMAT_BGRA8888 => [px[1], px[2], px[3], px[0]],
+        DRM_FORMAT_BGRX8888 => [px[1], px[2], px[3], 0xff],
+        DRM_FORMAT_ABGR2101010
+        | DRM_FORMAT_XBGR2101010
+        | DRM_FORMAT_ARGB2101010
+        | DRM_FORMAT_XRGB2101010 => {
+            let (red_low, alpha) = layout_2101010(fourcc.value);
+            let v = u32::from_le_bytes(px);
+            let a = if alpha {
+                ((v >> 30) * 0x55) as u8
+            } else {
+                0xff
+            };
+            let (low, high) = ((v >> 2) as u8, (v >> 22) as u8);
+            let (r, b) = if red_low { (low, high) } else { (high, low) };
+            [r, (v >> 12) as u8, b, a]
+        }
+        _ => return None,
+    };
+    Some(rgba)
+}
+
+/// Convert one RGBA8 pixel to the given format. Returns None if the fourcc is not supported.
+pub fn from_rgba(rgba: [u8; 4], fourcc: FourCC) -> Option<[u8; 4]> {
+    let px = match fourcc.value {
+        DRM_FORMAT_ARGB8888 => [rgba[2], rgba[1], rgba[0], rgba[3]],
+        DRM_FORMAT_XRGB8888 => [rgba[2], rgba[1], rgba[0], 0xff],
+        DRM_FORMAT_ABGR8888 => rgba,
+        DRM_FORMAT_XBGR8888 => [rgba[0], rgba[1], rgba[2], 0xff],
+        DRM_FORMAT_RGBA8888 => [rgba[3], rgba[2], rgba[1], rgba[0]],
+        DRM_FORMAT_RGBX8888 => [0xff, rgba[2], rgba[1], rgba[0]],
+        DRM_FORMAT_BGRA8888 => [rgba[3], rgba[0], rgba[1], rgba[2]],
+        DRM_FORMAT_BGRX8888 => [0xff, rgba[0], rgba[1], rgba[2]],
+        DRM_FORMAT_ABGR2101010
+        | DRM_FORMAT_XBGR2101010
+        | DRM_FORMAT_ARGB2101010
+        | DRM_FORMAT_XRGB2101010 => {
+            let (red_low, alpha) = layout_2101010(fourcc.value);
+            // replicate the top bits so that 0xff maps to 0x3ff
+            let widen = |c: u8| ((c as u32) << 2) | ((c as u32) >> 6);
+            let a = if alpha { rgba[3] as u32 >> 6 } else { 0x3 };
+            let (low, high) = if red_low {
+                (rgba[0], rgba[2])
+            } else {
+                (rgba[2], rgba[0])
+            };
+            let v = widen(low) | (widen(rgba[1]) << 10) | (widen(high) << 20) | (a << 30);
+            v.to_le_bytes()
+        }
+        _ => return None,
+    };
+    Some(px)
+}
+
+/// Convert an image between the supported 32-bit formats.
+/// The result is tightly packed (stride == width * 4).
+/// Conversions involving 10-bit formats go through RGBA8 and lose the extra precision.
+pub fn convert(
+    src: &[u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    from: FourCC,
+    to: FourCC,
+) -> Option<Vec<u8>> {
+    let row_len = width as usize * 4;
+    if stride < row_len || src.len() < stride * height as usize {
+        return None;
+    }
+
+    let mut dst = Vec::with_capacity(row_len * height as usize);
+    // an empty image may come with a stride of 0
+    for row in src.chunks(stride.max(1)).take(height as usize) {
+        for px in row[..row_len].chunks_exact(4) {
+            let rgba = to_rgba([px[0], px[1], px[2], px[3]], from)?;
+            dst.extend_from_slice(&from_rgba(rgba, to)?);
+        }
+    }
+    Some(dst)
+}
+
+/// Convert a CPU-visible frame to the given format.
+/// The returned frame points into the returned buffer, which must outlive it.
+/// DMA-buf and mouse frames cannot be converted.
+pub fn convert_frame(frame: &WlxFrame, to: FourCC) -> Option<(MemPtrFrame, Vec<u8>)> {
+    let (format, meta, mouse, pixels) = match frame {
+        WlxFrame::MemFd(memfd) => {
+            let f = &memfd.format;
+            let pixels = memfd.with_mapped(|src| {
+                convert(src, f.width, f.height, memfd.stride(), f.fourcc, to)
+            })??;
+            (memfd.format, memfd.meta, None, pixels)
+        }
+        WlxFrame::MemPtr(memptr) => {
+            let f = &memptr.format;
+            if memptr.ptr == 0 || f.height == 0 {
+                return None;
+            }
+            let src = unsafe { std::slice::from_raw_parts(memptr.ptr as *const u8, memptr.size) };
+            let pixels = convert(src, f.width, f.height, memptr.stride(), f.fourcc, to)?;
+            (memptr.format, memptr.meta, memptr.mouse, pixels)
+        }
+        WlxFrame::Dmabuf(_) | WlxFrame::Mouse(_) => return None,
+    };
+
+    let converted = MemPtrFrame {
+        format: FrameFormat {
+            fourcc: to,
+            ..format
+        },
+        meta,
+        ptr: pixels.as_ptr() as _,
+        size: pixels.len(),
+        mouse,
+    };
+    Some((converted, pixels))
+}
+
+/// The fourcc of RGBA8 pixels in memory byte order: R, G, B, A.
+pub const RGBA_FOURCC: u32 = DRM_FORMAT_ABGR8888;
+
+/// Delivers every CPU-visible frame of a capture as RGBA8, for renderers that can only
+/// sample RGBA. Frames in another format are converted on the CPU and delivered as
+/// `WlxFrame::MemPtr`, pointing into a buffer that stays valid until the next `receive`.
+/// 10-bit formats lose their extra precision. DMA-buf frames are passed through unchanged;
+/// initialize without DMA-buf formats to receive CPU frames only.
+///
+/// To avoid the conversion where the backend can, ask it for RGBA in the first place,
+/// e.g. with `PipewireCapture::with_format_preference`.
+pub struct RgbaCapture {
+    capture: Box<dyn WlxCapture>,
+    /// Backing memory of the last converted frame.
+    pixels: Vec<u8>,
+    /// The last CPU frame had to be converted.
+    converting: bool,
+}
+
+impl RgbaCapture {
+    pub fn new(capture: Box<dyn WlxCapture>) -> Self {
+        Self {
+            capture,
+            pixels: Vec::new(),
+            converting: false,
+        }
+    }
+
+    pub fn capture(&self) -> &dyn WlxCapture {
+        self.capture.as_ref()
+    }
+}
+
+/// Convert a CPU frame that is not RGBA8. Returns the frame unchanged if it needs no
+/// conversion, and None if it cannot be converted.
+fn to_rgba_frame(frame: WlxFrame) -> Option<(WlxFrame, Option<Vec<u8>>)> {
+    match frame.format() {
+        Some(format)
+            if format.fourcc.value != RGBA_FOURCC && !matches!(frame, WlxFrame::Dmabuf(_)) =>
+        {
+            let (converted, pixels) = convert_frame(&frame, RGBA_FOURCC.into())?;
+            Some((WlxFrame::MemPtr(converted), Some(pixels)))
+        }
+        _ => Some((frame, None)),
+    }
+}
+
+impl WlxCapture for RgbaCapture {
+    forward_capture!(capture: init, is_ready, supports_dmbuf, source_kind, pause, resume);
+    forward_capture!(capture: request_new_frame, last_activity, next_frame_eta);
+    forward_capture!(capture: set_embedded_cursor, freeze, unfreeze);
+    fn caps(&self) -> CaptureCaps {
+        let mut caps = self.capture.caps();
+        if self.converting {
+            caps.cost = CopyCost::DoubleCopy;
+        }
+        caps
+    }
+    fn receive(&mut self) -> Option<WlxFrame> {
+        let frame = self.capture.receive()?;
+        if let WlxFrame::MemFd(_) | WlxFrame::MemPtr(_) = &frame {
+            self.converting = frame
+                .format()
+                .is_some_and(|f| f.fourcc.value != RGBA_FOURCC);
+        }
+        let Some((frame, pixels)) = to_rgba_frame(frame) else {
+            log::debug!("Dropping frame that could not be converted to RGBA");
+            return None;
+        };
+        if let Some(pixels) = pixels {
+            self.pixels = pixels;
+        }
+        Some(frame)
+    }
+    /// Frames are converted on the capture thread, before `callback` sees them.
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        wrap_frame_callback(self.capture.as_mut(), callback, |frame, next| {
+            // the converted pixels must outlive the callback
+            let Some((frame, _pixels)) = to_rgba_frame(frame) else {
+                log::debug!("Dropping frame that could not be converted to RGBA");
+                return;
+            };
+            next(frame);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::DRM_FORMAT_NV12;
+
+    const RGBA: [u8; 4] = [0x11, 0x22, 0x33, 0x44];
+
+    /// Every 32-bit format, with `RGBA` in its memory byte order and whether alpha is kept.
+    const FORMATS_8888: [(u32, [u8; 4], bool); 8] = [
+        (DRM_FORMAT_ARGB8888, [0x33, 0x22, 0x11, 0x44], true),
+        (DRM_FORMAT_XRGB8888, [0x33, 0x22, 0x11, 0xff], false),
+        (DRM_FORMAT_ABGR8888, [0x11, 0x22, 0x33, 0x44], true),
+        (DRM_FORMAT_XBGR8888, [0x11, 0x22, 0x33, 0xff], false),
+        (DRM_FORMAT_RGBA8888, [0x44, 0x33, 0x22, 0x11], true),
+        (DRM_FORMAT_RGBX8888, [0xff, 0x33, 0x22, 0x11], false),
+        (DRM_FORMAT_BGRA8888, [0x44, 0x11, 0x22, 0x33], true),
+        (DRM_FORMAT_BGRX8888, [0xff, 0x11, 0x22, 0x33], false),
+    ];
+
+    const FORMATS_2101010: [u32; 4] = [
+        DRM_FORMAT_ABGR2101010,
+        DRM_FORMAT_XBGR2101010,
+        DRM_FORMAT_ARGB2101010,
+        DRM_FORMAT_XRGB2101010,
+    ];
+
+    #[test]
+    fn formats_8888() {
+        for (fourcc, px, alpha) in FORMATS_8888 {
+            let a = if alpha { RGBA[3] } else { 0xff };
+            let expected = [RGBA[0], RGBA[1], RGBA[2], a];
+            assert_eq!(to_rgba(px, fourcc.into()), Some(expected), "{:#x}", fourcc);
+            assert_eq!(from_rgba(RGBA, fourcc.into()), Some(px), "{:#x}", fourcc);
+        }
+    }
+
+    #[test]
+    fn formats_2101010() {
+        // opaque red: all ten bits of red and the two bits of alpha set
+        let red = [
+            (DRM_FORMAT_ABGR2101010, 0xc000_03ff_u32),
+            (DRM_FORMAT_XBGR2101010, 0xc000_03ff),
+            (DRM_FORMAT_ARGB2101010, 0xfff0_0000),
+            (DRM_FORMAT_XRGB2101010, 0xfff0_0000),
+        ];
+        for (fourcc, v) in red {
+            let px = v.to_le_bytes();
+            assert_eq!(to_rgba(px, fourcc.into()), Some([0xff, 0, 0, 0xff]));
+            assert_eq!(from_rgba([0xff, 0, 0, 0xff], fourcc.into()), Some(px));
+        }
+
+        // the top bits of each channel survive a round trip
+        for fourcc in FORMATS_2101010 {
+            let px = from_rgba(RGBA, fourcc.into()).unwrap();
+            let rgba = to_rgba(px, fourcc.into()).unwrap();
+            assert_eq!(rgba[..3], RGBA[..3], "{:#x}", fourcc);
+        }
+        let px = from_rgba([0, 0, 0, 0x80], DRM_FORMAT_ARGB2101010.into()).unwrap();
+        assert_eq!(u32::from_le_bytes(px) >> 30, 0x2);
+        // formats without alpha always read as opaque
+        let px = 0x0000_0000_u32.to_le_bytes();
+        assert_eq!(
+            to_rgba(px, DRM_FORMAT_XRGB2101010.into()),
+            Some([0, 0, 0, 0xff])
+        );
+    }
+
+    #[test]
+    fn unsupported_formats() {
+        assert_eq!(to_rgba(RGBA, DRM_FORMAT_NV12.into()), None);
+        assert_eq!(from_rgba(RGBA, DRM_FORMAT_NV12.into()), None);
+        let src = [0; 16];
+        let (from, to) = (DRM_FORMAT_NV12.into(), RGBA_FOURCC.into());
+        assert_eq!(convert(&src, 2, 2, 8, from, to), None);
+    }
+
+    #[test]
+    fn padded_rows_are_packed() {
+        // 2x2 XRGB8888 with 4 bytes of padding per row, filled with garbage
+        let mut src = Vec::new();
+        for row in 0..2u8 {
+            src.extend_from_slice(&[0x33, 0x22, row, 0x00, 0x30, 0x20, row, 0x00]);
+            src.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        }
+        let (from, to) = (DRM_FORMAT_XRGB8888.into(), RGBA_FOURCC.into());
+        let dst = convert(&src, 2, 2, 12, from, to).unwrap();
+        assert_eq!(
+            dst,
+            [
+                0x00, 0x22, 0x33, 0xff, 0x00, 0x20, 0x30, 0xff, //
+                0x01, 0x22, 0x33, 0xff, 0x01, 0x20, 0x30, 0xff,
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_strides_are_rejected() {
+        let src = [0; 24];
+        let (from, to) = (DRM_FORMAT_XRGB8888.into(), RGBA_FOURCC.into());
+        // rows shorter than the width
+        assert_eq!(convert(&src, 2, 2, 4, from, to), None);
+        // memory ends before the last row
+        assert_eq!(convert(&src, 2, 3, 12, from, to), None);
+        assert_eq!(convert(&src, 2, 2, 12, from, to).map(|d| d.len()), Some(16));
+        assert_eq!(convert(&[], 0, 0, 0, from, to), Some(Vec::new()));
+    }
+
+    #[test]
+    fn memptr_stride_from_size() {
+        // 1x2 BGRX8888 with rows padded to 8 bytes
+        let src: [u8; 16] = [
+            0xff, 0x11, 0x22, 0x33, 0, 0, 0, 0, //
+            0xff, 0x44, 0x55, 0x66, 0, 0, 0, 0,
+        ];
+        let frame = WlxFrame::MemPtr(MemPtrFrame {
+            format: FrameFormat {
+                width: 1,
+                height: 2,
+                fourcc: DRM_FORMAT_BGRX8888.into(),
+                ..Default::default()
+            },
+            ptr: src.as_ptr() as _,
+            size: src.len(),
+            ..Default::default()
+        });
+        let (converted, pixels) = convert_frame(&frame, RGBA_FOURCC.into()).unwrap();
+        assert_eq!(converted.format.fourcc.value, RGBA_FOURCC);
+        assert_eq!((converted.ptr, converted.size), (pixels.as_ptr() as _, 8));
+        assert_eq!(pixels, [0x11, 0x22, 0x33, 0xff, 0x44, 0x55, 0x66, 0xff]);
+    }
+}
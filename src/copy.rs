@@ -0,0 +1,112 @@
+//! Copying of large frames on the CPU.
+//!
+//! Frames of 4K monitors and above are much larger than the CPU caches, so a plain
+//! `memcpy` mostly waits on memory and evicts everything else from the cache on the way.
+//! Above `LARGE_COPY_THRESHOLD`, rows are split among several threads, and on x86_64
+//! written with non-temporal stores, which bypass the cache.
+
+use std::{num::NonZeroUsize, thread};
+
+/// Copies of at least this many bytes use the large copy path. A 1440p frame is below.
+pub const LARGE_COPY_THRESHOLD: usize = 16 * 1024 * 1024;
+
+/// Upper bound on threads for one copy; more than this saturates memory bandwidth.
+const MAX_COPY_THREADS: usize = 4;
+
+/// Copy `height` rows of `row_len` bytes from `src`, where rows start every `src_stride`
+/// bytes, into `dst`, where they are packed tightly.
+/// Returns false without copying if either slice is too short.
+pub fn copy_rows(
+    src: &[u8],
+    src_stride: usize,
+    row_len: usize,
+    height: usize,
+    dst: &mut [u8],
+) -> bool {
+    if height == 0 || row_len == 0 {
+        return true;
+    }
+    if src_stride < row_len
+        || src.len() < src_stride * (height - 1) + row_len
+        || dst.len() < row_len * height
+    {
+        return false;
+    }
+    let dst = &mut dst[..row_len * height];
+
+    let large = dst.len() >= LARGE_COPY_THRESHOLD;
+    let threads = if large {
+        thread::available_parallelism()
+            .map_or(1, NonZeroUsize::get)
+            .min(MAX_COPY_THREADS)
+    } else {
+        1
+    };
+
+    if threads <= 1 {
+        copy_row_range(src, src_stride, row_len, dst, large);
+        return true;
+    }
+
+    let rows_per_thread = height.div_ceil(threads);
+    thread::scope(|scope| {
+        for (i, dst) in dst.chunks_mut(rows_per_thread * row_len).enumerate() {
+            let src = &src[i * rows_per_thread * src_stride..];
+            scope.spawn(move || copy_row_range(src, src_stride, row_len, dst, large));
+        }
+    });
+    true
+}
+
+/// Copy as many rows as `dst` holds.
+fn copy_row_range(src: &[u8], src_stride: usize, row_len: usize, dst: &mut [u8], large: bool) {
+    if src_stride == row_len {
+        copy_bytes(&src[..dst.len()], dst, large);
+    } else {
+        for (y, dst_row) in dst.chunks_exact_mut(row_len).enumerate() {
+            let start = y * src_stride;
+            copy_bytes(&src[start..start + row_len], dst_row, large);
+        }
+    }
+    #[cfg(target_arch = "x86_64")]
+    if large {
+        // make the streamed rows visible before the frame is handed to another thread
+        unsafe { std::arch::x86_64::_mm_sfence() };
+    }
+}
+
+fn copy_bytes(src: &[u8], dst: &mut [u8], large: bool) {
+    #[cfg(target_arch = "x86_64")]
+    if large {
+        stream_copy(src, dst);
+        return;
+    }
+    let _ = large;
+    dst.copy_from_slice(src);
+}
+
+/// Copy with non-temporal stores. SSE2 is part of the x86_64 baseline.
+#[cfg(target_arch = "x86_64")]
+fn stream_copy(src: &[u8], dst: &mut [u8]) {
+    use std::arch::x86_64::{__m128i, _mm_loadu_si128, _mm_stream_si128};
+
+    debug_assert_eq!(src.len(), dst.len());
+    // streaming stores need 16-byte aligned destinations
+    let head = dst.as_ptr().align_offset(16).min(dst.len());
+    dst[..head].copy_from_slice(&src[..head]);
+
+    let body = (dst.len() - head) / 16 * 16;
+    unsafe {
+        let mut s = src.as_ptr().add(head);
+        let mut d = dst.as_mut_ptr().add(head);
+        let end = d.add(body);
+        while d < end {
+            _mm_stream_si128(d as *mut __m128i, _mm_loadu_si128(s as *const __m128i));
+            s = s.add(16);
+            d = d.add(16);
+        }
+    }
+
+    let tail = head + body;
+    dst[tail..].copy_from_slice(&src[tail..]);
+}
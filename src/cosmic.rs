@@ -0,0 +1,219 @@
+//! Workspaces of the COSMIC desktop, listed with cosmic-workspace and captured with
+//! ext-image-copy-capture. See `WlxClient::workspaces`.
+//!
+//! COSMIC does not offer wlr-screencopy; its outputs are captured with
+//! `ImageCopyCapture::output` and its windows with `ImageCopyCapture::toplevel`.
+
+use std::sync::Arc;
+
+pub use cosmic_protocols::{
+    image_capture_source::v1::client::zcosmic_workspace_image_capture_source_manager_v1::ZcosmicWorkspaceImageCaptureSourceManagerV1,
+    workspace::v1::client::zcosmic_workspace_manager_v1::ZcosmicWorkspaceManagerV1,
+};
+
+use cosmic_protocols::workspace::v1::client::{
+    zcosmic_workspace_group_handle_v1::{self, ZcosmicWorkspaceGroupHandleV1},
+    zcosmic_workspace_handle_v1::{self, ZcosmicWorkspaceHandleV1},
+    zcosmic_workspace_manager_v1,
+};
+use wayland_client::{
+    event_created_child, protocol::wl_output::WlOutput, Connection, Dispatch, Proxy, QueueHandle,
+};
+use wayland_protocols::ext::image_capture_source::v1::client::ext_image_capture_source_v1::ExtImageCaptureSourceV1;
+
+use crate::{
+    ext_image_copy::{ImageCopyCapture, ImageCopySource},
+    wayland::WlxClient,
+};
+
+/// `zcosmic_workspace_handle_v1.state` value of the workspace shown on its outputs.
+const STATE_ACTIVE: u32 = 0;
+
+/// A workspace, as listed by cosmic-workspace.
+pub struct WlxWorkspace {
+    pub handle: ZcosmicWorkspaceHandleV1,
+    /// Unique among the current workspaces, but may be reused once a workspace is removed.
+    pub id: u32,
+    pub name: Arc<str>,
+    /// Currently shown on its outputs.
+    pub active: bool,
+    /// Outputs the workspace belongs to, by id in `WlxClient::outputs`.
+    pub outputs: Vec<u32>,
+    /// All properties have been received at least once.
+    pub done: bool,
+}
+
+/// Workspaces that share a set of outputs.
+pub(crate) struct WorkspaceGroup {
+    handle: ZcosmicWorkspaceGroupHandleV1,
+    outputs: Vec<WlOutput>,
+    workspaces: Vec<ZcosmicWorkspaceHandleV1>,
+}
+
+impl WlxClient {
+    pub fn workspace(&self, id: u32) -> Option<&WlxWorkspace> {
+        self.workspaces.iter().find(|w| w.id == id)
+    }
+}
+
+impl ImageCopyCapture {
+    /// Capture the COSMIC workspace with the given `WlxWorkspace::id`,
+    /// whether or not it is currently shown.
+    pub fn cosmic_workspace(wl: WlxClient, id: u32) -> Self {
+        Self::new(wl, ImageCopySource::CosmicWorkspace(id))
+    }
+}
+
+pub(crate) fn create_source(wl: &WlxClient, id: u32) -> Option<ExtImageCaptureSourceV1> {
+    let Some(source_mgr) = wl.maybe_cosmic_workspace_source_mgr.as_ref() else {
+        log::warn!("Compositor does not support capturing workspaces");
+        return None;
+    };
+    let Some(workspace) = wl.workspace(id) else {
+        log::warn!("Workspace {} not found", id);
+        return None;
+    };
+    Some(source_mgr.create_source(&workspace.handle, &wl.queue_handle, ()))
+}
+
+impl Dispatch<ZcosmicWorkspaceManagerV1, ()> for WlxClient {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZcosmicWorkspaceManagerV1,
+        event: <ZcosmicWorkspaceManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            zcosmic_workspace_manager_v1::Event::WorkspaceGroup { workspace_group } => {
+                state.workspace_groups.push(WorkspaceGroup {
+                    handle: workspace_group,
+                    outputs: Vec::new(),
+                    workspaces: Vec::new(),
+                });
+            }
+            zcosmic_workspace_manager_v1::Event::Done => {
+                // changes to groups and workspaces are applied atomically on done
+                for workspace in state.workspaces.iter_mut() {
+                    let group = state
+                        .workspace_groups
+                        .iter()
+                        .find(|g| g.workspaces.contains(&workspace.handle));
+                    workspace.outputs = group
+                        .iter()
+                        .flat_map(|g| g.outputs.iter())
+                        .filter_map(|wl_output| {
+                            state
+                                .outputs
+                                .values()
+                                .find(|o| &o.wl_output == wl_output)
+                                .map(|o| o.id)
+                        })
+                        .collect();
+                    workspace.done = true;
+                }
+            }
+            zcosmic_workspace_manager_v1::Event::Finished => {
+                state.maybe_cosmic_workspace_mgr = None;
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(WlxClient, ZcosmicWorkspaceManagerV1, [
+        zcosmic_workspace_manager_v1::EVT_WORKSPACE_GROUP_OPCODE => (ZcosmicWorkspaceGroupHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ZcosmicWorkspaceGroupHandleV1, ()> for WlxClient {
+    fn event(
+        state: &mut Self,
+        proxy: &ZcosmicWorkspaceGroupHandleV1,
+        event: <ZcosmicWorkspaceGroupHandleV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let zcosmic_workspace_group_handle_v1::Event::Remove = event {
+            state.workspace_groups.retain(|g| &g.handle != proxy);
+            proxy.destroy();
+            return;
+        }
+        let Some(group) = state
+            .workspace_groups
+            .iter_mut()
+            .find(|g| &g.handle == proxy)
+        else {
+            return;
+        };
+        match event {
+            zcosmic_workspace_group_handle_v1::Event::OutputEnter { output } => {
+                group.outputs.push(output);
+            }
+            zcosmic_workspace_group_handle_v1::Event::OutputLeave { output } => {
+                group.outputs.retain(|o| o != &output);
+            }
+            zcosmic_workspace_group_handle_v1::Event::Workspace { workspace } => {
+                group.workspaces.push(workspace.clone());
+                state.workspaces.push(WlxWorkspace {
+                    id: workspace.id().protocol_id(),
+                    handle: workspace,
+                    name: "".into(),
+                    active: false,
+                    outputs: Vec::new(),
+                    done: false,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(WlxClient, ZcosmicWorkspaceGroupHandleV1, [
+        zcosmic_workspace_group_handle_v1::EVT_WORKSPACE_OPCODE => (ZcosmicWorkspaceHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ZcosmicWorkspaceHandleV1, ()> for WlxClient {
+    fn event(
+        state: &mut Self,
+        proxy: &ZcosmicWorkspaceHandleV1,
+        event: <ZcosmicWorkspaceHandleV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let zcosmic_workspace_handle_v1::Event::Remove = event {
+            state.workspaces.retain(|w| &w.handle != proxy);
+            for group in state.workspace_groups.iter_mut() {
+                group.workspaces.retain(|w| w != proxy);
+            }
+            proxy.destroy();
+            return;
+        }
+        let Some(workspace) = state.workspaces.iter_mut().find(|w| &w.handle == proxy) else {
+            return;
+        };
+        match event {
+            zcosmic_workspace_handle_v1::Event::Name { name } => workspace.name = name.into(),
+            zcosmic_workspace_handle_v1::Event::State { state } => {
+                workspace.active = state
+                    .chunks_exact(4)
+                    .any(|s| u32::from_ne_bytes([s[0], s[1], s[2], s[3]]) == STATE_ACTIVE);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZcosmicWorkspaceImageCaptureSourceManagerV1, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZcosmicWorkspaceImageCaptureSourceManagerV1,
+        _event: <ZcosmicWorkspaceImageCaptureSourceManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
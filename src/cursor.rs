@@ -0,0 +1,310 @@
+//! Draws the cursor into CPU frames, for consumers that cannot composite it themselves,
+//! e.g. thumbnailers and streamers. See `CursorCapture`.
+
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    callback::{forward_capture, wrap_frame_callback},
+    convert::{from_rgba, to_rgba},
+    frame::{monotonic_ns, FrameFormat, FrameMeta, MemPtrFrame, MouseMeta, WlxFrame},
+    CaptureCaps, CopyCost, FrameCallback, WlxCapture,
+};
+
+/// A cursor bitmap in RGBA8 with straight alpha, tightly packed.
+#[derive(Debug, Clone)]
+pub struct CursorImage {
+    pub width: u32,
+    pub height: u32,
+    /// The pixel of the bitmap that is placed at the cursor position.
+    pub hotspot: (u32, u32),
+    pub rgba: Vec<u8>,
+}
+
+/// The default arrow: `X` is the black outline, `.` the white fill.
+const ARROW: [&str; 19] = [
+    "X",
+    "XX",
+    "X.X",
+    "X..X",
+    "X...X",
+    "X....X",
+    "X.....X",
+    "X......X",
+    "X.......X",
+    "X........X",
+    "X.........X",
+    "X......XXXXX",
+    "X...X..X",
+    "X..XX..X",
+    "X.X  X..X",
+    "XX   X..X",
+    "X     X..X",
+    "      X..X",
+    "       XX",
+];
+
+impl CursorImage {
+    /// A plain arrow pointer, for backends that report where the cursor is but not
+    /// what it looks like.
+    pub fn arrow() -> Self {
+        let width = ARROW.iter().map(|row| row.len()).max().unwrap_or(0) as u32;
+        let height = ARROW.len() as u32;
+        let mut rgba = vec![0u8; (width * height * 4) as usize];
+        for (y, row) in ARROW.iter().enumerate() {
+            for (x, c) in row.bytes().enumerate() {
+                let px = match c {
+                    b'X' => [0x00, 0x00, 0x00, 0xff],
+                    b'.' => [0xff, 0xff, 0xff, 0xff],
+                    _ => continue,
+                };
+                let i = (y * width as usize + x) * 4;
+                rgba[i..i + 4].copy_from_slice(&px);
+            }
+        }
+        Self {
+            width,
+            height,
+            hotspot: (0, 0),
+            rgba,
+        }
+    }
+}
+
+impl Default for CursorImage {
+    fn default() -> Self {
+        Self::arrow()
+    }
+}
+
+/// The last CPU frame as the backend delivered it, to draw the cursor into again
+/// when only the cursor moves.
+struct CleanFrame {
+    format: FrameFormat,
+    meta: FrameMeta,
+    stride: usize,
+    pixels: Vec<u8>,
+}
+
+struct CursorState {
+    image: CursorImage,
+    mouse: Option<MouseMeta>,
+    clean: Option<CleanFrame>,
+}
+
+impl CursorState {
+    /// Draw the cursor into a CPU frame, or into the last one again for a cursor-only
+    /// update. Returns the frame unchanged if there is nothing to draw into, and the
+    /// buffer the new frame points into otherwise.
+    fn composite(&mut self, frame: WlxFrame) -> (WlxFrame, Option<Vec<u8>>) {
+        let timestamp = match &frame {
+            WlxFrame::MemFd(memfd) => {
+                let f = &memfd.format;
+                let stride = memfd.stride();
+                let pixels = memfd.with_mapped(|src| src[..stride * f.height as usize].to_vec());
+                let Some(pixels) = pixels else {
+                    return (frame, None);
+                };
+                self.clean = Some(CleanFrame {
+                    format: memfd.format,
+                    meta: memfd.meta,
+                    stride,
+                    pixels,
+                });
+                None
+            }
+            WlxFrame::MemPtr(memptr) => {
+                let f = &memptr.format;
+                if memptr.ptr == 0 || f.height == 0 {
+                    return (frame, None);
+                }
+                let src =
+                    unsafe { std::slice::from_raw_parts(memptr.ptr as *const u8, memptr.size) };
+                self.clean = Some(CleanFrame {
+                    format: memptr.format,
+                    meta: memptr.meta,
+                    stride: memptr.stride(),
+                    pixels: src.to_vec(),
+                });
+                if memptr.mouse.is_some() {
+                    self.mouse = memptr.mouse;
+                }
+                None
+            }
+            WlxFrame::Mouse(mouse) => {
+                self.mouse = Some(*mouse);
+                Some(monotonic_ns())
+            }
+            WlxFrame::Dmabuf(_) => return (frame, None),
+        };
+
+        let Some(clean) = self.clean.as_ref() else {
+            return (frame, None);
+        };
+        if to_rgba([0; 4], clean.format.fourcc).is_none() {
+            log::debug!(
+                "Not drawing the cursor into a frame of format {}",
+                clean.format.fourcc
+            );
+            self.clean = None;
+            return (frame, None);
+        }
+
+        let mut pixels = clean.pixels.clone();
+        if let Some(mouse) = self.mouse {
+            draw_cursor(&mut pixels, clean, &self.image, mouse);
+        }
+        let meta = FrameMeta {
+            timestamp: timestamp.unwrap_or(clean.meta.timestamp),
+            duplicate: timestamp.is_none() && clean.meta.duplicate,
+            stale: timestamp.is_none() && clean.meta.stale,
+            ..clean.meta
+        };
+        let composited = MemPtrFrame {
+            format: clean.format,
+            meta,
+            ptr: pixels.as_ptr() as _,
+            size: pixels.len(),
+            mouse: self.mouse,
+        };
+        (WlxFrame::MemPtr(composited), Some(pixels))
+    }
+}
+
+/// Blend `image` into `pixels` at the cursor position, which is relative to the crop
+/// rectangle of the frame if it has one.
+fn draw_cursor(pixels: &mut [u8], clean: &CleanFrame, image: &CursorImage, mouse: MouseMeta) {
+    let f = &clean.format;
+    let (x0, y0, width, height) = match clean.meta.crop {
+        Some(crop) => (crop.x, crop.y, crop.width, crop.height),
+        None => (0, 0, f.width, f.height),
+    };
+    let left = x0 as i64 + (mouse.x * width as f32) as i64 - image.hotspot.0 as i64;
+    let top = y0 as i64 + (mouse.y * height as f32) as i64 - image.hotspot.1 as i64;
+
+    for cy in 0..image.height as i64 {
+        let y = top + cy;
+        if y < 0 || y >= f.height as i64 {
+            continue;
+        }
+        for cx in 0..image.width as i64 {
+            let x = left + cx;
+            if x < 0 || x >= f.width as i64 {
+                continue;
+            }
+            let c = (cy * image.width as i64 + cx) as usize * 4;
+            let Some(cursor) = image.rgba.get(c..c + 4) else {
+                return;
+            };
+            let alpha = cursor[3] as u32;
+            if alpha == 0 {
+                continue;
+            }
+            let i = y as usize * clean.stride + x as usize * 4;
+            let Some(dst) = pixels.get_mut(i..i + 4) else {
+                continue;
+            };
+            let Some(under) = to_rgba([dst[0], dst[1], dst[2], dst[3]], f.fourcc) else {
+                return;
+            };
+            let blend = |c: u8, u: u8| ((c as u32 * alpha + u as u32 * (255 - alpha)) / 255) as u8;
+            let rgba = [
+                blend(cursor[0], under[0]),
+                blend(cursor[1], under[1]),
+                blend(cursor[2], under[2]),
+                (alpha + under[3] as u32 * (255 - alpha) / 255) as u8,
+            ];
+            if let Some(px) = from_rgba(rgba, f.fourcc) {
+                dst.copy_from_slice(&px);
+            }
+        }
+    }
+}
+
+/// Draws the cursor into every CPU frame of a capture, for backends that report the
+/// cursor position separately. Frames are delivered as `WlxFrame::MemPtr`, pointing into
+/// a buffer that stays valid until the next `receive`. Cursor-only updates are turned into
+/// the last frame with the cursor drawn at its new position. DMA-buf frames are passed
+/// through unchanged; initialize without DMA-buf formats to receive CPU frames only.
+///
+/// Backends that can embed the cursor themselves should rather be asked to, with
+/// `set_embedded_cursor`. Where they do, the cursor is drawn twice.
+pub struct CursorCapture {
+    capture: Box<dyn WlxCapture>,
+    state: Arc<Mutex<CursorState>>,
+    /// Backing memory of the last composited frame.
+    pixels: Vec<u8>,
+    /// The last frame had to be copied to draw into.
+    copying: bool,
+}
+
+impl CursorCapture {
+    pub fn new(capture: Box<dyn WlxCapture>) -> Self {
+        Self {
+            capture,
+            state: Arc::new(Mutex::new(CursorState {
+                image: CursorImage::arrow(),
+                mouse: None,
+                clean: None,
+            })),
+            pixels: Vec::new(),
+            copying: false,
+        }
+    }
+
+    /// The bitmap to draw at the cursor position, instead of `CursorImage::arrow`.
+    pub fn with_image(self, image: CursorImage) -> Self {
+        self.set_image(image);
+        self
+    }
+
+    /// Change the bitmap, e.g. when the cursor shape changes. Applies from the next frame.
+    pub fn set_image(&self, image: CursorImage) {
+        if let Ok(mut state) = self.state.lock() {
+            state.image = image;
+        }
+    }
+
+    pub fn capture(&self) -> &dyn WlxCapture {
+        self.capture.as_ref()
+    }
+}
+
+impl WlxCapture for CursorCapture {
+    forward_capture!(capture: init, is_ready, supports_dmbuf, source_kind, pause, resume);
+    forward_capture!(capture: request_new_frame, last_activity, next_frame_eta);
+    forward_capture!(capture: set_embedded_cursor, freeze, unfreeze);
+    fn caps(&self) -> CaptureCaps {
+        let mut caps = self.capture.caps();
+        if self.copying {
+            caps.cost = CopyCost::DoubleCopy;
+            caps.embedded_cursor = true;
+        }
+        caps
+    }
+    fn receive(&mut self) -> Option<WlxFrame> {
+        let frame = self.capture.receive()?;
+        let Ok(mut state) = self.state.lock() else {
+            return Some(frame);
+        };
+        let (frame, pixels) = state.composite(frame);
+        if let Some(pixels) = pixels {
+            self.pixels = pixels;
+            self.copying = true;
+        } else if !matches!(frame, WlxFrame::Mouse(_)) {
+            self.copying = false;
+        }
+        Some(frame)
+    }
+    /// The cursor is drawn on the capture thread, before `callback` sees the frames.
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        let state = self.state.clone();
+        wrap_frame_callback(self.capture.as_mut(), callback, move |frame, next| {
+            // the composited pixels must outlive the callback
+            let (frame, _pixels) = match state.lock() {
+                Ok(mut state) => state.composite(frame),
+                Err(_) => (frame, None),
+            };
+            next(frame);
+        })
+    }
+}
@@ -0,0 +1,710 @@
+//! Capture with ext-image-copy-capture, of single windows on compositors that list
+//! their windows with ext-foreign-toplevel-list (see `WlxClient::toplevels`), of outputs
+//! on compositors without wlr-screencopy, such as COSMIC, and of COSMIC's workspaces.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        mpsc::{self, Sender, SyncSender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use wayland_client::{
+    protocol::{wl_output::Transform, wl_shm::Format},
+    Connection, Dispatch, Proxy, QueueHandle, WEnum,
+};
+use wayland_protocols::ext::{
+    image_capture_source::v1::client::ext_image_capture_source_v1::ExtImageCaptureSourceV1,
+    image_copy_capture::v1::client::{
+        ext_image_copy_capture_frame_v1::{self, ExtImageCopyCaptureFrameV1},
+        ext_image_copy_capture_manager_v1::Options,
+        ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
+    },
+};
+
+use crate::{
+    audit::{self, AuditEvent},
+    factory::{escape, unescape},
+    frame::{
+        monotonic_ns, Activity, DrmFormat, FrameFormat, FrameMeta, FramePlane, LastFrame,
+        MemFdFrame, SourceKind, WlxFrame,
+    },
+    wayland::{output_name, wl_transform_to_frame_transform, WlxClient, WlxToplevel},
+    wlr_screencopy::{create_shm_buffer, fourcc_from_wlshm, ready_timestamp, BufData},
+    BackendKind, CaptureCaps, CopyCost, FrameCallback, WlxCapture,
+};
+
+/// Buffer constraints announced by the compositor for a session.
+#[derive(Default)]
+struct Constraints {
+    size: (u32, u32),
+    shm_formats: Vec<Format>,
+    /// All constraints of the latest batch have arrived.
+    done: bool,
+    /// The source is gone or can no longer be captured.
+    stopped: bool,
+    /// Name of the source, for the audit log.
+    name: Option<Arc<str>>,
+}
+
+type SharedConstraints = Arc<Mutex<Constraints>>;
+
+struct Session {
+    proxy: ExtImageCopyCaptureSessionV1,
+    constraints: SharedConstraints,
+    paint_cursors: bool,
+}
+
+enum CopyEvent {
+    Transform(Transform),
+    /// Presentation time of the copied content, in CLOCK_MONOTONIC nanoseconds.
+    PresentationTime(u64),
+    Ready,
+    Failed,
+}
+
+/// What an `ImageCopyCapture` captures.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageCopySource {
+    /// A window, by its `WlxToplevel::identifier`.
+    Toplevel(Arc<str>),
+    /// An output, by its id in `WlxClient::outputs`.
+    Output(u32),
+    /// A COSMIC workspace, by its `cosmic::WlxWorkspace::id`.
+    #[cfg(feature = "cosmic")]
+    CosmicWorkspace(u32),
+}
+
+/// What identifies a source across compositor restarts, when window identifiers and
+/// workspace ids have all changed, e.g. to capture "the same" window again next time.
+/// Stored in a single string form through `Display` and `FromStr`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceIdentity {
+    /// Window titles are kept as a hash, so that stored settings do not reveal them.
+    Window {
+        app_id: String,
+        title_hash: u64,
+    },
+    Output {
+        name: String,
+    },
+    Workspace {
+        name: String,
+    },
+}
+
+impl SourceIdentity {
+    /// The identity of a source as it is now, or None if the source is gone.
+    pub fn of(wl: &WlxClient, source: &ImageCopySource) -> Option<Self> {
+        match source {
+            ImageCopySource::Toplevel(identifier) => {
+                let toplevel = wl.toplevel(identifier)?;
+                Some(SourceIdentity::Window {
+                    app_id: toplevel.app_id.to_string(),
+                    title_hash: title_hash(&toplevel.title),
+                })
+            }
+            ImageCopySource::Output(id) => Some(SourceIdentity::Output {
+                name: wl.outputs.get(*id)?.name.to_string(),
+            }),
+            #[cfg(feature = "cosmic")]
+            ImageCopySource::CosmicWorkspace(id) => Some(SourceIdentity::Workspace {
+                name: wl.workspace(*id)?.name.to_string(),
+            }),
+        }
+    }
+}
+
+/// Fields are written as `key=value` pairs separated by `;`, after the kind of source.
+impl fmt::Display for SourceIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceIdentity::Window { app_id, title_hash } => write!(
+                f,
+                "window;app_id={};title={:016x}",
+                escape(app_id),
+                title_hash
+            ),
+            SourceIdentity::Output { name } => write!(f, "output;name={}", escape(name)),
+            SourceIdentity::Workspace { name } => write!(f, "workspace;name={}", escape(name)),
+        }
+    }
+}
+
+impl std::str::FromStr for SourceIdentity {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(';');
+        let kind = fields.next().ok_or(())?;
+        let mut app_id = None;
+        let mut title_hash = None;
+        let mut name = None;
+        for field in fields {
+            let (key, value) = field.split_once('=').ok_or(())?;
+            let value = unescape(value).ok_or(())?;
+            match key {
+                "app_id" => app_id = Some(value),
+                "title" => title_hash = Some(u64::from_str_radix(&value, 16).map_err(|_| ())?),
+                "name" => name = Some(value),
+                _ => return Err(()),
+            }
+        }
+        match kind {
+            "window" => Ok(SourceIdentity::Window {
+                app_id: app_id.ok_or(())?,
+                title_hash: title_hash.ok_or(())?,
+            }),
+            "output" => Ok(SourceIdentity::Output {
+                name: name.ok_or(())?,
+            }),
+            "workspace" => Ok(SourceIdentity::Workspace {
+                name: name.ok_or(())?,
+            }),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Hash of a window title for `SourceIdentity::Window`. FNV-1a, which unlike the
+/// standard library's hasher stays the same across builds.
+pub fn title_hash(title: &str) -> u64 {
+    title.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Finds the current source for a stored `SourceIdentity`.
+/// Implement this to change how loosely windows are matched, e.g. by app alone.
+pub trait SourceMatcher {
+    fn find(&self, wl: &WlxClient, identity: &SourceIdentity) -> Option<ImageCopySource>;
+}
+
+/// Matches outputs and workspaces by name. A window matches by app and title, or by
+/// app alone if it is the app's only window, as titles tend to change while it is open.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultMatcher;
+
+impl SourceMatcher for DefaultMatcher {
+    fn find(&self, wl: &WlxClient, identity: &SourceIdentity) -> Option<ImageCopySource> {
+        match identity {
+            SourceIdentity::Window {
+                app_id,
+                title_hash: hash,
+            } => {
+                let windows: Vec<&WlxToplevel> = wl
+                    .toplevels
+                    .iter()
+                    .filter(|t| t.done && *t.app_id == **app_id)
+                    .collect();
+                let toplevel = windows.iter().find(|t| title_hash(&t.title) == *hash).or(
+                    match windows.as_slice() {
+                        [only] => Some(only),
+                        _ => None,
+                    },
+                )?;
+                Some(ImageCopySource::Toplevel(toplevel.identifier.clone()))
+            }
+            SourceIdentity::Output { name } => wl
+                .outputs
+                .values()
+                .find(|o| *o.name == **name)
+                .map(|o| ImageCopySource::Output(o.id)),
+            #[cfg(feature = "cosmic")]
+            SourceIdentity::Workspace { name } => wl
+                .workspaces
+                .iter()
+                .find(|w| w.done && *w.name == **name)
+                .map(|w| ImageCopySource::CosmicWorkspace(w.id)),
+            #[cfg(not(feature = "cosmic"))]
+            SourceIdentity::Workspace { .. } => None,
+        }
+    }
+}
+
+/// Captures one source with ext-image-copy-capture.
+/// Frames are delivered as `WlxFrame::MemFd`; those of windows change size with the window.
+pub struct ImageCopyCapture {
+    source: ImageCopySource,
+    name: Arc<str>,
+    identity: Option<SourceIdentity>,
+    wl: Option<Box<WlxClient>>,
+    handle: Option<JoinHandle<Box<WlxClient>>>,
+    sender: Option<Sender<(WlxFrame, BufData)>>,
+    receiver: Option<mpsc::Receiver<(WlxFrame, BufData)>>,
+    buffers: VecDeque<BufData>,
+    last_frame: LastFrame,
+    frame_callback: Option<Arc<Mutex<FrameCallback>>>,
+    session: Option<Session>,
+    overlay_cursor: bool,
+    /// Found again by `restore`, for the audit log.
+    restored: bool,
+    activity: Activity,
+}
+
+impl ImageCopyCapture {
+    pub fn new(wl: WlxClient, source: ImageCopySource) -> Self {
+        let name = match &source {
+            ImageCopySource::Toplevel(identifier) => identifier.clone(),
+            ImageCopySource::Output(id) => output_name(&wl, *id),
+            #[cfg(feature = "cosmic")]
+            ImageCopySource::CosmicWorkspace(id) => wl
+                .workspace(*id)
+                .map_or_else(|| "".into(), |w| w.name.clone()),
+        };
+        Self {
+            identity: SourceIdentity::of(&wl, &source),
+            source,
+            name,
+            activity: wl.activity.clone(),
+            wl: Some(Box::new(wl)),
+            handle: None,
+            sender: None,
+            receiver: None,
+            buffers: VecDeque::with_capacity(2),
+            last_frame: LastFrame::default(),
+            frame_callback: None,
+            session: None,
+            overlay_cursor: true,
+            restored: false,
+        }
+    }
+
+    /// Capture the window with the given `WlxToplevel::identifier`.
+    pub fn toplevel(wl: WlxClient, identifier: &str) -> Self {
+        Self::new(wl, ImageCopySource::Toplevel(identifier.into()))
+    }
+
+    /// Capture the output with the given id in `WlxClient::outputs`.
+    pub fn output(wl: WlxClient, output_id: u32) -> Self {
+        Self::new(wl, ImageCopySource::Output(output_id))
+    }
+
+    /// Capture the source that `identity` was taken from, as found by `matcher`,
+    /// or None if no current source matches.
+    pub fn restore(
+        wl: WlxClient,
+        identity: &SourceIdentity,
+        matcher: &dyn SourceMatcher,
+    ) -> Option<Self> {
+        let source = matcher.find(&wl, identity)?;
+        let mut capture = Self::new(wl, source);
+        capture.restored = true;
+        Some(capture)
+    }
+
+    pub fn source(&self) -> &ImageCopySource {
+        &self.source
+    }
+
+    /// What to store in order to `restore` this capture after the compositor restarts.
+    /// None if the source was already gone when the capture was created.
+    pub fn identity(&self) -> Option<&SourceIdentity> {
+        self.identity.as_ref()
+    }
+
+    /// The source is gone, e.g. the window was closed, or the compositor stopped the
+    /// capture. No more frames will arrive.
+    pub fn stopped(&self) -> bool {
+        self.session
+            .as_ref()
+            .and_then(|s| s.constraints.lock().ok().map(|c| c.stopped))
+            .unwrap_or(false)
+    }
+
+    fn create_source(&self, wl: &WlxClient) -> Option<ExtImageCaptureSourceV1> {
+        let qh = &wl.queue_handle;
+        match &self.source {
+            ImageCopySource::Toplevel(identifier) => {
+                let Some(source_mgr) = wl.maybe_toplevel_source_mgr.as_ref() else {
+                    log::warn!("Compositor does not support capturing windows");
+                    return None;
+                };
+                let Some(toplevel) = wl.toplevel(identifier) else {
+                    log::warn!("{}: window not found", self.name);
+                    return None;
+                };
+                Some(source_mgr.create_source(&toplevel.handle, qh, ()))
+            }
+            ImageCopySource::Output(id) => {
+                let Some(source_mgr) = wl.maybe_output_source_mgr.as_ref() else {
+                    log::warn!("Compositor does not support capturing outputs");
+                    return None;
+                };
+                let Some(output) = wl.outputs.get(*id) else {
+                    log::warn!("{}: output not found", self.name);
+                    return None;
+                };
+                Some(source_mgr.create_source(&output.wl_output, qh, ()))
+            }
+            #[cfg(feature = "cosmic")]
+            ImageCopySource::CosmicWorkspace(id) => crate::cosmic::create_source(wl, *id),
+        }
+    }
+
+    fn create_session(&self, wl: &WlxClient) -> Option<Session> {
+        let Some(copy_mgr) = wl.maybe_image_copy_mgr.as_ref() else {
+            log::warn!("Compositor does not support ext-image-copy-capture");
+            return None;
+        };
+        let Some(source) = self.create_source(wl) else {
+            audit::record(
+                AuditEvent::Failed,
+                BackendKind::ExtImageCopy,
+                &*self.name,
+                Some("source not available".into()),
+            );
+            return None;
+        };
+        let options = if self.overlay_cursor {
+            Options::PaintCursors
+        } else {
+            Options::empty()
+        };
+        let constraints = SharedConstraints::new(Mutex::new(Constraints {
+            name: Some(self.name.clone()),
+            ..Default::default()
+        }));
+        let proxy =
+            copy_mgr.create_session(&source, options, &wl.queue_handle, constraints.clone());
+        // the session keeps capturing without its source
+        source.destroy();
+        let event = if self.restored {
+            AuditEvent::Restored
+        } else {
+            AuditEvent::Created
+        };
+        audit::record(event, BackendKind::ExtImageCopy, &*self.name, None);
+        Some(Session {
+            proxy,
+            constraints,
+            paint_cursors: self.overlay_cursor,
+        })
+    }
+}
+
+impl WlxCapture for ImageCopyCapture {
+    fn init(&mut self, _: &[DrmFormat]) {
+        debug_assert!(self.wl.is_some());
+
+        let (tx, rx) = mpsc::channel();
+        self.sender = Some(tx);
+        self.receiver = Some(rx);
+    }
+    fn is_ready(&self) -> bool {
+        self.receiver.is_some()
+    }
+    fn supports_dmbuf(&self) -> bool {
+        false
+    }
+    fn caps(&self) -> CaptureCaps {
+        CaptureCaps {
+            dmabuf: false,
+            cost: CopyCost::SingleCopy,
+            embedded_cursor: self.overlay_cursor,
+            per_seat_cursor: false,
+        }
+    }
+    fn source_kind(&self) -> SourceKind {
+        match self.source {
+            ImageCopySource::Toplevel(_) => SourceKind::Window,
+            _ => SourceKind::Monitor,
+        }
+    }
+    fn receive(&mut self) -> Option<WlxFrame> {
+        if self.last_frame.frozen {
+            return self.last_frame.duplicate();
+        }
+        if let Some(rx) = self.receiver.as_ref() {
+            if let Some((frame, data)) = rx.try_iter().last() {
+                while self.buffers.len() >= 2 {
+                    self.buffers.pop_front();
+                }
+                self.buffers.push_back(data);
+                return self.last_frame.update(Some(frame));
+            }
+        }
+        self.last_frame.take_stale()
+    }
+    fn pause(&mut self) {}
+    fn resume(&mut self) {
+        if self.sender.is_none() {
+            return;
+        }
+        self.receive(); // clear old frames
+        self.last_frame.mark_resumed();
+        while self.buffers.len() > 1 {
+            self.buffers.pop_front();
+        }
+        self.request_new_frame();
+    }
+    fn request_new_frame(&mut self) {
+        if self.last_frame.frozen {
+            return;
+        }
+        if let Some(handle) = self.handle.take() {
+            if handle.is_finished() {
+                self.wl = Some(handle.join().unwrap()); // safe to unwrap because we checked is_finished
+            } else {
+                self.handle = Some(handle);
+                return;
+            }
+        }
+
+        let Some(wl) = self.wl.take() else {
+            return;
+        };
+        if wl.error().is_some() || self.stopped() {
+            self.wl = Some(wl);
+            return;
+        }
+
+        // the cursor option can only be set when the session is created
+        if let Some(session) = self.session.take() {
+            if session.paint_cursors == self.overlay_cursor {
+                self.session = Some(session);
+            } else {
+                session.proxy.destroy();
+            }
+        }
+        if self.session.is_none() {
+            self.session = self.create_session(&wl);
+        }
+        let Some(session) = self.session.as_ref() else {
+            self.wl = Some(wl);
+            return;
+        };
+
+        self.handle = Some(std::thread::spawn({
+            let sender = self
+                .sender
+                .clone()
+                .expect("must call init once before request_new_frame");
+            let name = self.name.clone();
+            let kind = self.source_kind();
+            let proxy = session.proxy.clone();
+            let constraints = session.constraints.clone();
+            let callback = self.frame_callback.clone();
+            move || request_frame(wl, (name, kind), (proxy, constraints), sender, callback)
+        }));
+    }
+    fn set_embedded_cursor(&mut self, embedded: bool) -> bool {
+        self.overlay_cursor = embedded;
+        true
+    }
+    fn last_activity(&self) -> Option<u64> {
+        self.activity.get()
+    }
+    fn freeze(&mut self) {
+        self.last_frame.freeze();
+    }
+    fn unfreeze(&mut self) {
+        self.last_frame.frozen = false;
+        self.request_new_frame();
+    }
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        self.frame_callback = Some(Arc::new(Mutex::new(callback)));
+        Ok(())
+    }
+}
+
+/// Copy the source into a new shm buffer, once the session's buffer constraints are known.
+fn request_frame(
+    client: Box<WlxClient>,
+    (name, kind): (Arc<str>, SourceKind),
+    (session, constraints): (ExtImageCopyCaptureSessionV1, SharedConstraints),
+    sender: Sender<(WlxFrame, BufData)>,
+    callback: Option<Arc<Mutex<FrameCallback>>>,
+) -> Box<WlxClient> {
+    let mut client = client;
+
+    // constraints follow the creation of the session, and again whenever they change
+    let (size, shm_format) = loop {
+        if let Ok(c) = constraints.lock() {
+            if c.stopped {
+                log::info!("{}: capture stopped", name);
+                return client;
+            }
+            if c.done {
+                let format = c
+                    .shm_formats
+                    .iter()
+                    .copied()
+                    .find(|f| fourcc_from_wlshm(*f).is_some());
+                break (c.size, format);
+            }
+        }
+        client.dispatch();
+        if client.error().is_some() {
+            return client;
+        }
+    };
+    let Some((shm_format, fourcc)) = shm_format.and_then(|f| Some((f, fourcc_from_wlshm(f)?)))
+    else {
+        log::warn!("{}: no supported shm format offered", name);
+        return client;
+    };
+
+    let (width, height) = size;
+    let stride = width * 4;
+    let Some(data) = create_shm_buffer(&client, shm_format, size, stride, &client.queue_handle)
+    else {
+        log::warn!("{}: failed to create shared memory", name);
+        return client;
+    };
+
+    let (tx, rx) = mpsc::sync_channel::<CopyEvent>(16);
+    let proxy = session.create_frame(&client.queue_handle, tx);
+    proxy.attach_buffer(&data.wl_buffer);
+    proxy.damage_buffer(0, 0, width as _, height as _);
+    proxy.capture();
+
+    let mut frame = MemFdFrame {
+        format: FrameFormat {
+            width,
+            height,
+            fourcc,
+            ..Default::default()
+        },
+        plane: FramePlane {
+            fd: Some(data.fd),
+            offset: 0,
+            stride: stride as _,
+            size: stride * height,
+        },
+        meta: FrameMeta {
+            source: kind,
+            ..Default::default()
+        },
+    };
+
+    loop {
+        client.dispatch();
+        if client.error().is_some() {
+            return client;
+        }
+        for event in rx.try_iter() {
+            match event {
+                CopyEvent::Transform(transform) => {
+                    frame.format.transform = wl_transform_to_frame_transform(transform);
+                }
+                CopyEvent::PresentationTime(timestamp) => frame.meta.timestamp = timestamp,
+                CopyEvent::Ready => {
+                    if frame.meta.timestamp == 0 {
+                        frame.meta.timestamp = monotonic_ns();
+                    }
+                    log::trace!("{}: Frame ready", name);
+                    let frame = WlxFrame::MemFd(frame);
+                    if let Some(callback) = callback.as_ref() {
+                        // the buffer is released once the callback returns
+                        if let Ok(mut callback) = callback.lock() {
+                            callback(frame);
+                        }
+                    } else {
+                        let _ = sender.send((frame, data));
+                    }
+                    return client;
+                }
+                CopyEvent::Failed => {
+                    log::trace!("{}: Frame failed", name);
+                    return client;
+                }
+            }
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureSessionV1, SharedConstraints> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtImageCopyCaptureSessionV1,
+        event: <ExtImageCopyCaptureSessionV1 as Proxy>::Event,
+        data: &SharedConstraints,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let Ok(mut constraints) = data.lock() else {
+            return;
+        };
+        match event {
+            ext_image_copy_capture_session_v1::Event::Done => constraints.done = true,
+            ext_image_copy_capture_session_v1::Event::Stopped => {
+                if !constraints.stopped {
+                    let name = constraints.name.as_deref().unwrap_or_default();
+                    audit::record(AuditEvent::Revoked, BackendKind::ExtImageCopy, name, None);
+                }
+                constraints.stopped = true;
+            }
+            event => {
+                // the first constraint after done starts a new batch
+                if constraints.done {
+                    constraints.done = false;
+                    constraints.shm_formats.clear();
+                }
+                match event {
+                    ext_image_copy_capture_session_v1::Event::BufferSize { width, height } => {
+                        constraints.size = (width, height);
+                    }
+                    ext_image_copy_capture_session_v1::Event::ShmFormat {
+                        format: WEnum::Value(format),
+                    } => constraints.shm_formats.push(format),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureFrameV1, SyncSender<CopyEvent>> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        proxy: &ExtImageCopyCaptureFrameV1,
+        event: <ExtImageCopyCaptureFrameV1 as Proxy>::Event,
+        data: &SyncSender<CopyEvent>,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_image_copy_capture_frame_v1::Event::Transform {
+                transform: WEnum::Value(transform),
+            } => {
+                let _ = data.send(CopyEvent::Transform(transform));
+            }
+            ext_image_copy_capture_frame_v1::Event::PresentationTime {
+                tv_sec_hi,
+                tv_sec_lo,
+                tv_nsec,
+            } => {
+                let timestamp = ready_timestamp(tv_sec_hi, tv_sec_lo, tv_nsec);
+                let _ = data.send(CopyEvent::PresentationTime(timestamp));
+            }
+            ext_image_copy_capture_frame_v1::Event::Ready => {
+                let _ = data.send(CopyEvent::Ready);
+                proxy.destroy();
+            }
+            ext_image_copy_capture_frame_v1::Event::Failed { reason } => {
+                log::debug!("Image copy failed: {:?}", reason);
+                let _ = data.send(CopyEvent::Failed);
+                proxy.destroy();
+            }
+            _ => {}
+        }
+    }
+}
+
+// Plumbing below
+
+impl Dispatch<ExtImageCaptureSourceV1, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtImageCaptureSourceV1,
+        _event: <ExtImageCaptureSourceV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
@@ -0,0 +1,560 @@
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    frame::{DrmFormat, WlxFrame},
+    BackendKind, WlxCapture,
+};
+
+#[cfg(feature = "kms")]
+use crate::kms::KmsCapture;
+#[cfg(feature = "pipewire")]
+use crate::pipewire::PipewireCapture;
+#[cfg(feature = "weston")]
+use crate::weston::WestonCapture;
+#[cfg(feature = "xshm")]
+use crate::xshm::XshmCapture;
+#[cfg(feature = "wlr")]
+use crate::{
+    ext_image_copy::ImageCopyCapture, wayland::WlxClient, wlr_dmabuf::WlrDmabufCapture,
+    wlr_screencopy::WlrScreencopyCapture,
+};
+
+/// User-supplied persistence for learned backend preferences.
+pub trait PreferenceStorage {
+    fn load(&mut self, key: &str) -> Option<String>;
+    fn store(&mut self, key: &str, value: &str);
+}
+
+/// What has been observed about a backend for a given key.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BackendRecord {
+    pub attempts: u32,
+    pub failures: u32,
+    /// Average time from init until the first frame, over successful attempts.
+    pub first_frame_ns: u64,
+}
+
+impl BackendRecord {
+    fn successes(&self) -> u32 {
+        self.attempts.saturating_sub(self.failures)
+    }
+}
+
+/// Creates captures by trying backends in the order that has worked best before,
+/// measuring first-frame latency and failures as it goes.
+pub struct CaptureFactory<S: PreferenceStorage> {
+    storage: S,
+    /// How long to wait for the first frame before a backend counts as failed.
+    pub timeout: Duration,
+    pub dmabuf_formats: Vec<DrmFormat>,
+}
+
+/// Everything needed to bring back a capture in a later session,
+/// in a single string form for "remember my screens" settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureDescriptor {
+    pub kind: BackendKind,
+    /// Output name, e.g. `DP-1`. For PipeWire, the name given to the capture.
+    pub output: String,
+    /// PipeWire node id. Only valid within one portal session.
+    pub node_id: Option<u32>,
+    /// Portal restore token, for recreating the session that provides `node_id`.
+    pub restore_token: Option<String>,
+    /// Settings, stored along with the rest. `make_capture` applies these:
+    /// - `region=x,y,width,height`: `WlrScreencopyCapture::with_region`
+    /// - `compact=true`: `WlrScreencopyCapture::with_compact_stride`
+    /// - `memory_limit=<bytes>`: `with_memory_limit` of screencopy and PipeWire captures
+    /// - `yuv=true`: `PipewireCapture::with_yuv_formats`
+    /// - `damage=true`: `XshmCapture::with_damage`
+    /// - `mouse_poll_ms=<ms>`: `XshmCapture::with_mouse_poll`
+    ///
+    /// Other keys are left to the consumer.
+    pub options: Vec<(String, String)>,
+}
+
+impl CaptureDescriptor {
+    pub fn new(kind: BackendKind, output: &str) -> Self {
+        Self {
+            kind,
+            output: output.into(),
+            node_id: None,
+            restore_token: None,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn option(&self, key: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Set an option, replacing any previous value.
+    pub fn set_option(&mut self, key: &str, value: impl ToString) {
+        self.options.retain(|(k, _)| k != key);
+        self.options.push((key.into(), value.to_string()));
+    }
+
+    /// An option parsed as `T`, or None if absent or malformed.
+    #[allow(dead_code)] // unused without the backends that take options
+    fn parsed_option<T: FromStr>(&self, key: &str) -> Option<T> {
+        let value = self.option(key)?;
+        let parsed = value.parse().ok();
+        if parsed.is_none() {
+            log::warn!("Ignoring malformed capture option {}={}", key, value);
+        }
+        parsed
+    }
+
+    /// Construct an uninitialized capture for this descriptor, with the options it carries,
+    /// or None if the output is gone or the backend was not compiled in.
+    /// PipeWire node ids change between sessions: restore the portal session with
+    /// `restore_token` and update `node_id` before calling this.
+    pub fn make_capture(&self) -> Option<Box<dyn WlxCapture>> {
+        match self.kind {
+            #[cfg(feature = "pipewire")]
+            BackendKind::Pipewire => {
+                let mut capture = PipewireCapture::new(self.output.as_str().into(), self.node_id?);
+                if let Some(bytes) = self.parsed_option("memory_limit") {
+                    capture = capture.with_memory_limit(bytes);
+                }
+                if self.parsed_option("yuv") == Some(true) {
+                    capture = capture.with_yuv_formats();
+                }
+                Some(Box::new(capture))
+            }
+            #[cfg(feature = "wlr")]
+            BackendKind::WlrDmabuf => {
+                let (wl, id) = self.find_wayland_output()?;
+                Some(Box::new(WlrDmabufCapture::new(wl, id)))
+            }
+            #[cfg(feature = "wlr")]
+            BackendKind::WlrScreencopy => {
+                let (wl, id) = self.find_wayland_output()?;
+                let mut capture = WlrScreencopyCapture::new(wl, id);
+                if let Some(region) = self.option("region").and_then(parse_region) {
+                    let (x, y, width, height) = region;
+                    capture = capture.with_region(x, y, width, height);
+                }
+                if self.parsed_option("compact") == Some(true) {
+                    capture = capture.with_compact_stride();
+                }
+                if let Some(bytes) = self.parsed_option("memory_limit") {
+                    capture = capture.with_memory_limit(bytes);
+                }
+                Some(Box::new(capture))
+            }
+            #[cfg(feature = "wlr")]
+            BackendKind::ExtImageCopy => {
+                let (wl, id) = self.find_wayland_output()?;
+                Some(Box::new(ImageCopyCapture::output(wl, id)))
+            }
+            #[cfg(feature = "weston")]
+            BackendKind::Weston => {
+                let (wl, id) = self.find_wayland_output()?;
+                Some(Box::new(WestonCapture::new(wl, id)))
+            }
+            #[cfg(feature = "xshm")]
+            BackendKind::Xshm => {
+                let screen = XshmCapture::get_monitors()
+                    .ok()?
+                    .into_iter()
+                    .find(|s| *s.name == *self.output)?;
+                let mut capture = XshmCapture::new(screen);
+                if self.parsed_option("damage") == Some(true) {
+                    capture = capture.with_damage();
+                }
+                if let Some(ms) = self.parsed_option("mouse_poll_ms") {
+                    capture = capture.with_mouse_poll(Duration::from_millis(ms));
+                }
+                Some(Box::new(capture))
+            }
+            #[cfg(feature = "kms")]
+            BackendKind::Kms => {
+                let output = KmsCapture::get_outputs()
+                    .ok()?
+                    .into_iter()
+                    .find(|o| *o.name == *self.output)?;
+                Some(Box::new(KmsCapture::new(output)))
+            }
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "wlr")]
+    fn find_wayland_output(&self) -> Option<(WlxClient, u32)> {
+        let wl = WlxClient::new()?;
+        let id = wl
+            .outputs
+            .values()
+            .find(|o| *o.name == *self.output)
+            .map(|o| o.id)?;
+        Some((wl, id))
+    }
+}
+
+/// Fields are written as `key=value` pairs separated by `;`, with options prefixed by `opt.`.
+impl fmt::Display for CaptureDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "kind={};output={}",
+            self.kind.as_str(),
+            escape(&self.output)
+        )?;
+        if let Some(node_id) = self.node_id {
+            write!(f, ";node={}", node_id)?;
+        }
+        if let Some(token) = self.restore_token.as_ref() {
+            write!(f, ";token={}", escape(token))?;
+        }
+        for (key, value) in self.options.iter() {
+            write!(f, ";opt.{}={}", escape(key), escape(value))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for CaptureDescriptor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut kind = None;
+        let mut output = None;
+        let mut descriptor = CaptureDescriptor::new(BackendKind::Xshm, "");
+
+        for field in s.split(';') {
+            let (key, value) = field.split_once('=').ok_or(())?;
+            let value = unescape(value).ok_or(())?;
+            match key {
+                "kind" => kind = Some(value.parse()?),
+                "output" => output = Some(value),
+                "node" => descriptor.node_id = Some(value.parse().map_err(|_| ())?),
+                "token" => descriptor.restore_token = Some(value),
+                _ => {
+                    let key = key.strip_prefix("opt.").ok_or(())?;
+                    descriptor.options.push((unescape(key).ok_or(())?, value));
+                }
+            }
+        }
+
+        descriptor.kind = kind.ok_or(())?;
+        descriptor.output = output.ok_or(())?;
+        Ok(descriptor)
+    }
+}
+
+/// `x,y,width,height`, as written for the `region` option.
+#[allow(dead_code)] // unused without the wlr backend
+fn parse_region(s: &str) -> Option<(i32, i32, i32, i32)> {
+    let mut parts = s.split(',').map(|p| p.trim().parse().ok());
+    let region = (
+        parts.next()??,
+        parts.next()??,
+        parts.next()??,
+        parts.next()??,
+    );
+    parts.next().is_none().then_some(region)
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace(';', "%3B")
+        .replace('=', "%3D")
+}
+
+pub(crate) fn unescape(s: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            out.push(b);
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+impl<S: PreferenceStorage> CaptureFactory<S> {
+    pub fn new(storage: S, dmabuf_formats: Vec<DrmFormat>) -> Self {
+        Self {
+            storage,
+            timeout: Duration::from_secs(2),
+            dmabuf_formats,
+        }
+    }
+
+    pub fn records(&mut self, key: &str) -> Vec<(BackendKind, BackendRecord)> {
+        self.storage
+            .load(key)
+            .map(|s| parse_records(&s))
+            .unwrap_or_default()
+    }
+
+    /// Order `candidates` by what has been learned for `key`:
+    /// backends that worked come first (fastest first), then untried ones
+    /// in the given order, then ones that never worked.
+    pub fn preferred_order(&mut self, key: &str, candidates: &[BackendKind]) -> Vec<BackendKind> {
+        let records = self.records(key);
+        let record = |kind: &BackendKind| {
+            records
+                .iter()
+                .find(|(k, _)| k == kind)
+                .map(|(_, r)| *r)
+                .unwrap_or_default()
+        };
+
+        let mut order: Vec<(usize, BackendKind)> = candidates.iter().copied().enumerate().collect();
+        order.sort_by_key(|(i, kind)| {
+            let r = record(kind);
+            match (r.attempts, r.successes()) {
+                (0, _) => (1, 0, *i),
+                (_, 0) => (2, 0, *i),
+                _ => (0, r.first_frame_ns, *i),
+            }
+        });
+        order.into_iter().map(|(_, kind)| kind).collect()
+    }
+
+    /// Try each candidate in preferred order until one delivers a frame within `timeout`.
+    /// `make` constructs an uninitialized capture for the given backend.
+    /// Blocks while probing; the probe frame is discarded.
+    pub fn create<F>(
+        &mut self,
+        key: &str,
+        candidates: &[BackendKind],
+        mut make: F,
+    ) -> Option<(BackendKind, Box<dyn WlxCapture>)>
+    where
+        F: FnMut(BackendKind) -> Option<Box<dyn WlxCapture>>,
+    {
+        for kind in self.preferred_order(key, candidates) {
+            let Some(mut capture) = make(kind) else {
+                continue;
+            };
+
+            let latency = probe(capture.as_mut(), &self.dmabuf_formats, self.timeout);
+            self.record(key, kind, latency);
+
+            if latency.is_some() {
+                log::info!("{}: using {} backend", key, kind.as_str());
+                return Some((kind, capture));
+            }
+            log::warn!("{}: {} backend produced no frames", key, kind.as_str());
+        }
+        None
+    }
+
+    /// Bring back a capture from a descriptor, probing it like `create` does.
+    /// Blocks while probing; the probe frame is discarded.
+    pub fn open(&mut self, descriptor: &CaptureDescriptor) -> Option<Box<dyn WlxCapture>> {
+        let key = preference_key(&descriptor.output);
+        let mut capture = descriptor.make_capture()?;
+
+        let latency = probe(capture.as_mut(), &self.dmabuf_formats, self.timeout);
+        self.record(&key, descriptor.kind, latency);
+
+        if latency.is_none() {
+            log::warn!(
+                "{}: {} backend produced no frames",
+                key,
+                descriptor.kind.as_str()
+            );
+            return None;
+        }
+        Some(capture)
+    }
+
+    fn record(&mut self, key: &str, kind: BackendKind, latency: Option<Duration>) {
+        let mut records = self.records(key);
+        let idx = match records.iter().position(|(k, _)| *k == kind) {
+            Some(idx) => idx,
+            None => {
+                records.push((kind, BackendRecord::default()));
+                records.len() - 1
+            }
+        };
+
+        let r = &mut records[idx].1;
+        r.attempts += 1;
+        match latency {
+            Some(latency) => {
+                let n = r.successes() as u64;
+                r.first_frame_ns = (r.first_frame_ns * (n - 1) + latency.as_nanos() as u64) / n;
+            }
+            None => r.failures += 1,
+        }
+
+        self.storage.store(key, &format_records(&records));
+    }
+}
+
+/// A preference key for the given output on the current compositor.
+pub fn preference_key(output_name: &str) -> String {
+    let desktop = std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_else(|_| "unknown".into());
+    format!("{}/{}", desktop, output_name)
+}
+
+fn probe(
+    capture: &mut dyn WlxCapture,
+    dmabuf_formats: &[DrmFormat],
+    timeout: Duration,
+) -> Option<Duration> {
+    let start = Instant::now();
+    capture.init(dmabuf_formats);
+    while start.elapsed() < timeout {
+        capture.request_new_frame();
+        // a cursor update alone does not show that the backend delivers images
+        if let Some(WlxFrame::Dmabuf(_) | WlxFrame::MemFd(_) | WlxFrame::MemPtr(_)) =
+            capture.receive()
+        {
+            return Some(start.elapsed());
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    None
+}
+
+fn parse_records(s: &str) -> Vec<(BackendKind, BackendRecord)> {
+    s.split(';')
+        .filter_map(|entry| {
+            let mut parts = entry.split(':');
+            let kind = parts.next()?.parse().ok()?;
+            let record = BackendRecord {
+                attempts: parts.next()?.parse().ok()?,
+                failures: parts.next()?.parse().ok()?,
+                first_frame_ns: parts.next()?.parse().ok()?,
+            };
+            // more failures than attempts means the stored string is corrupt
+            (record.failures <= record.attempts).then_some((kind, record))
+        })
+        .collect()
+}
+
+fn format_records(records: &[(BackendKind, BackendRecord)]) -> String {
+    records
+        .iter()
+        .map(|(kind, r)| {
+            format!(
+                "{}:{}:{}:{}",
+                kind.as_str(),
+                r.attempts,
+                r.failures,
+                r.first_frame_ns
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_round_trip() {
+        for s in ["", "DP-1", "a;b=c", "100%", "%3B", "ünïcödé;="] {
+            let escaped = escape(s);
+            assert!(!escaped.contains(';') && !escaped.contains('='));
+            assert_eq!(unescape(&escaped).as_deref(), Some(s));
+        }
+    }
+
+    #[test]
+    fn unescape_rejects_malformed() {
+        assert_eq!(unescape("%"), None);
+        assert_eq!(unescape("%4"), None);
+        assert_eq!(unescape("%zz"), None);
+        assert_eq!(unescape("%ff"), None);
+    }
+
+    #[test]
+    fn descriptor_round_trip() {
+        let mut descriptor = CaptureDescriptor::new(BackendKind::Pipewire, "screen;1=main");
+        descriptor.node_id = Some(42);
+        descriptor.restore_token = Some("to%ken".into());
+        descriptor.set_option("memory_limit", 1 << 20);
+        descriptor.set_option("my;key", "a=b");
+
+        let parsed: CaptureDescriptor = descriptor.to_string().parse().unwrap();
+        assert_eq!(parsed, descriptor);
+        assert_eq!(parsed.parsed_option::<usize>("memory_limit"), Some(1 << 20));
+        assert_eq!(parsed.option("my;key"), Some("a=b"));
+    }
+
+    #[test]
+    fn descriptor_rejects_malformed() {
+        assert!("output=DP-1".parse::<CaptureDescriptor>().is_err());
+        assert!("kind=xshm".parse::<CaptureDescriptor>().is_err());
+        assert!("kind=xshm;output=DP-1;bogus"
+            .parse::<CaptureDescriptor>()
+            .is_err());
+        assert!("kind=xshm;output=DP-1;node=x"
+            .parse::<CaptureDescriptor>()
+            .is_err());
+    }
+
+    #[test]
+    fn set_option_replaces() {
+        let mut descriptor = CaptureDescriptor::new(BackendKind::Xshm, "DP-1");
+        descriptor.set_option("damage", false);
+        descriptor.set_option("damage", true);
+        assert_eq!(descriptor.options.len(), 1);
+        assert_eq!(descriptor.parsed_option::<bool>("damage"), Some(true));
+        assert_eq!(descriptor.parsed_option::<u64>("damage"), None);
+    }
+
+    #[test]
+    fn region_option() {
+        assert_eq!(parse_region("1,2,300,400"), Some((1, 2, 300, 400)));
+        assert_eq!(parse_region("-10, 0, 5, 5"), Some((-10, 0, 5, 5)));
+        assert_eq!(parse_region("1,2,3"), None);
+        assert_eq!(parse_region("1,2,3,4,5"), None);
+        assert_eq!(parse_region("a,b,c,d"), None);
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let records = vec![
+            (
+                BackendKind::WlrDmabuf,
+                BackendRecord {
+                    attempts: 3,
+                    failures: 1,
+                    first_frame_ns: 12_000_000,
+                },
+            ),
+            (
+                BackendKind::Xshm,
+                BackendRecord {
+                    attempts: 1,
+                    failures: 1,
+                    first_frame_ns: 0,
+                },
+            ),
+        ];
+        let parsed = parse_records(&format_records(&records));
+        assert_eq!(parsed.len(), records.len());
+        for ((kind, r), (parsed_kind, parsed_r)) in records.iter().zip(parsed.iter()) {
+            assert_eq!(kind, parsed_kind);
+            assert_eq!(r.attempts, parsed_r.attempts);
+            assert_eq!(r.failures, parsed_r.failures);
+            assert_eq!(r.first_frame_ns, parsed_r.first_frame_ns);
+        }
+    }
+
+    #[test]
+    fn records_skip_invalid_entries() {
+        let parsed = parse_records("xshm:1:2:0;nonsense;xshm:2:1");
+        assert!(parsed.is_empty());
+        assert_eq!(parse_records("").len(), 0);
+    }
+}
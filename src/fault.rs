@@ -0,0 +1,75 @@
+//! Injection of failures into the backends, so that applications can test how they recover.
+//! Only active with the `fault-injection` feature; otherwise nothing is ever injected.
+
+use std::{
+    sync::mpsc::{SyncSender, TrySendError},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// A finished frame finds the frame channel full and is dropped.
+    ChannelFull,
+    /// The compositor reports the copy as failed (screencopy) or cancelled (export-dmabuf).
+    FrameFailed,
+    /// Allocating the shared memory for a screencopy frame fails as if no fds were left.
+    FdExhausted,
+    /// The compositor's ready event is held back for the given time.
+    DelayReady(Duration),
+}
+
+#[cfg(feature = "fault-injection")]
+static FAULTS: std::sync::Mutex<Vec<(Fault, u32)>> = std::sync::Mutex::new(Vec::new());
+
+/// Make the next `count` places where `fault` can happen fail, across all captures.
+#[cfg(feature = "fault-injection")]
+pub fn inject(fault: Fault, count: u32) {
+    if count == 0 {
+        return;
+    }
+    if let Ok(mut faults) = FAULTS.lock() {
+        faults.push((fault, count));
+    }
+}
+
+/// Drop all faults that have not been triggered yet.
+#[cfg(feature = "fault-injection")]
+pub fn clear() {
+    if let Ok(mut faults) = FAULTS.lock() {
+        faults.clear();
+    }
+}
+
+/// Consume one pending fault that matches.
+#[cfg(feature = "fault-injection")]
+pub(crate) fn take(matches: impl Fn(&Fault) -> bool) -> Option<Fault> {
+    let mut faults = FAULTS.lock().ok()?;
+    let idx = faults.iter().position(|(fault, _)| matches(fault))?;
+    let fault = faults[idx].0;
+    faults[idx].1 -= 1;
+    if faults[idx].1 == 0 {
+        faults.remove(idx);
+    }
+    log::debug!("Injecting fault: {:?}", fault);
+    Some(fault)
+}
+
+#[cfg(not(feature = "fault-injection"))]
+pub(crate) fn take(_: impl Fn(&Fault) -> bool) -> Option<Fault> {
+    None
+}
+
+/// `SyncSender::try_send`, failing as if the channel was full when `ChannelFull` is injected.
+pub(crate) fn try_send<T>(sender: &SyncSender<T>, value: T) -> Result<(), TrySendError<T>> {
+    if take(|f| *f == Fault::ChannelFull).is_some() {
+        return Err(TrySendError::Full(value));
+    }
+    sender.try_send(value)
+}
+
+/// Hold back a ready event if `DelayReady` is injected.
+pub(crate) fn delay_ready() {
+    if let Some(Fault::DelayReady(delay)) = take(|f| matches!(f, Fault::DelayReady(_))) {
+        std::thread::sleep(delay);
+    }
+}
@@ -0,0 +1,82 @@
+//! Bookkeeping of the fds opened by this crate, for tracking down leaks.
+//! Only active with the `fd-debug` feature; otherwise everything here is a no-op.
+
+use std::os::fd::RawFd;
+
+/// Open fds by their number together with the device and inode of their file, since fd
+/// numbers are reused right after a close that happened outside of this bookkeeping.
+#[cfg(feature = "fd-debug")]
+type OpenFds = std::collections::BTreeMap<(RawFd, u64, u64), &'static str>;
+
+#[cfg(feature = "fd-debug")]
+static OPEN_FDS: std::sync::Mutex<OpenFds> =
+    std::sync::Mutex::new(std::collections::BTreeMap::new());
+
+#[cfg(feature = "fd-debug")]
+fn key(fd: RawFd) -> Option<(RawFd, u64, u64)> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+        log::warn!(
+            "cannot track fd {}: {}",
+            fd,
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+    Some((fd, stat.st_dev as _, stat.st_ino as _))
+}
+
+/// Record that `fd` was opened or received, and what it is for.
+#[cfg(feature = "fd-debug")]
+pub(crate) fn track(fd: RawFd, kind: &'static str) {
+    let Some(key) = key(fd) else {
+        return;
+    };
+    if let Ok(mut fds) = OPEN_FDS.lock() {
+        if let Some(old) = fds.insert(key, kind) {
+            log::warn!("fd {} ({}) is already tracked as {}", fd, kind, old);
+        }
+    }
+}
+
+#[cfg(not(feature = "fd-debug"))]
+pub(crate) fn track(_: RawFd, _: &'static str) {}
+
+/// Record that `fd` is about to be closed.
+#[cfg(feature = "fd-debug")]
+pub(crate) fn untrack(fd: RawFd) {
+    let Some(key) = key(fd) else {
+        return;
+    };
+    if let Ok(mut fds) = OPEN_FDS.lock() {
+        if fds.remove(&key).is_none() {
+            log::warn!("closing fd {} which was never tracked", fd);
+        }
+    }
+}
+
+#[cfg(not(feature = "fd-debug"))]
+pub(crate) fn untrack(_: RawFd) {}
+
+/// Log the fds that are still open, e.g. after a capture was dropped.
+#[cfg(feature = "fd-debug")]
+pub(crate) fn report(context: &str) {
+    let fds = debug_open_fds();
+    if fds.is_empty() {
+        log::debug!("{}: no fds open", context);
+    } else {
+        log::warn!("{}: {} fds still open: {:?}", context, fds.len(), fds);
+    }
+}
+
+#[cfg(not(feature = "fd-debug"))]
+pub(crate) fn report(_: &str) {}
+
+/// The fds opened by this crate that have not been closed yet, with what they are for.
+#[cfg(feature = "fd-debug")]
+pub fn debug_open_fds() -> Vec<(RawFd, &'static str)> {
+    OPEN_FDS
+        .lock()
+        .map(|fds| fds.iter().map(|((fd, ..), kind)| (*fd, *kind)).collect())
+        .unwrap_or_default()
+}
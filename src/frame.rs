@@ -1,4 +1,13 @@
-use std::{fmt::Display, os::fd::RawFd};
+use std::{
+    fmt::Display,
+    io::Write,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct FourCC {
@@ -40,8 +49,21 @@ pub const DRM_FORMAT_ARGB8888: u32 = 0x34325241; // AR24
 pub const DRM_FORMAT_ABGR8888: u32 = 0x34324241; // AB24
 pub const DRM_FORMAT_XRGB8888: u32 = 0x34325258; // XR24
 pub const DRM_FORMAT_XBGR8888: u32 = 0x34324258; // XB24
+pub const DRM_FORMAT_RGBA8888: u32 = 0x34324152; // RA24
+pub const DRM_FORMAT_RGBX8888: u32 = 0x34325852; // RX24
+pub const DRM_FORMAT_BGRA8888: u32 = 0x34324142; // BA24
+pub const DRM_FORMAT_BGRX8888: u32 = 0x34325842; // BX24
 pub const DRM_FORMAT_ABGR2101010: u32 = 0x30334241; // AB30
 pub const DRM_FORMAT_XBGR2101010: u32 = 0x30334258; // XB30
+pub const DRM_FORMAT_ARGB2101010: u32 = 0x30335241; // AR30
+pub const DRM_FORMAT_XRGB2101010: u32 = 0x30335258; // XR30
+pub const DRM_FORMAT_YUYV: u32 = 0x56595559; // YUYV
+pub const DRM_FORMAT_UYVY: u32 = 0x59565955; // UYVY
+pub const DRM_FORMAT_NV12: u32 = 0x3231564e; // NV12
+pub const DRM_FORMAT_YUV420: u32 = 0x32315559; // YU12
+
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+pub const DRM_FORMAT_MOD_INVALID: u64 = 0x00ffffffffffffff;
 
 #[cfg(feature = "egl")]
 #[rustfmt::skip]
@@ -53,13 +75,286 @@ const EGL_DMABUF_PLANE_ATTRS: [isize; 20] = [
     0x3440,0x3441,0x3442,0x3449,0x344A,
 ];
 
+#[derive(Clone)]
 pub enum WlxFrame {
     Dmabuf(DmabufFrame),
     MemFd(MemFdFrame),
     MemPtr(MemPtrFrame),
+    /// Cursor moved, but no new image is available.
+    Mouse(MouseMeta),
 }
 
+impl WlxFrame {
+    /// Returns None for `WlxFrame::Mouse`, which has no image.
+    pub fn format(&self) -> Option<&FrameFormat> {
+        match self {
+            WlxFrame::Dmabuf(f) => Some(&f.format),
+            WlxFrame::MemFd(f) => Some(&f.format),
+            WlxFrame::MemPtr(f) => Some(&f.format),
+            WlxFrame::Mouse(_) => None,
+        }
+    }
+    /// Returns None for `WlxFrame::Mouse`, which has no image.
+    pub fn meta(&self) -> Option<&FrameMeta> {
+        match self {
+            WlxFrame::Dmabuf(f) => Some(&f.meta),
+            WlxFrame::MemFd(f) => Some(&f.meta),
+            WlxFrame::MemPtr(f) => Some(&f.meta),
+            WlxFrame::Mouse(_) => None,
+        }
+    }
+    /// See `FrameFormat::letterbox`.
+    pub fn letterbox(&self, target_width: u32, target_height: u32) -> Option<Letterbox> {
+        Some(self.format()?.letterbox(target_width, target_height))
+    }
+    pub fn meta_mut(&mut self) -> Option<&mut FrameMeta> {
+        match self {
+            WlxFrame::Dmabuf(f) => Some(&mut f.meta),
+            WlxFrame::MemFd(f) => Some(&mut f.meta),
+            WlxFrame::MemPtr(f) => Some(&mut f.meta),
+            WlxFrame::Mouse(_) => None,
+        }
+    }
+}
+
+/// Per-frame information that is not part of the frame format.
 #[derive(Debug, Clone, Copy, Default)]
+pub struct FrameMeta {
+    /// Time at which the frame was captured, in nanoseconds of `CLOCK_MONOTONIC`.
+    /// Provided by the compositor where available, otherwise taken on arrival.
+    pub timestamp: u64,
+    /// Same contents as a previously delivered frame, e.g. while the capture is frozen.
+    pub duplicate: bool,
+    /// The last frame from before a pause, delivered again right after `resume`
+    /// so that there is something to show until the next fresh frame arrives.
+    /// Always set together with `duplicate`.
+    pub stale: bool,
+    /// Part of the image to show, in pixels before the transform is applied.
+    /// None for the whole image. Set by `region::RegionCapture`.
+    pub crop: Option<CropRect>,
+    /// What kind of source the frame shows.
+    pub source: SourceKind,
+}
+
+/// What a capture shows. Window sources change size with the window, so consumers
+/// typically letterbox them instead of assuming the size of a monitor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SourceKind {
+    #[default]
+    Monitor,
+    Window,
+    /// A virtual output created for the stream, e.g. an extended desktop.
+    Virtual,
+    /// A part of a monitor.
+    Region,
+    /// A camera, e.g. a webcam streamed through PipeWire.
+    Camera,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A copy of a frame in memory that belongs to `LastFrame` instead of the backend, so that
+/// it stays valid and unchanged after the backend has released, reused or unmapped the
+/// original buffer.
+///
+/// DMA-buf frames are not retained: their memory cannot be copied without a GPU, and
+/// a duplicated fd still refers to a buffer that the producer keeps rendering into.
+struct RetainedFrame {
+    frame: WlxFrame,
+    /// The memfd holding the copy of a `MemFd` frame, which `frame` refers to.
+    fd: Option<OwnedFd>,
+    /// Copy of the pixels of a `MemPtr` frame, which `frame` points into.
+    _pixels: Vec<u8>,
+}
+
+impl RetainedFrame {
+    fn new(frame: &WlxFrame) -> Option<Self> {
+        let mut frame = frame.clone();
+        let mut fd = None;
+        let mut pixels = Vec::new();
+        match &mut frame {
+            WlxFrame::MemFd(memfd) => {
+                let copy = memfd.with_mapped(copy_to_memfd)??;
+                memfd.plane.fd = Some(copy.as_raw_fd());
+                memfd.plane.offset = 0;
+                memfd.plane.size = memfd.map_range().len() as _;
+                fd = Some(copy);
+            }
+            WlxFrame::MemPtr(memptr) => {
+                if memptr.ptr == 0 {
+                    return None;
+                }
+                let bytes =
+                    unsafe { std::slice::from_raw_parts(memptr.ptr as *const u8, memptr.size) };
+                pixels = bytes.to_vec();
+                memptr.ptr = pixels.as_ptr() as usize;
+            }
+            WlxFrame::Dmabuf(_) | WlxFrame::Mouse(_) => return None,
+        }
+        Some(Self {
+            frame,
+            fd,
+            _pixels: pixels,
+        })
+    }
+}
+
+/// Copy `bytes` into a new memfd.
+fn copy_to_memfd(bytes: &[u8]) -> Option<OwnedFd> {
+    let fd = unsafe { libc::memfd_create(c"wlx-capture-retained".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        log::warn!(
+            "Failed to create a memfd: {}",
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    crate::fd_debug::track(fd.as_raw_fd(), "retained frame");
+    let mut file = std::fs::File::from(fd);
+    if let Err(e) = file.write_all(bytes) {
+        log::warn!("Failed to copy a frame: {}", e);
+        crate::fd_debug::untrack(file.as_raw_fd());
+        return None;
+    }
+    Some(file.into())
+}
+
+impl Drop for RetainedFrame {
+    fn drop(&mut self) {
+        if let Some(fd) = self.fd.as_ref() {
+            crate::fd_debug::untrack(fd.as_raw_fd());
+        }
+    }
+}
+
+/// Remembers the last delivered frame so that it can be delivered again.
+#[derive(Default)]
+pub(crate) struct LastFrame {
+    /// Shares its memory with the backend; only valid until the backend reuses the buffer.
+    frame: Option<WlxFrame>,
+    /// Owned copy of `frame`, which duplicates are made from.
+    retained: Option<RetainedFrame>,
+    pub frozen: bool,
+    /// Resumed, and no fresh frame has been delivered since.
+    resumed: bool,
+}
+
+impl LastFrame {
+    /// Pass through a newly received frame, remembering it if it carries an image.
+    pub fn update(&mut self, frame: Option<WlxFrame>) -> Option<WlxFrame> {
+        if let Some(frame) = frame.as_ref().filter(|f| f.meta().is_some()) {
+            crate::verify::check(frame);
+            self.frame = Some(frame.clone());
+            self.retained = None;
+            self.resumed = false;
+        }
+        frame
+    }
+
+    /// Keep an owned copy of the last frame, for delivering it again once the backend has
+    /// released its buffer. Must be called while that buffer is still valid, e.g. before
+    /// a pause hands buffers back to the producer. Only copies once per frame.
+    pub fn retain(&mut self) {
+        if self.retained.is_none() {
+            self.retained = self.frame.as_ref().and_then(RetainedFrame::new);
+        }
+    }
+
+    /// Stop delivering fresh frames, and keep delivering a copy of the last one.
+    /// Returns false and does nothing if there is no copy to deliver, i.e. before the
+    /// first frame or if the last frame is a DMA-buf.
+    pub fn freeze(&mut self) -> bool {
+        self.retain();
+        self.frozen = self.retained.is_some();
+        self.frozen
+    }
+
+    /// Have `take_stale` return the last frame once, unless a fresh frame comes first.
+    /// Call before releasing the buffers of earlier frames.
+    pub fn mark_resumed(&mut self) {
+        self.retain();
+        self.resumed = self.retained.is_some();
+    }
+
+    /// The last frame again, flagged as stale, if the capture was just resumed.
+    pub fn take_stale(&mut self) -> Option<WlxFrame> {
+        if !std::mem::take(&mut self.resumed) {
+            return None;
+        }
+        let mut frame = self.duplicate()?;
+        if let Some(meta) = frame.meta_mut() {
+            meta.stale = true;
+        }
+        Some(frame)
+    }
+
+    /// When the frame after the last one is expected, if frames come every `interval` ns.
+    /// Slots that have already passed are skipped. None before the first frame.
+    pub fn eta(&self, interval: u64) -> Option<u64> {
+        let last = self.frame.as_ref()?.meta()?.timestamp;
+        if self.frozen || interval == 0 || last == 0 {
+            return None;
+        }
+        let now = monotonic_ns();
+        let missed = now.saturating_sub(last) / interval;
+        Some(last + (missed + 1) * interval)
+    }
+
+    /// The last delivered frame again, flagged as a duplicate. Its memory stays valid until
+    /// the next fresh frame. None unless `retain` was called since that frame.
+    pub fn duplicate(&self) -> Option<WlxFrame> {
+        let mut frame = self.retained.as_ref()?.frame.clone();
+        if let Some(meta) = frame.meta_mut() {
+            meta.duplicate = true;
+        }
+        Some(frame)
+    }
+}
+
+/// Current time in nanoseconds of `CLOCK_MONOTONIC`, the clock used by `FrameMeta::timestamp`.
+pub fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Time between frames in nanoseconds for a refresh rate in mHz, or None if unknown.
+pub(crate) fn refresh_interval(refresh: i32) -> Option<u64> {
+    (refresh > 0).then(|| 1_000_000_000_000 / refresh as u64)
+}
+
+/// How often idle worker threads report that they are alive, see `WlxCapture::last_activity`.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When a capture's worker threads last made progress, shared between the capture
+/// and its threads.
+#[derive(Clone, Default)]
+pub(crate) struct Activity(Arc<AtomicU64>);
+
+impl Activity {
+    pub fn touch(&self) {
+        self.0.store(monotonic_ns(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        let timestamp = self.0.load(Ordering::Relaxed);
+        (timestamp != 0).then_some(timestamp)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum Transform {
     #[default]
     Undefined,
@@ -73,13 +368,24 @@ pub enum Transform {
     Flipped270,
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+/// The order in which rows are stored in memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RowOrder {
+    /// The first row in memory is the top of the image.
+    #[default]
+    TopDown,
+    /// The first row in memory is the bottom of the image (y-inverted).
+    BottomUp,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct FrameFormat {
     pub width: u32,
     pub height: u32,
     pub fourcc: FourCC,
     pub modifier: u64,
     pub transform: Transform,
+    pub origin: RowOrder,
 }
 
 impl FrameFormat {
@@ -92,6 +398,45 @@ impl FrameFormat {
     pub fn set_mod(&mut self, mod_hi: u32, mod_low: u32) {
         self.modifier = ((mod_hi as u64) << 32) + mod_low as u64;
     }
+
+    /// Width and height as displayed, after applying the transform.
+    pub fn display_size(&self) -> (u32, u32) {
+        match self.transform {
+            Transform::Rotated90
+            | Transform::Rotated270
+            | Transform::Flipped90
+            | Transform::Flipped270 => (self.height, self.width),
+            _ => (self.width, self.height),
+        }
+    }
+
+    /// Where to place this frame inside a target of the given size,
+    /// scaled to fit while keeping the aspect ratio (letterbox or pillarbox).
+    pub fn letterbox(&self, target_width: u32, target_height: u32) -> Letterbox {
+        let (width, height) = self.display_size();
+        if width == 0 || height == 0 || target_width == 0 || target_height == 0 {
+            return Letterbox::default();
+        }
+
+        let scale = (target_width as f32 / width as f32).min(target_height as f32 / height as f32);
+        let w = width as f32 * scale / target_width as f32;
+        let h = height as f32 * scale / target_height as f32;
+        Letterbox {
+            x: (1.0 - w) * 0.5,
+            y: (1.0 - h) * 0.5,
+            width: w,
+            height: h,
+        }
+    }
+}
+
+/// A rectangle within a target area, in normalized 0..1 coordinates.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Letterbox {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
 }
 
 #[derive(Clone, Copy, Default)]
@@ -99,6 +444,9 @@ pub struct FramePlane {
     pub fd: Option<RawFd>,
     pub offset: u32,
     pub stride: i32,
+    /// Bytes of the plane from `offset` on, or 0 if unknown. Where the source only tells
+    /// the size of the memory behind `fd`, this reaches to the end of that memory.
+    pub size: u32,
 }
 
 #[derive(Default, Clone)]
@@ -107,11 +455,47 @@ pub struct DrmFormat {
     pub modifiers: Vec<u64>,
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct DmabufFrame {
     pub format: FrameFormat,
+    pub meta: FrameMeta,
     pub num_planes: usize,
     pub planes: [FramePlane; 4],
+    /// Identifies the underlying buffer, so imported images can be cached across frames.
+    pub buffer_key: Option<BufferKey>,
+}
+
+/// Stable identity of a buffer that is reused across frames, taken from the device
+/// and inode behind the first plane's file descriptor, which unlike the fd number
+/// stay with the buffer for as long as it exists.
+/// A key may be reused by a new buffer once the old one is freed, e.g. after the stream
+/// renegotiates its format, so cached imports should be dropped when the format changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BufferKey {
+    /// A buffer from a PipeWire stream's pool.
+    Pipewire { node_id: u32, dev: u64, ino: u64 },
+    /// A buffer from any other backend.
+    Inode { dev: u64, ino: u64 },
+}
+
+impl BufferKey {
+    pub fn from_fd(fd: RawFd) -> Option<Self> {
+        let (dev, ino) = fd_inode(fd)?;
+        Some(BufferKey::Inode { dev, ino })
+    }
+
+    pub(crate) fn pipewire(node_id: u32, fd: RawFd) -> Option<Self> {
+        let (dev, ino) = fd_inode(fd)?;
+        Some(BufferKey::Pipewire { node_id, dev, ino })
+    }
+}
+
+fn fd_inode(fd: RawFd) -> Option<(u64, u64)> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+        return None;
+    }
+    Some((stat.st_dev as _, stat.st_ino as _))
 }
 
 impl DmabufFrame {
@@ -150,6 +534,18 @@ impl DmabufFrame {
         vec
     }
 
+    /// Size in bytes of the memory behind the given plane's fd, as far as the planes
+    /// sharing that fd tell, e.g. for `VkMemoryAllocateInfo::allocationSize`.
+    /// None if the plane does not exist or a plane sharing its fd has an unknown size.
+    pub fn allocation_size(&self, plane: usize) -> Option<u64> {
+        let fd = self.planes.get(..self.num_planes)?.get(plane)?.fd?;
+        self.planes[..self.num_planes]
+            .iter()
+            .filter(|p| p.fd == Some(fd))
+            .map(|p| (p.size > 0).then_some(p.offset as u64 + p.size as u64))
+            .try_fold(0, |end, plane_end| Some(end.max(plane_end?)))
+    }
+
     /// Returns true if all planes have a valid file descriptor.
     pub fn is_valid(&self) -> bool {
         for i in 0..self.num_planes {
@@ -161,21 +557,74 @@ impl DmabufFrame {
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct MemFdFrame {
     pub format: FrameFormat,
+    pub meta: FrameMeta,
     pub plane: FramePlane,
 }
 
-#[derive(Default)]
+impl MemFdFrame {
+    /// Distance in bytes between the start of two rows.
+    pub fn stride(&self) -> usize {
+        self.plane.stride.unsigned_abs() as _
+    }
+
+    /// Byte range of the frame within `plane.fd`, the same for all backends:
+    /// `offset..offset + stride * height`.
+    /// The offset is not guaranteed to be page-aligned; round it down before calling mmap.
+    pub fn map_range(&self) -> std::ops::Range<usize> {
+        let start = self.plane.offset as usize;
+        start..start + self.stride() * self.format.height as usize
+    }
+
+    /// Map the frame read-only and pass its bytes (`map_range`) to `f`.
+    /// Returns None if there is no fd or it cannot be mapped.
+    pub fn with_mapped<R>(&self, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        let fd = self.plane.fd?;
+        let range = self.map_range();
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                range.end,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+
+        let bytes = unsafe { std::slice::from_raw_parts(ptr as *const u8, range.end) };
+        let result = f(&bytes[range.start..]);
+
+        unsafe {
+            libc::munmap(ptr, range.end);
+        }
+        Some(result)
+    }
+}
+
+#[derive(Default, Clone)]
 pub struct MemPtrFrame {
     pub format: FrameFormat,
+    pub meta: FrameMeta,
     pub ptr: usize,
+    /// Exactly `stride * height` bytes, with no padding after the last row.
     pub size: usize,
     pub mouse: Option<MouseMeta>,
 }
 
-#[derive(Default)]
+impl MemPtrFrame {
+    /// Distance in bytes between the start of two rows.
+    pub fn stride(&self) -> usize {
+        self.size / self.format.height.max(1) as usize
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
 pub struct MouseMeta {
     pub x: f32,
     pub y: f32,
@@ -0,0 +1,155 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    frame::{DrmFormat, WlxFrame},
+    WlxCapture,
+};
+
+/// Initialize the captures and block until each has delivered its first frame,
+/// so that multi-monitor overlays appear all at once rather than one by one.
+/// Returns the first frame of each capture, in order, or None for captures that
+/// produced none within `timeout`; those keep running and may deliver frames later.
+pub fn start_synchronized(
+    captures: &mut [Box<dyn WlxCapture>],
+    dmabuf_formats: &[DrmFormat],
+    timeout: Duration,
+) -> Vec<Option<WlxFrame>> {
+    let start = Instant::now();
+    for capture in captures.iter_mut() {
+        capture.init(dmabuf_formats);
+    }
+
+    let mut first: Vec<Option<WlxFrame>> = captures.iter().map(|_| None).collect();
+    while start.elapsed() < timeout {
+        for (capture, first) in captures.iter_mut().zip(first.iter_mut()) {
+            if first.is_some() {
+                continue;
+            }
+            capture.request_new_frame();
+            // cursor-only updates don't show anything yet
+            *first = capture.receive().filter(|f| f.meta().is_some());
+        }
+        if first.iter().all(Option::is_some) {
+            return first;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    let missing = first.iter().filter(|f| f.is_none()).count();
+    log::warn!(
+        "{} of {} captures produced no frame within {:?}",
+        missing,
+        first.len(),
+        timeout
+    );
+    first
+}
+
+/// Drives several captures together and delivers their frames as a set,
+/// so that multi-monitor setups don't show monitors updating out of phase.
+pub struct CaptureGroup {
+    captures: Vec<Box<dyn WlxCapture>>,
+    pending: Vec<Option<WlxFrame>>,
+    window_ns: u64,
+}
+
+impl CaptureGroup {
+    /// Frames are grouped if their timestamps are no more than `window_ns` apart.
+    pub fn new(captures: Vec<Box<dyn WlxCapture>>, window_ns: u64) -> Self {
+        let pending = captures.iter().map(|_| None).collect();
+        Self {
+            captures,
+            pending,
+            window_ns,
+        }
+    }
+
+    pub fn captures(&self) -> &[Box<dyn WlxCapture>] {
+        &self.captures
+    }
+
+    pub fn captures_mut(&mut self) -> &mut [Box<dyn WlxCapture>] {
+        &mut self.captures
+    }
+
+    pub fn init(&mut self, dmabuf_formats: &[DrmFormat]) {
+        for capture in self.captures.iter_mut() {
+            capture.init(dmabuf_formats);
+        }
+    }
+
+    /// Like `init`, but blocks until every capture has produced a frame, see
+    /// `start_synchronized`. Returns false if some capture timed out.
+    /// The first frames are kept for grouping by the next `receive`.
+    pub fn init_synchronized(&mut self, dmabuf_formats: &[DrmFormat], timeout: Duration) -> bool {
+        let first = start_synchronized(&mut self.captures, dmabuf_formats, timeout);
+        let all = first.iter().all(Option::is_some);
+        self.pending = first;
+        all
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.captures.iter().all(|c| c.is_ready())
+    }
+
+    pub fn pause(&mut self) {
+        for capture in self.captures.iter_mut() {
+            capture.pause();
+        }
+        self.pending.iter_mut().for_each(|p| *p = None);
+    }
+
+    pub fn resume(&mut self) {
+        for capture in self.captures.iter_mut() {
+            capture.resume();
+        }
+    }
+
+    /// Request a new frame from every capture that doesn't have one waiting.
+    pub fn request_new_frame(&mut self) {
+        for (capture, pending) in self.captures.iter_mut().zip(self.pending.iter()) {
+            if pending.is_none() {
+                capture.request_new_frame();
+            }
+        }
+    }
+
+    /// Returns one frame per capture, in the order the captures were given,
+    /// once every capture has produced a frame within the grouping window.
+    pub fn receive(&mut self) -> Option<Vec<WlxFrame>> {
+        for (capture, pending) in self.captures.iter_mut().zip(self.pending.iter_mut()) {
+            // cursor-only updates can't be grouped by time
+            if let Some(frame) = capture.receive().filter(|f| f.meta().is_some()) {
+                *pending = Some(frame);
+            }
+        }
+
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        let mut oldest = u64::MAX;
+        let mut newest = 0;
+        for pending in self.pending.iter() {
+            let timestamp = pending.as_ref()?.meta()?.timestamp;
+            oldest = oldest.min(timestamp);
+            newest = newest.max(timestamp);
+        }
+
+        if newest - oldest <= self.window_ns {
+            return self.pending.iter_mut().map(|p| p.take()).collect();
+        }
+
+        // frames too old to ever be matched are dropped, a newer one will take their place
+        for pending in self.pending.iter_mut() {
+            if pending
+                .as_ref()
+                .and_then(|f| f.meta())
+                .is_some_and(|m| m.timestamp + self.window_ns < newest)
+            {
+                *pending = None;
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,138 @@
+//! Recreating a capture in another process, for building a crash-isolated
+//! capture daemon on top of this crate.
+//!
+//! The parent describes the capture with a `CaptureDescriptor` and starts the daemon
+//! with `spawn_capture_process`; the daemon picks the descriptor up with
+//! `received_descriptor` and creates a new capture with `CaptureDescriptor::make_capture`.
+//! No capture state or fds are transferred: the daemon opens its own connections, and
+//! the parent's capture keeps running until it is dropped.
+//! Both sides get a pidfd of the other, so each notices when the other dies,
+//! without races against pid reuse.
+
+use std::{
+    io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    process::{Child, Command},
+};
+
+use crate::factory::CaptureDescriptor;
+
+/// Environment variable the descriptor is passed in.
+pub const DESCRIPTOR_ENV: &str = "WLX_CAPTURE_DESCRIPTOR";
+
+/// Environment variable the pid of the spawning process is passed in.
+pub const PARENT_PID_ENV: &str = "WLX_CAPTURE_PARENT_PID";
+
+/// A pidfd, which refers to one process even after its pid is reused.
+pub struct PidFd(OwnedFd);
+
+impl PidFd {
+    /// Requires Linux 5.3.
+    pub fn open(pid: u32) -> io::Result<Self> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(fd as _) }))
+    }
+
+    /// The process that started this one with `spawn_capture_process`.
+    /// Fails if that process already exited, as this process was then reparented
+    /// to init or a subreaper.
+    pub fn parent() -> io::Result<Self> {
+        let spawner = std::env::var(PARENT_PID_ENV)
+            .ok()
+            .and_then(|pid| pid.parse::<libc::pid_t>().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "not started by spawn_capture_process",
+                )
+            })?;
+        let ppid = unsafe { libc::getppid() };
+        if ppid != spawner {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "parent process has exited",
+            ));
+        }
+        let pidfd = Self::open(ppid as _)?;
+        // the parent may have exited between getppid and pidfd_open
+        if pidfd.has_exited() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "parent process has exited",
+            ));
+        }
+        Ok(pidfd)
+    }
+
+    /// Whether the process has exited. Does not block.
+    pub fn has_exited(&self) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd: self.0.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // a pidfd becomes readable once the process exits
+        unsafe { libc::poll(&mut pollfd, 1, 0) > 0 }
+    }
+
+    /// Duplicate one of the process' fds into this process, e.g. a dmabuf it exported.
+    /// Requires Linux 5.6 and ptrace access to the process.
+    pub fn get_fd(&self, target_fd: i32) -> io::Result<OwnedFd> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_getfd, self.0.as_raw_fd(), target_fd, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as _) })
+    }
+}
+
+impl AsFd for PidFd {
+    /// For waiting on the process in an event loop; readable once it exits.
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+/// A process started by `spawn_capture_process`.
+pub struct CaptureProcess {
+    pub child: Child,
+    pub pidfd: PidFd,
+}
+
+/// Start `command` with `descriptor` and the pid of this process in its environment,
+/// see `received_descriptor` and `PidFd::parent`.
+/// The descriptor should name an output or carry a restore token rather than rely on a
+/// PipeWire node id, unless the portal session stays open in this process.
+pub fn spawn_capture_process(
+    command: &mut Command,
+    descriptor: &CaptureDescriptor,
+) -> io::Result<CaptureProcess> {
+    let child = command
+        .env(DESCRIPTOR_ENV, descriptor.to_string())
+        .env(PARENT_PID_ENV, std::process::id().to_string())
+        .spawn()?;
+    match PidFd::open(child.id()) {
+        Ok(pidfd) => Ok(CaptureProcess { child, pidfd }),
+        Err(e) => {
+            let mut child = child;
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(e)
+        }
+    }
+}
+
+/// The descriptor passed by `spawn_capture_process`, if this process was started by it.
+pub fn received_descriptor() -> Option<CaptureDescriptor> {
+    let value = std::env::var(DESCRIPTOR_ENV).ok()?;
+    match value.parse() {
+        Ok(descriptor) => Some(descriptor),
+        Err(()) => {
+            log::warn!("Malformed {}: {}", DESCRIPTOR_ENV, value);
+            None
+        }
+    }
+}
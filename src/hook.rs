@@ -0,0 +1,198 @@
+//! Lightweight per-frame processing inside the pipeline, e.g. sampling brightness for
+//! ambient lighting, without copying frames to another thread. See `HookCapture`.
+
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    callback::{forward_capture, wrap_frame_callback},
+    frame::WlxFrame,
+    FrameCallback, WlxCapture,
+};
+
+/// Time a hook may take per frame unless configured otherwise.
+pub const DEFAULT_HOOK_BUDGET: Duration = Duration::from_millis(2);
+
+/// A hook that overruns its budget on this many frames in a row is disabled.
+pub const MAX_CONSECUTIVE_OVERRUNS: u32 = 3;
+
+/// What happens to a frame after the hook has seen it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookResult {
+    /// Deliver the frame as usual.
+    Continue,
+    /// Drop the frame, e.g. because it did not change anything the consumer cares about.
+    Drop,
+}
+
+/// Looks at each frame before it is delivered. The frame is only valid during the call.
+pub type FrameHook = Box<dyn FnMut(&WlxFrame) -> HookResult + Send>;
+
+/// A hook took longer than its budget.
+#[derive(Debug, Clone, Copy)]
+pub struct HookOverrun {
+    pub elapsed: Duration,
+    pub budget: Duration,
+    /// The hook overran `MAX_CONSECUTIVE_OVERRUNS` times in a row and will not run again
+    /// until it is set anew.
+    pub disabled: bool,
+}
+
+/// Counters of a `HookCapture`'s hook.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HookStats {
+    pub calls: u64,
+    pub overruns: u64,
+    /// Longest time a call took.
+    pub max_elapsed: Duration,
+}
+
+struct HookState {
+    hook: Option<FrameHook>,
+    budget: Duration,
+    consecutive_overruns: u32,
+    stats: HookStats,
+    overruns: mpsc::Sender<HookOverrun>,
+}
+
+impl HookState {
+    /// Run the hook on `frame`, if one is set. Returns whether to deliver the frame.
+    fn run(&mut self, frame: &WlxFrame) -> bool {
+        let Some(hook) = self.hook.as_mut() else {
+            return true;
+        };
+        let start = Instant::now();
+        let result = hook(frame);
+        let elapsed = start.elapsed();
+
+        self.stats.calls += 1;
+        self.stats.max_elapsed = self.stats.max_elapsed.max(elapsed);
+        if elapsed <= self.budget {
+            self.consecutive_overruns = 0;
+            return result == HookResult::Continue;
+        }
+
+        self.stats.overruns += 1;
+        self.consecutive_overruns += 1;
+        let disabled = self.consecutive_overruns >= MAX_CONSECUTIVE_OVERRUNS;
+        if disabled {
+            log::warn!(
+                "Frame hook overran its budget of {:?} {} times in a row, disabling it",
+                self.budget,
+                self.consecutive_overruns
+            );
+            self.hook = None;
+        } else {
+            log::debug!("Frame hook took {:?} of {:?}", elapsed, self.budget);
+        }
+        let _ = self.overruns.send(HookOverrun {
+            elapsed,
+            budget: self.budget,
+            disabled,
+        });
+        result == HookResult::Continue
+    }
+}
+
+/// Runs a `FrameHook` on every frame of a capture, before it is delivered.
+/// With a frame callback set through `set_frame_callback`, the hook runs on the capture
+/// thread right before the callback; otherwise it runs inside `receive`.
+///
+/// Hooks should only read a few pixels or metadata. A hook that takes longer than its
+/// budget is reported through `iter_overruns`, and disabled once it overran
+/// `MAX_CONSECUTIVE_OVERRUNS` frames in a row, so that it cannot stall the capture.
+pub struct HookCapture {
+    capture: Box<dyn WlxCapture>,
+    state: Arc<Mutex<HookState>>,
+    rx_overrun: mpsc::Receiver<HookOverrun>,
+}
+
+impl HookCapture {
+    pub fn new(capture: Box<dyn WlxCapture>) -> Self {
+        let (tx_overrun, rx_overrun) = mpsc::channel();
+        Self {
+            capture,
+            state: Arc::new(Mutex::new(HookState {
+                hook: None,
+                budget: DEFAULT_HOOK_BUDGET,
+                consecutive_overruns: 0,
+                stats: HookStats::default(),
+                overruns: tx_overrun,
+            })),
+            rx_overrun,
+        }
+    }
+
+    /// Time the hook may take per frame, instead of `DEFAULT_HOOK_BUDGET`.
+    pub fn with_budget(self, budget: Duration) -> Self {
+        if let Ok(mut state) = self.state.lock() {
+            state.budget = budget;
+        }
+        self
+    }
+
+    /// Run `hook` on the following frames, replacing and re-enabling any previous hook.
+    pub fn set_frame_hook<F>(&self, hook: F)
+    where
+        F: FnMut(&WlxFrame) -> HookResult + Send + 'static,
+    {
+        if let Ok(mut state) = self.state.lock() {
+            state.hook = Some(Box::new(hook));
+            state.consecutive_overruns = 0;
+        }
+    }
+
+    /// Stop running the hook.
+    pub fn clear_frame_hook(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.hook = None;
+        }
+    }
+
+    /// Whether a hook is set and has not been disabled for overrunning its budget.
+    pub fn hook_active(&self) -> bool {
+        self.state.lock().map(|s| s.hook.is_some()).unwrap_or(false)
+    }
+
+    pub fn stats(&self) -> HookStats {
+        self.state.lock().map(|s| s.stats).unwrap_or_default()
+    }
+
+    /// Overruns since the last call, oldest first.
+    pub fn iter_overruns(&self) -> impl Iterator<Item = HookOverrun> + '_ {
+        self.rx_overrun.try_iter()
+    }
+
+    pub fn capture(&self) -> &dyn WlxCapture {
+        self.capture.as_ref()
+    }
+}
+
+impl WlxCapture for HookCapture {
+    forward_capture!(capture: init, is_ready, supports_dmbuf, caps, source_kind, pause, resume);
+    forward_capture!(capture: request_new_frame, last_activity, next_frame_eta);
+    forward_capture!(capture: set_embedded_cursor, freeze, unfreeze);
+    fn receive(&mut self) -> Option<WlxFrame> {
+        let frame = self.capture.receive()?;
+        let deliver = match self.state.lock() {
+            Ok(mut state) => state.run(&frame),
+            Err(_) => true,
+        };
+        deliver.then_some(frame)
+    }
+    /// The hook runs on the capture thread, before `callback` sees the frames.
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        let state = self.state.clone();
+        wrap_frame_callback(self.capture.as_mut(), callback, move |frame, next| {
+            let deliver = match state.lock() {
+                Ok(mut state) => state.run(&frame),
+                Err(_) => true,
+            };
+            if deliver {
+                next(frame);
+            }
+        })
+    }
+}
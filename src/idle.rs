@@ -0,0 +1,233 @@
+//! Keeping the session from going idle while a capture is being watched,
+//! so that a desktop mirrored into VR does not blank while the user is away from the keyboard.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+#[cfg(feature = "wayland")]
+use wayland_client::{protocol::wl_surface::WlSurface, Connection, Dispatch, Proxy, QueueHandle};
+#[cfg(feature = "wayland")]
+use wayland_protocols::wp::idle_inhibit::zv1::client::{
+    zwp_idle_inhibit_manager_v1::ZwpIdleInhibitManagerV1, zwp_idle_inhibitor_v1::ZwpIdleInhibitorV1,
+};
+
+#[cfg(feature = "wayland")]
+use crate::wayland::WlxClient;
+use crate::{
+    callback::{forward_capture, wrap_frame_callback},
+    frame::WlxFrame,
+    FrameCallback, WlxCapture,
+};
+
+enum Backend {
+    #[cfg(feature = "wayland")]
+    Wayland {
+        connection: Arc<Connection>,
+        queue_handle: QueueHandle<WlxClient>,
+        manager: ZwpIdleInhibitManagerV1,
+        surface: WlSurface,
+        inhibitor: Option<ZwpIdleInhibitorV1>,
+    },
+    /// Suspends the screen saver and DPMS through the MIT-SCREEN-SAVER extension.
+    /// The server lifts the suspension by itself if the connection is closed.
+    #[cfg(feature = "xshm")]
+    X11 {
+        connection: xcb::Connection,
+        suspended: bool,
+    },
+}
+
+/// Keeps the screen from blanking or locking while active.
+pub struct IdleInhibitor {
+    backend: Backend,
+}
+
+impl IdleInhibitor {
+    /// Inhibit idling with the idle-inhibit protocol. Compositors only honor the
+    /// inhibitor while `surface` is mapped and visible on an output, e.g. the surface of a
+    /// shown `preview::PreviewSurface`; an unmapped, minimized or fully covered surface
+    /// inhibits nothing. Consumers without a window of their own, such as a VR overlay,
+    /// should inhibit through their desktop's D-Bus or portal inhibit API instead.
+    /// Requests are flushed right away, so `wl` need not be dispatched for them.
+    /// Returns None if the compositor does not offer zwp_idle_inhibit_manager_v1.
+    #[cfg(feature = "wayland")]
+    pub fn wayland(wl: &WlxClient, surface: &WlSurface) -> Option<Self> {
+        Some(Self {
+            backend: Backend::Wayland {
+                connection: wl.connection.clone(),
+                queue_handle: wl.queue_handle.clone(),
+                manager: wl.maybe_idle_inhibit_mgr.clone()?,
+                surface: surface.clone(),
+                inhibitor: None,
+            },
+        })
+    }
+
+    /// Inhibit idling of the X11 session by suspending its screen saver, which also
+    /// keeps displays from powering down via DPMS.
+    /// Returns None if the X server cannot be reached or lacks MIT-SCREEN-SAVER.
+    #[cfg(feature = "xshm")]
+    pub fn x11() -> Option<Self> {
+        let (connection, _) =
+            xcb::Connection::connect_with_extensions(None, &[xcb::Extension::ScreenSaver], &[])
+                .map_err(|e| log::warn!("X11: cannot suspend the screen saver: {}", e))
+                .ok()?;
+        Some(Self {
+            backend: Backend::X11 {
+                connection,
+                suspended: false,
+            },
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        match &self.backend {
+            #[cfg(feature = "wayland")]
+            Backend::Wayland { inhibitor, .. } => inhibitor.is_some(),
+            #[cfg(feature = "xshm")]
+            Backend::X11 { suspended, .. } => *suspended,
+        }
+    }
+
+    /// Start inhibiting. Does nothing if already active.
+    pub fn inhibit(&mut self) {
+        self.set_active(true);
+    }
+
+    /// Stop inhibiting. Does nothing if not active.
+    pub fn release(&mut self) {
+        self.set_active(false);
+    }
+
+    fn set_active(&mut self, active: bool) {
+        if self.is_active() == active {
+            return;
+        }
+        match &mut self.backend {
+            #[cfg(feature = "wayland")]
+            Backend::Wayland {
+                connection,
+                queue_handle,
+                manager,
+                surface,
+                inhibitor,
+            } => {
+                if active {
+                    *inhibitor = Some(manager.create_inhibitor(surface, queue_handle, ()));
+                } else if let Some(inhibitor) = inhibitor.take() {
+                    inhibitor.destroy();
+                }
+                if let Err(e) = connection.flush() {
+                    log::warn!("Failed to flush idle inhibitor: {}", e);
+                }
+            }
+            #[cfg(feature = "xshm")]
+            Backend::X11 {
+                connection,
+                suspended,
+            } => {
+                let result = connection.send_and_check_request(&xcb::screensaver::Suspend {
+                    suspend: active as u32,
+                });
+                match result {
+                    Ok(()) => *suspended = active,
+                    Err(e) => log::warn!("X11: failed to suspend the screen saver: {}", e),
+                }
+            }
+        }
+        log::debug!(
+            "Idle inhibitor {}",
+            if active { "taken" } else { "released" }
+        );
+    }
+}
+
+impl Drop for IdleInhibitor {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(feature = "wayland")]
+impl Dispatch<ZwpIdleInhibitorV1, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpIdleInhibitorV1,
+        _event: <ZwpIdleInhibitorV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+/// Holds an `IdleInhibitor` while frames are being received from a capture,
+/// or delivered to its frame callback, and releases it while the capture is paused.
+pub struct IdleInhibitCapture {
+    capture: Box<dyn WlxCapture>,
+    /// Shared with the frame callback, which takes it on the capture thread.
+    inhibitor: Arc<Mutex<IdleInhibitor>>,
+    paused: Arc<AtomicBool>,
+}
+
+impl IdleInhibitCapture {
+    pub fn new(capture: Box<dyn WlxCapture>, inhibitor: IdleInhibitor) -> Self {
+        Self {
+            capture,
+            inhibitor: Arc::new(Mutex::new(inhibitor)),
+            paused: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether idling is being inhibited right now.
+    pub fn is_inhibiting(&self) -> bool {
+        self.inhibitor.lock().is_ok_and(|i| i.is_active())
+    }
+
+    fn set_inhibiting(&self, active: bool) {
+        if let Ok(mut inhibitor) = self.inhibitor.lock() {
+            if active {
+                inhibitor.inhibit();
+            } else {
+                inhibitor.release();
+            }
+        }
+    }
+}
+
+impl WlxCapture for IdleInhibitCapture {
+    forward_capture!(capture: init, is_ready, supports_dmbuf, caps, request_new_frame);
+    forward_capture!(capture: last_activity, next_frame_eta);
+    forward_capture!(capture: set_embedded_cursor, freeze, unfreeze);
+    fn receive(&mut self) -> Option<WlxFrame> {
+        let frame = self.capture.receive();
+        if frame.is_some() && !self.paused.load(Ordering::Relaxed) {
+            self.set_inhibiting(true);
+        }
+        frame
+    }
+    fn pause(&mut self) {
+        self.paused.store(true, Ordering::Relaxed);
+        self.set_inhibiting(false);
+        self.capture.pause();
+    }
+    fn resume(&mut self) {
+        self.paused.store(false, Ordering::Relaxed);
+        self.capture.resume();
+    }
+    /// Frames bypass `receive` in callback mode, so the inhibitor is taken as they arrive.
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        let inhibitor = self.inhibitor.clone();
+        let paused = self.paused.clone();
+        wrap_frame_callback(self.capture.as_mut(), callback, move |frame, next| {
+            if !matches!(frame, WlxFrame::Mouse(_)) && !paused.load(Ordering::Relaxed) {
+                if let Ok(mut inhibitor) = inhibitor.lock() {
+                    inhibitor.inhibit();
+                }
+            }
+            next(frame);
+        })
+    }
+}
@@ -0,0 +1,976 @@
+//! Capture of the framebuffers that the display hardware scans out, straight from the
+//! kernel's KMS interface, for kiosk and embedded setups. Works without a Wayland or X
+//! session and does not involve the compositor, if there is one.
+//!
+//! The framebuffer of a CRTC is looked up with `drmModeGetFB2` and exported as a dmabuf.
+//! The kernel only hands out its buffers to the DRM master or to a process with
+//! `CAP_SYS_ADMIN`; see `KmsOutput::check_access`. Hardware cursor planes and overlay
+//! planes are not part of the framebuffer. The rotation of the plane that scans it out is
+//! reported as `format.transform`.
+//!
+//! Frames are the buffer that is on screen at the time of the request. A compositor
+//! renders into it again once it flips to another buffer, so import them right away.
+//!
+//! Outputs that a compositor leased to a VR runtime (e.g. Monado in direct mode) are
+//! captured the same way; see `KmsCapture::get_leased_outputs`.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    ffi::c_ulong,
+    fs::{self, File, OpenOptions},
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+};
+
+use crate::{
+    factory::CaptureDescriptor,
+    fault, fd_debug,
+    frame::{
+        monotonic_ns, refresh_interval, Activity, BufferKey, DmabufFrame, DrmFormat, FrameFormat,
+        FrameMeta, FramePlane, LastFrame, RowOrder, Transform, WlxFrame, DRM_FORMAT_MOD_INVALID,
+        HEARTBEAT_INTERVAL,
+    },
+    output::OutputInfo,
+    BackendKind, CaptureCaps, CopyCost, FrameCallback, WlxCapture,
+};
+
+/// A connected connector that is driven by a CRTC.
+#[derive(Debug)]
+pub struct KmsOutput {
+    /// Index of the output in the list returned by `KmsCapture::get_outputs`.
+    pub id: u32,
+    /// Connector name as the kernel and most compositors spell it, e.g. `DP-1`.
+    pub name: Arc<str>,
+    /// The DRM device the output belongs to, e.g. `/dev/dri/card0`.
+    pub device: PathBuf,
+    pub connector_id: u32,
+    pub crtc_id: u32,
+    /// Resolution of the current mode.
+    pub size: (i32, i32),
+    /// Refresh rate of the current mode in mHz, or 0 if unknown.
+    pub refresh: i32,
+    /// Physical width and height in millimeters, or 0 if unknown.
+    pub physical_size: (i32, i32),
+    /// The connector has the `non-desktop` property set, as VR headsets do. Compositors
+    /// leave such outputs dark, so a lit one is driven by a lessee.
+    pub non_desktop: bool,
+}
+
+impl KmsOutput {
+    /// Check that this process may read the output's framebuffer, i.e. that it is the
+    /// DRM master or has `CAP_SYS_ADMIN`, by looking up the current framebuffer once.
+    pub fn check_access(&self) -> Result<(), Box<dyn Error>> {
+        let device = open_device(&self.device)?;
+        let fb = current_fb(&device, self.crtc_id)?;
+        close_handles(&device, &fb);
+        Ok(())
+    }
+}
+
+/// KMS has no desktop layout: every output is placed at the origin, unscaled.
+impl From<&KmsOutput> for OutputInfo {
+    fn from(output: &KmsOutput) -> Self {
+        OutputInfo {
+            id: output.id,
+            name: output.name.clone(),
+            logical_pos: (0, 0),
+            logical_size: output.size,
+            size: output.size,
+            transform: Transform::Normal,
+            scale: 1.0,
+            refresh: output.refresh,
+        }
+    }
+}
+
+/// Delivers `WlxFrame::Dmabuf` frames of the framebuffer on a CRTC.
+///
+/// The framebuffers are scanned out and rendered into again by the compositor, so frames
+/// can neither be frozen nor delivered again after a pause: `freeze` does nothing.
+pub struct KmsCapture {
+    pub output: Arc<KmsOutput>,
+    sender: Option<mpsc::SyncSender<()>>,
+    receiver: Option<mpsc::Receiver<WlxFrame>>,
+    fds: VecDeque<RawFd>,
+    last_frame: LastFrame,
+    frame_callback: Option<FrameCallback>,
+    activity: Activity,
+}
+
+impl KmsCapture {
+    pub fn new(output: Arc<KmsOutput>) -> Self {
+        Self {
+            output,
+            sender: None,
+            receiver: None,
+            fds: VecDeque::new(),
+            last_frame: LastFrame::default(),
+            frame_callback: None,
+            activity: Activity::default(),
+        }
+    }
+
+    /// The state needed to recreate this capture elsewhere,
+    /// e.g. in a process started with `handoff::spawn_capture_process`.
+    pub fn descriptor(&self) -> CaptureDescriptor {
+        CaptureDescriptor::new(BackendKind::Kms, &self.output.name)
+    }
+
+    /// The outputs that are lit up, on all DRM devices. Listing them needs no privileges.
+    pub fn get_outputs() -> Result<Vec<Arc<KmsOutput>>, Box<dyn Error>> {
+        let mut devices = fs::read_dir("/dev/dri")?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with("card"))
+            })
+            .collect::<Vec<_>>();
+        devices.sort();
+
+        let mut outputs = Vec::new();
+        for path in devices {
+            let device = match open_device(&path) {
+                Ok(device) => device,
+                Err(e) => {
+                    log::debug!("KMS: could not open {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            match device_outputs(&device, &path) {
+                Ok(found) => outputs.extend(found),
+                Err(e) => log::debug!("KMS: could not query {}: {}", path.display(), e),
+            }
+        }
+        Ok(outputs
+            .into_iter()
+            .enumerate()
+            .map(|(id, mut output)| {
+                output.id = id as _;
+                Arc::new(output)
+            })
+            .collect())
+    }
+
+    /// The non-desktop outputs that are lit up, i.e. headsets or secondary displays that a
+    /// compositor leased to a VR runtime. Capturing one shows what the runtime scans out.
+    /// The lessee is the DRM master of its lease only, so reading the framebuffers still
+    /// needs `CAP_SYS_ADMIN` or the compositor's DRM master.
+    pub fn get_leased_outputs() -> Result<Vec<Arc<KmsOutput>>, Box<dyn Error>> {
+        Ok(Self::get_outputs()?
+            .into_iter()
+            .filter(|output| output.non_desktop)
+            .collect())
+    }
+
+    /// The outputs in the backend-agnostic form shared with Wayland and X11.
+    pub fn get_output_infos() -> Result<Vec<OutputInfo>, Box<dyn Error>> {
+        Ok(Self::get_outputs()?
+            .iter()
+            .map(|output| OutputInfo::from(output.as_ref()))
+            .collect())
+    }
+}
+
+impl WlxCapture for KmsCapture {
+    fn init(&mut self, _: &[DrmFormat]) {
+        let (tx_frame, rx_frame) = mpsc::sync_channel(2);
+        let (tx_cmd, rx_cmd) = mpsc::sync_channel(2);
+        self.sender = Some(tx_cmd);
+        self.receiver = Some(rx_frame);
+
+        std::thread::spawn({
+            let output = self.output.clone();
+            let mut frame_callback = self.frame_callback.take();
+            let activity = self.activity.clone();
+            move || {
+                let device = match open_device(&output.device) {
+                    Ok(device) => device,
+                    Err(e) => {
+                        log::error!(
+                            "{}: failed to open {}: {}",
+                            output.name,
+                            output.device.display(),
+                            e
+                        );
+                        return;
+                    }
+                };
+
+                // looked up with the first framebuffer, None inside if there is no rotation
+                let mut rotation: Option<Option<PlaneRotation>> = None;
+
+                loop {
+                    activity.touch();
+                    match rx_cmd.recv_timeout(HEARTBEAT_INTERVAL) {
+                        Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                        Ok(_) => {
+                            let frame = match export_frame(&device, output.crtc_id, &mut rotation) {
+                                Ok(Some(frame)) => frame,
+                                Ok(None) => {
+                                    log::trace!("{}: no framebuffer on crtc", output.name);
+                                    continue;
+                                }
+                                Err(e) => {
+                                    log::warn!(
+                                        "{}: failed to export framebuffer: {}",
+                                        output.name,
+                                        e
+                                    );
+                                    continue;
+                                }
+                            };
+                            log::trace!("{}: captured frame", output.name);
+
+                            if let Some(callback) = frame_callback.as_mut() {
+                                callback(WlxFrame::Dmabuf(frame.clone()));
+                                // the consumer had to import the frame during the callback
+                                close_planes(&frame);
+                                continue;
+                            }
+                            match fault::try_send(&tx_frame, WlxFrame::Dmabuf(frame)) {
+                                Ok(_) => (),
+                                Err(mpsc::TrySendError::Full(WlxFrame::Dmabuf(frame))) => {
+                                    log::debug!("{}: channel full", output.name);
+                                    close_planes(&frame);
+                                }
+                                Err(mpsc::TrySendError::Disconnected(frame)) => {
+                                    if let WlxFrame::Dmabuf(frame) = frame {
+                                        close_planes(&frame);
+                                    }
+                                    log::warn!(
+                                        "{}: capture thread channel closed (send)",
+                                        output.name
+                                    );
+                                    break;
+                                }
+                                Err(_) => (),
+                            }
+                        }
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
+                            log::debug!("{}: capture thread channel closed (recv)", output.name);
+                            break;
+                        }
+                    }
+                }
+                log::debug!("{}: capture thread stopped", output.name);
+            }
+        });
+    }
+    fn is_ready(&self) -> bool {
+        self.receiver.is_some()
+    }
+    fn supports_dmbuf(&self) -> bool {
+        true
+    }
+    fn caps(&self) -> CaptureCaps {
+        CaptureCaps {
+            dmabuf: true,
+            cost: CopyCost::ZeroCopy,
+            embedded_cursor: false,
+            per_seat_cursor: false,
+        }
+    }
+    fn receive(&mut self) -> Option<WlxFrame> {
+        if let Some(rx) = self.receiver.as_ref() {
+            let mut last = None;
+            for frame in rx.try_iter() {
+                if let Some(WlxFrame::Dmabuf(skipped)) = last.replace(frame) {
+                    close_planes(&skipped);
+                }
+            }
+            if let Some(WlxFrame::Dmabuf(last)) = last {
+                // keep the fds of recent frames open for consumers that are still importing
+                while self.fds.len() > 6 * last.num_planes {
+                    close_fd(self.fds.pop_back().unwrap()); // safe unwrap
+                }
+                for fd in unique_fds(&last) {
+                    self.fds.push_front(fd);
+                }
+                return self.last_frame.update(Some(WlxFrame::Dmabuf(last)));
+            }
+        }
+        self.last_frame.take_stale()
+    }
+    fn pause(&mut self) {}
+    fn resume(&mut self) {
+        self.receive(); // clear old frames
+        self.last_frame.mark_resumed();
+        self.request_new_frame();
+    }
+    fn request_new_frame(&mut self) {
+        if let Some(sender) = &self.sender {
+            if let Err(mpsc::TrySendError::Disconnected(_)) = sender.try_send(()) {
+                log::debug!("{}: capture thread is gone", self.output.name);
+            }
+        }
+    }
+    fn last_activity(&self) -> Option<u64> {
+        self.activity.get()
+    }
+    fn next_frame_eta(&self) -> Option<u64> {
+        self.last_frame.eta(refresh_interval(self.output.refresh)?)
+    }
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        self.frame_callback = Some(callback);
+        Ok(())
+    }
+}
+
+impl Drop for KmsCapture {
+    fn drop(&mut self) {
+        for fd in self.fds.drain(..) {
+            close_fd(fd);
+        }
+        if let Some(rx) = self.receiver.as_ref() {
+            for frame in rx.try_iter() {
+                if let WlxFrame::Dmabuf(frame) = frame {
+                    close_planes(&frame);
+                }
+            }
+        }
+        fd_debug::report("kms capture dropped");
+    }
+}
+
+fn close_fd(fd: RawFd) {
+    fd_debug::untrack(fd);
+    let _ = unsafe { OwnedFd::from_raw_fd(fd) };
+}
+
+/// Planes of one buffer share an fd, which must only be closed once.
+fn unique_fds(frame: &DmabufFrame) -> Vec<RawFd> {
+    let mut fds = Vec::with_capacity(frame.num_planes);
+    for fd in frame.planes[..frame.num_planes].iter().filter_map(|p| p.fd) {
+        if !fds.contains(&fd) {
+            fds.push(fd);
+        }
+    }
+    fds
+}
+
+/// Close the fds of a frame that will not be delivered.
+fn close_planes(frame: &DmabufFrame) {
+    for fd in unique_fds(frame) {
+        close_fd(fd);
+    }
+}
+
+fn open_device(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_CLOEXEC)
+        .open(path)
+}
+
+/// The outputs of one device, with `id` left at 0.
+fn device_outputs(device: &File, path: &Path) -> io::Result<Vec<KmsOutput>> {
+    // the first call returns the counts, the second fills the arrays
+    let mut res = DrmModeCardRes::default();
+    ioctl(device, DRM_IOCTL_MODE_GETRESOURCES, &mut res)?;
+    let mut connector_ids = vec![0u32; res.count_connectors as usize];
+    res = DrmModeCardRes {
+        connector_id_ptr: connector_ids.as_mut_ptr() as u64,
+        count_connectors: connector_ids.len() as u32,
+        ..Default::default()
+    };
+    ioctl(device, DRM_IOCTL_MODE_GETRESOURCES, &mut res)?;
+    connector_ids.truncate(res.count_connectors as usize);
+
+    let mut outputs = Vec::new();
+    for connector_id in connector_ids {
+        // asking for no modes makes the DRM master probe the connector again
+        let mut mode = DrmModeModeInfo::default();
+        let mut conn = DrmModeGetConnector {
+            connector_id,
+            modes_ptr: &mut mode as *mut _ as u64,
+            count_modes: 1,
+            ..Default::default()
+        };
+        ioctl(device, DRM_IOCTL_MODE_GETCONNECTOR, &mut conn)?;
+        if conn.connection != DRM_MODE_CONNECTED || conn.encoder_id == 0 {
+            continue;
+        }
+
+        let mut encoder = DrmModeGetEncoder {
+            encoder_id: conn.encoder_id,
+            ..Default::default()
+        };
+        ioctl(device, DRM_IOCTL_MODE_GETENCODER, &mut encoder)?;
+        if encoder.crtc_id == 0 {
+            continue;
+        }
+
+        let mut crtc = DrmModeCrtc {
+            crtc_id: encoder.crtc_id,
+            ..Default::default()
+        };
+        ioctl(device, DRM_IOCTL_MODE_GETCRTC, &mut crtc)?;
+        if crtc.mode_valid == 0 {
+            continue;
+        }
+
+        let non_desktop = connector_non_desktop(device, connector_id, conn.count_props)
+            .unwrap_or_else(|e| {
+                log::debug!(
+                    "KMS: could not read properties of connector {}: {}",
+                    connector_id,
+                    e
+                );
+                false
+            });
+
+        let type_name = CONNECTOR_TYPE_NAMES
+            .get(conn.connector_type as usize)
+            .unwrap_or(&"Unknown");
+        outputs.push(KmsOutput {
+            id: 0,
+            name: format!("{}-{}", type_name, conn.connector_type_id).into(),
+            device: path.to_path_buf(),
+            connector_id,
+            crtc_id: encoder.crtc_id,
+            size: (crtc.mode.hdisplay as _, crtc.mode.vdisplay as _),
+            refresh: mode_refresh(&crtc.mode),
+            physical_size: (conn.mm_width as _, conn.mm_height as _),
+            non_desktop,
+        });
+    }
+    Ok(outputs)
+}
+
+/// Whether the connector has the `non-desktop` property set.
+fn connector_non_desktop(device: &File, connector_id: u32, count_props: u32) -> io::Result<bool> {
+    let mut prop_ids = vec![0u32; count_props as usize];
+    let mut prop_values = vec![0u64; count_props as usize];
+    let mut mode = DrmModeModeInfo::default();
+    let mut conn = DrmModeGetConnector {
+        connector_id,
+        modes_ptr: &mut mode as *mut _ as u64,
+        count_modes: 1,
+        props_ptr: prop_ids.as_mut_ptr() as u64,
+        prop_values_ptr: prop_values.as_mut_ptr() as u64,
+        count_props,
+        ..Default::default()
+    };
+    ioctl(device, DRM_IOCTL_MODE_GETCONNECTOR, &mut conn)?;
+    let count = conn.count_props.min(count_props) as usize;
+
+    for (&prop_id, &value) in prop_ids[..count].iter().zip(&prop_values) {
+        let mut prop = DrmModeGetProperty {
+            prop_id,
+            ..Default::default()
+        };
+        ioctl(device, DRM_IOCTL_MODE_GETPROPERTY, &mut prop)?;
+        if prop.name.starts_with(b"non-desktop\0") {
+            return Ok(value != 0);
+        }
+    }
+    Ok(false)
+}
+
+/// Refresh rate of a mode in mHz, from its pixel clock and timings.
+fn mode_refresh(mode: &DrmModeModeInfo) -> i32 {
+    let mut vtotal = mode.vtotal as u64;
+    if mode.flags & DRM_MODE_FLAG_DBLSCAN != 0 {
+        vtotal *= 2;
+    }
+    if mode.flags & DRM_MODE_FLAG_INTERLACE != 0 {
+        vtotal /= 2;
+    }
+    if mode.vscan > 1 {
+        vtotal *= mode.vscan as u64;
+    }
+    let pixels = mode.htotal as u64 * vtotal;
+    if pixels == 0 {
+        return 0;
+    }
+    (mode.clock as u64 * 1_000_000 / pixels) as i32
+}
+
+/// The framebuffer on the CRTC, or None if it shows none.
+/// Its GEM handles belong to the caller and must be closed with `close_handles`.
+fn current_fb(device: &File, crtc_id: u32) -> io::Result<Option<DrmModeFbCmd2>> {
+    let mut crtc = DrmModeCrtc {
+        crtc_id,
+        ..Default::default()
+    };
+    ioctl(device, DRM_IOCTL_MODE_GETCRTC, &mut crtc)?;
+    if crtc.fb_id == 0 {
+        return Ok(None);
+    }
+
+    let mut fb = DrmModeFbCmd2 {
+        fb_id: crtc.fb_id,
+        ..Default::default()
+    };
+    ioctl(device, DRM_IOCTL_MODE_GETFB2, &mut fb)?;
+    if fb.handles[0] == 0 {
+        // the kernel leaves the handles out for unprivileged callers
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "framebuffers are only shared with the DRM master or CAP_SYS_ADMIN",
+        ));
+    }
+    Ok(Some(fb))
+}
+
+fn close_handles(device: &File, fb: &Option<DrmModeFbCmd2>) {
+    let Some(fb) = fb else {
+        return;
+    };
+    for (i, &handle) in fb.handles.iter().enumerate() {
+        // planes of one buffer share a handle, which is only closed once
+        if handle == 0 || fb.handles[..i].contains(&handle) {
+            continue;
+        }
+        let mut close = DrmGemClose { handle, pad: 0 };
+        if let Err(e) = ioctl(device, DRM_IOCTL_GEM_CLOSE, &mut close) {
+            log::debug!("KMS: failed to close GEM handle {}: {}", handle, e);
+        }
+    }
+}
+
+/// Export the framebuffer on the CRTC as a dmabuf frame, with the rotation of its plane.
+/// `rotation` caches the plane and property to read it from.
+fn export_frame(
+    device: &File,
+    crtc_id: u32,
+    rotation: &mut Option<Option<PlaneRotation>>,
+) -> io::Result<Option<DmabufFrame>> {
+    let fb = current_fb(device, crtc_id)?;
+    let result = fb.as_ref().map(|fb| fb_to_frame(device, fb)).transpose();
+    close_handles(device, &fb);
+
+    let (Some(mut frame), Some(fb)) = (result?, fb) else {
+        return Ok(None);
+    };
+    if rotation.is_none() {
+        match find_rotation(device, crtc_id, fb.fb_id) {
+            Ok(found) => *rotation = Some(found),
+            Err(e) => log::debug!(
+                "KMS: could not find the rotation of crtc {}: {}",
+                crtc_id,
+                e
+            ),
+        }
+    }
+    frame.format.transform = match rotation.as_ref().and_then(|r| r.as_ref()) {
+        Some(r) => r.transform(device).unwrap_or(Transform::Normal),
+        None => Transform::Normal,
+    };
+    Ok(Some(frame))
+}
+
+/// The `rotation` property of the plane that scans out a CRTC.
+struct PlaneRotation {
+    plane_id: u32,
+    prop_id: u32,
+}
+
+impl PlaneRotation {
+    fn transform(&self, device: &File) -> io::Result<Transform> {
+        let value = object_properties(device, self.plane_id, DRM_MODE_OBJECT_PLANE)?
+            .into_iter()
+            .find(|&(id, _)| id == self.prop_id)
+            .map(|(_, value)| value)
+            .unwrap_or(DRM_MODE_ROTATE_0);
+        Ok(rotation_to_transform(value))
+    }
+}
+
+/// The plane that shows `fb_id` on the CRTC, if the driver lets its rotation be set.
+fn find_rotation(device: &File, crtc_id: u32, fb_id: u32) -> io::Result<Option<PlaneRotation>> {
+    // primary planes are only listed to clients that ask for them
+    let mut cap = DrmSetClientCap {
+        capability: DRM_CLIENT_CAP_UNIVERSAL_PLANES,
+        value: 1,
+    };
+    ioctl(device, DRM_IOCTL_SET_CLIENT_CAP, &mut cap)?;
+
+    let mut res = DrmModeGetPlaneRes::default();
+    ioctl(device, DRM_IOCTL_MODE_GETPLANERESOURCES, &mut res)?;
+    let mut plane_ids = vec![0u32; res.count_planes as usize];
+    res = DrmModeGetPlaneRes {
+        plane_id_ptr: plane_ids.as_mut_ptr() as u64,
+        count_planes: plane_ids.len() as u32,
+    };
+    ioctl(device, DRM_IOCTL_MODE_GETPLANERESOURCES, &mut res)?;
+    plane_ids.truncate(res.count_planes as usize);
+
+    for plane_id in plane_ids {
+        let mut plane = DrmModeGetPlane {
+            plane_id,
+            ..Default::default()
+        };
+        ioctl(device, DRM_IOCTL_MODE_GETPLANE, &mut plane)?;
+        if plane.crtc_id != crtc_id || plane.fb_id != fb_id {
+            continue;
+        }
+        for (prop_id, _) in object_properties(device, plane_id, DRM_MODE_OBJECT_PLANE)? {
+            let mut prop = DrmModeGetProperty {
+                prop_id,
+                ..Default::default()
+            };
+            ioctl(device, DRM_IOCTL_MODE_GETPROPERTY, &mut prop)?;
+            if prop.name.starts_with(b"rotation\0") {
+                return Ok(Some(PlaneRotation { plane_id, prop_id }));
+            }
+        }
+        return Ok(None);
+    }
+    Ok(None)
+}
+
+/// Ids and values of the properties of a KMS object.
+fn object_properties(device: &File, obj_id: u32, obj_type: u32) -> io::Result<Vec<(u32, u64)>> {
+    // the first call returns the count, the second fills the arrays
+    let mut props = DrmModeObjGetProperties {
+        obj_id,
+        obj_type,
+        ..Default::default()
+    };
+    ioctl(device, DRM_IOCTL_MODE_OBJ_GETPROPERTIES, &mut props)?;
+    let count = props.count_props;
+    let mut ids = vec![0u32; count as usize];
+    let mut values = vec![0u64; count as usize];
+    props = DrmModeObjGetProperties {
+        props_ptr: ids.as_mut_ptr() as u64,
+        prop_values_ptr: values.as_mut_ptr() as u64,
+        count_props: count,
+        obj_id,
+        obj_type,
+    };
+    ioctl(device, DRM_IOCTL_MODE_OBJ_GETPROPERTIES, &mut props)?;
+    let count = props.count_props.min(count) as usize;
+    Ok(ids.into_iter().zip(values).take(count).collect())
+}
+
+/// KMS rotates counter-clockwise like wl_output, and reflects before rotating.
+fn rotation_to_transform(rotation: u64) -> Transform {
+    let reflect_x = rotation & DRM_MODE_REFLECT_X != 0;
+    let reflect_y = rotation & DRM_MODE_REFLECT_Y != 0;
+    let mut quarter_turns = match rotation & DRM_MODE_ROTATE_MASK {
+        DRM_MODE_ROTATE_90 => 1,
+        DRM_MODE_ROTATE_180 => 2,
+        DRM_MODE_ROTATE_270 => 3,
+        _ => 0,
+    };
+    // a vertical reflection is a horizontal one, turned by 180 degrees
+    if reflect_y {
+        quarter_turns += 2;
+    }
+    match (reflect_x != reflect_y, quarter_turns % 4) {
+        (false, 0) => Transform::Normal,
+        (false, 1) => Transform::Rotated90,
+        (false, 2) => Transform::Rotated180,
+        (false, _) => Transform::Rotated270,
+        (true, 0) => Transform::Flipped,
+        (true, 1) => Transform::Flipped90,
+        (true, 2) => Transform::Flipped180,
+        (true, _) => Transform::Flipped270,
+    }
+}
+
+fn fb_to_frame(device: &File, fb: &DrmModeFbCmd2) -> io::Result<DmabufFrame> {
+    let mut frame = DmabufFrame {
+        format: FrameFormat {
+            width: fb.width,
+            height: fb.height,
+            fourcc: fb.pixel_format.into(),
+            modifier: if fb.flags & DRM_MODE_FB_MODIFIERS != 0 {
+                fb.modifier[0]
+            } else {
+                DRM_FORMAT_MOD_INVALID
+            },
+            origin: RowOrder::TopDown,
+            ..Default::default()
+        },
+        meta: FrameMeta {
+            timestamp: monotonic_ns(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    for (plane, &handle) in fb.handles.iter().enumerate() {
+        if handle == 0 {
+            break;
+        }
+        let shared = fb.handles[..plane]
+            .iter()
+            .position(|&h| h == handle)
+            .and_then(|p| frame.planes[p].fd);
+        let fd = match shared {
+            Some(fd) => fd,
+            None => match export_handle(device, handle) {
+                Ok(fd) => fd,
+                Err(e) => {
+                    close_planes(&frame);
+                    return Err(e);
+                }
+            },
+        };
+        frame.planes[plane] = FramePlane {
+            fd: Some(fd),
+            offset: fb.offsets[plane],
+            stride: fb.pitches[plane] as _,
+            size: 0,
+        };
+        frame.num_planes = plane + 1;
+    }
+    frame.buffer_key = frame.planes[0].fd.and_then(BufferKey::from_fd);
+    Ok(frame)
+}
+
+fn export_handle(device: &File, handle: u32) -> io::Result<RawFd> {
+    let mut prime = DrmPrimeHandle {
+        handle,
+        flags: libc::O_CLOEXEC as u32,
+        fd: -1,
+    };
+    ioctl(device, DRM_IOCTL_PRIME_HANDLE_TO_FD, &mut prime)?;
+    fd_debug::track(prime.fd, "kms framebuffer");
+    Ok(prime.fd)
+}
+
+fn ioctl<T>(device: &File, request: c_ulong, arg: &mut T) -> io::Result<()> {
+    loop {
+        let ret = unsafe { libc::ioctl(device.as_raw_fd(), request as _, arg as *mut T) };
+        if ret == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if !matches!(err.raw_os_error(), Some(libc::EINTR) | Some(libc::EAGAIN)) {
+            return Err(err);
+        }
+    }
+}
+
+// Kernel UAPI from drm.h and drm_mode.h.
+
+const fn drm_iowr<T>(nr: u32) -> c_ulong {
+    ((3 << 30) | ((std::mem::size_of::<T>() as u32) << 16) | ((b'd' as u32) << 8) | nr) as c_ulong
+}
+
+const fn drm_iow<T>(nr: u32) -> c_ulong {
+    ((1 << 30) | ((std::mem::size_of::<T>() as u32) << 16) | ((b'd' as u32) << 8) | nr) as c_ulong
+}
+
+const DRM_IOCTL_GEM_CLOSE: c_ulong = drm_iow::<DrmGemClose>(0x09);
+const DRM_IOCTL_SET_CLIENT_CAP: c_ulong = drm_iow::<DrmSetClientCap>(0x0d);
+const DRM_IOCTL_PRIME_HANDLE_TO_FD: c_ulong = drm_iowr::<DrmPrimeHandle>(0x2d);
+const DRM_IOCTL_MODE_GETRESOURCES: c_ulong = drm_iowr::<DrmModeCardRes>(0xa0);
+const DRM_IOCTL_MODE_GETCRTC: c_ulong = drm_iowr::<DrmModeCrtc>(0xa1);
+const DRM_IOCTL_MODE_GETENCODER: c_ulong = drm_iowr::<DrmModeGetEncoder>(0xa6);
+const DRM_IOCTL_MODE_GETCONNECTOR: c_ulong = drm_iowr::<DrmModeGetConnector>(0xa7);
+const DRM_IOCTL_MODE_GETPROPERTY: c_ulong = drm_iowr::<DrmModeGetProperty>(0xaa);
+const DRM_IOCTL_MODE_GETPLANERESOURCES: c_ulong = drm_iowr::<DrmModeGetPlaneRes>(0xb5);
+const DRM_IOCTL_MODE_GETPLANE: c_ulong = drm_iowr::<DrmModeGetPlane>(0xb6);
+const DRM_IOCTL_MODE_OBJ_GETPROPERTIES: c_ulong = drm_iowr::<DrmModeObjGetProperties>(0xb9);
+const DRM_IOCTL_MODE_GETFB2: c_ulong = drm_iowr::<DrmModeFbCmd2>(0xce);
+
+const DRM_CLIENT_CAP_UNIVERSAL_PLANES: u64 = 2;
+const DRM_MODE_OBJECT_PLANE: u32 = 0xeeeeeeee;
+const DRM_MODE_ROTATE_0: u64 = 1 << 0;
+const DRM_MODE_ROTATE_90: u64 = 1 << 1;
+const DRM_MODE_ROTATE_180: u64 = 1 << 2;
+const DRM_MODE_ROTATE_270: u64 = 1 << 3;
+const DRM_MODE_ROTATE_MASK: u64 = 0xf;
+const DRM_MODE_REFLECT_X: u64 = 1 << 4;
+const DRM_MODE_REFLECT_Y: u64 = 1 << 5;
+const DRM_MODE_CONNECTED: u32 = 1;
+const DRM_MODE_FB_MODIFIERS: u32 = 1 << 1;
+const DRM_MODE_FLAG_INTERLACE: u32 = 1 << 4;
+const DRM_MODE_FLAG_DBLSCAN: u32 = 1 << 5;
+
+/// Indexed by `DRM_MODE_CONNECTOR_*`, as in the kernel's `drm_connector_enum_list`.
+const CONNECTOR_TYPE_NAMES: &[&str] = &[
+    "Unknown",
+    "VGA",
+    "DVI-I",
+    "DVI-D",
+    "DVI-A",
+    "Composite",
+    "SVIDEO",
+    "LVDS",
+    "Component",
+    "DIN",
+    "DP",
+    "HDMI-A",
+    "HDMI-B",
+    "TV",
+    "eDP",
+    "Virtual",
+    "DSI",
+    "DPI",
+    "Writeback",
+    "SPI",
+    "USB",
+];
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmGemClose {
+    handle: u32,
+    pad: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmSetClientCap {
+    capability: u64,
+    value: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmPrimeHandle {
+    handle: u32,
+    flags: u32,
+    fd: i32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmModeCardRes {
+    fb_id_ptr: u64,
+    crtc_id_ptr: u64,
+    connector_id_ptr: u64,
+    encoder_id_ptr: u64,
+    count_fbs: u32,
+    count_crtcs: u32,
+    count_connectors: u32,
+    count_encoders: u32,
+    min_width: u32,
+    max_width: u32,
+    min_height: u32,
+    max_height: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmModeModeInfo {
+    clock: u32,
+    hdisplay: u16,
+    hsync_start: u16,
+    hsync_end: u16,
+    htotal: u16,
+    hskew: u16,
+    vdisplay: u16,
+    vsync_start: u16,
+    vsync_end: u16,
+    vtotal: u16,
+    vscan: u16,
+    vrefresh: u32,
+    flags: u32,
+    type_: u32,
+    name: [u8; 32],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmModeCrtc {
+    set_connectors_ptr: u64,
+    count_connectors: u32,
+    crtc_id: u32,
+    fb_id: u32,
+    x: u32,
+    y: u32,
+    gamma_size: u32,
+    mode_valid: u32,
+    mode: DrmModeModeInfo,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmModeGetEncoder {
+    encoder_id: u32,
+    encoder_type: u32,
+    crtc_id: u32,
+    possible_crtcs: u32,
+    possible_clones: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmModeGetConnector {
+    encoders_ptr: u64,
+    modes_ptr: u64,
+    props_ptr: u64,
+    prop_values_ptr: u64,
+    count_modes: u32,
+    count_props: u32,
+    count_encoders: u32,
+    encoder_id: u32,
+    connector_id: u32,
+    connector_type: u32,
+    connector_type_id: u32,
+    connection: u32,
+    mm_width: u32,
+    mm_height: u32,
+    subpixel: u32,
+    pad: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmModeGetPlaneRes {
+    plane_id_ptr: u64,
+    count_planes: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmModeGetPlane {
+    plane_id: u32,
+    crtc_id: u32,
+    fb_id: u32,
+    possible_crtcs: u32,
+    gamma_size: u32,
+    count_format_types: u32,
+    format_type_ptr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmModeObjGetProperties {
+    props_ptr: u64,
+    prop_values_ptr: u64,
+    count_props: u32,
+    obj_id: u32,
+    obj_type: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmModeGetProperty {
+    values_ptr: u64,
+    enum_blob_ptr: u64,
+    prop_id: u32,
+    flags: u32,
+    name: [u8; 32],
+    count_values: u32,
+    count_enum_blobs: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmModeFbCmd2 {
+    fb_id: u32,
+    width: u32,
+    height: u32,
+    pixel_format: u32,
+    flags: u32,
+    handles: [u32; 4],
+    pitches: [u32; 4],
+    offsets: [u32; 4],
+    modifier: [u64; 4],
+}
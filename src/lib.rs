@@ -1,29 +1,378 @@
 #![allow(dead_code)]
-use frame::{DrmFormat, WlxFrame};
+use frame::{DrmFormat, SourceKind, WlxFrame};
 
+pub mod ack;
+pub mod ambient;
+pub mod audit;
+pub mod callback;
+pub mod convert;
+pub mod copy;
+pub mod cursor;
+pub mod factory;
+pub mod fault;
+pub mod fd_debug;
 pub mod frame;
+pub mod group;
+pub mod handoff;
+pub mod hook;
+pub mod output;
+pub mod picker;
+pub mod region;
+pub mod screenshot;
+pub mod thumbnail;
+pub mod verify;
+
+#[cfg(any(feature = "wayland", feature = "xshm"))]
+pub mod idle;
+
+#[cfg(feature = "kms")]
+pub mod kms;
+
+#[cfg(feature = "logind")]
+pub mod session;
+
+#[cfg(feature = "testing")]
+pub mod mock;
+
+#[cfg(feature = "cosmic")]
+pub mod cosmic;
+
+#[cfg(feature = "wlr")]
+pub mod ext_image_copy;
+
+#[cfg(feature = "tokio")]
+pub mod tokio_channel;
+
+#[cfg(feature = "wayland")]
+pub mod preview;
+
+#[cfg(feature = "wayland")]
+pub mod protocol_log;
+
+#[cfg(feature = "wlr")]
+pub mod repaint;
+
+#[cfg(feature = "wlr")]
+pub mod virtual_output;
 
 #[cfg(feature = "wayland")]
 pub mod wayland;
 
+#[cfg(feature = "weston")]
+pub mod weston;
+
+#[cfg(feature = "wl-drm")]
+pub mod wl_drm;
+
 #[cfg(feature = "wlr")]
 pub mod wlr_dmabuf;
 
 #[cfg(feature = "wlr")]
 pub mod wlr_screencopy;
 
+#[cfg(feature = "pipewire")]
+pub mod mutter;
+
 #[cfg(feature = "pipewire")]
 pub mod pipewire;
 
+#[cfg(feature = "xshm")]
+pub mod xcomposite;
+
 #[cfg(feature = "xshm")]
 pub mod xshm;
 
+/// The capture backends provided by this crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BackendKind {
+    Pipewire,
+    WlrDmabuf,
+    WlrScreencopy,
+    /// Windows, and outputs on compositors without wlr-screencopy; see `ext_image_copy`.
+    ExtImageCopy,
+    Xshm,
+    /// Windows on X11, see `xcomposite`.
+    XComposite,
+    /// The framebuffers on screen, without a compositor; see `kms`.
+    Kms,
+    /// Outputs on Weston, see `weston`.
+    Weston,
+}
+
+impl BackendKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BackendKind::Pipewire => "pipewire",
+            BackendKind::WlrDmabuf => "wlr-dmabuf",
+            BackendKind::WlrScreencopy => "wlr-screencopy",
+            BackendKind::ExtImageCopy => "ext-image-copy",
+            BackendKind::Xshm => "xshm",
+            BackendKind::XComposite => "xcomposite",
+            BackendKind::Kms => "kms",
+            BackendKind::Weston => "weston",
+        }
+    }
+}
+
+impl std::str::FromStr for BackendKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pipewire" => Ok(BackendKind::Pipewire),
+            "wlr-dmabuf" => Ok(BackendKind::WlrDmabuf),
+            "wlr-screencopy" => Ok(BackendKind::WlrScreencopy),
+            "ext-image-copy" => Ok(BackendKind::ExtImageCopy),
+            "xshm" => Ok(BackendKind::Xshm),
+            "xcomposite" => Ok(BackendKind::XComposite),
+            "kms" => Ok(BackendKind::Kms),
+            "weston" => Ok(BackendKind::Weston),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether a backend can be used in the current session.
+#[derive(Debug, Clone)]
+pub struct BackendInfo {
+    pub kind: BackendKind,
+    /// The backend's cargo feature was enabled.
+    pub compiled: bool,
+    pub usable: bool,
+    /// Why the backend is not usable, if it is not.
+    pub reason: Option<String>,
+}
+
+impl BackendInfo {
+    fn new(kind: BackendKind, compiled: bool, reason: Option<String>) -> Self {
+        let reason = if compiled {
+            reason
+        } else {
+            Some("not compiled in".into())
+        };
+        Self {
+            kind,
+            compiled,
+            usable: reason.is_none(),
+            reason,
+        }
+    }
+}
+
+/// Describe every backend, including ones that were not compiled in,
+/// and check whether it can be used in the current session.
+/// Connects to the Wayland compositor and X server to find out; does not start any capture.
+pub fn available_backends() -> Vec<BackendInfo> {
+    #[cfg(feature = "wlr")]
+    let (dmabuf, screencopy, ext_image_copy) = match wayland::WlxClient::new() {
+        Some(wl) => {
+            let missing = |interface: &str| {
+                let status = wl
+                    .protocol_report()
+                    .iter()
+                    .find(|p| p.interface == interface)?;
+                if status.bound.is_some() {
+                    return None;
+                }
+                Some(match status.offered {
+                    Some(offered) => format!("{} v{} is too old", interface, offered),
+                    None => format!("compositor does not offer {}", interface),
+                })
+            };
+            (
+                missing("zwlr_export_dmabuf_manager_v1"),
+                missing("zwlr_screencopy_manager_v1"),
+                missing("ext_image_copy_capture_manager_v1").or_else(|| {
+                    let windows = missing("ext_foreign_toplevel_image_capture_source_manager_v1")
+                        .or_else(|| missing("ext_foreign_toplevel_list_v1"));
+                    // usable for either windows or outputs
+                    windows.and(missing("ext_output_image_capture_source_manager_v1"))
+                }),
+            )
+        }
+        None => {
+            let reason = "no Wayland compositor with the required protocols".to_string();
+            (Some(reason.clone()), Some(reason.clone()), Some(reason))
+        }
+    };
+    #[cfg(not(feature = "wlr"))]
+    let (dmabuf, screencopy, ext_image_copy) = (None, None, None);
+
+    #[cfg(feature = "pipewire")]
+    let pipewire = {
+        let runtime_dir = std::env::var("XDG_RUNTIME_DIR").unwrap_or_default();
+        let remote = std::env::var("PIPEWIRE_REMOTE").unwrap_or_else(|_| "pipewire-0".into());
+        let socket = std::path::Path::new(&runtime_dir).join(remote);
+        if socket.exists() {
+            None
+        } else {
+            Some(format!("PipeWire socket {} not found", socket.display()))
+        }
+    };
+    #[cfg(not(feature = "pipewire"))]
+    let pipewire = None;
+
+    #[cfg(feature = "xshm")]
+    let xshm = match xshm::XshmCapture::get_monitors() {
+        Ok(monitors) if monitors.is_empty() => Some("X server reports no monitors".to_string()),
+        Ok(_) => None,
+        Err(e) => Some(e.to_string()),
+    };
+    #[cfg(not(feature = "xshm"))]
+    let xshm = None;
+
+    #[cfg(feature = "xshm")]
+    let xcomposite = std::env::var("DISPLAY")
+        .map_err(|e| e.to_string())
+        .and_then(|display| {
+            xcb::Connection::connect_with_extensions(
+                Some(&display),
+                &[xcb::Extension::Composite, xcb::Extension::Shm],
+                &[],
+            )
+            .map_err(|e| e.to_string())
+        })
+        .err();
+    #[cfg(not(feature = "xshm"))]
+    let xcomposite = None;
+
+    #[cfg(feature = "kms")]
+    let kms = match kms::KmsCapture::get_outputs() {
+        Ok(outputs) => match outputs.first() {
+            Some(output) => output.check_access().err().map(|e| e.to_string()),
+            None => Some("no DRM device with active outputs".to_string()),
+        },
+        Err(e) => Some(e.to_string()),
+    };
+    #[cfg(not(feature = "kms"))]
+    let kms = None;
+
+    #[cfg(feature = "weston")]
+    let weston = match wayland::WlxClient::new() {
+        Some(wl) if wl.maybe_weston_capture.is_some() => None,
+        Some(_) => Some(
+            "compositor does not offer weston_capture_v1 to this client; \
+             Weston only offers it to privileged clients"
+                .to_string(),
+        ),
+        None => Some("no Wayland compositor with the required protocols".to_string()),
+    };
+    #[cfg(not(feature = "weston"))]
+    let weston = None;
+
+    vec![
+        BackendInfo::new(BackendKind::Pipewire, cfg!(feature = "pipewire"), pipewire),
+        BackendInfo::new(BackendKind::WlrDmabuf, cfg!(feature = "wlr"), dmabuf),
+        BackendInfo::new(
+            BackendKind::WlrScreencopy,
+            cfg!(feature = "wlr"),
+            screencopy,
+        ),
+        BackendInfo::new(
+            BackendKind::ExtImageCopy,
+            cfg!(feature = "wlr"),
+            ext_image_copy,
+        ),
+        BackendInfo::new(BackendKind::Xshm, cfg!(feature = "xshm"), xshm),
+        BackendInfo::new(BackendKind::XComposite, cfg!(feature = "xshm"), xcomposite),
+        BackendInfo::new(BackendKind::Kms, cfg!(feature = "kms"), kms),
+        BackendInfo::new(BackendKind::Weston, cfg!(feature = "weston"), weston),
+    ]
+}
+
+/// Rough cost of getting a frame to the consumer, for energy-aware backend choices.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CopyCost {
+    /// The compositor's GPU buffer is shared as-is.
+    ZeroCopy,
+    /// The frame is read back into shared memory once, then uploaded by the consumer.
+    #[default]
+    SingleCopy,
+    /// As `SingleCopy`, with an extra CPU copy inside the backend.
+    DoubleCopy,
+}
+
+/// What a capture backend is able to do.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaptureCaps {
+    pub dmabuf: bool,
+    /// Expected cost with the current configuration and, where known, negotiated format.
+    pub cost: CopyCost,
+    /// The cursor is composited into captured frames.
+    pub embedded_cursor: bool,
+    /// The seat whose cursor is composited can be selected.
+    /// When false, the compositor draws every seat's cursor into the frame.
+    pub per_seat_cursor: bool,
+}
+
+/// A capture refused to allocate shared memory beyond its configured limit.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryLimitExceeded {
+    /// Bytes needed to hold a single frame.
+    pub required: usize,
+    pub limit: usize,
+}
+
+/// Runs on a backend's capture thread for every new frame, see `WlxCapture::set_frame_callback`.
+/// The frame's buffers are only guaranteed to stay valid until the callback returns.
+pub type FrameCallback = Box<dyn FnMut(WlxFrame) + Send>;
+
 pub trait WlxCapture {
     fn init(&mut self, dmabuf_formats: &[DrmFormat]);
     fn is_ready(&self) -> bool;
     fn supports_dmbuf(&self) -> bool;
+    fn caps(&self) -> CaptureCaps {
+        let dmabuf = self.supports_dmbuf();
+        CaptureCaps {
+            dmabuf,
+            cost: if dmabuf {
+                CopyCost::ZeroCopy
+            } else {
+                CopyCost::SingleCopy
+            },
+            ..Default::default()
+        }
+    }
+    /// What kind of source this captures, also found in `FrameMeta::source`.
+    fn source_kind(&self) -> SourceKind {
+        SourceKind::Monitor
+    }
     fn receive(&mut self) -> Option<WlxFrame>;
     fn pause(&mut self);
     fn resume(&mut self);
     fn request_new_frame(&mut self);
+    /// When the capture's worker thread last made progress, in nanoseconds of
+    /// `CLOCK_MONOTONIC` like `FrameMeta::timestamp`. A thread that waits for frames
+    /// still checks in every `frame::HEARTBEAT_INTERVAL`, so a much older timestamp means
+    /// that it is stuck or gone, not that the screen is static. Backends that only run a
+    /// thread while a frame is requested report activity only while one is in flight.
+    /// None if the backend has no worker thread or it has not started yet.
+    fn last_activity(&self) -> Option<u64> {
+        None
+    }
+    /// When the next frame is expected, in nanoseconds of `CLOCK_MONOTONIC` like
+    /// `FrameMeta::timestamp`, from the stream's frame rate or the output's refresh rate.
+    /// Lets consumers pick up frames just before their own deadline instead of polling
+    /// `receive` several times per frame. Only a hint: nothing arrives while the screen
+    /// is static. None if the backend has no cadence to go by, or no frame arrived yet.
+    fn next_frame_eta(&self) -> Option<u64> {
+        None
+    }
+    /// Draw the cursor into frames or leave it out, starting with the next requested frame,
+    /// so that consumers can switch between an embedded cursor and drawing their own.
+    /// Returns false if the backend cannot do so; `caps` tells whether frames contain it.
+    fn set_embedded_cursor(&mut self, embedded: bool) -> bool {
+        embedded == self.caps().embedded_cursor
+    }
+    /// Stop capturing new frames and keep delivering a copy of the last one, flagged as
+    /// a duplicate. Does nothing unless the backend implements it, before the first frame,
+    /// and for DMA-buf frames, which cannot be copied and stay shared with the producer.
+    fn freeze(&mut self) {}
+    fn unfreeze(&mut self) {}
+    /// Deliver new frames to `callback` on the capture thread instead of through `receive`,
+    /// so that imports and uploads can happen off the render thread.
+    /// Must be called before `init`. Returns the callback if the backend does not support it.
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        Err(callback)
+    }
 }
@@ -0,0 +1,411 @@
+//! A backend that draws synthetic frames instead of capturing anything, for integration
+//! tests of consumers on machines without a compositor or X server.
+
+use std::{
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    fault,
+    frame::{
+        monotonic_ns, Activity, DrmFormat, FrameFormat, FrameMeta, LastFrame, MemPtrFrame,
+        RowOrder, SourceKind, WlxFrame, DRM_FORMAT_XRGB8888, HEARTBEAT_INTERVAL,
+    },
+    FrameCallback, WlxCapture,
+};
+
+/// What a `MockCapture` draws.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MockPattern {
+    /// Eight vertical bars: white, yellow, cyan, green, magenta, red, blue, black.
+    #[default]
+    ColorBars,
+    /// A diagonal gradient that moves by a few pixels every frame, so that consecutive
+    /// frames differ.
+    Gradient,
+}
+
+/// Colors of `MockPattern::ColorBars`, as XRGB8888 in memory order: B, G, R, X.
+const BARS: [[u8; 4]; 8] = [
+    [0xff, 0xff, 0xff, 0xff],
+    [0x00, 0xff, 0xff, 0xff],
+    [0xff, 0xff, 0x00, 0xff],
+    [0x00, 0xff, 0x00, 0xff],
+    [0xff, 0x00, 0xff, 0xff],
+    [0x00, 0x00, 0xff, 0xff],
+    [0xff, 0x00, 0x00, 0xff],
+    [0x00, 0x00, 0x00, 0xff],
+];
+
+/// Pixels the gradient moves by per frame.
+const GRADIENT_STEP: u32 = 4;
+
+/// Draw frame number `seq` of `pattern`, tightly packed XRGB8888.
+/// The gradient wraps around at the edges, so it keeps moving for any `seq`.
+fn draw(pattern: MockPattern, width: u32, height: u32, seq: u64) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+    let (w, h) = (width.max(1) as u64, height.max(1) as u64);
+    let offset = seq.wrapping_mul(GRADIENT_STEP as u64);
+    let (offset_x, offset_y) = (offset % w, offset % h);
+    for y in 0..height as u64 {
+        for x in 0..width as u64 {
+            let px = match pattern {
+                MockPattern::ColorBars => BARS[(x * BARS.len() as u64 / w) as usize],
+                MockPattern::Gradient => {
+                    let r = ((x + offset_x) % w * 255 / w) as u8;
+                    let g = ((y + offset_y) % h * 255 / h) as u8;
+                    [r ^ g, g, r, 0xff]
+                }
+            };
+            pixels.extend_from_slice(&px);
+        }
+    }
+    pixels
+}
+
+/// Delivers synthetic `WlxFrame::MemPtr` frames in XRGB8888, tightly packed, through the
+/// usual request/receive cycle. Frames are drawn on request, but no more often than `fps`.
+/// A frame stays valid until the next one is received.
+pub struct MockCapture {
+    width: u32,
+    height: u32,
+    fps: u32,
+    pattern: MockPattern,
+    source_kind: SourceKind,
+    sender: Option<mpsc::SyncSender<()>>,
+    receiver: Option<mpsc::Receiver<(WlxFrame, Vec<u8>)>>,
+    /// Backing memory of the last received frame.
+    pixels: Vec<u8>,
+    last_frame: LastFrame,
+    frame_callback: Option<FrameCallback>,
+    activity: Activity,
+}
+
+impl MockCapture {
+    pub fn new(width: u32, height: u32, fps: u32) -> Self {
+        Self {
+            width,
+            height,
+            fps,
+            pattern: MockPattern::default(),
+            source_kind: SourceKind::Monitor,
+            sender: None,
+            receiver: None,
+            pixels: Vec::new(),
+            last_frame: LastFrame::default(),
+            frame_callback: None,
+            activity: Activity::default(),
+        }
+    }
+
+    pub fn with_pattern(mut self, pattern: MockPattern) -> Self {
+        self.pattern = pattern;
+        self
+    }
+
+    /// Pretend to capture another kind of source, e.g. a window.
+    pub fn with_source_kind(mut self, source_kind: SourceKind) -> Self {
+        self.source_kind = source_kind;
+        self
+    }
+
+    fn interval(&self) -> Option<u64> {
+        (self.fps > 0).then(|| 1_000_000_000 / self.fps as u64)
+    }
+}
+
+impl WlxCapture for MockCapture {
+    fn init(&mut self, _: &[DrmFormat]) {
+        let (tx_frame, rx_frame) = mpsc::sync_channel(4);
+        let (tx_cmd, rx_cmd) = mpsc::sync_channel(2);
+        self.sender = Some(tx_cmd);
+        self.receiver = Some(rx_frame);
+
+        std::thread::spawn({
+            let (width, height, pattern) = (self.width, self.height, self.pattern);
+            let source = self.source_kind;
+            let interval = self.interval().map(Duration::from_nanos);
+            let mut frame_callback = self.frame_callback.take();
+            let activity = self.activity.clone();
+            move || {
+                let mut seq = 0;
+                let mut next_frame = Instant::now();
+                loop {
+                    activity.touch();
+                    match rx_cmd.recv_timeout(HEARTBEAT_INTERVAL) {
+                        Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                        Ok(_) => {
+                            if let Some(interval) = interval {
+                                std::thread::sleep(
+                                    next_frame.saturating_duration_since(Instant::now()),
+                                );
+                                next_frame = Instant::now() + interval;
+                            }
+                            let pixels = draw(pattern, width, height, seq);
+                            seq += 1;
+                            let frame = WlxFrame::MemPtr(MemPtrFrame {
+                                format: FrameFormat {
+                                    width,
+                                    height,
+                                    fourcc: DRM_FORMAT_XRGB8888.into(),
+                                    origin: RowOrder::TopDown,
+                                    ..Default::default()
+                                },
+                                meta: FrameMeta {
+                                    timestamp: monotonic_ns(),
+                                    source,
+                                    ..Default::default()
+                                },
+                                ptr: pixels.as_ptr() as _,
+                                size: pixels.len(),
+                                mouse: None,
+                            });
+                            log::trace!("mock: drew frame {}", seq);
+
+                            if let Some(callback) = frame_callback.as_mut() {
+                                callback(frame);
+                                continue;
+                            }
+                            match fault::try_send(&tx_frame, (frame, pixels)) {
+                                Ok(_) => (),
+                                Err(mpsc::TrySendError::Full(_)) => {
+                                    log::debug!("mock: channel full");
+                                }
+                                Err(mpsc::TrySendError::Disconnected(_)) => {
+                                    log::warn!("mock: capture thread channel closed (send)");
+                                    break;
+                                }
+                            }
+                        }
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
+                            log::debug!("mock: capture thread channel closed (recv)");
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+    }
+    fn is_ready(&self) -> bool {
+        self.receiver.is_some()
+    }
+    fn supports_dmbuf(&self) -> bool {
+        false
+    }
+    fn source_kind(&self) -> SourceKind {
+        self.source_kind
+    }
+    fn receive(&mut self) -> Option<WlxFrame> {
+        if self.last_frame.frozen {
+            return self.last_frame.duplicate();
+        }
+        if let Some((frame, pixels)) = self.receiver.as_ref()?.try_iter().last() {
+            self.pixels = pixels;
+            return self.last_frame.update(Some(frame));
+        }
+        self.last_frame.take_stale()
+    }
+    fn pause(&mut self) {}
+    fn resume(&mut self) {
+        self.receive(); // clear old frames
+        self.last_frame.mark_resumed();
+        self.request_new_frame();
+    }
+    fn request_new_frame(&mut self) {
+        if self.last_frame.frozen {
+            return;
+        }
+        if let Some(sender) = &self.sender {
+            if let Err(e) = sender.try_send(()) {
+                log::trace!("mock: frame already requested: {}", e);
+            }
+        }
+    }
+    fn last_activity(&self) -> Option<u64> {
+        self.activity.get()
+    }
+    fn next_frame_eta(&self) -> Option<u64> {
+        self.last_frame.eta(self.interval()?)
+    }
+    fn freeze(&mut self) {
+        self.last_frame.freeze();
+    }
+    fn unfreeze(&mut self) {
+        self.last_frame.frozen = false;
+        self.request_new_frame();
+    }
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        self.frame_callback = Some(callback);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        convert::{RgbaCapture, RGBA_FOURCC},
+        frame::CropRect,
+        hook::{HookCapture, HookResult},
+        region::RegionSource,
+    };
+
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 16;
+
+    /// Request frames until one with an image arrives.
+    fn next_frame(capture: &mut dyn WlxCapture) -> Option<WlxFrame> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(2) {
+            capture.request_new_frame();
+            if let Some(frame @ WlxFrame::MemPtr(_)) = capture.receive() {
+                return Some(frame);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        None
+    }
+
+    fn pixels(frame: &WlxFrame) -> Vec<u8> {
+        let WlxFrame::MemPtr(memptr) = frame else {
+            panic!("expected a MemPtr frame");
+        };
+        unsafe { std::slice::from_raw_parts(memptr.ptr as *const u8, memptr.size) }.to_vec()
+    }
+
+    fn mock() -> MockCapture {
+        MockCapture::new(WIDTH, HEIGHT, 0)
+    }
+
+    #[test]
+    fn gradient_never_overflows() {
+        for seq in [0, 1, u32::MAX as u64, u64::MAX / 4, u64::MAX] {
+            let pixels = draw(MockPattern::Gradient, WIDTH, HEIGHT, seq);
+            assert_eq!(pixels.len(), (WIDTH * HEIGHT * 4) as usize);
+        }
+        assert_ne!(
+            draw(MockPattern::Gradient, WIDTH, HEIGHT, 0),
+            draw(MockPattern::Gradient, WIDTH, HEIGHT, 1)
+        );
+        assert!(draw(MockPattern::Gradient, 0, 0, 7).is_empty());
+    }
+
+    #[test]
+    fn color_bars() {
+        let pixels = draw(MockPattern::ColorBars, WIDTH, HEIGHT, 0);
+        let bar_width = (WIDTH / BARS.len() as u32) as usize;
+        for (i, bar) in BARS.iter().enumerate() {
+            let px = i * bar_width * 4;
+            assert_eq!(&pixels[px..px + 4], bar);
+        }
+    }
+
+    #[test]
+    fn frozen_frames_outlive_new_ones() {
+        let mut capture = mock().with_pattern(MockPattern::Gradient);
+        capture.init(&[]);
+        let frame = next_frame(&mut capture).expect("no frame");
+        let expected = pixels(&frame);
+
+        capture.freeze();
+        for _ in 0..3 {
+            capture.request_new_frame();
+            let frozen = capture.receive().expect("no frozen frame");
+            assert!(frozen.meta().unwrap().duplicate);
+            assert_eq!(pixels(&frozen), expected);
+        }
+
+        capture.unfreeze();
+        let fresh = next_frame(&mut capture).expect("no frame after unfreeze");
+        assert!(!fresh.meta().unwrap().duplicate);
+        assert_ne!(pixels(&fresh), expected);
+    }
+
+    #[test]
+    fn resume_repeats_the_last_frame() {
+        let mut capture = mock().with_pattern(MockPattern::Gradient);
+        capture.init(&[]);
+        let frame = next_frame(&mut capture).expect("no frame");
+        let expected = pixels(&frame);
+
+        capture.pause();
+        capture.resume();
+        let stale = capture.receive().expect("no stale frame");
+        let meta = stale.meta().unwrap();
+        assert!(meta.stale && meta.duplicate);
+        assert_eq!(pixels(&stale), expected);
+    }
+
+    #[test]
+    fn regions_crop_the_shared_frame() {
+        let source = RegionSource::new(Box::new(mock()));
+        let rect = CropRect {
+            x: 8,
+            y: 4,
+            width: 16,
+            height: 100,
+        };
+        let mut left = source.add_region(rect);
+        let mut right = source.add_region(CropRect { x: 32, ..rect });
+        left.init(&[]);
+        right.init(&[]);
+
+        let frame = next_frame(&mut left).expect("no frame");
+        let meta = frame.meta().unwrap();
+        assert_eq!(meta.source, SourceKind::Region);
+        // clipped to the frame
+        assert_eq!(meta.crop, Some(CropRect { height: 12, ..rect }));
+
+        let frame = next_frame(&mut right).expect("no frame");
+        assert_eq!(frame.meta().unwrap().crop.map(|c| c.x), Some(32));
+    }
+
+    #[test]
+    fn rgba_conversion() {
+        let mut capture = RgbaCapture::new(Box::new(mock()));
+        capture.init(&[]);
+        let frame = next_frame(&mut capture).expect("no frame");
+        assert_eq!(frame.format().unwrap().fourcc.value, RGBA_FOURCC);
+
+        let pixels = pixels(&frame);
+        let bar_width = (WIDTH / BARS.len() as u32) as usize;
+        for (i, [b, g, r, _]) in BARS.iter().enumerate() {
+            let px = i * bar_width * 4;
+            assert_eq!(&pixels[px..px + 4], &[*r, *g, *b, 0xff]);
+        }
+    }
+
+    #[test]
+    fn rgba_conversion_in_callbacks() {
+        let (tx, rx) = mpsc::channel();
+        let mut capture = RgbaCapture::new(Box::new(mock()));
+        let callback: FrameCallback = Box::new(move |frame| {
+            let _ = tx.send(frame.format().map(|f| f.fourcc.value));
+        });
+        assert!(capture.set_frame_callback(callback).is_ok());
+        capture.init(&[]);
+        capture.request_new_frame();
+        let fourcc = rx.recv_timeout(Duration::from_secs(2)).expect("no frame");
+        assert_eq!(fourcc, Some(RGBA_FOURCC));
+    }
+
+    #[test]
+    fn hooks_see_and_drop_frames() {
+        let mut capture = HookCapture::new(Box::new(mock()));
+        capture.set_frame_hook(|_| HookResult::Drop);
+        capture.init(&[]);
+
+        let start = Instant::now();
+        while capture.stats().calls < 3 && start.elapsed() < Duration::from_secs(2) {
+            capture.request_new_frame();
+            assert!(capture.receive().is_none());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(capture.stats().calls >= 3);
+
+        capture.set_frame_hook(|_| HookResult::Continue);
+        assert!(next_frame(&mut capture).is_some());
+        assert!(capture.hook_active());
+    }
+}
@@ -0,0 +1,207 @@
+//! Screen casts straight from GNOME's Mutter, without going through the portal.
+//!
+//! The portal, which is built on top of Mutter's ScreenCast interface, asks the user to
+//! pick a source for every new session. Talking to Mutter directly records monitors by
+//! connector name without a dialog, for clients that the user trusts with the screen.
+//! Mutter may refuse callers it does not trust; fall back to the portal then.
+//!
+//! The streams are published on the session's PipeWire daemon and are captured with
+//! `PipewireCapture` like portal streams. They end when the session is stopped or dropped.
+
+use std::{collections::HashMap, future::poll_fn, pin::Pin};
+
+use ashpd::zbus;
+use zbus::export::futures_core::Stream as _;
+use zbus::proxy::{CacheProperties, SignalStream};
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+
+use crate::{
+    frame::SourceKind,
+    pipewire::{AshpdError, CursorMode, PipewireCapture, PipewireStream},
+};
+
+/// Bus name of Mutter's ScreenCast service.
+pub const MUTTER_BUS_NAME: &str = "org.gnome.Mutter.ScreenCast";
+
+const SCREEN_CAST_PATH: &str = "/org/gnome/Mutter/ScreenCast";
+const SCREEN_CAST_INTERFACE: &str = "org.gnome.Mutter.ScreenCast";
+const SESSION_INTERFACE: &str = "org.gnome.Mutter.ScreenCast.Session";
+const STREAM_INTERFACE: &str = "org.gnome.Mutter.ScreenCast.Stream";
+
+type Properties<'a> = HashMap<&'a str, Value<'a>>;
+
+/// A stream that was recorded but has not been assigned a PipeWire node yet.
+struct PendingStream {
+    proxy: zbus::Proxy<'static>,
+    node_added: Pin<Box<SignalStream<'static>>>,
+}
+
+/// A ScreenCast session with Mutter. Streams stay alive for as long as this is kept,
+/// as Mutter closes the session when its client leaves the bus.
+pub struct MutterScreenCast {
+    connection: zbus::Connection,
+    session: zbus::Proxy<'static>,
+    closed: Pin<Box<SignalStream<'static>>>,
+    pending: Vec<PendingStream>,
+    started: bool,
+}
+
+impl MutterScreenCast {
+    /// Whether Mutter's ScreenCast service is on the session bus.
+    pub async fn is_available() -> bool {
+        let Ok(connection) = zbus::Connection::session().await else {
+            return false;
+        };
+        let Ok(dbus) = zbus::fdo::DBusProxy::new(&connection).await else {
+            return false;
+        };
+        match MUTTER_BUS_NAME.try_into() {
+            Ok(name) => dbus.name_has_owner(name).await.unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    /// Create a session. Nothing is recorded until `start`.
+    pub async fn new() -> Result<Self, AshpdError> {
+        let connection = zbus::Connection::session().await?;
+        let screen_cast =
+            mutter_proxy(&connection, SCREEN_CAST_PATH, SCREEN_CAST_INTERFACE).await?;
+        let session_path: OwnedObjectPath = screen_cast
+            .call("CreateSession", &(Properties::new(),))
+            .await?;
+        log::debug!(
+            "Created Mutter ScreenCast session {}",
+            session_path.as_str()
+        );
+
+        let session = mutter_proxy(&connection, session_path.as_str(), SESSION_INTERFACE).await?;
+        let closed = session.receive_signal("Closed").await?;
+        Ok(Self {
+            connection,
+            session,
+            closed: Box::pin(closed),
+            pending: Vec::new(),
+            started: false,
+        })
+    }
+
+    /// Record the monitor on the given connector, e.g. the name of an `OutputInfo`
+    /// such as `DP-1`. An empty connector records the primary monitor.
+    /// Must be called before `start`.
+    pub async fn record_monitor(
+        &mut self,
+        connector: &str,
+        cursor_mode: CursorMode,
+    ) -> Result<(), AshpdError> {
+        let mode = match cursor_mode {
+            CursorMode::Hidden => 0u32,
+            CursorMode::Embedded => 1,
+            CursorMode::Metadata => 2,
+        };
+        let mut properties = Properties::new();
+        properties.insert("cursor-mode", mode.into());
+        let stream_path: OwnedObjectPath = self
+            .session
+            .call("RecordMonitor", &(connector, properties))
+            .await?;
+
+        let proxy = mutter_proxy(&self.connection, stream_path.as_str(), STREAM_INTERFACE).await?;
+        // subscribe before starting, as the node is announced right after `Start`
+        let node_added = proxy.receive_signal("PipeWireStreamAdded").await?;
+        self.pending.push(PendingStream {
+            proxy,
+            node_added: Box::pin(node_added),
+        });
+        Ok(())
+    }
+
+    /// Start the session and wait for the PipeWire nodes of the recorded monitors,
+    /// in the order they were recorded.
+    pub async fn start(&mut self) -> Result<Vec<PipewireStream>, AshpdError> {
+        self.session.call::<_, _, ()>("Start", &()).await?;
+        self.started = true;
+
+        let mut streams = Vec::with_capacity(self.pending.len());
+        for mut pending in self.pending.drain(..) {
+            let Some(message) = poll_fn(|cx| pending.node_added.as_mut().poll_next(cx)).await
+            else {
+                return Err(AshpdError::NoResponse);
+            };
+            let node_id: u32 = message.body().deserialize()?;
+
+            let mut parameters: HashMap<String, OwnedValue> = pending
+                .proxy
+                .get_property("Parameters")
+                .await
+                .unwrap_or_default();
+            let mut pair = |key: &str| {
+                parameters
+                    .remove(key)
+                    .and_then(|v| <(i32, i32)>::try_from(v).ok())
+            };
+            streams.push(PipewireStream {
+                node_id,
+                position: pair("position"),
+                size: pair("size"),
+                source_kind: SourceKind::Monitor,
+            });
+        }
+        Ok(streams)
+    }
+
+    /// Wait until Mutter closes the session, e.g. because the monitor was unplugged.
+    pub async fn closed(&mut self) {
+        let _ = poll_fn(|cx| self.closed.as_mut().poll_next(cx)).await;
+    }
+
+    /// Stop all streams of the session.
+    pub async fn stop(self) -> Result<(), AshpdError> {
+        if self.started {
+            self.session.call::<_, _, ()>("Stop", &()).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Record one monitor without showing a dialog, and set up a capture of it.
+/// The capture has not been initialized yet. Keep the session for as long as it is used.
+pub async fn mutter_capture_monitor(
+    connector: &str,
+    embed_mouse: bool,
+) -> Result<(MutterScreenCast, PipewireCapture), AshpdError> {
+    let cursor_mode = if embed_mouse {
+        CursorMode::Embedded
+    } else {
+        CursorMode::Hidden
+    };
+    let mut session = MutterScreenCast::new().await?;
+    session.record_monitor(connector, cursor_mode).await?;
+    let stream = session
+        .start()
+        .await?
+        .into_iter()
+        .next()
+        .ok_or(AshpdError::NoResponse)?;
+
+    let name = if connector.is_empty() {
+        "primary"
+    } else {
+        connector
+    };
+    let capture = PipewireCapture::new(name.into(), stream.node_id);
+    Ok((session, capture))
+}
+
+async fn mutter_proxy(
+    connection: &zbus::Connection,
+    path: &str,
+    interface: &'static str,
+) -> Result<zbus::Proxy<'static>, zbus::Error> {
+    zbus::proxy::Builder::new(connection)
+        .destination(MUTTER_BUS_NAME)?
+        .path(path.to_string())?
+        .interface(interface)?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await
+}
@@ -0,0 +1,51 @@
+use std::sync::Arc;
+
+use crate::frame::Transform;
+
+/// Description of an output that is the same for Wayland and X11 sessions.
+/// Obtained from `WlxClient::output_infos`, `XshmCapture::get_output_infos`
+/// or `KmsCapture::get_output_infos`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputInfo {
+    /// Wayland global name, or the index of the monitor on X11 and KMS.
+    pub id: u32,
+    pub name: Arc<str>,
+    /// Position on the desktop, in logical pixels.
+    pub logical_pos: (i32, i32),
+    /// Size on the desktop, in logical pixels.
+    pub logical_size: (i32, i32),
+    /// Resolution in pixels, before the transform is applied.
+    pub size: (i32, i32),
+    pub transform: Transform,
+    /// Pixels per logical pixel; 1.0 on X11.
+    pub scale: f32,
+    /// Refresh rate in mHz, or 0 if unknown.
+    pub refresh: i32,
+}
+
+impl OutputInfo {
+    /// Whether the transform swaps width and height.
+    pub fn is_rotated(&self) -> bool {
+        is_rotated(self.transform)
+    }
+}
+
+pub(crate) fn is_rotated(transform: Transform) -> bool {
+    matches!(
+        transform,
+        Transform::Rotated90 | Transform::Rotated270 | Transform::Flipped90 | Transform::Flipped270
+    )
+}
+
+/// Ratio of pixels to logical pixels along the output's width on the desktop.
+pub(crate) fn scale_of(size: (i32, i32), logical_size: (i32, i32), transform: Transform) -> f32 {
+    let width = if is_rotated(transform) {
+        size.1
+    } else {
+        size.0
+    };
+    if width <= 0 || logical_size.0 <= 0 {
+        return 1.0;
+    }
+    width as f32 / logical_size.0 as f32
+}
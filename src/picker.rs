@@ -0,0 +1,239 @@
+//! One list of everything that can be captured, whatever the session type,
+//! for applications that show their own source picker.
+//!
+//! Outputs are listed by the backends that can capture them. Windows come from whatever
+//! lists them in the session and are added with `add_window`. Sources that only the
+//! ScreenCast portal can provide show up as pseudo-entries that open the portal dialog.
+
+use std::fmt;
+
+use crate::{factory::CaptureDescriptor, frame::SourceKind, output::OutputInfo, BackendKind};
+
+/// Identifies a source across sessions for as long as its name stays the same,
+/// e.g. to remember the user's choice.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SourceId(String);
+
+impl SourceId {
+    fn output(name: &str) -> Self {
+        Self(format!("output:{}", name))
+    }
+
+    fn window(identifier: &str) -> Self {
+        Self(format!("window:{}", identifier))
+    }
+
+    fn portal(kind: SourceKind) -> Self {
+        Self(format!("portal:{:?}", kind).to_lowercase())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SourceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<String> for SourceId {
+    fn from(id: String) -> Self {
+        Self(id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceTarget {
+    Output(OutputInfo),
+    Window {
+        app_id: String,
+        /// Handle of the window in the protocol that listed it.
+        identifier: String,
+    },
+    /// Selected in the portal dialog, which the user has to go through.
+    Portal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PickerEntry {
+    pub id: SourceId,
+    pub kind: SourceKind,
+    /// Name to show to the user.
+    pub label: String,
+    pub target: SourceTarget,
+    /// Backends that can capture the source, in the order they were added.
+    pub backends: Vec<BackendKind>,
+}
+
+/// Collects sources from all backends into one list. An entry added twice, e.g. an
+/// output listed by several backends, is kept once with the backends of both.
+#[derive(Debug, Clone, Default)]
+pub struct SourcePicker {
+    entries: Vec<PickerEntry>,
+}
+
+impl SourcePicker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add(&mut self, entry: PickerEntry) {
+        let Some(existing) = self.entries.iter_mut().find(|e| e.id == entry.id) else {
+            self.entries.push(entry);
+            return;
+        };
+        for backend in entry.backends {
+            if !existing.backends.contains(&backend) {
+                existing.backends.push(backend);
+            }
+        }
+    }
+
+    pub fn add_outputs(&mut self, outputs: &[OutputInfo], backends: &[BackendKind]) {
+        for output in outputs {
+            self.add(PickerEntry {
+                id: SourceId::output(&output.name),
+                kind: SourceKind::Monitor,
+                label: output.name.to_string(),
+                target: SourceTarget::Output(output.clone()),
+                backends: backends.to_vec(),
+            });
+        }
+    }
+
+    /// The outputs of a Wayland session, if the compositor offers a protocol to capture them.
+    #[cfg(feature = "wayland")]
+    pub fn add_wayland_outputs(&mut self, wl: &crate::wayland::WlxClient) {
+        let mut backends = Vec::new();
+        if wl.maybe_wlr_dmabuf_mgr.is_some() {
+            backends.push(BackendKind::WlrDmabuf);
+        }
+        if wl.maybe_wlr_screencopy_mgr.is_some() {
+            backends.push(BackendKind::WlrScreencopy);
+        }
+        if wl.maybe_image_copy_mgr.is_some() && wl.maybe_output_source_mgr.is_some() {
+            backends.push(BackendKind::ExtImageCopy);
+        }
+        #[cfg(feature = "weston")]
+        if wl.maybe_weston_capture.is_some() {
+            backends.push(BackendKind::Weston);
+        }
+        if !backends.is_empty() {
+            self.add_outputs(&wl.output_infos(), &backends);
+        }
+    }
+
+    /// The windows of a Wayland session, if the compositor can capture them.
+    #[cfg(feature = "wayland")]
+    pub fn add_wayland_toplevels(&mut self, wl: &crate::wayland::WlxClient) {
+        if wl.maybe_toplevel_source_mgr.is_none() || wl.maybe_image_copy_mgr.is_none() {
+            return;
+        }
+        for toplevel in wl.toplevels.iter().filter(|t| t.done) {
+            self.add_window(
+                &toplevel.app_id,
+                &toplevel.identifier,
+                &toplevel.title,
+                &[BackendKind::ExtImageCopy],
+            );
+        }
+    }
+
+    /// The monitors of an X11 session.
+    #[cfg(feature = "xshm")]
+    pub fn add_xshm_outputs(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let outputs = crate::xshm::XshmCapture::get_output_infos()?;
+        self.add_outputs(&outputs, &[BackendKind::Xshm]);
+        Ok(())
+    }
+
+    /// The windows of an X11 session.
+    #[cfg(feature = "xshm")]
+    pub fn add_x11_windows(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for window in crate::xcomposite::XCompositeCapture::get_windows()? {
+            self.add_window(
+                &window.app_id,
+                &window.id.to_string(),
+                &window.title,
+                &[BackendKind::XComposite],
+            );
+        }
+        Ok(())
+    }
+
+    /// The outputs that are lit up on the DRM devices, captured without a compositor.
+    #[cfg(feature = "kms")]
+    pub fn add_kms_outputs(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let outputs = crate::kms::KmsCapture::get_output_infos()?;
+        self.add_outputs(&outputs, &[BackendKind::Kms]);
+        Ok(())
+    }
+
+    /// A window, as listed by e.g. foreign-toplevel or the compositor's IPC.
+    /// `identifier` must stay the same for as long as the window exists.
+    pub fn add_window(
+        &mut self,
+        app_id: &str,
+        identifier: &str,
+        title: &str,
+        backends: &[BackendKind],
+    ) {
+        self.add(PickerEntry {
+            id: SourceId::window(identifier),
+            kind: SourceKind::Window,
+            label: title.into(),
+            target: SourceTarget::Window {
+                app_id: app_id.into(),
+                identifier: identifier.into(),
+            },
+            backends: backends.to_vec(),
+        });
+    }
+
+    /// Entries that open the portal dialog for the given kinds of source, e.g. the
+    /// `source_kinds` the portal reported, when they cannot be listed directly.
+    pub fn add_portal_entries(&mut self, kinds: &[SourceKind]) {
+        for &kind in kinds {
+            let label = match kind {
+                SourceKind::Monitor => "Screen",
+                SourceKind::Window => "Window",
+                SourceKind::Virtual => "Virtual screen",
+                SourceKind::Region => "Region",
+                SourceKind::Camera => "Camera",
+            };
+            self.add(PickerEntry {
+                id: SourceId::portal(kind),
+                kind,
+                label: label.into(),
+                target: SourceTarget::Portal,
+                backends: vec![BackendKind::Pipewire],
+            });
+        }
+    }
+
+    pub fn entries(&self) -> &[PickerEntry] {
+        &self.entries
+    }
+
+    pub fn get(&self, id: &SourceId) -> Option<&PickerEntry> {
+        self.entries.iter().find(|e| &e.id == id)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// A descriptor for capturing the entry with its first backend. None for windows
+    /// and portal entries, which have no descriptor that could bring them back.
+    pub fn descriptor(&self, id: &SourceId) -> Option<CaptureDescriptor> {
+        let entry = self.get(id)?;
+        match (&entry.target, entry.backends.first()) {
+            (SourceTarget::Output(output), Some(&backend)) => {
+                Some(CaptureDescriptor::new(backend, &output.name))
+            }
+            _ => None,
+        }
+    }
+}
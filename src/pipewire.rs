@@ -1,26 +1,49 @@
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
 
 use ashpd::desktop::{
-    screencast::{CursorMode, Screencast, SourceType},
-    PersistMode,
+    remote_desktop::{DeviceType, RemoteDesktop},
+    screencast::{Screencast, SourceType},
+    ResponseError, Session,
 };
+use ashpd::PortalError;
 
+pub use ashpd::desktop::screencast::CursorMode;
+pub use ashpd::desktop::PersistMode;
 pub use ashpd::Error as AshpdError;
 
+use ashpd::zbus;
+use zbus::export::futures_core::Stream as _;
+use zbus::proxy::{CacheProperties, OwnerChangedStream};
+
 use pipewire as pw;
 use pw::spa;
 
 use pw::properties::properties;
-use pw::stream::{Stream, StreamFlags};
+use pw::stream::{Stream, StreamFlags, StreamRef, StreamState};
 use pw::{context::Context, main_loop::MainLoop, Error};
+use spa::buffer::ChunkFlags;
+use spa::buffer::Data;
+use spa::buffer::DataFlags;
 use spa::buffer::DataType;
 use spa::buffer::MetaData;
 use spa::buffer::MetaType;
 use spa::param::video::VideoFormat;
 use spa::param::video::VideoInfoRaw;
 use spa::param::ParamType;
+use spa::pod::deserialize::PodDeserializer;
 use spa::pod::serialize::GenError;
 use spa::pod::ChoiceValue;
 use spa::pod::Pod;
@@ -29,9 +52,20 @@ use spa::utils::Choice;
 use spa::utils::ChoiceEnum;
 use spa::utils::ChoiceFlags;
 
+use crate::audit::{self, AuditEvent};
+use crate::factory::CaptureDescriptor;
+use crate::fault;
+use crate::frame::monotonic_ns;
+use crate::frame::Activity;
+use crate::frame::BufferKey;
 use crate::frame::DrmFormat;
 use crate::frame::FourCC;
 use crate::frame::FrameFormat;
+use crate::frame::FrameMeta;
+use crate::frame::LastFrame;
+use crate::frame::MouseMeta;
+use crate::frame::RowOrder;
+use crate::frame::SourceKind;
 use crate::frame::Transform;
 use crate::frame::WlxFrame;
 use crate::frame::DRM_FORMAT_ABGR2101010;
@@ -40,34 +74,235 @@ use crate::frame::DRM_FORMAT_ARGB8888;
 use crate::frame::DRM_FORMAT_XBGR2101010;
 use crate::frame::DRM_FORMAT_XBGR8888;
 use crate::frame::DRM_FORMAT_XRGB8888;
+use crate::frame::HEARTBEAT_INTERVAL;
 use crate::frame::{DmabufFrame, FramePlane, MemFdFrame, MemPtrFrame};
+use crate::frame::{DRM_FORMAT_NV12, DRM_FORMAT_UYVY, DRM_FORMAT_YUV420, DRM_FORMAT_YUYV};
+use crate::BackendKind;
+use crate::CaptureCaps;
+use crate::CopyCost;
+use crate::FrameCallback;
+use crate::MemoryLimitExceeded;
 use crate::WlxCapture;
 
+/// A single stream selected by the user in the portal dialog.
 pub struct PipewireStream {
     pub node_id: u32,
     pub position: Option<(i32, i32)>,
     pub size: Option<(i32, i32)>,
+    /// Monitor unless the portal reports otherwise.
+    pub source_kind: SourceKind,
+}
+
+impl From<SourceType> for SourceKind {
+    fn from(source_type: SourceType) -> Self {
+        match source_type {
+            SourceType::Monitor => SourceKind::Monitor,
+            SourceType::Window => SourceKind::Window,
+            SourceType::Virtual => SourceKind::Virtual,
+        }
+    }
 }
 
 pub struct PipewireSelectScreenResult {
+    /// One entry per selected source. Contains at most one entry unless `multiple` was set.
     pub streams: Vec<PipewireStream>,
     pub restore_token: Option<String>,
+    /// The cursor mode in effect, which may differ from the one asked for
+    /// if the portal does not support it.
+    pub cursor_mode: CursorMode,
+}
+
+/// Pick the first of `wanted` that the portal supports, or None if it supports none.
+/// Portals before version 2 do not report their modes; the first one is used for those.
+async fn select_cursor_mode(
+    screencast: &Screencast<'_>,
+    wanted: &[CursorMode],
+) -> Option<CursorMode> {
+    let first = *wanted.first()?;
+    let Ok(available) = screencast.available_cursor_modes().await else {
+        return Some(first);
+    };
+    let mode = wanted
+        .iter()
+        .copied()
+        .find(|mode| available.contains(*mode))?;
+    if mode != first {
+        log::warn!(
+            "Portal does not support cursor mode {:?}, using {:?}",
+            first,
+            mode
+        );
+    }
+    Some(mode)
+}
+
+/// Why the ScreenCast portal did not give out a stream, sorted by what the application
+/// can do about it.
+#[derive(Debug)]
+pub enum SelectScreenError {
+    /// The user dismissed the dialog. Asking again right away would be a nuisance.
+    Cancelled,
+    /// The portal refused without asking, e.g. because of a stored permission or a policy.
+    /// Asking again won't help; point the user to the desktop's privacy settings instead.
+    Denied(AshpdError),
+    /// There is no portal with ScreenCast, or it is too old. Fall back to another backend.
+    Unavailable(AshpdError),
+    /// The portal supports none of the cursor modes that fit `embed_mouse`, e.g. it can only
+    /// embed the cursor but was asked to leave it out of the image.
+    CursorUnsupported,
+    /// Anything else, e.g. no source was selected or the session bus went away.
+    Failed(AshpdError),
+}
+
+/// D-Bus errors of a name without an owner or an object without the interface.
+const DBUS_UNAVAILABLE_ERRORS: [&str; 4] = [
+    "org.freedesktop.DBus.Error.ServiceUnknown",
+    "org.freedesktop.DBus.Error.NameHasNoOwner",
+    "org.freedesktop.DBus.Error.UnknownInterface",
+    "org.freedesktop.DBus.Error.UnknownMethod",
+];
+
+impl From<AshpdError> for SelectScreenError {
+    fn from(e: AshpdError) -> Self {
+        let kind: fn(AshpdError) -> Self = match &e {
+            AshpdError::Response(ResponseError::Cancelled)
+            | AshpdError::Portal(PortalError::Cancelled(_)) => |_| Self::Cancelled,
+            // the portal ends the request with "other" when the permission store says no
+            AshpdError::Response(ResponseError::Other)
+            | AshpdError::Portal(PortalError::NotAllowed(_)) => Self::Denied,
+            AshpdError::PortalNotFound(_) | AshpdError::RequiresVersion(_, _) => Self::Unavailable,
+            AshpdError::Zbus(zbus::Error::MethodError(name, _, _)) => {
+                if DBUS_UNAVAILABLE_ERRORS.contains(&name.as_str()) {
+                    Self::Unavailable
+                } else if name.as_str() == "org.freedesktop.DBus.Error.AccessDenied" {
+                    Self::Denied
+                } else {
+                    Self::Failed
+                }
+            }
+            AshpdError::Zbus(zbus::Error::FDO(fdo)) => match **fdo {
+                zbus::fdo::Error::ServiceUnknown(_)
+                | zbus::fdo::Error::NameHasNoOwner(_)
+                | zbus::fdo::Error::UnknownInterface(_)
+                | zbus::fdo::Error::UnknownMethod(_) => Self::Unavailable,
+                zbus::fdo::Error::AccessDenied(_) => Self::Denied,
+                _ => Self::Failed,
+            },
+            _ => Self::Failed,
+        };
+        kind(e)
+    }
+}
+
+impl std::fmt::Display for SelectScreenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cancelled => f.write_str("Screen selection was cancelled"),
+            Self::Denied(e) => write!(f, "Screen capture was denied: {}", e),
+            Self::Unavailable(e) => write!(f, "ScreenCast portal is unavailable: {}", e),
+            Self::CursorUnsupported => {
+                f.write_str("ScreenCast portal does not support the requested cursor mode")
+            }
+            Self::Failed(e) => write!(f, "Screen selection failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SelectScreenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Cancelled | Self::CursorUnsupported => None,
+            Self::Denied(e) | Self::Unavailable(e) | Self::Failed(e) => Some(e),
+        }
+    }
 }
 
+/// Ask the ScreenCast portal for one or more sources to capture.
+///
+/// If `multiple` is set, the user may select several sources in a single dialog,
+/// and each will be returned as its own `PipewireStream`.
+/// If the portal cannot embed the cursor, it is hidden instead; see `cursor_mode` in the result.
+/// Without `embed_mouse`, the cursor is hidden or else sent as metadata, which keeps it out
+/// of the image too. If the portal supports neither of the modes tried,
+/// `SelectScreenError::CursorUnsupported` is returned.
 pub async fn pipewire_select_screen(
     token: Option<&str>,
     embed_mouse: bool,
     screens_only: bool,
     persist: bool,
     multiple: bool,
-) -> Result<PipewireSelectScreenResult, AshpdError> {
+) -> Result<PipewireSelectScreenResult, SelectScreenError> {
+    let persist_mode = if persist {
+        PersistMode::ExplicitlyRevoked
+    } else {
+        PersistMode::DoNot
+    };
+    pipewire_select_screen_with_persist_mode(
+        token,
+        embed_mouse,
+        screens_only,
+        persist_mode,
+        multiple,
+    )
+    .await
+}
+
+/// Like `pipewire_select_screen`, with control over how long the portal remembers
+/// the selection. `PersistMode::Application` tokens are only valid while this process runs,
+/// `PersistMode::ExplicitlyRevoked` ones until the user revokes them.
+pub async fn pipewire_select_screen_with_persist_mode(
+    token: Option<&str>,
+    embed_mouse: bool,
+    screens_only: bool,
+    persist_mode: PersistMode,
+    multiple: bool,
+) -> Result<PipewireSelectScreenResult, SelectScreenError> {
+    let result = start_screencast(token, embed_mouse, screens_only, persist_mode, multiple).await;
+    match &result {
+        Ok(selection) => {
+            let event = if token.is_some() {
+                AuditEvent::Restored
+            } else {
+                AuditEvent::Created
+            };
+            let nodes: Vec<String> = selection
+                .streams
+                .iter()
+                .map(|s| s.node_id.to_string())
+                .collect();
+            let identity = format!("portal nodes {}", nodes.join(", "));
+            audit::record(event, BackendKind::Pipewire, identity, None);
+        }
+        Err(e) => {
+            audit::record(
+                AuditEvent::Failed,
+                BackendKind::Pipewire,
+                "portal",
+                Some(e.to_string()),
+            );
+        }
+    }
+    result
+}
+
+async fn start_screencast(
+    token: Option<&str>,
+    embed_mouse: bool,
+    screens_only: bool,
+    persist_mode: PersistMode,
+    multiple: bool,
+) -> Result<PipewireSelectScreenResult, SelectScreenError> {
     let proxy = Screencast::new().await?;
     let session = proxy.create_session().await?;
 
-    let cursor_mode = if embed_mouse {
-        CursorMode::Embedded
+    let wanted = if embed_mouse {
+        [CursorMode::Embedded, CursorMode::Hidden]
     } else {
-        CursorMode::Hidden
+        [CursorMode::Hidden, CursorMode::Metadata]
+    };
+    let Some(cursor_mode) = select_cursor_mode(&proxy, &wanted).await else {
+        let _ = session.close().await;
+        return Err(SelectScreenError::CursorUnsupported);
     };
 
     let source_type = if screens_only {
@@ -76,12 +311,6 @@ pub async fn pipewire_select_screen(
         SourceType::Monitor | SourceType::Window | SourceType::Virtual
     };
 
-    let persist_mode = if persist {
-        PersistMode::ExplicitlyRevoked
-    } else {
-        PersistMode::DoNot
-    };
-
     proxy
         .select_sources(
             &session,
@@ -102,16 +331,396 @@ pub async fn pipewire_select_screen(
             node_id: stream.pipe_wire_node_id(),
             position: stream.position(),
             size: stream.size(),
+            source_kind: stream
+                .source_type()
+                .map_or(SourceKind::Monitor, SourceKind::from),
         })
         .collect();
     if !streams.is_empty() {
         return Ok(PipewireSelectScreenResult {
             streams,
             restore_token: response.restore_token().map(String::from),
+            cursor_mode,
         });
     }
 
-    Err(ashpd::Error::NoResponse)
+    Err(ashpd::Error::NoResponse.into())
+}
+
+/// A selection saved by the consumer, to be restored at the next startup.
+#[derive(Debug, Clone)]
+pub struct SavedSession {
+    /// The `restore_token` of the last result for this selection.
+    pub restore_token: String,
+    pub embed_mouse: bool,
+    pub screens_only: bool,
+    pub multiple: bool,
+}
+
+pub enum SessionRestore {
+    /// The session was recreated. Its new restore token must be saved, as the old one is spent.
+    Restored(PipewireSelectScreenResult),
+    /// The portal did not restore the session, e.g. because the token was revoked or the
+    /// dialog was dismissed. The source needs to be selected again.
+    NeedsSelection(SelectScreenError),
+}
+
+/// Recreate sessions for the given saved selections, one result per entry in the same order.
+/// Valid restore tokens let the portal start the session without showing a dialog.
+/// For a token that is no longer accepted the portal falls back to its dialog;
+/// if that is dismissed, the selection is reported as `NeedsSelection`.
+pub async fn pipewire_restore_sessions(
+    saved: &[SavedSession],
+    persist_mode: PersistMode,
+) -> Vec<SessionRestore> {
+    let mut results = Vec::with_capacity(saved.len());
+    for session in saved {
+        let result = pipewire_select_screen_with_persist_mode(
+            Some(&session.restore_token),
+            session.embed_mouse,
+            session.screens_only,
+            persist_mode,
+            session.multiple,
+        )
+        .await;
+        results.push(match result {
+            Ok(selection) => SessionRestore::Restored(selection),
+            Err(e) => {
+                log::info!("Portal session was not restored: {}", e);
+                SessionRestore::NeedsSelection(e)
+            }
+        });
+    }
+    results
+}
+
+/// Bus name of the desktop portal that provides ScreenCast and RemoteDesktop.
+pub const PORTAL_BUS_NAME: &str = "org.freedesktop.portal.Desktop";
+
+/// What the ScreenCast portal offers.
+#[derive(Debug, Clone)]
+pub struct PortalCaps {
+    pub source_kinds: Vec<SourceKind>,
+    pub cursor_modes: Vec<CursorMode>,
+}
+
+/// Ask the ScreenCast portal what it supports. Starts the portal if it is activatable
+/// but not running yet.
+pub async fn probe_portal() -> Result<PortalCaps, AshpdError> {
+    let screencast = Screencast::new().await?;
+    let source_types = screencast.available_source_types().await?;
+    let cursor_modes = screencast.available_cursor_modes().await?;
+    Ok(PortalCaps {
+        source_kinds: source_types.iter().map(SourceKind::from).collect(),
+        cursor_modes: cursor_modes.iter().collect(),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub enum PortalEvent {
+    /// The portal started or restarted. Streams of sessions from before a restart are
+    /// gone, so their captures need a new selection, which should now succeed.
+    /// None if the new portal did not answer the probe.
+    Started(Option<PortalCaps>),
+    /// The portal left the bus, e.g. because it crashed. Selections fail until it is back.
+    Stopped,
+}
+
+/// Follows the portal's ownership of its bus name, to notice when it goes away and
+/// when it comes back.
+pub struct PortalWatcher {
+    owner_changes: Pin<Box<OwnerChangedStream<'static>>>,
+}
+
+impl PortalWatcher {
+    pub async fn new() -> Result<Self, AshpdError> {
+        let connection = zbus::Connection::session().await?;
+        let portal: zbus::Proxy<'static> = zbus::proxy::Builder::new(&connection)
+            .destination(PORTAL_BUS_NAME)?
+            .path("/org/freedesktop/portal/desktop")?
+            .interface("org.freedesktop.portal.ScreenCast")?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        let owner_changes = portal.receive_owner_changed().await?;
+        Ok(Self {
+            owner_changes: Box::pin(owner_changes),
+        })
+    }
+
+    /// Wait until the portal starts or stops, and probe it after it started.
+    /// None if the connection to the session bus was lost.
+    pub async fn next_event(&mut self) -> Option<PortalEvent> {
+        let owner = poll_fn(|cx| self.owner_changes.as_mut().poll_next(cx)).await?;
+        if owner.is_none() {
+            log::info!("Portal left the bus");
+            return Some(PortalEvent::Stopped);
+        }
+        log::info!("Portal started, probing capabilities");
+        let caps = match probe_portal().await {
+            Ok(caps) => Some(caps),
+            Err(e) => {
+                log::warn!("Could not probe the portal: {}", e);
+                None
+            }
+        };
+        Some(PortalEvent::Started(caps))
+    }
+}
+
+/// The node a `PipewireCapture::camera` connects to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipewireTarget {
+    Id(u32),
+    /// `node.name`, which unlike the id survives restarts of the daemon.
+    Name(String),
+}
+
+/// `node.name` of the stream gamescope publishes its composited output on.
+pub const GAMESCOPE_NODE_NAME: &str = "gamescope";
+
+/// Whether this process runs inside a gamescope session, e.g. in Steam's gaming mode.
+pub fn is_gamescope_session() -> bool {
+    std::env::var_os("GAMESCOPE_WAYLAND_DISPLAY").is_some()
+}
+
+/// Look up gamescope's PipeWire node. gamescope publishes its output without going
+/// through the ScreenCast portal, so the node can be captured directly by its id.
+/// Blocks for one roundtrip to the PipeWire daemon, and fails if that takes more than
+/// two seconds. Ok(None) if no gamescope node exists.
+pub fn find_gamescope_node() -> Result<Option<u32>, Error> {
+    let nodes = find_nodes(|node| node.name == GAMESCOPE_NODE_NAME)?;
+    Ok(nodes.first().map(|node| node.id))
+}
+
+/// A node in the PipeWire graph.
+#[derive(Debug, Clone)]
+pub struct PipewireNode {
+    pub id: u32,
+    /// `node.name`, which stays the same across restarts of the daemon.
+    pub name: String,
+    /// `node.description`, for showing to users.
+    pub description: String,
+}
+
+/// List the cameras that PipeWire knows of, e.g. from libcamera or V4L2, for capturing
+/// with `PipewireCapture::camera`. Blocks for one roundtrip to the PipeWire daemon,
+/// and fails if that takes more than two seconds.
+pub fn find_camera_nodes() -> Result<Vec<PipewireNode>, Error> {
+    find_nodes(|node| {
+        node.props.get(*pw::keys::MEDIA_CLASS) == Some("Video/Source")
+            && node.props.get(*pw::keys::MEDIA_ROLE) == Some("Camera")
+    })
+}
+
+struct NodeProps<'a> {
+    name: &'a str,
+    props: &'a spa::utils::dict::DictRef,
+}
+
+/// How long looking up nodes waits for the PipeWire daemon to answer.
+const FIND_NODES_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The nodes for which `filter` returns true, in the order the registry announced them.
+/// Fails with ETIMEDOUT if the daemon does not answer within `FIND_NODES_TIMEOUT`.
+fn find_nodes(filter: impl Fn(&NodeProps) -> bool + 'static) -> Result<Vec<PipewireNode>, Error> {
+    let main_loop = MainLoop::new(None)?;
+    let context = Context::new(&main_loop)?;
+    let core = context.connect(None)?;
+    let registry = core.get_registry()?;
+
+    let found: Rc<RefCell<Vec<PipewireNode>>> = Rc::new(RefCell::new(Vec::new()));
+    let done = Rc::new(Cell::new(false));
+
+    // all globals are announced before the reply to this sync
+    let pending = core.sync(0)?;
+
+    let _core_listener = core
+        .add_listener_local()
+        .done({
+            let done = done.clone();
+            let main_loop = main_loop.clone();
+            move |id, seq| {
+                if id == pw::core::PW_ID_CORE && seq == pending {
+                    done.set(true);
+                    main_loop.quit();
+                }
+            }
+        })
+        .error({
+            let done = done.clone();
+            let main_loop = main_loop.clone();
+            move |id, _seq, res, message| {
+                log::warn!("PipeWire core error on {}: {} {}", id, res, message);
+                if id == pw::core::PW_ID_CORE {
+                    done.set(true);
+                    main_loop.quit();
+                }
+            }
+        })
+        .register();
+
+    let _registry_listener = registry
+        .add_listener_local()
+        .global({
+            let found = found.clone();
+            move |global| {
+                if global.type_ != pw::types::ObjectType::Node {
+                    return;
+                }
+                let Some(props) = global.props else {
+                    return;
+                };
+                let name = props.get(*pw::keys::NODE_NAME).unwrap_or_default();
+                if filter(&NodeProps { name, props }) {
+                    found.borrow_mut().push(PipewireNode {
+                        id: global.id,
+                        name: name.to_string(),
+                        description: props
+                            .get(*pw::keys::NODE_DESCRIPTION)
+                            .unwrap_or(name)
+                            .to_string(),
+                    });
+                }
+            }
+        })
+        .register();
+
+    let timed_out = Rc::new(Cell::new(false));
+    let timeout = main_loop.loop_().add_timer({
+        let timed_out = timed_out.clone();
+        let main_loop = main_loop.clone();
+        move |_| {
+            timed_out.set(true);
+            main_loop.quit();
+        }
+    });
+    let _ = timeout.update_timer(Some(FIND_NODES_TIMEOUT), None);
+
+    while !done.get() {
+        main_loop.run();
+        if timed_out.get() && !done.get() {
+            log::warn!(
+                "PipeWire did not list its nodes within {:?}",
+                FIND_NODES_TIMEOUT
+            );
+            let timed_out = spa::utils::result::SpaResult::from_c(-libc::ETIMEDOUT);
+            return Err(timed_out.into_result().unwrap_err().into());
+        }
+    }
+
+    Ok(found.take())
+}
+
+/// A ScreenCast selection made through a RemoteDesktop session.
+/// The session stays open for as long as this is kept alive.
+pub struct PipewireRemoteDesktop {
+    pub selection: PipewireSelectScreenResult,
+    proxy: RemoteDesktop<'static>,
+    session: Session<'static, RemoteDesktop<'static>>,
+}
+
+impl PipewireRemoteDesktop {
+    /// The portal proxy, for injecting input into the selected streams.
+    pub fn proxy(&self) -> &RemoteDesktop<'static> {
+        &self.proxy
+    }
+
+    pub fn session(&self) -> &Session<'static, RemoteDesktop<'static>> {
+        &self.session
+    }
+
+    /// End the session, which also stops its streams.
+    pub async fn close(self) -> Result<(), SelectScreenError> {
+        Ok(self.session.close().await?)
+    }
+}
+
+/// Like `pipewire_select_screen`, but pairs the ScreenCast with a RemoteDesktop session
+/// and asks for the cursor as metadata rather than embedded in the image.
+///
+/// The compositor then reports the authoritative pointer position with every buffer,
+/// which `PipewireCapture` delivers through `MemPtrFrame::mouse`, `WlxFrame::Mouse`
+/// and `PipewireCapture::mouse`.
+/// Pointer access is requested so that input can be injected through the same session.
+/// If the portal does not support cursor metadata, the cursor is embedded instead.
+/// Errors are classified as for `pipewire_select_screen`.
+pub async fn pipewire_select_remote_desktop(
+    token: Option<&str>,
+    screens_only: bool,
+    persist: bool,
+    multiple: bool,
+) -> Result<PipewireRemoteDesktop, SelectScreenError> {
+    let proxy = RemoteDesktop::new().await?;
+    let screencast = Screencast::new().await?;
+    let session = proxy.create_session().await?;
+
+    let source_type = if screens_only {
+        SourceType::Monitor.into()
+    } else {
+        SourceType::Monitor | SourceType::Window | SourceType::Virtual
+    };
+
+    let persist_mode = if persist {
+        PersistMode::ExplicitlyRevoked
+    } else {
+        PersistMode::DoNot
+    };
+
+    proxy
+        .select_devices(&session, DeviceType::Pointer.into(), token, persist_mode)
+        .await?;
+
+    let wanted = [
+        CursorMode::Metadata,
+        CursorMode::Embedded,
+        CursorMode::Hidden,
+    ];
+    // every portal supports at least one of them
+    let cursor_mode = select_cursor_mode(&screencast, &wanted)
+        .await
+        .unwrap_or(CursorMode::Hidden);
+
+    screencast
+        .select_sources(
+            &session,
+            cursor_mode,
+            source_type,
+            multiple,
+            None,
+            PersistMode::DoNot,
+        )
+        .await?;
+
+    let response = proxy.start(&session, None).await?.response()?;
+
+    let streams: Vec<_> = response
+        .streams()
+        .unwrap_or_default()
+        .iter()
+        .map(|stream| PipewireStream {
+            node_id: stream.pipe_wire_node_id(),
+            position: stream.position(),
+            size: stream.size(),
+            source_kind: stream
+                .source_type()
+                .map_or(SourceKind::Monitor, SourceKind::from),
+        })
+        .collect();
+    if streams.is_empty() {
+        let _ = session.close().await;
+        return Err(ashpd::Error::NoResponse.into());
+    }
+
+    Ok(PipewireRemoteDesktop {
+        selection: PipewireSelectScreenResult {
+            streams,
+            restore_token: response.restore_token().map(String::from),
+            cursor_mode,
+        },
+        proxy,
+        session,
+    })
 }
 
 #[derive(Default)]
@@ -120,9 +729,157 @@ struct StreamData {
     stream: Option<Stream>,
 }
 
+/// The graph clock that drove the cycle of the most recent frame.
+/// Recorders can use it to place frames on the same timeline as other PipeWire streams,
+/// such as an audio capture driven by the same clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PipewireClock {
+    /// Id of the driver's clock. Streams reporting the same id share a timeline.
+    pub id: u32,
+    /// CLOCK_MONOTONIC time at the start of the cycle, in nanoseconds.
+    pub nsec: u64,
+    /// Units of `position` and `duration`, as numerator and denominator in seconds.
+    pub rate: (u32, u32),
+    /// Position of the cycle on the driver's timeline, in `rate` units.
+    pub position: u64,
+    /// Length of the cycle, in `rate` units.
+    pub duration: u64,
+    /// Speed of the driver relative to its nominal rate, as used for rate matching.
+    pub rate_diff: f64,
+}
+
+/// The video sizes offered to the producer, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoSizeRange {
+    pub default: (u32, u32),
+    pub min: (u32, u32),
+    pub max: (u32, u32),
+}
+
+impl Default for VideoSizeRange {
+    /// Anything up to 8192x8192, leaving the choice to the producer.
+    fn default() -> Self {
+        Self {
+            default: (256, 256),
+            min: (1, 1),
+            max: (8192, 8192),
+        }
+    }
+}
+
+impl VideoSizeRange {
+    /// Prefer the known size of the source, e.g. `PipewireStream::size`, and nothing larger,
+    /// so that producers do not pick a scaled size. Smaller sizes stay allowed, since
+    /// windows shrink.
+    pub fn native(width: u32, height: u32) -> Self {
+        Self {
+            default: (width, height),
+            min: (1, 1),
+            max: (width, height),
+        }
+    }
+}
+
+/// Counters collected by the PipeWire loop thread.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PipewireStats {
+    /// Buffers received from PipeWire.
+    pub buffers: u64,
+    /// Buffers dropped because PipeWire marked them as corrupted.
+    pub corrupted: u64,
+}
+
+/// Events about the stream, other than frames.
+#[derive(Debug, Clone, Copy)]
+pub enum PipewireEvent {
+    /// A DMA-buf was added to the stream's buffer pool.
+    BufferAdded(BufferKey),
+    /// A DMA-buf was removed from the pool, e.g. because the stream renegotiated.
+    BufferRemoved(BufferKey),
+    /// Not even a single SHM buffer fits in the memory limit; the stream has been paused.
+    MemoryLimitExceeded(MemoryLimitExceeded),
+}
+
+/// Provides the DMA-bufs of a stream's buffer pool, e.g. from the consumer's GPU allocator,
+/// so that frames are rendered straight into images the consumer already owns.
+pub trait DmabufAllocator: Send {
+    /// Allocate a buffer for the negotiated format. The planes' fds remain owned by the allocator.
+    fn allocate(&mut self, format: &FrameFormat) -> Option<DmabufFrame>;
+    /// The stream has stopped using a buffer returned by `allocate`.
+    fn release(&mut self, buffer: &DmabufFrame);
+}
+
+/// State shared between a `PipewireCapture` and its loop thread.
+#[derive(Clone)]
+struct Shared {
+    pending_format: Arc<Mutex<Option<FrameFormat>>>,
+    stats: Arc<Mutex<PipewireStats>>,
+    events: mpsc::Sender<PipewireEvent>,
+    /// Cursor position reported since the last `receive`, if the stream carries cursor metadata.
+    mouse: Arc<Mutex<Option<MouseMeta>>>,
+    /// Upper bound for the SHM buffer pool, in bytes.
+    memory_limit: Option<usize>,
+    /// Formats the consumer would rather receive, most preferred first.
+    format_preference: Vec<FourCC>,
+    /// Allocates the buffer pool instead of the producer, if set.
+    allocator: Option<Arc<Mutex<Box<dyn DmabufAllocator>>>>,
+    clock: Arc<Mutex<Option<PipewireClock>>>,
+    /// Stream properties added by the consumer, applied over the defaults.
+    properties: Vec<(String, String)>,
+    /// The loop thread's pw_main_loop while it is alive, or 0.
+    main_loop: Arc<Mutex<usize>>,
+    source_kind: SourceKind,
+    activity: Activity,
+    /// Nanoseconds between frames at the negotiated frame rate, or 0 if unknown.
+    frame_interval: Arc<AtomicU64>,
+    /// The only DMA-buf format to offer when the stream (re)connects, if set.
+    pinned_format: Arc<Mutex<Option<DrmFormat>>>,
+    video_size: VideoSizeRange,
+    /// Offer YUV formats along with the RGB ones, for cameras.
+    yuv: bool,
+    /// The negotiated buffers are DMA-bufs rather than SHM. A LINEAR modifier is 0 too,
+    /// so the modifier alone does not tell.
+    dmabuf: Arc<AtomicBool>,
+}
+
+/// Publishes the pw_main_loop in `Shared::main_loop` and withdraws it before the loop is destroyed.
+struct MainLoopHandle(Arc<Mutex<usize>>);
+
+impl MainLoopHandle {
+    fn new(shared: &Arc<Mutex<usize>>, main_loop: &MainLoop) -> Self {
+        if let Ok(mut ptr) = shared.lock() {
+            *ptr = main_loop.as_raw_ptr() as usize;
+        }
+        Self(shared.clone())
+    }
+}
+
+impl Drop for MainLoopHandle {
+    fn drop(&mut self) {
+        if let Ok(mut ptr) = self.0.lock() {
+            *ptr = 0;
+        }
+    }
+}
+
+/// How long `Drop` waits for the loop thread at each stage of stopping it.
+const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn wait_finished<T>(handle: &JoinHandle<T>, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while !handle.is_finished() {
+        if start.elapsed() > timeout {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    true
+}
+
 pub enum PwChangeRequest {
     Pause,
     Resume,
+    Reconnect(u32),
     Stop,
 }
 
@@ -132,28 +889,254 @@ pub struct PipewireCapture {
     rx_frame: Option<mpsc::Receiver<WlxFrame>>,
     node_id: u32,
     handle: Option<JoinHandle<Result<(), Error>>>,
+    shared: Shared,
+    rx_event: mpsc::Receiver<PipewireEvent>,
+    last_frame: LastFrame,
+    last_mouse: Option<MouseMeta>,
+    frame_callback: Option<FrameCallback>,
 }
 
 impl PipewireCapture {
     pub fn new(name: Arc<str>, node_id: u32) -> Self {
+        let (tx_event, rx_event) = mpsc::channel();
         PipewireCapture {
             name,
             tx_ctrl: None,
             rx_frame: None,
             node_id,
             handle: None,
+            shared: Shared {
+                pending_format: Arc::new(Mutex::new(None)),
+                stats: Arc::new(Mutex::new(PipewireStats::default())),
+                events: tx_event,
+                mouse: Arc::new(Mutex::new(None)),
+                memory_limit: None,
+                format_preference: Vec::new(),
+                allocator: None,
+                clock: Arc::new(Mutex::new(None)),
+                properties: Vec::new(),
+                main_loop: Arc::new(Mutex::new(0)),
+                source_kind: SourceKind::Monitor,
+                activity: Activity::default(),
+                frame_interval: Arc::new(AtomicU64::new(0)),
+                pinned_format: Arc::new(Mutex::new(None)),
+                video_size: VideoSizeRange::default(),
+                yuv: false,
+                dmabuf: Arc::new(AtomicBool::new(false)),
+            },
+            rx_event,
+            last_frame: LastFrame::default(),
+            last_mouse: None,
+            frame_callback: None,
+        }
+    }
+
+    /// A capture of gamescope's composited output, found via `find_gamescope_node`.
+    /// None if gamescope's node could not be found.
+    pub fn gamescope() -> Option<Self> {
+        match find_gamescope_node() {
+            Ok(Some(node_id)) => Some(Self::new(GAMESCOPE_NODE_NAME.into(), node_id)),
+            Ok(None) => {
+                log::info!("No gamescope PipeWire node found");
+                None
+            }
+            Err(e) => {
+                log::warn!("Failed to look up gamescope PipeWire node: {}", e);
+                None
+            }
+        }
+    }
+
+    /// A capture of a camera node, e.g. one found with `find_camera_nodes`. Cameras are
+    /// not shared through the ScreenCast portal, so the node is connected to directly.
+    /// The packed YUV formats YUYV and UYVY are offered, which arrive as single-plane
+    /// frames with 2 bytes per pixel.
+    pub fn camera(name: Arc<str>, target: PipewireTarget) -> Self {
+        let capture = match target {
+            PipewireTarget::Id(node_id) => Self::new(name, node_id),
+            // the session manager links the stream to the node of that name
+            PipewireTarget::Name(node_name) => {
+                Self::new(name, pw::constants::ID_ANY).with_property("target.object", node_name)
+            }
+        };
+        capture
+            .with_property(*pw::keys::MEDIA_ROLE, "Camera")
+            .with_source_kind(SourceKind::Camera)
+            .with_yuv_formats()
+    }
+
+    /// Also offer the packed YUV formats YUYV and UYVY for SHM buffers, after the RGB ones
+    /// unless `with_format_preference` says otherwise. Planar formats such as NV12 are
+    /// only received as DMA-bufs, since CPU frames have a single plane.
+    /// Must be called before `init`.
+    pub fn with_yuv_formats(mut self) -> Self {
+        self.shared.yuv = true;
+        self
+    }
+
+    /// Cap the memory used by the SHM buffer pool. The buffer count is reduced to fit,
+    /// and `PipewireEvent::MemoryLimitExceeded` is sent if not even one buffer fits.
+    /// DMA-buf streams are not affected. Must be called before `init`.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.shared.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Offer formats to the producer in this order, most preferred first,
+    /// e.g. so that an RGBA renderer does not have to swizzle BGRA frames.
+    /// Applies to both the DMA-buf and the SHM formats. Must be called before `init`.
+    pub fn with_format_preference(mut self, fourccs: Vec<FourCC>) -> Self {
+        self.shared.format_preference = fourccs;
+        self
+    }
+
+    /// Allocate the stream's buffers with the given allocator instead of letting the producer
+    /// allocate them. Only DMA-buf formats are offered, so `init` must be given at least one.
+    /// Must be called before `init`.
+    pub fn with_allocator(mut self, allocator: Box<dyn DmabufAllocator>) -> Self {
+        self.shared.allocator = Some(Arc::new(Mutex::new(allocator)));
+        self
+    }
+
+    /// Offer only the given video sizes, e.g. `VideoSizeRange::native` with the size of the
+    /// output or window, for producers that otherwise stream windows at odd sizes.
+    /// Must be called before `init`.
+    pub fn with_size_range(mut self, range: VideoSizeRange) -> Self {
+        self.shared.video_size = range;
+        self
+    }
+
+    /// Add or override a property of the stream, such as `node.name`, `node.description`,
+    /// `media.role` or `priority.session`, to make it identifiable in tools like pw-top
+    /// and routable by the session manager. Must be called before `init`.
+    pub fn with_property(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.shared.properties.push((key.into(), value.into()));
+        self
+    }
+
+    /// Set what kind of source the node shows, as found in `PipewireStream::source_kind`.
+    /// Frames are marked as monitor frames otherwise. Must be called before `init`.
+    pub fn with_source_kind(mut self, source_kind: SourceKind) -> Self {
+        self.shared.source_kind = source_kind;
+        self
+    }
+
+    /// A snapshot of the counters: the buffers the loop thread took from the stream, and how
+    /// many of them were dropped as corrupted. Buffers skipped for a newer one in the same
+    /// process call are not counted. The counters start at zero when the capture is created
+    /// and are never reset, not even by `pause` or a renegotiation; diff two snapshots to get
+    /// the counts for an interval.
+    pub fn stats(&self) -> PipewireStats {
+        self.shared.stats.lock().map(|s| *s).unwrap_or_default()
+    }
+
+    /// The format negotiated with PipeWire, if any.
+    /// Available as soon as negotiation completes, before the first frame arrives.
+    pub fn pending_format(&self) -> Option<FrameFormat> {
+        self.shared.pending_format.lock().ok().and_then(|f| *f)
+    }
+
+    /// The DMA-buf format and modifier the producer fixated, if any.
+    /// None before negotiation completes and for SHM streams.
+    pub fn negotiated_format(&self) -> Option<DrmFormat> {
+        let format = self.pending_format()?;
+        self.shared
+            .dmabuf
+            .load(Ordering::Relaxed)
+            .then(|| DrmFormat {
+                fourcc: format.fourcc,
+                modifiers: vec![format.modifier],
+            })
+    }
+
+    /// Offer only `format` on future (re)connects, e.g. the `negotiated_format` of an
+    /// earlier run, so that restarted streams come back in the same format without
+    /// renegotiating. SHM formats are still offered as a fallback. Ignored unless the format
+    /// is among those given to `init`. None to offer all formats again.
+    pub fn pin_format(&self, format: Option<DrmFormat>) {
+        if let Ok(mut pinned) = self.shared.pinned_format.lock() {
+            *pinned = format;
+        }
+    }
+
+    /// The clock of the cycle that produced the most recent frame, if the stream has
+    /// been given a position by its driver.
+    pub fn clock(&self) -> Option<PipewireClock> {
+        self.shared.clock.lock().ok().and_then(|c| *c)
+    }
+
+    /// The most recent cursor position seen by `receive`.
+    /// Only available on streams started with cursor metadata, see `pipewire_select_remote_desktop`.
+    /// DMA-buf and MemFd frames carry no cursor, so consumers of those should read it from here.
+    pub fn mouse(&self) -> Option<MouseMeta> {
+        self.last_mouse
+    }
+
+    /// Drain stream events received since the last call.
+    pub fn iter_events(&self) -> impl Iterator<Item = PipewireEvent> + '_ {
+        self.rx_event.try_iter()
+    }
+
+    /// The state needed to recreate this capture elsewhere, e.g. in a process started
+    /// with `handoff::spawn_capture_process`. The restore token of the portal session is
+    /// not known here; set it from the selection result where that is available.
+    pub fn descriptor(&self) -> CaptureDescriptor {
+        let mut descriptor = CaptureDescriptor::new(BackendKind::Pipewire, &self.name);
+        descriptor.node_id = Some(self.node_id);
+        if let Some(bytes) = self.shared.memory_limit {
+            descriptor.set_option("memory_limit", bytes);
+        }
+        if self.shared.yuv {
+            descriptor.set_option("yuv", true);
+        }
+        descriptor
+    }
+
+    /// Re-target the running stream to a different node without restarting the loop thread.
+    /// Useful when a restored portal session hands out a new node id.
+    pub fn reconnect(&mut self, node_id: u32) {
+        self.node_id = node_id;
+        if let Ok(mut pending) = self.shared.pending_format.lock() {
+            *pending = None;
+        }
+        if let Some(tx_ctrl) = &self.tx_ctrl {
+            if tx_ctrl.send(PwChangeRequest::Reconnect(node_id)).is_err() {
+                log::warn!("{}: disconnected, cannot reconnect stream", &self.name);
+            }
         }
     }
 }
 
 impl Drop for PipewireCapture {
+    /// Completes within a bounded time even if the loop thread is stuck, e.g. on a dead daemon.
     fn drop(&mut self) {
         if let Some(tx_ctrl) = &self.tx_ctrl {
             let _ = tx_ctrl.send(PwChangeRequest::Stop);
         }
-        if let Some(handle) = self.handle.take() {
-            let _ = handle.join();
+        let Some(handle) = self.handle.take() else {
+            return;
+        };
+
+        if !wait_finished(&handle, STOP_TIMEOUT) {
+            log::warn!(
+                "{}: pipewire loop did not stop, forcing it to quit",
+                &self.name
+            );
+            if let Ok(ptr) = self.shared.main_loop.lock() {
+                if *ptr != 0 {
+                    // safe from any thread: signals the loop through its eventfd
+                    unsafe { pw::sys::pw_main_loop_quit(*ptr as *mut pw::sys::pw_main_loop) };
+                }
+            }
+            if !wait_finished(&handle, STOP_TIMEOUT) {
+                log::error!(
+                    "{}: pipewire loop is wedged, abandoning its thread",
+                    &self.name
+                );
+                return;
+            }
         }
+        let _ = handle.join();
     }
 }
 
@@ -169,8 +1152,10 @@ impl WlxCapture for PipewireCapture {
             let name = self.name.clone();
             let node_id = self.node_id;
             let formats = dmabuf_formats.to_vec();
+            let shared = self.shared.clone();
+            let callback = self.frame_callback.take();
 
-            move || main_loop(name, node_id, formats, tx_frame, rx_ctrl)
+            move || main_loop(name, node_id, formats, shared, tx_frame, callback, rx_ctrl)
         }));
     }
     fn is_ready(&self) -> bool {
@@ -179,13 +1164,45 @@ impl WlxCapture for PipewireCapture {
     fn supports_dmbuf(&self) -> bool {
         true
     }
+    fn caps(&self) -> CaptureCaps {
+        // producers fall back to SHM if none of the DMA-buf formats can be negotiated
+        let cost = match self.pending_format() {
+            Some(_) if !self.shared.dmabuf.load(Ordering::Relaxed) => CopyCost::SingleCopy,
+            _ => CopyCost::ZeroCopy,
+        };
+        CaptureCaps {
+            dmabuf: true,
+            cost,
+            ..Default::default()
+        }
+    }
+    fn source_kind(&self) -> SourceKind {
+        self.shared.source_kind
+    }
     fn receive(&mut self) -> Option<WlxFrame> {
+        if self.last_frame.frozen {
+            return self.last_frame.duplicate();
+        }
+        let mouse = self.shared.mouse.lock().ok().and_then(|mut m| m.take());
+        if mouse.is_some() {
+            self.last_mouse = mouse;
+        }
         if let Some(rx) = self.rx_frame.as_ref() {
-            return rx.try_iter().last();
+            if let Some(mut frame) = rx.try_iter().last() {
+                if let (WlxFrame::MemPtr(memptr), Some(mouse)) = (&mut frame, mouse) {
+                    memptr.mouse = Some(mouse);
+                }
+                return self.last_frame.update(Some(frame));
+            }
+        }
+        if let Some(frame) = self.last_frame.take_stale() {
+            return Some(frame);
         }
-        None
+        mouse.map(WlxFrame::Mouse)
     }
     fn pause(&mut self) {
+        // kept for `resume`, before the stream hands the buffer back to the producer
+        self.last_frame.retain();
         if let Some(tx_ctrl) = &self.tx_ctrl {
             match tx_ctrl.send(PwChangeRequest::Pause) {
                 Ok(_) => (),
@@ -205,30 +1222,65 @@ impl WlxCapture for PipewireCapture {
             }
         }
         self.receive(); // clear old frames
+        self.last_frame.mark_resumed();
     }
     fn request_new_frame(&mut self) {}
+    fn last_activity(&self) -> Option<u64> {
+        self.shared.activity.get()
+    }
+    fn next_frame_eta(&self) -> Option<u64> {
+        match self.shared.frame_interval.load(Ordering::Relaxed) {
+            0 => None,
+            interval => self.last_frame.eta(interval),
+        }
+    }
+    fn freeze(&mut self) {
+        if self.last_frame.freeze() {
+            self.pause();
+        }
+    }
+    fn unfreeze(&mut self) {
+        if std::mem::take(&mut self.last_frame.frozen) {
+            self.resume();
+        }
+    }
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        self.frame_callback = Some(callback);
+        Ok(())
+    }
 }
 
 fn main_loop(
     name: Arc<str>,
     node_id: u32,
     dmabuf_formats: Vec<DrmFormat>,
+    shared: Shared,
     sender: mpsc::SyncSender<WlxFrame>,
+    mut frame_callback: Option<FrameCallback>,
     receiver: pw::channel::Receiver<PwChangeRequest>,
 ) -> Result<(), Error> {
     let main_loop = MainLoop::new(None)?;
+    let _main_loop_handle = MainLoopHandle::new(&shared.main_loop, &main_loop);
     let context = Context::new(&main_loop)?;
     let core = context.connect(None)?;
 
-    let stream = Stream::new(
-        &core,
-        &name,
-        properties! {
-            *pw::keys::MEDIA_TYPE => "Video",
-            *pw::keys::MEDIA_CATEGORY => "Capture",
-            *pw::keys::MEDIA_ROLE => "Screen",
-        },
-    )?;
+    let mut props = properties! {
+        *pw::keys::MEDIA_TYPE => "Video",
+        *pw::keys::MEDIA_CATEGORY => "Capture",
+        *pw::keys::MEDIA_ROLE => "Screen",
+    };
+    for (key, value) in shared.properties.iter() {
+        props.insert(key.as_str(), value.as_str());
+    }
+
+    let stream = Stream::new(&core, &name, props)?;
+
+    // spa_buffers in the pool, for reading metadata that `Buffer` does not expose
+    let pool: Rc<RefCell<Vec<usize>>> = Rc::new(RefCell::new(Vec::new()));
+    // buffers from `shared.allocator`, by spa_buffer
+    let allocated: Rc<RefCell<Vec<(usize, DmabufFrame)>>> = Rc::new(RefCell::new(Vec::new()));
+    // spa_io_position provided by the driver, or null
+    let io_position: Rc<Cell<usize>> = Rc::new(Cell::new(0));
 
     let _listener = stream
         .add_local_listener_with_user_data(FrameFormat::default())
@@ -236,10 +1288,29 @@ fn main_loop(
             let name = name.clone();
             move |_, _, old, new| {
                 log::info!("{}: stream state changed: {:?} -> {:?}", &name, old, new);
+                if let StreamState::Error(msg) = &new {
+                    // a stream that ran until its node went away was ended by the producer
+                    let event = match old {
+                        StreamState::Paused | StreamState::Streaming => AuditEvent::Revoked,
+                        _ => AuditEvent::Failed,
+                    };
+                    audit::record(event, BackendKind::Pipewire, &*name, Some(msg.clone()));
+                }
+            }
+        })
+        .io_changed({
+            let io_position = io_position.clone();
+            move |_, _, id, area, size| {
+                if id != spa::sys::SPA_IO_Position {
+                    return;
+                }
+                let valid = size as usize >= std::mem::size_of::<spa::sys::spa_io_position>();
+                io_position.set(if valid { area as usize } else { 0 });
             }
         })
         .param_changed({
             let name = name.clone();
+            let shared = shared.clone();
             move |stream, format, id, param| {
                 let Some(param) = param else {
                     return;
@@ -256,18 +1327,66 @@ fn main_loop(
                 format.height = info.size().height;
                 format.fourcc = spa_to_fourcc(info.format());
                 format.modifier = info.modifier();
+                // flips are reported per buffer through the VideoTransform meta
+                format.origin = RowOrder::TopDown;
+
+                if let Ok(mut pending) = shared.pending_format.lock() {
+                    *pending = Some(*format);
+                }
 
-                let kind = if format.modifier != 0 {
-                    "DMA-buf"
+                // variable rate streams only announce their maximum
+                let rate = match info.framerate() {
+                    rate if rate.num > 0 => rate,
+                    _ => info.max_framerate(),
+                };
+                let interval = if rate.num > 0 {
+                    1_000_000_000 * rate.denom as u64 / rate.num as u64
                 } else {
-                    "SHM"
+                    0
                 };
+                shared.frame_interval.store(interval, Ordering::Relaxed);
+
+                let dmabuf = format_has_modifier(param);
+                shared.dmabuf.store(dmabuf, Ordering::Relaxed);
+                let kind = if dmabuf { "DMA-buf" } else { "SHM" };
 
                 log::info!("{}: got {} video format:", &name, &kind);
                 log::info!("  format: {} ({:?})", info.format().as_raw(), info.format());
                 log::info!("  size: {}x{}", info.size().width, info.size().height);
                 log::info!("  modifier: {}", info.modifier());
-                let Ok(params_bytes) = obj_to_bytes(get_buffer_params()) else {
+
+                // no supported format has more than 32 bits per pixel
+                let frame_size = format.width as usize * format.height as usize * 4;
+                let max_buffers = match shared.memory_limit {
+                    Some(_) if !dmabuf && frame_size == 0 => {
+                        log::warn!("{}: negotiated an empty video size", &name);
+                        return;
+                    }
+                    Some(limit) if !dmabuf => {
+                        if frame_size > limit {
+                            log::error!(
+                                "{}: a {} byte frame exceeds the memory limit of {} bytes",
+                                &name,
+                                frame_size,
+                                limit
+                            );
+                            let _ = shared.events.send(PipewireEvent::MemoryLimitExceeded(
+                                MemoryLimitExceeded {
+                                    required: frame_size,
+                                    limit,
+                                },
+                            ));
+                            let _ = stream.set_active(false);
+                            return;
+                        }
+                        Some((limit / frame_size).min(MAX_BUFFERS as usize) as i32)
+                    }
+                    _ => None,
+                };
+
+                let dmabuf_only = shared.allocator.is_some();
+                let Ok(params_bytes) = obj_to_bytes(get_buffer_params(max_buffers, dmabuf_only))
+                else {
                     log::warn!("{}: failed to serialize buffer params", &name);
                     return;
                 };
@@ -290,15 +1409,86 @@ fn main_loop(
                 .unwrap(); // want panic
                 let xform_pod = Pod::from_bytes(&xform_bytes).unwrap(); // want panic
 
-                let mut pods = [params_pod, header_pod, xform_pod];
+                let cursor_bytes =
+                    obj_to_bytes(get_meta_object(spa::sys::SPA_META_Cursor, CURSOR_META_SIZE))
+                        .unwrap(); // want panic
+                let cursor_pod = Pod::from_bytes(&cursor_bytes).unwrap(); // want panic
+
+                let mut pods = [params_pod, header_pod, xform_pod, cursor_pod];
                 if let Err(e) = stream.update_params(&mut pods) {
                     log::error!("{}: failed to update params: {}", &name, e);
                 }
             }
         })
+        .add_buffer({
+            let name = name.clone();
+            let shared = shared.clone();
+            let pool = pool.clone();
+            let allocated = allocated.clone();
+            move |stream, _, buffer| {
+                let Some(spa_buffer) = unsafe { buffer.as_ref() }.map(|b| b.buffer as usize) else {
+                    return;
+                };
+                pool.borrow_mut().push(spa_buffer);
+
+                if shared.allocator.is_none() {
+                    // the producer allocated the buffer, whose type settles the question
+                    if let Some(data_type) = buffer_data_type(buffer) {
+                        shared
+                            .dmabuf
+                            .store(data_type == DataType::DmaBuf, Ordering::Relaxed);
+                    }
+                }
+
+                if let Some(allocator) = shared.allocator.as_ref() {
+                    let format = shared.pending_format.lock().ok().and_then(|f| *f);
+                    let Ok(mut allocator) = allocator.lock() else {
+                        return;
+                    };
+                    match format.and_then(|format| allocator.allocate(&format)) {
+                        Some(frame) if unsafe { attach_allocated(buffer, &frame) } => {
+                            allocated.borrow_mut().push((spa_buffer, frame));
+                        }
+                        Some(frame) => {
+                            log::error!("{}: allocated buffer does not fit the pool", &name);
+                            allocator.release(&frame);
+                        }
+                        None => log::error!("{}: allocator did not provide a buffer", &name),
+                    }
+                }
+
+                if let Some(key) = pool_buffer_key(stream, buffer) {
+                    let _ = shared.events.send(PipewireEvent::BufferAdded(key));
+                }
+            }
+        })
+        .remove_buffer({
+            let shared = shared.clone();
+            let pool = pool.clone();
+            let allocated = allocated.clone();
+            move |stream, _, buffer| {
+                let Some(spa_buffer) = unsafe { buffer.as_ref() }.map(|b| b.buffer as usize) else {
+                    return;
+                };
+                pool.borrow_mut().retain(|b| *b != spa_buffer);
+                if let Some(key) = pool_buffer_key(stream, buffer) {
+                    let _ = shared.events.send(PipewireEvent::BufferRemoved(key));
+                }
+
+                let mut allocated = allocated.borrow_mut();
+                if let Some(idx) = allocated.iter().position(|(b, _)| *b == spa_buffer) {
+                    let (_, frame) = allocated.swap_remove(idx);
+                    if let Some(Ok(mut allocator)) = shared.allocator.as_ref().map(|a| a.lock()) {
+                        allocator.release(&frame);
+                    }
+                }
+            }
+        })
         .process({
             let name = name.clone();
+            let shared = shared.clone();
             move |stream, format| {
+                shared.activity.touch();
                 let mut maybe_buffer = None;
                 // discard all but the newest frame
                 while let Some(buffer) = stream.dequeue_buffer() {
@@ -306,11 +1496,27 @@ fn main_loop(
                 }
 
                 if let Some(mut buffer) = maybe_buffer {
+                    let Ok(mut stats) = shared.stats.lock() else {
+                        return;
+                    };
+                    stats.buffers += 1;
+
+                    let mut meta = FrameMeta {
+                        timestamp: monotonic_ns(),
+                        source: shared.source_kind,
+                        ..Default::default()
+                    };
+
                     if let MetaData::Header(header) = buffer.find_meta_data(MetaType::Header) {
                         if header.flags & spa::sys::SPA_META_HEADER_FLAG_CORRUPTED != 0 {
                             log::warn!("{}: PipeWire buffer is corrupt.", &name);
+                            stats.corrupted += 1;
                             return;
                         }
+                        // compositors stamp video buffers using CLOCK_MONOTONIC
+                        if header.pts > 0 {
+                            meta.timestamp = header.pts as _;
+                        }
                     }
 
                     if let MetaData::VideoTransform(transform) =
@@ -336,74 +1542,140 @@ fn main_loop(
                         return;
                     }
 
+                    let spa_buffer = pool
+                        .borrow()
+                        .iter()
+                        .map(|b| *b as *const spa::sys::spa_buffer)
+                        .find(|b| unsafe { (**b).datas } as usize == datas.as_ptr() as usize);
+                    if let Some((x, y)) = spa_buffer.and_then(|b| unsafe { cursor_position(b) }) {
+                        if let Ok(mut mouse) = shared.mouse.lock() {
+                            *mouse = Some(MouseMeta {
+                                x: x as f32 / format.width as f32,
+                                y: y as f32 / format.height as f32,
+                            });
+                        }
+                    }
+
+                    if datas
+                        .iter()
+                        .any(|d| d.chunk().flags().contains(ChunkFlags::CORRUPTED))
+                    {
+                        log::warn!("{}: PipeWire chunk is corrupt.", &name);
+                        stats.corrupted += 1;
+                        return;
+                    }
+                    drop(stats);
+
+                    let position = io_position.get() as *const spa::sys::spa_io_position;
+                    if let Some(position) = unsafe { position.as_ref() } {
+                        let clock = &position.clock;
+                        if let Ok(mut shared_clock) = shared.clock.lock() {
+                            *shared_clock = Some(PipewireClock {
+                                id: clock.id,
+                                nsec: clock.nsec,
+                                rate: (clock.rate.num, clock.rate.denom),
+                                position: clock.position,
+                                duration: clock.duration,
+                                rate_diff: clock.rate_diff,
+                            });
+                        }
+                    }
+
                     let planes: Vec<FramePlane> = datas
                         .iter()
                         .map(|p| FramePlane {
                             fd: Some(p.as_raw().fd as _),
                             offset: p.chunk().offset(),
                             stride: p.chunk().stride(),
+                            // some producers leave the chunk size at 0 for dmabufs
+                            size: match p.chunk().size() {
+                                0 => p.as_raw().maxsize.saturating_sub(p.chunk().offset()),
+                                size => size,
+                            },
                         })
                         .collect();
 
-                    match datas[0].type_() {
+                    let frame = match datas[0].type_() {
                         DataType::DmaBuf => {
                             let mut dmabuf = DmabufFrame {
                                 format: *format,
+                                meta,
                                 num_planes: planes.len(),
+                                buffer_key: BufferKey::pipewire(
+                                    stream.node_id(),
+                                    datas[0].as_raw().fd as _,
+                                ),
                                 ..Default::default()
                             };
                             dmabuf.planes[..planes.len()].copy_from_slice(&planes[..planes.len()]);
 
-                            let frame = WlxFrame::Dmabuf(dmabuf);
-                            match sender.try_send(frame) {
-                                Ok(_) => (),
-                                Err(mpsc::TrySendError::Full(_)) => (),
-                                Err(mpsc::TrySendError::Disconnected(_)) => {
-                                    log::warn!("{}: disconnected, stopping stream", &name);
-                                    let _ = stream.disconnect();
-                                }
-                            }
+                            WlxFrame::Dmabuf(dmabuf)
+                        }
+                        DataType::MemFd | DataType::MemPtr if datas.len() > 1 => {
+                            log::warn!(
+                                "{}: CPU frames with {} planes are not supported",
+                                &name,
+                                datas.len()
+                            );
+                            return;
                         }
                         DataType::MemFd => {
+                            // chunk offset is relative to the mapped region, not the fd
+                            let raw = datas[0].as_raw();
                             let memfd = MemFdFrame {
                                 format: *format,
+                                meta,
                                 plane: FramePlane {
-                                    fd: Some(datas[0].as_raw().fd as _),
-                                    offset: datas[0].chunk().offset(),
+                                    fd: Some(raw.fd as _),
+                                    offset: raw.mapoffset + datas[0].chunk().offset(),
                                     stride: datas[0].chunk().stride(),
+                                    size: datas[0].chunk().size(),
                                 },
                             };
 
-                            let frame = WlxFrame::MemFd(memfd);
-                            match sender.try_send(frame) {
-                                Ok(_) => (),
-                                Err(mpsc::TrySendError::Full(_)) => (),
-                                Err(mpsc::TrySendError::Disconnected(_)) => {
-                                    log::warn!("{}: disconnected, stopping stream", &name);
-                                    let _ = stream.disconnect();
-                                }
-                            }
+                            WlxFrame::MemFd(memfd)
                         }
                         DataType::MemPtr => {
+                            let Some(size) = memptr_size(&datas[0], format) else {
+                                log::warn!(
+                                    "{}: inconsistent MemPtr buffer: {:?}",
+                                    &name,
+                                    datas[0].chunk()
+                                );
+                                return;
+                            };
+                            let raw = datas[0].as_raw();
                             let memptr = MemPtrFrame {
                                 format: *format,
-                                ptr: datas[0].as_raw().data as _,
-                                size: datas[0].chunk().size() as _,
+                                meta,
+                                ptr: raw.data as usize + datas[0].chunk().offset() as usize,
+                                size,
                                 mouse: None,
                             };
 
-                            let frame = WlxFrame::MemPtr(memptr);
-                            match sender.try_send(frame) {
-                                Ok(_) => (),
-                                Err(mpsc::TrySendError::Full(_)) => (),
-                                Err(mpsc::TrySendError::Disconnected(_)) => {
-                                    log::warn!("{}: disconnected, stopping stream", &name);
-                                    let _ = stream.disconnect();
-                                }
-                            }
+                            WlxFrame::MemPtr(memptr)
                         }
                         _ => {
-                            log::error!("Received invalid frame data type ({:?})", datas[0].type_())
+                            log::error!(
+                                "Received invalid frame data type ({:?})",
+                                datas[0].type_()
+                            );
+                            return;
+                        }
+                    };
+
+                    if let Some(callback) = frame_callback.as_mut() {
+                        // the buffer is requeued once this returns
+                        callback(frame);
+                        return;
+                    }
+
+                    match fault::try_send(&sender, frame) {
+                        Ok(_) => (),
+                        Err(mpsc::TrySendError::Full(_)) => (),
+                        Err(mpsc::TrySendError::Disconnected(_)) => {
+                            log::warn!("{}: disconnected, stopping stream", &name);
+                            let _ = stream.disconnect();
                         }
                     }
                 }
@@ -411,29 +1683,24 @@ fn main_loop(
         })
         .register()?;
 
-    let mut format_params: Vec<Vec<u8>> = dmabuf_formats
-        .iter()
-        .filter_map(|f| obj_to_bytes(get_format_params(Some(f))).ok())
-        .collect();
-
-    format_params.push(obj_to_bytes(get_format_params(None)).unwrap()); // safe unwrap: known
-                                                                        // good values
+    let mut dmabuf_formats = dmabuf_formats;
+    dmabuf_formats.sort_by_key(|f| preference_rank(&shared.format_preference, f.fourcc));
 
-    let mut params: Vec<&Pod> = format_params
-        .iter()
-        .filter_map(|bytes| Pod::from_bytes(bytes))
-        .collect();
+    let mut flags = StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS;
+    if shared.allocator.is_some() {
+        if dmabuf_formats.is_empty() {
+            log::error!("{}: an allocator requires DMA-buf formats", &name);
+        }
+        flags |= StreamFlags::ALLOC_BUFFERS;
+    }
 
-    stream.connect(
-        spa::utils::Direction::Input,
-        Some(node_id),
-        StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS,
-        params.as_mut_slice(),
-    )?;
+    let format_params = stream_format_params(&name, &dmabuf_formats, &shared);
+    connect_stream(&stream, node_id, &format_params, flags)?;
 
     let _receiver = receiver.attach(main_loop.loop_(), {
         let name = name.clone();
         let main_loop = main_loop.clone();
+        let shared = shared.clone();
         move |req| match req {
             PwChangeRequest::Pause => {
                 let _ = stream.set_active(false);
@@ -441,6 +1708,15 @@ fn main_loop(
             PwChangeRequest::Resume => {
                 let _ = stream.set_active(true);
             }
+            PwChangeRequest::Reconnect(node_id) => {
+                log::info!("{}: reconnecting to node {}", &name, node_id);
+                let _ = stream.disconnect();
+                // the pinned format may have changed since the last connect
+                let format_params = stream_format_params(&name, &dmabuf_formats, &shared);
+                if let Err(e) = connect_stream(&stream, node_id, &format_params, flags) {
+                    log::error!("{}: failed to reconnect: {}", &name, e);
+                }
+            }
             PwChangeRequest::Stop => {
                 main_loop.quit();
                 log::info!("{}: stopping pipewire loop", &name);
@@ -448,11 +1724,196 @@ fn main_loop(
         }
     });
 
+    // keeps the heartbeat going while the producer sends no frames
+    let heartbeat = main_loop.loop_().add_timer({
+        let activity = shared.activity.clone();
+        move |_| activity.touch()
+    });
+    shared.activity.touch();
+    let _ = heartbeat.update_timer(Some(HEARTBEAT_INTERVAL), Some(HEARTBEAT_INTERVAL));
+
     main_loop.run();
     log::info!("{}: pipewire loop exited", &name);
     Ok::<(), Error>(())
 }
 
+/// The SPA_DATA_* type of a pool buffer's first data.
+fn buffer_data_type(buffer: *mut pw::sys::pw_buffer) -> Option<DataType> {
+    let spa_buffer = unsafe { buffer.as_ref()?.buffer.as_ref()? };
+    if spa_buffer.n_datas == 0 {
+        return None;
+    }
+    let data = unsafe { spa_buffer.datas.as_ref()? };
+    Some(DataType::from_raw(data.type_))
+}
+
+/// Whether a format param fixates a modifier, which only DMA-buf formats carry.
+fn format_has_modifier(param: &Pod) -> bool {
+    match PodDeserializer::deserialize_any_from(param.as_bytes()) {
+        Ok((_, Value::Object(obj))) => obj
+            .properties
+            .iter()
+            .any(|p| p.key == spa::sys::SPA_FORMAT_VIDEO_modifier),
+        _ => false,
+    }
+}
+
+/// Identity of a DMA-buf in the stream's pool, matching `DmabufFrame::buffer_key`.
+fn pool_buffer_key(stream: &StreamRef, buffer: *mut pw::sys::pw_buffer) -> Option<BufferKey> {
+    if buffer_data_type(buffer)? != DataType::DmaBuf {
+        return None;
+    }
+    let data = unsafe { buffer.as_ref()?.buffer.as_ref()?.datas.as_ref()? };
+    BufferKey::pipewire(stream.node_id(), data.fd as _)
+}
+
+/// Point the datas of a pool buffer at the planes of a consumer-allocated DMA-buf.
+/// Returns false if the buffer has room for fewer planes than the DMA-buf has.
+unsafe fn attach_allocated(buffer: *mut pw::sys::pw_buffer, frame: &DmabufFrame) -> bool {
+    let Some(spa_buffer) = buffer.as_ref().and_then(|b| b.buffer.as_mut()) else {
+        return false;
+    };
+    if spa_buffer.datas.is_null() || (spa_buffer.n_datas as usize) < frame.num_planes {
+        return false;
+    }
+    let datas = std::slice::from_raw_parts_mut(spa_buffer.datas, spa_buffer.n_datas as usize);
+    for (data, plane) in datas.iter_mut().zip(&frame.planes[..frame.num_planes]) {
+        let Some(fd) = plane.fd else {
+            return false;
+        };
+        // the size of a DMA-buf is only available by seeking to its end
+        let size = libc::lseek(fd, 0, libc::SEEK_END);
+        data.type_ = DataType::DmaBuf.as_raw();
+        data.flags = DataFlags::READABLE.bits();
+        data.fd = fd as _;
+        data.mapoffset = 0;
+        data.maxsize = if size > 0 {
+            size as _
+        } else {
+            plane.stride as u32 * frame.format.height
+        };
+        data.data = std::ptr::null_mut();
+        if let Some(chunk) = data.chunk.as_mut() {
+            chunk.offset = plane.offset;
+            chunk.stride = plane.stride;
+            chunk.size = 0;
+        }
+    }
+    true
+}
+
+/// Room for the cursor position plus a 64x64 RGBA bitmap.
+const CURSOR_META_SIZE: usize = std::mem::size_of::<spa::sys::spa_meta_cursor>()
+    + std::mem::size_of::<spa::sys::spa_meta_bitmap>()
+    + 64 * 64 * 4;
+
+/// Cursor position from the buffer's cursor meta, in stream pixels.
+/// Only present when the stream was started with `CursorMode::Metadata`.
+unsafe fn cursor_position(buffer: *const spa::sys::spa_buffer) -> Option<(i32, i32)> {
+    let buffer = buffer.as_ref()?;
+    if buffer.metas.is_null() {
+        return None;
+    }
+    let metas = std::slice::from_raw_parts(buffer.metas, buffer.n_metas as usize);
+    let meta = metas
+        .iter()
+        .find(|m| m.type_ == spa::sys::SPA_META_Cursor)?;
+    if meta.data.is_null()
+        || (meta.size as usize) < std::mem::size_of::<spa::sys::spa_meta_cursor>()
+    {
+        return None;
+    }
+    let cursor = &*(meta.data as *const spa::sys::spa_meta_cursor);
+    // an id of 0 means the cursor is not over this stream
+    if cursor.id == 0 {
+        return None;
+    }
+    Some((cursor.position.x, cursor.position.y))
+}
+
+/// Size of a MemPtr frame from the chunk offset on: exactly stride * height, as
+/// `MemPtrFrame::stride` expects, with a packed stride if the chunk reports none.
+/// Some producers report a chunk size of 0, which is taken as a full frame.
+/// Returns None if the buffer cannot hold a full frame.
+fn memptr_size(data: &Data, format: &FrameFormat) -> Option<usize> {
+    let raw = data.as_raw();
+    if raw.data.is_null() {
+        return None;
+    }
+
+    let chunk = data.chunk();
+    let bytes_per_pixel = match format.fourcc.value {
+        DRM_FORMAT_YUYV | DRM_FORMAT_UYVY => 2,
+        _ => 4,
+    };
+    let stride = match chunk.stride() {
+        0 => format.width as usize * bytes_per_pixel,
+        stride => stride.unsigned_abs() as usize,
+    };
+    // the frame ends with its last row, however much more the chunk holds
+    let size = stride * format.height as usize;
+    if (chunk.size() != 0 && (chunk.size() as usize) < size)
+        || chunk.offset() as usize + size > raw.maxsize as usize
+    {
+        return None;
+    }
+    Some(size)
+}
+
+/// The formats to offer the producer: the DMA-buf formats, or only the pinned one,
+/// then the SHM formats unless the consumer allocates the buffers.
+fn stream_format_params(name: &str, dmabuf_formats: &[DrmFormat], shared: &Shared) -> Vec<Vec<u8>> {
+    let (preference, size, yuv) = (&shared.format_preference, &shared.video_size, shared.yuv);
+    let pinned = shared.pinned_format.lock().ok().and_then(|p| p.clone());
+    let pinned = pinned.filter(|pinned| {
+        let importable = dmabuf_formats.iter().any(|f| {
+            f.fourcc == pinned.fourcc && pinned.modifiers.iter().all(|m| f.modifiers.contains(m))
+        });
+        if !importable {
+            log::warn!(
+                "{}: pinned format {} is not among the DMA-buf formats, offering all",
+                name,
+                pinned.fourcc
+            );
+        }
+        importable && !pinned.modifiers.is_empty()
+    });
+    let offered = match pinned.as_ref() {
+        Some(pinned) => std::slice::from_ref(pinned),
+        None => dmabuf_formats,
+    };
+
+    let mut format_params: Vec<Vec<u8>> = offered
+        .iter()
+        .filter_map(|f| obj_to_bytes(get_format_params(Some(f), preference, size, yuv)).ok())
+        .collect();
+    // SHM buffers would have to be allocated by the consumer as well
+    if shared.allocator.is_none() {
+        // safe unwrap: known good values
+        format_params.push(obj_to_bytes(get_format_params(None, preference, size, yuv)).unwrap());
+    }
+    format_params
+}
+
+fn connect_stream(
+    stream: &StreamRef,
+    node_id: u32,
+    format_params: &[Vec<u8>],
+    flags: StreamFlags,
+) -> Result<(), Error> {
+    let mut params: Vec<&Pod> = format_params
+        .iter()
+        .filter_map(|bytes| Pod::from_bytes(bytes))
+        .collect();
+
+    stream.connect(
+        spa::utils::Direction::Input,
+        Some(node_id),
+        flags,
+        params.as_mut_slice(),
+    )
+}
+
 fn obj_to_bytes(obj: spa::pod::Object) -> Result<Vec<u8>, GenError> {
     Ok(spa::pod::serialize::PodSerializer::serialize(
         std::io::Cursor::new(Vec::new()),
@@ -462,10 +1923,20 @@ fn obj_to_bytes(obj: spa::pod::Object) -> Result<Vec<u8>, GenError> {
     .into_inner())
 }
 
-fn get_buffer_params() -> Object {
-    let data_types = (1 << DataType::MemFd.as_raw())
-        | (1 << DataType::MemPtr.as_raw())
-        | (1 << DataType::DmaBuf.as_raw());
+/// Buffers asked for when the count is limited, unless fewer fit.
+const DEFAULT_BUFFERS: i32 = 2;
+
+/// Upper bound on the buffer count derived from the memory limit.
+const MAX_BUFFERS: i32 = 16;
+
+fn get_buffer_params(max_buffers: Option<i32>, dmabuf_only: bool) -> Object {
+    let data_types = if dmabuf_only {
+        1 << DataType::DmaBuf.as_raw()
+    } else {
+        (1 << DataType::MemFd.as_raw())
+            | (1 << DataType::MemPtr.as_raw())
+            | (1 << DataType::DmaBuf.as_raw())
+    };
 
     let property = Property {
         key: spa::sys::SPA_PARAM_BUFFERS_dataType,
@@ -473,11 +1944,28 @@ fn get_buffer_params() -> Object {
         value: Value::Int(data_types),
     };
 
-    spa::pod::object!(
+    let mut obj = spa::pod::object!(
         spa::utils::SpaTypes::ObjectParamBuffers,
         spa::param::ParamType::Buffers,
         property,
-    )
+    );
+
+    if let Some(max) = max_buffers {
+        obj.properties.push(Property {
+            key: spa::sys::SPA_PARAM_BUFFERS_buffers,
+            flags: PropertyFlags::empty(),
+            value: Value::Choice(ChoiceValue::Int(Choice(
+                ChoiceFlags::empty(),
+                ChoiceEnum::Range {
+                    default: DEFAULT_BUFFERS.min(max),
+                    min: 1,
+                    max,
+                },
+            ))),
+        });
+    }
+
+    obj
 }
 
 fn get_meta_object(key: u32, size: usize) -> Object {
@@ -501,7 +1989,34 @@ fn get_meta_object(key: u32, size: usize) -> Object {
     )
 }
 
-fn get_format_params(fmt: Option<&DrmFormat>) -> Object {
+/// SHM formats offered to the producer, in default order of preference.
+const SHM_FORMATS: [u32; 6] = [
+    DRM_FORMAT_ABGR8888,
+    DRM_FORMAT_ARGB8888,
+    DRM_FORMAT_XBGR8888,
+    DRM_FORMAT_XRGB8888,
+    DRM_FORMAT_ABGR2101010,
+    DRM_FORMAT_XBGR2101010,
+];
+
+/// SHM formats offered after `SHM_FORMATS` with `PipewireCapture::with_yuv_formats`.
+/// Only packed ones, as the planes of planar formats arrive as separate buffer datas.
+const YUV_FORMATS: [u32; 2] = [DRM_FORMAT_YUYV, DRM_FORMAT_UYVY];
+
+/// Position of `fourcc` in the consumer's preference, or after all preferred formats.
+fn preference_rank(preference: &[FourCC], fourcc: FourCC) -> usize {
+    preference
+        .iter()
+        .position(|f| *f == fourcc)
+        .unwrap_or(preference.len())
+}
+
+fn get_format_params(
+    fmt: Option<&DrmFormat>,
+    preference: &[FourCC],
+    size: &VideoSizeRange,
+    yuv: bool,
+) -> Object {
     let mut obj = spa::pod::object!(
         spa::utils::SpaTypes::ObjectParamFormat,
         spa::param::ParamType::EnumFormat,
@@ -521,16 +2036,16 @@ fn get_format_params(fmt: Option<&DrmFormat>) -> Object {
             Range,
             Rectangle,
             spa::utils::Rectangle {
-                width: 256,
-                height: 256,
+                width: size.default.0,
+                height: size.default.1,
             },
             spa::utils::Rectangle {
-                width: 1,
-                height: 1,
+                width: size.min.0,
+                height: size.min.1,
             },
             spa::utils::Rectangle {
-                width: 8192,
-                height: 8192,
+                width: size.max.0,
+                height: size.max.1,
             }
         ),
         spa::pod::property!(
@@ -574,19 +2089,29 @@ fn get_format_params(fmt: Option<&DrmFormat>) -> Object {
         };
         obj.properties.push(prop);
     } else {
-        let prop = spa::pod::property!(
-            spa::param::format::FormatProperties::VideoFormat,
-            Choice,
-            Enum,
-            Id,
-            spa::param::video::VideoFormat::RGBA,
-            spa::param::video::VideoFormat::RGBA,
-            spa::param::video::VideoFormat::BGRA,
-            spa::param::video::VideoFormat::RGBx,
-            spa::param::video::VideoFormat::BGRx,
-            spa::param::video::VideoFormat::ABGR_210LE,
-            spa::param::video::VideoFormat::xBGR_210LE,
-        );
+        let yuv_formats = if yuv { &YUV_FORMATS[..] } else { &[] };
+        let mut formats: Vec<FourCC> = SHM_FORMATS
+            .iter()
+            .chain(yuv_formats)
+            .map(|f| (*f).into())
+            .collect();
+        formats.sort_by_key(|f| preference_rank(preference, *f));
+        let ids: Vec<spa::utils::Id> = formats
+            .into_iter()
+            .map(|f| spa::utils::Id(fourcc_to_spa(f).as_raw()))
+            .collect();
+
+        let prop = Property {
+            key: spa::param::format::FormatProperties::VideoFormat.as_raw(),
+            flags: PropertyFlags::empty(),
+            value: Value::Choice(ChoiceValue::Id(Choice(
+                ChoiceFlags::empty(),
+                ChoiceEnum::Enum {
+                    default: ids[0],
+                    alternatives: ids,
+                },
+            ))),
+        };
         obj.properties.push(prop);
     }
 
@@ -601,6 +2126,10 @@ fn fourcc_to_spa(fourcc: FourCC) -> VideoFormat {
         DRM_FORMAT_XBGR8888 => VideoFormat::RGBx,
         DRM_FORMAT_ABGR2101010 => VideoFormat::ABGR_210LE,
         DRM_FORMAT_XBGR2101010 => VideoFormat::xBGR_210LE,
+        DRM_FORMAT_YUYV => VideoFormat::YUY2,
+        DRM_FORMAT_UYVY => VideoFormat::UYVY,
+        DRM_FORMAT_NV12 => VideoFormat::NV12,
+        DRM_FORMAT_YUV420 => VideoFormat::I420,
         _ => panic!("Unsupported format"),
     }
 }
@@ -614,6 +2143,10 @@ fn spa_to_fourcc(spa: VideoFormat) -> FourCC {
         VideoFormat::RGBx => DRM_FORMAT_XBGR8888.into(),
         VideoFormat::ABGR_210LE => DRM_FORMAT_ABGR2101010.into(),
         VideoFormat::xBGR_210LE => DRM_FORMAT_XBGR2101010.into(),
+        VideoFormat::YUY2 => DRM_FORMAT_YUYV.into(),
+        VideoFormat::UYVY => DRM_FORMAT_UYVY.into(),
+        VideoFormat::NV12 => DRM_FORMAT_NV12.into(),
+        VideoFormat::I420 => DRM_FORMAT_YUV420.into(),
         _ => panic!("Unsupported format"),
     }
 }
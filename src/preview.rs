@@ -0,0 +1,243 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+
+use wayland_client::{
+    event_created_child,
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_surface::{self, WlSurface},
+    },
+    Connection, Dispatch, Proxy, QueueHandle,
+};
+use wayland_protocols::{
+    wp::{
+        linux_dmabuf::zv1::client::zwp_linux_buffer_params_v1::{self, ZwpLinuxBufferParamsV1},
+        viewporter::client::wp_viewport::WpViewport,
+    },
+    xdg::shell::client::{
+        xdg_surface::{self, XdgSurface},
+        xdg_toplevel::{self, XdgToplevel},
+    },
+};
+
+use crate::{
+    frame::DmabufFrame,
+    wayland::{frame_transform_to_wl_transform, WlxClient},
+};
+
+/// Window state, updated while the client is dispatched.
+#[derive(Default)]
+pub(crate) struct PreviewState {
+    configured: AtomicBool,
+    closed: AtomicBool,
+    /// Size chosen by the compositor, packed into one value; 0 lets the client decide.
+    size: AtomicU64,
+}
+
+/// User data of buffers created for the preview.
+pub(crate) struct PreviewBuffer;
+
+/// A toplevel window that shows dmabuf frames as they are, without a renderer.
+/// The compositor imports the planes itself (linux-dmabuf) and scales them to
+/// the window size (viewporter), so a preview costs no copies on the client side.
+///
+/// Events for the window are handled by `WlxClient::dispatch_pending`,
+/// which must be called regularly on the client the preview was created with.
+pub struct PreviewSurface {
+    surface: WlSurface,
+    xdg_surface: XdgSurface,
+    toplevel: XdgToplevel,
+    viewport: WpViewport,
+    buffer: Option<WlBuffer>,
+    state: Arc<PreviewState>,
+    default_size: (i32, i32),
+}
+
+impl PreviewSurface {
+    /// Open a window of the given logical size, unless the compositor picks another.
+    /// Returns None if wl_compositor, xdg_wm_base, wp_viewporter or zwp_linux_dmabuf_v1
+    /// is not available.
+    pub fn new(client: &WlxClient, title: &str, width: i32, height: i32) -> Option<Self> {
+        let compositor = client.maybe_wl_compositor.as_ref()?;
+        let wm_base = client.maybe_xdg_wm_base.as_ref()?;
+        let viewporter = client.maybe_viewporter.as_ref()?;
+        client.maybe_linux_dmabuf.as_ref()?;
+
+        let qh = &client.queue_handle;
+        let state = Arc::new(PreviewState::default());
+
+        let surface = compositor.create_surface(qh, ());
+        let xdg_surface = wm_base.get_xdg_surface(&surface, qh, state.clone());
+        let toplevel = xdg_surface.get_toplevel(qh, state.clone());
+        toplevel.set_title(title.into());
+        toplevel.set_app_id("wlx-capture-preview".into());
+        let viewport = viewporter.get_viewport(&surface, qh, ());
+
+        // the first commit has no buffer; the compositor answers with a configure
+        surface.commit();
+        let _ = client.connection.flush();
+
+        Some(Self {
+            surface,
+            xdg_surface,
+            toplevel,
+            viewport,
+            buffer: None,
+            state,
+            default_size: (width, height),
+        })
+    }
+
+    /// The window's surface, e.g. for an `idle::IdleInhibitor`.
+    pub fn surface(&self) -> &WlSurface {
+        &self.surface
+    }
+
+    /// True once the user has asked to close the window.
+    pub fn is_closed(&self) -> bool {
+        self.state.closed.load(Ordering::Relaxed)
+    }
+
+    /// Display the given frame. The planes are imported when the compositor receives
+    /// the request, so the frame may be released as soon as this returns.
+    /// Returns false if the frame was not shown, e.g. because the window is not configured yet.
+    pub fn show(&mut self, client: &WlxClient, frame: &DmabufFrame) -> bool {
+        if !self.state.configured.load(Ordering::Relaxed) || self.is_closed() {
+            return false;
+        }
+        let Some(buffer) = client.create_dmabuf_buffer(frame, PreviewBuffer) else {
+            return false;
+        };
+
+        let (width, height) = match self.state.size.load(Ordering::Relaxed) {
+            0 => self.default_size,
+            packed => ((packed >> 32) as i32, (packed & 0xFFFFFFFF) as i32),
+        };
+
+        self.surface
+            .set_buffer_transform(frame_transform_to_wl_transform(frame.format.transform));
+        self.surface.attach(Some(&buffer), 0, 0);
+        self.surface.damage_buffer(0, 0, i32::MAX, i32::MAX);
+        self.viewport.set_destination(width, height);
+        self.surface.commit();
+
+        if let Some(old) = self.buffer.replace(buffer) {
+            old.destroy();
+        }
+        let _ = client.connection.flush();
+        true
+    }
+}
+
+impl Drop for PreviewSurface {
+    fn drop(&mut self) {
+        self.viewport.destroy();
+        self.toplevel.destroy();
+        self.xdg_surface.destroy();
+        self.surface.destroy();
+        if let Some(buffer) = self.buffer.take() {
+            buffer.destroy();
+        }
+    }
+}
+
+impl Dispatch<XdgSurface, Arc<PreviewState>> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        proxy: &XdgSurface,
+        event: <XdgSurface as Proxy>::Event,
+        data: &Arc<PreviewState>,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let xdg_surface::Event::Configure { serial } = event {
+            proxy.ack_configure(serial);
+            data.configured.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Dispatch<XdgToplevel, Arc<PreviewState>> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &XdgToplevel,
+        event: <XdgToplevel as Proxy>::Event,
+        data: &Arc<PreviewState>,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            xdg_toplevel::Event::Configure { width, height, .. } => {
+                let packed = if width > 0 && height > 0 {
+                    ((width as u64) << 32) | height as u64
+                } else {
+                    0
+                };
+                data.size.store(packed, Ordering::Relaxed);
+            }
+            xdg_toplevel::Event::Close => {
+                data.closed.store(true, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwpLinuxBufferParamsV1, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpLinuxBufferParamsV1,
+        event: <ZwpLinuxBufferParamsV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let zwp_linux_buffer_params_v1::Event::Failed = event {
+            log::warn!("Compositor failed to import dmabuf");
+        }
+    }
+
+    event_created_child!(WlxClient, ZwpLinuxBufferParamsV1, [
+        zwp_linux_buffer_params_v1::EVT_CREATED_OPCODE => (WlBuffer, PreviewBuffer),
+    ]);
+}
+
+// Plumbing below
+
+impl Dispatch<WlSurface, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlSurface,
+        _event: wl_surface::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WpViewport, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WpViewport,
+        _event: <WpViewport as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlBuffer, PreviewBuffer> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlBuffer,
+        _event: <WlBuffer as Proxy>::Event,
+        _data: &PreviewBuffer,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
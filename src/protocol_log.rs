@@ -0,0 +1,335 @@
+//! Recording of capture protocol traffic, for reproducing compositor-specific event orderings.
+//!
+//! Enable with `WlxClient::record_protocol`. Every event received for a wlr-screencopy
+//! or wlr-export-dmabuf frame is written as one line:
+//!
+//! ```text
+//! <ns since start> <interface>@<object id> <event> <arg>=<value> ...
+//! ```
+//!
+//! `read_log` parses such a file back, and `RecordedEvent` turns the lines into the
+//! typed events the dispatch handlers receive, so that event sequences seen on one
+//! compositor can be fed to handling code elsewhere.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    os::fd::{AsRawFd, OwnedFd},
+    path::Path,
+    time::Duration,
+};
+
+use smithay_client_toolkit::reexports::protocols_wlr::{
+    export_dmabuf::v1::client::zwlr_export_dmabuf_frame_v1,
+    screencopy::v1::client::zwlr_screencopy_frame_v1,
+};
+use wayland_client::WEnum;
+
+use crate::frame::monotonic_ns;
+
+pub const SCREENCOPY_FRAME: &str = "zwlr_screencopy_frame_v1";
+pub const EXPORT_DMABUF_FRAME: &str = "zwlr_export_dmabuf_frame_v1";
+
+/// Writes received events to a file.
+pub(crate) struct ProtocolRecorder {
+    writer: BufWriter<File>,
+    start_ns: u64,
+}
+
+impl ProtocolRecorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            start_ns: monotonic_ns(),
+        })
+    }
+
+    pub fn screencopy(&mut self, object_id: u32, event: &zwlr_screencopy_frame_v1::Event) {
+        use zwlr_screencopy_frame_v1::Event;
+        let (name, args): (&str, Vec<(&str, i64)>) = match event {
+            Event::Buffer {
+                format,
+                width,
+                height,
+                stride,
+            } => (
+                "buffer",
+                vec![
+                    ("format", u32::from(*format) as _),
+                    ("width", *width as _),
+                    ("height", *height as _),
+                    ("stride", *stride as _),
+                ],
+            ),
+            Event::Flags { flags } => ("flags", vec![("flags", u32::from(*flags) as _)]),
+            Event::Ready {
+                tv_sec_hi,
+                tv_sec_lo,
+                tv_nsec,
+            } => (
+                "ready",
+                vec![
+                    ("tv_sec_hi", *tv_sec_hi as _),
+                    ("tv_sec_lo", *tv_sec_lo as _),
+                    ("tv_nsec", *tv_nsec as _),
+                ],
+            ),
+            Event::Failed => ("failed", vec![]),
+            Event::Damage {
+                x,
+                y,
+                width,
+                height,
+            } => (
+                "damage",
+                vec![
+                    ("x", *x as _),
+                    ("y", *y as _),
+                    ("width", *width as _),
+                    ("height", *height as _),
+                ],
+            ),
+            Event::LinuxDmabuf {
+                format,
+                width,
+                height,
+            } => (
+                "linux_dmabuf",
+                vec![
+                    ("format", *format as _),
+                    ("width", *width as _),
+                    ("height", *height as _),
+                ],
+            ),
+            Event::BufferDone => ("buffer_done", vec![]),
+            _ => ("unknown", vec![]),
+        };
+        self.write(SCREENCOPY_FRAME, object_id, name, &args);
+    }
+
+    pub fn export_dmabuf(&mut self, object_id: u32, event: &zwlr_export_dmabuf_frame_v1::Event) {
+        use zwlr_export_dmabuf_frame_v1::Event;
+        let (name, args): (&str, Vec<(&str, i64)>) = match event {
+            Event::Frame {
+                width,
+                height,
+                offset_x,
+                offset_y,
+                buffer_flags,
+                flags,
+                format,
+                mod_high,
+                mod_low,
+                num_objects,
+            } => (
+                "frame",
+                vec![
+                    ("width", *width as _),
+                    ("height", *height as _),
+                    ("offset_x", *offset_x as _),
+                    ("offset_y", *offset_y as _),
+                    ("buffer_flags", *buffer_flags as _),
+                    ("flags", u32::from(*flags) as _),
+                    ("format", *format as _),
+                    ("mod_high", *mod_high as _),
+                    ("mod_low", *mod_low as _),
+                    ("num_objects", *num_objects as _),
+                ],
+            ),
+            Event::Object {
+                index,
+                fd,
+                size,
+                offset,
+                stride,
+                plane_index,
+            } => (
+                "object",
+                vec![
+                    ("index", *index as _),
+                    ("fd", fd.as_raw_fd() as _),
+                    ("size", *size as _),
+                    ("offset", *offset as _),
+                    ("stride", *stride as _),
+                    ("plane_index", *plane_index as _),
+                ],
+            ),
+            Event::Ready {
+                tv_sec_hi,
+                tv_sec_lo,
+                tv_nsec,
+            } => (
+                "ready",
+                vec![
+                    ("tv_sec_hi", *tv_sec_hi as _),
+                    ("tv_sec_lo", *tv_sec_lo as _),
+                    ("tv_nsec", *tv_nsec as _),
+                ],
+            ),
+            Event::Cancel { reason } => ("cancel", vec![("reason", u32::from(*reason) as _)]),
+            _ => ("unknown", vec![]),
+        };
+        self.write(EXPORT_DMABUF_FRAME, object_id, name, &args);
+    }
+
+    fn write(&mut self, interface: &str, object_id: u32, event: &str, args: &[(&str, i64)]) {
+        let time_ns = monotonic_ns().saturating_sub(self.start_ns);
+        let mut line = format!("{} {}@{} {}", time_ns, interface, object_id, event);
+        for (name, value) in args {
+            line.push_str(&format!(" {}={}", name, value));
+        }
+        // flushed right away, so the log is complete up to a crash
+        if let Err(e) = writeln!(self.writer, "{}", line).and_then(|_| self.writer.flush()) {
+            log::warn!("Failed to write protocol log: {}", e);
+        }
+    }
+}
+
+/// One line of a protocol log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedEvent {
+    /// Nanoseconds since recording started.
+    pub time_ns: u64,
+    pub interface: String,
+    pub object_id: u32,
+    pub event: String,
+    pub args: Vec<(String, i64)>,
+}
+
+impl RecordedEvent {
+    pub fn arg(&self, name: &str) -> Option<i64> {
+        self.args.iter().find(|(n, _)| n == name).map(|(_, v)| *v)
+    }
+
+    fn uint(&self, name: &str) -> Option<u32> {
+        self.arg(name).map(|v| v as u32)
+    }
+
+    /// The event as received by the wlr-screencopy frame handler.
+    /// None if this is not a screencopy frame event.
+    pub fn screencopy_event(&self) -> Option<zwlr_screencopy_frame_v1::Event> {
+        use zwlr_screencopy_frame_v1::Event;
+        if self.interface != SCREENCOPY_FRAME {
+            return None;
+        }
+        Some(match self.event.as_str() {
+            "buffer" => Event::Buffer {
+                format: WEnum::from(self.uint("format")?),
+                width: self.uint("width")?,
+                height: self.uint("height")?,
+                stride: self.uint("stride")?,
+            },
+            "flags" => Event::Flags {
+                flags: WEnum::from(self.uint("flags")?),
+            },
+            "ready" => Event::Ready {
+                tv_sec_hi: self.uint("tv_sec_hi")?,
+                tv_sec_lo: self.uint("tv_sec_lo")?,
+                tv_nsec: self.uint("tv_nsec")?,
+            },
+            "failed" => Event::Failed,
+            "damage" => Event::Damage {
+                x: self.uint("x")?,
+                y: self.uint("y")?,
+                width: self.uint("width")?,
+                height: self.uint("height")?,
+            },
+            "linux_dmabuf" => Event::LinuxDmabuf {
+                format: self.uint("format")?,
+                width: self.uint("width")?,
+                height: self.uint("height")?,
+            },
+            "buffer_done" => Event::BufferDone,
+            _ => return None,
+        })
+    }
+
+    /// The event as received by the wlr-export-dmabuf frame handler.
+    /// The recorded fds are gone, so objects carry an fd of /dev/null instead.
+    /// None if this is not an export-dmabuf frame event.
+    pub fn export_dmabuf_event(&self) -> Option<zwlr_export_dmabuf_frame_v1::Event> {
+        use zwlr_export_dmabuf_frame_v1::Event;
+        if self.interface != EXPORT_DMABUF_FRAME {
+            return None;
+        }
+        Some(match self.event.as_str() {
+            "frame" => Event::Frame {
+                width: self.uint("width")?,
+                height: self.uint("height")?,
+                offset_x: self.uint("offset_x")?,
+                offset_y: self.uint("offset_y")?,
+                buffer_flags: self.uint("buffer_flags")?,
+                flags: WEnum::from(self.uint("flags")?),
+                format: self.uint("format")?,
+                mod_high: self.uint("mod_high")?,
+                mod_low: self.uint("mod_low")?,
+                num_objects: self.uint("num_objects")?,
+            },
+            "object" => Event::Object {
+                index: self.uint("index")?,
+                fd: OwnedFd::from(File::open("/dev/null").ok()?),
+                size: self.uint("size")?,
+                offset: self.uint("offset")?,
+                stride: self.uint("stride")?,
+                plane_index: self.uint("plane_index")?,
+            },
+            "ready" => Event::Ready {
+                tv_sec_hi: self.uint("tv_sec_hi")?,
+                tv_sec_lo: self.uint("tv_sec_lo")?,
+                tv_nsec: self.uint("tv_nsec")?,
+            },
+            "cancel" => Event::Cancel {
+                reason: WEnum::from(self.uint("reason")?),
+            },
+            _ => return None,
+        })
+    }
+}
+
+fn parse_line(line: &str) -> Option<RecordedEvent> {
+    let mut parts = line.split_whitespace();
+    let time_ns = parts.next()?.parse().ok()?;
+    let (interface, object_id) = parts.next()?.split_once('@')?;
+    let event = parts.next()?.to_string();
+    let args = parts
+        .map(|arg| {
+            let (name, value) = arg.split_once('=')?;
+            Some((name.to_string(), value.parse().ok()?))
+        })
+        .collect::<Option<_>>()?;
+    Some(RecordedEvent {
+        time_ns,
+        interface: interface.to_string(),
+        object_id: object_id.parse().ok()?,
+        event,
+        args,
+    })
+}
+
+/// Read a log written by `WlxClient::record_protocol`. Malformed lines are skipped.
+pub fn read_log(path: impl AsRef<Path>) -> io::Result<Vec<RecordedEvent>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        match parse_line(&line) {
+            Some(event) => events.push(event),
+            None if line.trim().is_empty() => {}
+            None => log::warn!("Skipping malformed protocol log line: {}", line),
+        }
+    }
+    Ok(events)
+}
+
+/// Hand the events to `handler` in order. With `realtime`, the recorded gaps between
+/// events are reproduced, for handling code that is sensitive to timing.
+pub fn replay(events: &[RecordedEvent], realtime: bool, mut handler: impl FnMut(&RecordedEvent)) {
+    let mut last_ns = events.first().map_or(0, |e| e.time_ns);
+    for event in events {
+        if realtime {
+            std::thread::sleep(Duration::from_nanos(event.time_ns.saturating_sub(last_ns)));
+        }
+        last_ns = event.time_ns;
+        handler(event);
+    }
+}
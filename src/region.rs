@@ -0,0 +1,307 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    frame::{CropRect, DrmFormat, LastFrame, MouseMeta, SourceKind, WlxFrame},
+    CaptureCaps, WlxCapture,
+};
+
+/// State shared by the regions of one source.
+struct SourceState {
+    capture: Box<dyn WlxCapture>,
+    initialized: bool,
+    /// Origin of the source's frames within the output, if the source itself is cropped.
+    offset: (u32, u32),
+    /// Latest frame with an image, numbered so that each region delivers it once.
+    frame: Option<(u64, WlxFrame)>,
+    /// Latest cursor-only update, numbered like `frame`.
+    mouse: Option<(u64, MouseMeta)>,
+    seq: u64,
+    /// Region that last requested a frame, and the frame that was current at the time.
+    requested: Option<(usize, u64)>,
+    regions: usize,
+    /// Regions that are not paused.
+    active: usize,
+}
+
+impl SourceState {
+    fn poll(&mut self) {
+        let Some(frame) = self.capture.receive() else {
+            return;
+        };
+        self.seq += 1;
+        match frame {
+            WlxFrame::Mouse(mouse) => self.mouse = Some((self.seq, mouse)),
+            frame => {
+                self.frame = Some((self.seq, frame));
+                self.requested = None;
+            }
+        }
+    }
+
+    fn frame_size(&self) -> Option<(u32, u32)> {
+        let format = self.frame.as_ref()?.1.format()?;
+        Some((format.width, format.height))
+    }
+}
+
+/// Splits one capture into several region captures, so that e.g. a dashboard can show
+/// multiple areas of one monitor while the compositor copies the monitor only once.
+///
+/// Regions deliver the source's frames unchanged, with `FrameMeta::crop` set to the
+/// part to show. Nothing is copied on the client side; the consumer samples the
+/// cropped rectangle when rendering.
+///
+/// All regions must be used from the thread that created the source.
+pub struct RegionSource {
+    state: Rc<RefCell<SourceState>>,
+}
+
+impl RegionSource {
+    /// Share `capture`, which must not be initialized yet, among regions.
+    /// Frame callbacks are not supported on the regions.
+    pub fn new(capture: Box<dyn WlxCapture>) -> Self {
+        Self::with_offset(capture, (0, 0))
+    }
+
+    fn with_offset(capture: Box<dyn WlxCapture>, offset: (u32, u32)) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(SourceState {
+                capture,
+                initialized: false,
+                offset,
+                frame: None,
+                mouse: None,
+                seq: 0,
+                requested: None,
+                regions: 0,
+                active: 0,
+            })),
+        }
+    }
+
+    /// Capture the given regions of a Wayland output with wlr-screencopy. The compositor
+    /// copies only the bounding box of the regions, which are then cropped on the client.
+    /// Rectangles are in pixels of the output. If the output is transformed,
+    /// the whole output is copied.
+    #[cfg(feature = "wlr")]
+    pub fn wlr_screencopy(
+        wl: crate::wayland::WlxClient,
+        output_id: u32,
+        rects: &[CropRect],
+    ) -> (Self, Vec<RegionCapture>) {
+        use crate::wlr_screencopy::WlrScreencopyCapture;
+        use wayland_client::protocol::wl_output::Transform;
+
+        let bounds = rects.iter().copied().reduce(|a, b| {
+            let x = a.x.min(b.x);
+            let y = a.y.min(b.y);
+            CropRect {
+                x,
+                y,
+                width: (a.x + a.width).max(b.x + b.width) - x,
+                height: (a.y + a.height).max(b.y + b.height) - y,
+            }
+        });
+
+        let server_crop = wl.outputs.get(output_id).and_then(|output| {
+            if output.transform != Transform::Normal || output.logical_size.0 <= 0 {
+                return None;
+            }
+            let scale = output.size.0 as f32 / output.logical_size.0 as f32;
+            let bounds = bounds?;
+            let x = (bounds.x as f32 / scale).floor();
+            let y = (bounds.y as f32 / scale).floor();
+            let right = ((bounds.x + bounds.width) as f32 / scale).ceil();
+            let bottom = ((bounds.y + bounds.height) as f32 / scale).ceil();
+            let offset = ((x * scale).round() as u32, (y * scale).round() as u32);
+            let region = (x as i32, y as i32, (right - x) as i32, (bottom - y) as i32);
+            Some((region, offset))
+        });
+
+        let capture = WlrScreencopyCapture::new(wl, output_id);
+        let source = match server_crop {
+            Some(((x, y, width, height), offset)) => {
+                Self::with_offset(Box::new(capture.with_region(x, y, width, height)), offset)
+            }
+            None => Self::new(Box::new(capture)),
+        };
+        let regions = rects.iter().map(|rect| source.add_region(*rect)).collect();
+        (source, regions)
+    }
+
+    /// A capture of the given rectangle, in pixels of the source's frames before their
+    /// transform is applied, or of the output if created by `wlr_screencopy`.
+    /// The rectangle is clipped to the frame.
+    pub fn add_region(&self, rect: CropRect) -> RegionCapture {
+        let mut state = self.state.borrow_mut();
+        let index = state.regions;
+        state.regions += 1;
+        state.active += 1;
+        RegionCapture {
+            state: self.state.clone(),
+            index,
+            rect,
+            seen: 0,
+            paused: false,
+            last_frame: LastFrame::default(),
+        }
+    }
+}
+
+/// One region of a `RegionSource`.
+pub struct RegionCapture {
+    state: Rc<RefCell<SourceState>>,
+    index: usize,
+    rect: CropRect,
+    /// Number of the last frame or cursor update delivered.
+    seen: u64,
+    paused: bool,
+    last_frame: LastFrame,
+}
+
+impl RegionCapture {
+    pub fn rect(&self) -> CropRect {
+        self.rect
+    }
+
+    /// The region within a frame of the given size.
+    fn crop(&self, offset: (u32, u32), (width, height): (u32, u32)) -> CropRect {
+        let x = self.rect.x.saturating_sub(offset.0).min(width);
+        let y = self.rect.y.saturating_sub(offset.1).min(height);
+        CropRect {
+            x,
+            y,
+            width: self.rect.width.min(width - x),
+            height: self.rect.height.min(height - y),
+        }
+    }
+}
+
+/// Make a cursor position relative to the region. None if the cursor is outside.
+fn crop_mouse(mouse: MouseMeta, crop: CropRect, (width, height): (u32, u32)) -> Option<MouseMeta> {
+    if crop.width == 0 || crop.height == 0 {
+        return None;
+    }
+    let x = (mouse.x * width as f32 - crop.x as f32) / crop.width as f32;
+    let y = (mouse.y * height as f32 - crop.y as f32) / crop.height as f32;
+    if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) {
+        return None;
+    }
+    Some(MouseMeta { x, y })
+}
+
+impl WlxCapture for RegionCapture {
+    fn init(&mut self, dmabuf_formats: &[DrmFormat]) {
+        let mut state = self.state.borrow_mut();
+        if !state.initialized {
+            state.capture.init(dmabuf_formats);
+            state.initialized = true;
+        }
+    }
+    fn is_ready(&self) -> bool {
+        self.state.borrow().capture.is_ready()
+    }
+    fn supports_dmbuf(&self) -> bool {
+        self.state.borrow().capture.supports_dmbuf()
+    }
+    fn caps(&self) -> CaptureCaps {
+        self.state.borrow().capture.caps()
+    }
+    fn source_kind(&self) -> SourceKind {
+        SourceKind::Region
+    }
+    fn receive(&mut self) -> Option<WlxFrame> {
+        if self.last_frame.frozen {
+            return self.last_frame.duplicate();
+        }
+        let mut state = self.state.borrow_mut();
+        state.poll();
+        let size = state.frame_size()?;
+
+        match (&state.frame, state.mouse) {
+            (Some((seq, frame)), _) if *seq > self.seen => {
+                self.seen = *seq;
+                let crop = self.crop(state.offset, size);
+                let mut frame = frame.clone();
+                if let Some(meta) = frame.meta_mut() {
+                    meta.crop = Some(crop);
+                    meta.source = SourceKind::Region;
+                }
+                if let WlxFrame::MemPtr(memptr) = &mut frame {
+                    memptr.mouse = memptr.mouse.and_then(|m| crop_mouse(m, crop, size));
+                }
+                self.last_frame.update(Some(frame))
+            }
+            (_, Some((seq, mouse))) if seq > self.seen => {
+                self.seen = seq;
+                let crop = self.crop(state.offset, size);
+                crop_mouse(mouse, crop, size).map(WlxFrame::Mouse)
+            }
+            _ => self.last_frame.take_stale(),
+        }
+    }
+    fn pause(&mut self) {
+        if self.paused {
+            return;
+        }
+        self.paused = true;
+        let mut state = self.state.borrow_mut();
+        state.active -= 1;
+        if state.active == 0 {
+            state.capture.pause();
+        }
+    }
+    fn resume(&mut self) {
+        if !self.paused {
+            return;
+        }
+        self.paused = false;
+        self.last_frame.mark_resumed();
+        let mut state = self.state.borrow_mut();
+        state.active += 1;
+        if state.active == 1 {
+            state.capture.resume();
+        }
+    }
+    fn last_activity(&self) -> Option<u64> {
+        self.state.borrow().capture.last_activity()
+    }
+    fn next_frame_eta(&self) -> Option<u64> {
+        self.state.borrow().capture.next_frame_eta()
+    }
+    /// Applies to the source, and so to all of its regions.
+    fn set_embedded_cursor(&mut self, embedded: bool) -> bool {
+        self.state
+            .borrow_mut()
+            .capture
+            .set_embedded_cursor(embedded)
+    }
+    fn request_new_frame(&mut self) {
+        if self.last_frame.frozen {
+            return;
+        }
+        let mut state = self.state.borrow_mut();
+        // one request per frame is shared by all regions, but a region asking
+        // again before a frame arrived gets through, in case the request was lost
+        let seq = state.seq;
+        if matches!(state.requested, Some((index, at)) if index != self.index && at == seq) {
+            return;
+        }
+        state.requested = Some((self.index, seq));
+        state.capture.request_new_frame();
+    }
+    fn freeze(&mut self) {
+        self.last_frame.freeze();
+    }
+    fn unfreeze(&mut self) {
+        self.last_frame.frozen = false;
+        self.request_new_frame();
+    }
+}
+
+impl Drop for RegionCapture {
+    fn drop(&mut self) {
+        // a dropped region no longer keeps the source running
+        self.pause();
+    }
+}
@@ -0,0 +1,404 @@
+//! Frame requests timed to the compositor's repaints of an output, for the request-driven
+//! wlr backends. A capture requested right after a repaint copies the newest image, instead
+//! of one that is up to a refresh old depending on when the consumer happened to ask.
+//!
+//! Repaints are observed through frame callbacks on a transparent 1x1 surface that is put on
+//! the output with wlr-layer-shell. Compositors only send them when the output repaints, so
+//! nothing arrives while the screen does not change; see `MAX_REPAINT_WAIT`.
+//!
+//! The surface is opt-in, as it has a cost: it sits in the overlay layer above everything on
+//! the output, and wlroots compositors do not scan out a fullscreen client directly while
+//! another surface is mapped on top of it. The output is then composited every frame, e.g.
+//! a fullscreen game loses direct scanout for as long as the capture is running.
+
+use std::{
+    os::fd::AsRawFd,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use smithay_client_toolkit::reexports::protocols_wlr::layer_shell::v1::client::{
+    zwlr_layer_shell_v1::Layer,
+    zwlr_layer_surface_v1::{self, Anchor, KeyboardInteractivity, ZwlrLayerSurfaceV1},
+};
+use wayland_client::{
+    protocol::{
+        wl_callback::{self, WlCallback},
+        wl_region::WlRegion,
+        wl_shm::Format,
+        wl_surface::WlSurface,
+    },
+    Connection, Dispatch, Proxy, QueueHandle,
+};
+
+use crate::{
+    frame::{DrmFormat, SourceKind, WlxFrame},
+    wayland::WlxClient,
+    wlr_screencopy::{create_shm_buffer, BufData},
+    CaptureCaps, FrameCallback, WlxCapture,
+};
+
+/// A request waits at most this long for a repaint, so that captures of idle outputs still
+/// deliver frames.
+pub const MAX_REPAINT_WAIT: Duration = Duration::from_millis(100);
+
+/// How often the clock thread wakes up to check for requests and whether to stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// State of the layer surface, updated while the clock's client is dispatched.
+#[derive(Default)]
+pub(crate) struct RepaintState {
+    configured: AtomicBool,
+    closed: AtomicBool,
+    repaints: AtomicU64,
+}
+
+struct Pending {
+    capture: Box<dyn WlxCapture + Send>,
+    /// When the consumer asked for a frame that has not been requested yet.
+    since: Option<Instant>,
+}
+
+impl Pending {
+    fn request(&mut self) {
+        if self.since.take().is_some() {
+            self.capture.request_new_frame();
+        }
+    }
+}
+
+/// Holds back `request_new_frame` until the compositor has repainted the output, or until
+/// `MAX_REPAINT_WAIT` has passed. Meant for `WlrScreencopyCapture` and `WlrDmabufCapture`,
+/// which copy the output at the time of the request; other captures gain nothing from it.
+///
+/// Repaints are only watched after `with_overlay_surface`. Until then, without
+/// wlr-layer-shell, or once the compositor closes the surface, requests are passed on
+/// right away, as if the capture was not wrapped.
+pub struct RepaintCapture {
+    pending: Arc<Mutex<Pending>>,
+    state: Option<Arc<RepaintState>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl RepaintCapture {
+    /// Wrap `capture`. Requests are passed on right away until `with_overlay_surface`.
+    pub fn new(capture: Box<dyn WlxCapture + Send>) -> Self {
+        Self {
+            pending: Arc::new(Mutex::new(Pending {
+                capture,
+                since: None,
+            })),
+            state: None,
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Watch the repaints of `output_id` through `wl`, which must be a client of its own,
+    /// since it is dispatched on another thread.
+    ///
+    /// This maps a 1x1 surface in the overlay layer of the output for as long as the capture
+    /// lives, which keeps wlroots compositors from scanning out fullscreen clients directly
+    /// on that output; see the module documentation.
+    pub fn with_overlay_surface(mut self, wl: WlxClient, output_id: u32) -> Self {
+        if self.state.is_some() {
+            return self;
+        }
+        match RepaintSurface::new(&wl, output_id) {
+            Some(surface) => {
+                self.state = Some(surface.state.clone());
+                std::thread::spawn({
+                    let pending = self.pending.clone();
+                    let stop = self.stop.clone();
+                    move || run_clock(wl, surface, pending, stop)
+                });
+            }
+            None => log::warn!("Cannot watch repaints, frames will be requested right away"),
+        }
+        self
+    }
+
+    /// Whether requests are currently held back until a repaint.
+    pub fn is_aligned(&self) -> bool {
+        self.state.as_ref().is_some_and(|s| {
+            s.configured.load(Ordering::Relaxed) && !s.closed.load(Ordering::Relaxed)
+        })
+    }
+
+    /// Repaints seen since the capture was created.
+    pub fn repaints(&self) -> u64 {
+        self.state
+            .as_ref()
+            .map(|s| s.repaints.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for RepaintCapture {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl WlxCapture for RepaintCapture {
+    fn init(&mut self, dmabuf_formats: &[DrmFormat]) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.capture.init(dmabuf_formats);
+        }
+    }
+    fn is_ready(&self) -> bool {
+        self.pending
+            .lock()
+            .map(|p| p.capture.is_ready())
+            .unwrap_or(false)
+    }
+    fn supports_dmbuf(&self) -> bool {
+        self.pending
+            .lock()
+            .map(|p| p.capture.supports_dmbuf())
+            .unwrap_or(false)
+    }
+    fn caps(&self) -> CaptureCaps {
+        self.pending
+            .lock()
+            .map(|p| p.capture.caps())
+            .unwrap_or_default()
+    }
+    fn source_kind(&self) -> SourceKind {
+        self.pending
+            .lock()
+            .map(|p| p.capture.source_kind())
+            .unwrap_or_default()
+    }
+    fn receive(&mut self) -> Option<WlxFrame> {
+        self.pending.lock().ok()?.capture.receive()
+    }
+    fn pause(&mut self) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.since = None;
+            pending.capture.pause();
+        }
+    }
+    fn resume(&mut self) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.capture.resume();
+        }
+    }
+    /// Requests the frame after the next repaint, or right away if repaints are not known.
+    fn request_new_frame(&mut self) {
+        let aligned = self.is_aligned();
+        if let Ok(mut pending) = self.pending.lock() {
+            if aligned {
+                pending.since.get_or_insert_with(Instant::now);
+            } else {
+                pending.capture.request_new_frame();
+            }
+        }
+    }
+    fn last_activity(&self) -> Option<u64> {
+        self.pending.lock().ok()?.capture.last_activity()
+    }
+    fn next_frame_eta(&self) -> Option<u64> {
+        self.pending.lock().ok()?.capture.next_frame_eta()
+    }
+    fn set_embedded_cursor(&mut self, embedded: bool) -> bool {
+        self.pending
+            .lock()
+            .map(|mut p| p.capture.set_embedded_cursor(embedded))
+            .unwrap_or(false)
+    }
+    fn freeze(&mut self) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.capture.freeze();
+        }
+    }
+    fn unfreeze(&mut self) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.capture.unfreeze();
+        }
+    }
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        match self.pending.lock() {
+            Ok(mut pending) => pending.capture.set_frame_callback(callback),
+            Err(_) => Err(callback),
+        }
+    }
+}
+
+/// The invisible surface whose frame callbacks mark the repaints.
+struct RepaintSurface {
+    surface: WlSurface,
+    layer_surface: ZwlrLayerSurfaceV1,
+    state: Arc<RepaintState>,
+    /// Fully transparent, attached once the surface is configured.
+    buffer: Option<BufData>,
+}
+
+impl RepaintSurface {
+    fn new(wl: &WlxClient, output_id: u32) -> Option<Self> {
+        let compositor = wl.maybe_wl_compositor.as_ref()?;
+        let layer_shell = wl.maybe_layer_shell.as_ref()?;
+        let output = wl.outputs.get(output_id)?;
+        let qh = &wl.queue_handle;
+        let state = Arc::new(RepaintState::default());
+
+        let surface = compositor.create_surface(qh, ());
+        let layer_surface = layer_shell.get_layer_surface(
+            &surface,
+            Some(&output.wl_output),
+            Layer::Overlay,
+            "wlx-capture-repaint".into(),
+            qh,
+            state.clone(),
+        );
+        layer_surface.set_size(1, 1);
+        layer_surface.set_anchor(Anchor::Top | Anchor::Left);
+        layer_surface.set_exclusive_zone(-1);
+        layer_surface.set_keyboard_interactivity(KeyboardInteractivity::None);
+
+        // let pointer input pass through
+        let region = compositor.create_region(qh, ());
+        surface.set_input_region(Some(&region));
+        region.destroy();
+
+        // the first commit has no buffer; the compositor answers with a configure
+        surface.commit();
+        let _ = wl.connection.flush();
+
+        Some(Self {
+            surface,
+            layer_surface,
+            state,
+            buffer: None,
+        })
+    }
+
+    /// Ask for the callback of the next repaint.
+    fn request_callback(&self, qh: &QueueHandle<WlxClient>) {
+        self.surface.frame(qh, self.state.clone());
+        self.surface.commit();
+    }
+}
+
+impl Drop for RepaintSurface {
+    fn drop(&mut self) {
+        self.layer_surface.destroy();
+        self.surface.destroy();
+    }
+}
+
+fn run_clock(
+    mut wl: WlxClient,
+    mut surface: RepaintSurface,
+    pending: Arc<Mutex<Pending>>,
+    stop: Arc<AtomicBool>,
+) {
+    let mut seen = 0;
+
+    while !stop.load(Ordering::Relaxed) {
+        let state = surface.state.clone();
+        if state.closed.load(Ordering::Relaxed) || wl.error().is_some() {
+            // requests go straight to the capture from now on
+            if let Ok(mut pending) = pending.lock() {
+                pending.request();
+            }
+            break;
+        }
+
+        if surface.buffer.is_none() && state.configured.load(Ordering::Relaxed) {
+            let Some(buffer) =
+                create_shm_buffer(&wl, Format::Argb8888, (1, 1), 4, &wl.queue_handle)
+            else {
+                log::warn!("Failed to create shared memory for the repaint surface");
+                state.closed.store(true, Ordering::Relaxed);
+                continue;
+            };
+            surface.surface.attach(Some(&buffer.wl_buffer), 0, 0);
+            surface.buffer = Some(buffer);
+            surface.request_callback(&wl.queue_handle);
+        }
+
+        let repaints = state.repaints.load(Ordering::Relaxed);
+        if let Ok(mut pending) = pending.lock() {
+            if repaints != seen {
+                pending.request();
+            } else if pending
+                .since
+                .is_some_and(|t| t.elapsed() >= MAX_REPAINT_WAIT)
+            {
+                log::trace!("No repaint within {:?}", MAX_REPAINT_WAIT);
+                pending.request();
+            }
+        }
+        if repaints != seen {
+            seen = repaints;
+            surface.request_callback(&wl.queue_handle);
+        }
+
+        let _ = wl.connection.flush();
+        let mut fd = libc::pollfd {
+            fd: wl.connection.backend().poll_fd().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut fd, 1, POLL_INTERVAL.as_millis() as _) };
+        wl.dispatch_pending();
+    }
+    drop(surface);
+    let _ = wl.connection.flush();
+    log::debug!("Repaint clock stopped");
+}
+
+impl Dispatch<ZwlrLayerSurfaceV1, Arc<RepaintState>> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        proxy: &ZwlrLayerSurfaceV1,
+        event: <ZwlrLayerSurfaceV1 as Proxy>::Event,
+        data: &Arc<RepaintState>,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_layer_surface_v1::Event::Configure { serial, .. } => {
+                proxy.ack_configure(serial);
+                data.configured.store(true, Ordering::Relaxed);
+            }
+            zwlr_layer_surface_v1::Event::Closed => {
+                log::debug!("Repaint surface closed by the compositor");
+                data.closed.store(true, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlCallback, Arc<RepaintState>> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlCallback,
+        event: <WlCallback as Proxy>::Event,
+        data: &Arc<RepaintState>,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let wl_callback::Event::Done { .. } = event {
+            data.repaints.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Plumbing below
+
+impl Dispatch<WlRegion, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlRegion,
+        _event: <WlRegion as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
@@ -0,0 +1,165 @@
+//! One frame of every output at once, for "snapshot my whole desktop" features.
+//! See `screenshot_all`.
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    convert::{convert_frame, RGBA_FOURCC},
+    factory::CaptureDescriptor,
+    frame::{FrameFormat, FrameMeta, WlxFrame},
+    output::OutputInfo,
+    picker::{SourcePicker, SourceTarget},
+    BackendKind, WlxCapture,
+};
+
+/// Backends that deliver CPU frames without a portal dialog, cheapest first.
+const SCREENSHOT_BACKENDS: [BackendKind; 4] = [
+    BackendKind::WlrScreencopy,
+    BackendKind::ExtImageCopy,
+    BackendKind::Weston,
+    BackendKind::Xshm,
+];
+
+/// A frame of one output, with where the output sits on the desktop.
+pub struct Screenshot {
+    pub output: OutputInfo,
+    pub backend: BackendKind,
+    /// Format of `data`: RGBA8 in the frame's size, with the frame's transform.
+    pub format: FrameFormat,
+    pub meta: FrameMeta,
+    /// Tightly packed RGBA8 pixels.
+    pub data: Vec<u8>,
+}
+
+/// An output that has not delivered a frame yet, and the backends left to try.
+struct Pending {
+    output: OutputInfo,
+    backends: Vec<BackendKind>,
+    capture: Option<(BackendKind, Box<dyn WlxCapture>)>,
+    /// When the current backend was started.
+    started: Instant,
+}
+
+impl Pending {
+    /// Start the next backend that can be constructed. False once none are left.
+    fn start_next(&mut self) -> bool {
+        self.capture = None;
+        while !self.backends.is_empty() {
+            let kind = self.backends.remove(0);
+            let descriptor = CaptureDescriptor::new(kind, &self.output.name);
+            let Some(mut capture) = descriptor.make_capture() else {
+                continue;
+            };
+            // no DMA-buf formats: the pixels are read on the CPU
+            capture.init(&[]);
+            self.capture = Some((kind, capture));
+            self.started = Instant::now();
+            return true;
+        }
+        false
+    }
+}
+
+/// The outputs of the session with the backends that can capture them.
+fn list_outputs() -> SourcePicker {
+    #[allow(unused_mut)]
+    let mut picker = SourcePicker::new();
+    #[cfg(feature = "wlr")]
+    if let Some(wl) = crate::wayland::WlxClient::new() {
+        picker.add_wayland_outputs(&wl);
+    }
+    #[cfg(feature = "xshm")]
+    if picker.entries().is_empty() {
+        if let Err(e) = picker.add_xshm_outputs() {
+            log::debug!("No X11 monitors to take screenshots of: {}", e);
+        }
+    }
+    picker
+}
+
+/// Capture one frame of every connected output at the same time, each with the cheapest
+/// backend that delivers within `timeout`. Blocks until every output has a frame or ran
+/// out of backends. Outputs that only a portal or DMA-buf backend can capture are left
+/// out, as are outputs that produced no frame; the result is in the order of the outputs.
+pub fn screenshot_all(timeout: Duration) -> Vec<Screenshot> {
+    let mut pending: Vec<Pending> = list_outputs()
+        .entries()
+        .iter()
+        .filter_map(|entry| {
+            let SourceTarget::Output(output) = &entry.target else {
+                return None;
+            };
+            let backends = SCREENSHOT_BACKENDS
+                .into_iter()
+                .filter(|b| entry.backends.contains(b))
+                .collect();
+            Some(Pending {
+                output: output.clone(),
+                backends,
+                capture: None,
+                started: Instant::now(),
+            })
+        })
+        .collect();
+    pending.retain_mut(|p| {
+        let started = p.start_next();
+        if !started {
+            log::warn!("{}: no backend can take a screenshot", p.output.name);
+        }
+        started
+    });
+
+    let mut shots: Vec<Option<Screenshot>> = Vec::new();
+    shots.resize_with(pending.len(), || None);
+    loop {
+        let mut waiting = false;
+        for (p, shot) in pending.iter_mut().zip(shots.iter_mut()) {
+            if shot.is_some() {
+                continue;
+            }
+            let Some((kind, capture)) = p.capture.as_mut() else {
+                continue;
+            };
+            let kind = *kind;
+            capture.request_new_frame();
+            match capture.receive() {
+                Some(WlxFrame::Mouse(_)) | None => {}
+                Some(frame) => match convert_frame(&frame, RGBA_FOURCC.into()) {
+                    Some((converted, data)) => {
+                        *shot = Some(Screenshot {
+                            output: p.output.clone(),
+                            backend: kind,
+                            format: converted.format,
+                            meta: converted.meta,
+                            data,
+                        });
+                        p.capture = None;
+                        continue;
+                    }
+                    None => {
+                        log::warn!(
+                            "{}: {} frame could not be read",
+                            p.output.name,
+                            kind.as_str()
+                        );
+                        p.start_next();
+                    }
+                },
+            }
+            if p.capture.is_some() && p.started.elapsed() > timeout {
+                log::warn!(
+                    "{}: {} backend produced no frames",
+                    p.output.name,
+                    kind.as_str()
+                );
+                p.start_next();
+            }
+            waiting |= p.capture.is_some();
+        }
+        if !waiting {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    shots.into_iter().flatten().collect()
+}
@@ -0,0 +1,75 @@
+//! Lock state of the login session, from systemd-logind over D-Bus.
+//!
+//! While the session is locked, captures deliver the lock screen or nothing at all,
+//! so consumers may want to show a placeholder instead.
+
+use std::{future::poll_fn, pin::Pin};
+
+use zbus::{export::futures_core::Stream, proxy::PropertyStream, zvariant::OwnedObjectPath};
+
+const LOGIND: &str = "org.freedesktop.login1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    Locked,
+    Unlocked,
+}
+
+/// Follows the `LockedHint` of the session this process belongs to, which lock screens
+/// set while they are shown.
+pub struct SessionLockWatcher {
+    session: zbus::Proxy<'static>,
+    changes: PropertyStream<'static, bool>,
+}
+
+impl SessionLockWatcher {
+    pub async fn new() -> zbus::Result<Self> {
+        let connection = zbus::Connection::system().await?;
+        let manager = zbus::Proxy::new(
+            &connection,
+            LOGIND,
+            "/org/freedesktop/login1",
+            "org.freedesktop.login1.Manager",
+        )
+        .await?;
+
+        // "auto" also finds the session of processes started outside of it, e.g. by systemd
+        let path: OwnedObjectPath = match manager.call("GetSession", &("auto",)).await {
+            Ok(path) => path,
+            Err(e) => {
+                log::debug!("logind: no session for \"auto\": {}", e);
+                manager
+                    .call("GetSessionByPID", &(std::process::id(),))
+                    .await?
+            }
+        };
+        log::debug!("logind: watching session {}", path.as_str());
+
+        let session = zbus::Proxy::new(
+            &connection,
+            LOGIND,
+            path.into_inner(),
+            "org.freedesktop.login1.Session",
+        )
+        .await?;
+        let changes = session.receive_property_changed("LockedHint").await;
+        Ok(Self { session, changes })
+    }
+
+    pub async fn is_locked(&self) -> zbus::Result<bool> {
+        self.session.get_property("LockedHint").await
+    }
+
+    /// Wait until the session is locked or unlocked.
+    /// None if the connection to logind was lost.
+    pub async fn next_event(&mut self) -> Option<SessionEvent> {
+        loop {
+            let change = poll_fn(|cx| Pin::new(&mut self.changes).poll_next(cx)).await?;
+            match change.get().await {
+                Ok(true) => return Some(SessionEvent::Locked),
+                Ok(false) => return Some(SessionEvent::Unlocked),
+                Err(e) => log::warn!("logind: could not read LockedHint: {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{convert::to_rgba, frame::FourCC};
+
+/// A small RGBA8 preview of a capture source, for building source pickers.
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    /// Tightly packed RGBA8 pixels.
+    pub data: Vec<u8>,
+}
+
+impl Thumbnail {
+    /// Downscale a CPU-visible image so that neither side exceeds `max_size`.
+    /// Uses nearest-neighbor sampling; transforms are not applied.
+    /// Returns None if the fourcc is not supported or the source is too small.
+    pub fn from_pixels(
+        src: &[u8],
+        width: u32,
+        height: u32,
+        stride: usize,
+        fourcc: FourCC,
+        max_size: u32,
+    ) -> Option<Self> {
+        if width == 0 || height == 0 || max_size == 0 {
+            return None;
+        }
+        if src.len() < stride * (height as usize - 1) + width as usize * 4 {
+            return None;
+        }
+
+        let scale = width.max(height).div_ceil(max_size).max(1);
+        let out_width = width / scale;
+        let out_height = height / scale;
+        if out_width == 0 || out_height == 0 {
+            return None;
+        }
+        let mut data = Vec::with_capacity((out_width * out_height * 4) as usize);
+
+        for y in 0..out_height {
+            let row = &src[(y * scale) as usize * stride..];
+            for x in 0..out_width {
+                let i = (x * scale) as usize * 4;
+                let px = [row[i], row[i + 1], row[i + 2], row[i + 3]];
+                data.extend_from_slice(&to_rgba(px, fourcc)?);
+            }
+        }
+
+        Some(Self {
+            width: out_width,
+            height: out_height,
+            data,
+        })
+    }
+}
+
+/// Take a one-off thumbnail of a Wayland output using wlr-screencopy,
+/// which needs neither a portal session nor a GPU import.
+#[cfg(feature = "wlr")]
+pub fn wlr_thumbnail(
+    wl: &mut crate::wayland::WlxClient,
+    output_id: u32,
+    max_size: u32,
+) -> Option<Thumbnail> {
+    let (_, frame, _data) = crate::wlr_screencopy::request_screencopy_frames(
+        wl,
+        &[output_id],
+        (None, true),
+        false,
+        None,
+    )
+    .ok()?
+    .into_iter()
+    .next()?;
+
+    let thumbnail = frame.with_mapped(|pixels| {
+        Thumbnail::from_pixels(
+            pixels,
+            frame.format.width,
+            frame.format.height,
+            frame.stride(),
+            frame.format.fourcc,
+            max_size,
+        )
+    });
+    if thumbnail.is_none() {
+        log::warn!("Failed to map screencopy buffer for thumbnail");
+    }
+    thumbnail.flatten()
+}
+
+/// Take a one-off thumbnail of an X11 monitor using XShm.
+#[cfg(feature = "xshm")]
+pub fn xshm_thumbnail(screen: &crate::xshm::XshmScreen, max_size: u32) -> Option<Thumbnail> {
+    let display = std::env::var("DISPLAY").ok()?;
+    let d = rxscreen::Display::new(display).ok()?;
+    let shm = d.shm().monitor(&screen.monitor).build().ok()?;
+    let image = shm.capture().ok()?;
+    let pixels = unsafe { image.as_bytes() };
+
+    Thumbnail::from_pixels(
+        pixels,
+        image.width() as _,
+        image.height() as _,
+        image.width() as usize * 4,
+        crate::frame::DRM_FORMAT_XRGB8888.into(),
+        max_size,
+    )
+}
@@ -0,0 +1,78 @@
+//! Frame delivery for async applications on tokio: the results of a callback on the capture
+//! thread arrive on a `tokio::sync::mpsc::Receiver`, which wakes the awaiting task, instead
+//! of being polled from a std receiver inside `spawn_blocking`.
+
+use tokio::sync::mpsc;
+
+use crate::{
+    frame::{DrmFormat, WlxFrame},
+    FrameCallback, WlxCapture,
+};
+
+/// Like `callback::CallbackCapture`, but hands the callback's results to a tokio channel.
+/// The receiver can be moved into a task while the capture stays with its owner.
+pub struct TokioCapture {
+    capture: Box<dyn WlxCapture>,
+    /// Set if the backend has no capture thread to run the callback on,
+    /// in which case it runs inside `pump` instead.
+    fallback: Option<FrameCallback>,
+}
+
+impl TokioCapture {
+    /// `capture` must not be initialized yet. `callback` runs on the capture thread, where
+    /// the frame is valid, and its result is sent to the returned receiver. Results are
+    /// dropped while `capacity` of them wait to be received; it must be at least 1.
+    pub fn new<U, F>(
+        mut capture: Box<dyn WlxCapture>,
+        capacity: usize,
+        mut callback: F,
+    ) -> (Self, mpsc::Receiver<U>)
+    where
+        U: Send + 'static,
+        F: FnMut(WlxFrame) -> Option<U> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let callback: FrameCallback = Box::new(move |frame| {
+            let Some(result) = callback(frame) else {
+                return;
+            };
+            if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(result) {
+                log::trace!("Dropping frame result, the receiver is behind");
+            }
+        });
+        let fallback = capture.set_frame_callback(callback).err();
+
+        let capture = Self { capture, fallback };
+        (capture, receiver)
+    }
+
+    pub fn capture(&self) -> &dyn WlxCapture {
+        self.capture.as_ref()
+    }
+
+    /// Access to the wrapped capture for pausing, freezing and the like.
+    /// Its `receive` only yields `WlxFrame::Mouse` updates while the callback is in use.
+    pub fn capture_mut(&mut self) -> &mut dyn WlxCapture {
+        self.capture.as_mut()
+    }
+
+    pub fn init(&mut self, dmabuf_formats: &[DrmFormat]) {
+        self.capture.init(dmabuf_formats);
+    }
+
+    /// Whether the backend has no capture thread, so that `pump` must be called for
+    /// results to arrive.
+    pub fn needs_pump(&self) -> bool {
+        self.fallback.is_some()
+    }
+
+    /// Run the callback on the newest frame, for backends without a capture thread.
+    /// Does nothing for the others.
+    pub fn pump(&mut self) {
+        if let Some(callback) = self.fallback.as_mut() {
+            if let Some(frame) = self.capture.receive() {
+                callback(frame);
+            }
+        }
+    }
+}
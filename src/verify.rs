@@ -0,0 +1,214 @@
+//! Self-test of delivered frames, to turn reports of black or garbled captures into
+//! concrete findings. With the `frame-verify` feature, every frame a capture delivers
+//! through `receive` is checked and anomalies are logged as warnings; `verify` can be
+//! called on any frame regardless of the feature.
+//!
+//! CPU-visible frames are checksummed. Linear dmabufs are mapped for the same checks;
+//! other dmabufs are only checked against the size of their memory.
+
+use std::os::fd::RawFd;
+
+use crate::{
+    convert::to_rgba,
+    frame::{DmabufFrame, FrameFormat, MemFdFrame, WlxFrame, DRM_FORMAT_MOD_LINEAR},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anomaly {
+    /// Every byte of the image is zero.
+    AllZero,
+    /// Rows are shorter than the width of the image.
+    StrideTooSmall { stride: usize, min: usize },
+    /// The memory ends before the last row of the image.
+    BufferTooSmall { required: usize, available: usize },
+    /// The frame's memory could not be mapped for reading.
+    MapFailed,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameReport {
+    /// CRC-32 of the image rows, without row padding. None if the image was not readable.
+    pub crc: Option<u32>,
+    pub anomalies: Vec<Anomaly>,
+}
+
+/// Check one frame. Cursor-only frames give an empty report.
+pub fn verify(frame: &WlxFrame) -> FrameReport {
+    let mut report = FrameReport::default();
+    match frame {
+        WlxFrame::MemFd(memfd) => verify_memfd(memfd, &mut report),
+        WlxFrame::MemPtr(memptr) => {
+            let stride = memptr.stride();
+            if memptr.ptr == 0 {
+                report.anomalies.push(Anomaly::MapFailed);
+            } else {
+                let bytes =
+                    unsafe { std::slice::from_raw_parts(memptr.ptr as *const u8, memptr.size) };
+                verify_rows(bytes, stride, &memptr.format, &mut report);
+            }
+        }
+        WlxFrame::Dmabuf(dmabuf) => verify_dmabuf(dmabuf, &mut report),
+        WlxFrame::Mouse(_) => {}
+    }
+    report
+}
+
+fn verify_memfd(memfd: &MemFdFrame, report: &mut FrameReport) {
+    let Some(available) = memfd.plane.fd.and_then(fd_size) else {
+        report.anomalies.push(Anomaly::MapFailed);
+        return;
+    };
+    let required = memfd.map_range().end;
+    if available < required {
+        report.anomalies.push(Anomaly::BufferTooSmall {
+            required,
+            available,
+        });
+        return;
+    }
+    let stride = memfd.stride();
+    if memfd
+        .with_mapped(|bytes| verify_rows(bytes, stride, &memfd.format, report))
+        .is_none()
+    {
+        report.anomalies.push(Anomaly::MapFailed);
+    }
+}
+
+fn verify_dmabuf(dmabuf: &DmabufFrame, report: &mut FrameReport) {
+    let height = dmabuf.format.height as usize;
+    for plane in dmabuf.planes.iter().take(dmabuf.num_planes) {
+        let Some(available) = plane.fd.and_then(fd_size) else {
+            report.anomalies.push(Anomaly::MapFailed);
+            return;
+        };
+        // planes after the first may be subsampled, so only the first can be fully checked
+        let required = plane.offset as usize + plane.stride.unsigned_abs() as usize;
+        let required = if dmabuf.num_planes == 1 {
+            required + plane.stride.unsigned_abs() as usize * height.saturating_sub(1)
+        } else {
+            required
+        };
+        if available < required {
+            report.anomalies.push(Anomaly::BufferTooSmall {
+                required,
+                available,
+            });
+            return;
+        }
+    }
+    if dmabuf.format.modifier != DRM_FORMAT_MOD_LINEAR || dmabuf.num_planes != 1 {
+        return;
+    }
+    let memfd = MemFdFrame {
+        format: dmabuf.format,
+        meta: dmabuf.meta,
+        plane: dmabuf.planes[0],
+    };
+    verify_memfd(&memfd, report);
+}
+
+/// Checksum the rows and check the stride against the width.
+fn verify_rows(bytes: &[u8], stride: usize, format: &FrameFormat, report: &mut FrameReport) {
+    let height = format.height as usize;
+    // all supported formats have 4 bytes per pixel
+    let row_len = if to_rgba([0; 4], format.fourcc).is_some() {
+        format.width as usize * 4
+    } else {
+        stride
+    };
+    if stride < row_len {
+        report.anomalies.push(Anomaly::StrideTooSmall {
+            stride,
+            min: row_len,
+        });
+        return;
+    }
+    let required = stride * height.saturating_sub(1) + row_len;
+    if height > 0 && bytes.len() < required {
+        report.anomalies.push(Anomaly::BufferTooSmall {
+            required,
+            available: bytes.len(),
+        });
+        return;
+    }
+    let mut crc = Crc32::new();
+    let mut all_zero = true;
+    for y in 0..height {
+        let row = &bytes[y * stride..y * stride + row_len];
+        crc.update(row);
+        all_zero &= row.iter().all(|&b| b == 0);
+    }
+    report.crc = Some(crc.finish());
+    if all_zero && height > 0 {
+        report.anomalies.push(Anomaly::AllZero);
+    }
+}
+
+/// Size of the memory behind an fd. Works for memfds, shm and dmabufs.
+fn fd_size(fd: RawFd) -> Option<usize> {
+    let size = unsafe { libc::lseek(fd, 0, libc::SEEK_END) };
+    unsafe { libc::lseek(fd, 0, libc::SEEK_SET) };
+    (size >= 0).then_some(size as usize)
+}
+
+/// CRC-32 (IEEE), as used by zlib and PNG.
+struct Crc32(u32);
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xEDB88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+impl Crc32 {
+    fn new() -> Self {
+        Self(0xFFFFFFFF)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = CRC_TABLE[((self.0 ^ b as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// Verify a frame that is about to be delivered and log what is wrong with it.
+#[cfg(feature = "frame-verify")]
+pub(crate) fn check(frame: &WlxFrame) {
+    if frame.meta().is_none_or(|m| m.duplicate) {
+        return;
+    }
+    let report = verify(frame);
+    for anomaly in report.anomalies.iter() {
+        log::warn!(
+            "Frame verification: {:?} in {:?} frame",
+            anomaly,
+            frame.format()
+        );
+    }
+    if let Some(crc) = report.crc {
+        log::trace!("Frame verification: crc {:08x}", crc);
+    }
+}
+
+#[cfg(not(feature = "frame-verify"))]
+pub(crate) fn check(_: &WlxFrame) {}
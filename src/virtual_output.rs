@@ -0,0 +1,231 @@
+//! Outputs that do not exist physically, created through the IPC of sway or Hyprland,
+//! for mirroring apps to a screen that is only shown in VR or sent to a stream.
+//!
+//! wlroots has no protocol for creating outputs; both compositors add them to their
+//! headless backend, from where they are captured like any other output.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{wayland::WlxClient, wlr_screencopy::WlrScreencopyCapture};
+
+/// Why a virtual output could not be created.
+#[derive(Debug)]
+pub enum VirtualOutputError {
+    /// Neither sway nor Hyprland was found through their environment variables.
+    Unsupported,
+    /// Talking to the compositor's IPC socket failed.
+    Ipc(io::Error),
+    /// The compositor refused the command, with its reason.
+    Rejected(String),
+    /// The compositor accepted the command, but no new output appeared in time.
+    Timeout,
+}
+
+impl From<io::Error> for VirtualOutputError {
+    fn from(err: io::Error) -> Self {
+        VirtualOutputError::Ipc(err)
+    }
+}
+
+impl fmt::Display for VirtualOutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VirtualOutputError::Unsupported => {
+                write!(
+                    f,
+                    "compositor cannot create outputs (only sway and Hyprland)"
+                )
+            }
+            VirtualOutputError::Ipc(err) => write!(f, "compositor IPC failed: {}", err),
+            VirtualOutputError::Rejected(reason) => {
+                write!(f, "compositor refused to create an output: {}", reason)
+            }
+            VirtualOutputError::Timeout => write!(f, "virtual output did not appear"),
+        }
+    }
+}
+
+impl std::error::Error for VirtualOutputError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VirtualOutputError::Ipc(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// i3-ipc message type that runs its payload as commands.
+const SWAY_RUN_COMMAND: u32 = 0;
+const SWAY_IPC_MAGIC: &[u8] = b"i3-ipc";
+
+/// Numbers the outputs named by this process, on compositors that let it choose.
+static OUTPUT_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// A compositor IPC socket that can create and remove outputs.
+#[derive(Debug, Clone)]
+enum Compositor {
+    Sway(PathBuf),
+    Hyprland(PathBuf),
+}
+
+impl Compositor {
+    fn detect() -> Option<Self> {
+        if let Some(socket) = std::env::var_os("SWAYSOCK") {
+            return Some(Compositor::Sway(socket.into()));
+        }
+        let signature = std::env::var("HYPRLAND_INSTANCE_SIGNATURE").ok()?;
+        let runtime_dir = std::env::var("XDG_RUNTIME_DIR").unwrap_or_default();
+        // older releases kept their sockets in /tmp
+        [runtime_dir.as_str(), "/tmp"]
+            .iter()
+            .map(|dir| {
+                PathBuf::from(dir)
+                    .join("hypr")
+                    .join(&signature)
+                    .join(".socket.sock")
+            })
+            .find(|path| path.exists())
+            .map(Compositor::Hyprland)
+    }
+
+    fn run(&self, command: &str) -> Result<(), VirtualOutputError> {
+        log::debug!("Compositor IPC: {}", command);
+        match self {
+            Compositor::Sway(path) => {
+                let mut stream = UnixStream::connect(path)?;
+                let mut message = SWAY_IPC_MAGIC.to_vec();
+                message.extend_from_slice(&(command.len() as u32).to_ne_bytes());
+                message.extend_from_slice(&SWAY_RUN_COMMAND.to_ne_bytes());
+                message.extend_from_slice(command.as_bytes());
+                stream.write_all(&message)?;
+
+                let mut header = [0u8; 14];
+                stream.read_exact(&mut header)?;
+                let mut len = [0u8; 4];
+                len.copy_from_slice(&header[6..10]);
+                let mut reply = vec![0u8; u32::from_ne_bytes(len) as usize];
+                stream.read_exact(&mut reply)?;
+                let reply = String::from_utf8_lossy(&reply);
+                // one {"success": bool, "error": string} per command
+                if reply.contains("\"success\": false") || reply.contains("\"success\":false") {
+                    return Err(VirtualOutputError::Rejected(reply.into_owned()));
+                }
+                Ok(())
+            }
+            Compositor::Hyprland(path) => {
+                let mut stream = UnixStream::connect(path)?;
+                stream.write_all(command.as_bytes())?;
+                let mut reply = String::new();
+                stream.read_to_string(&mut reply)?;
+                if reply.trim() != "ok" {
+                    return Err(VirtualOutputError::Rejected(reply));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A headless output created for this process. Removed from the compositor on drop.
+pub struct VirtualOutput {
+    compositor: Compositor,
+    name: Arc<str>,
+    id: u32,
+}
+
+impl VirtualOutput {
+    /// Create an output of `width`x`height` pixels, and wait up to `timeout` for it to
+    /// appear in `wl.outputs`. The compositor places it next to the existing outputs.
+    pub fn create(
+        wl: &mut WlxClient,
+        (width, height): (u32, u32),
+        timeout: Duration,
+    ) -> Result<Self, VirtualOutputError> {
+        let compositor = Compositor::detect().ok_or(VirtualOutputError::Unsupported)?;
+        let existing: Vec<u32> = wl.outputs.values().map(|o| o.id).collect();
+
+        match &compositor {
+            Compositor::Sway(_) => compositor.run("create_output")?,
+            Compositor::Hyprland(_) => {
+                // the monitor rule applies as soon as the output is added
+                let n = OUTPUT_COUNTER.fetch_add(1, Ordering::Relaxed);
+                let name = format!("WLX-{}-{}", std::process::id(), n);
+                compositor.run(&format!(
+                    "keyword monitor {},{}x{},auto,1",
+                    name, width, height
+                ))?;
+                compositor.run(&format!("output create headless {}", name))?;
+            }
+        }
+
+        let start = Instant::now();
+        let (id, name) = loop {
+            wl.dispatch_pending();
+            let added = wl
+                .outputs
+                .values()
+                .find(|o| !existing.contains(&o.id) && o.is_done());
+            if let Some(output) = added {
+                break (output.id, output.name.clone());
+            }
+            if start.elapsed() > timeout || wl.error().is_some() {
+                return Err(VirtualOutputError::Timeout);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        let output = Self {
+            compositor,
+            name,
+            id,
+        };
+        log::info!("{}: virtual output created", output.name);
+
+        // sway names the output itself, so the mode can only be set once it exists
+        if let Compositor::Sway(_) = &output.compositor {
+            output.compositor.run(&format!(
+                "output {} mode --custom {}x{}",
+                output.name, width, height
+            ))?;
+        }
+        Ok(output)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The id in `WlxClient::outputs` of the client that created the output.
+    pub fn output_id(&self) -> u32 {
+        self.id
+    }
+
+    /// Capture the output through `wl`, which may be a new client. Without any window on
+    /// it, the compositor only repaints the output when the cursor or the wallpaper changes.
+    pub fn capture(&self, wl: WlxClient) -> Option<WlrScreencopyCapture> {
+        let id = wl.outputs.values().find(|o| *o.name == *self.name)?.id;
+        Some(WlrScreencopyCapture::new(wl, id))
+    }
+}
+
+impl Drop for VirtualOutput {
+    fn drop(&mut self) {
+        let command = match &self.compositor {
+            Compositor::Sway(_) => format!("output {} unplug", self.name),
+            Compositor::Hyprland(_) => format!("output remove {}", self.name),
+        };
+        match self.compositor.run(&command) {
+            Ok(()) => log::info!("{}: virtual output removed", self.name),
+            Err(e) => log::warn!("{}: could not remove virtual output: {}", self.name, e),
+        }
+    }
+}
@@ -1,5 +1,9 @@
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
+    fmt,
+    ops::RangeInclusive,
+    os::fd::BorrowedFd,
+    path::Path,
     sync::{Arc, Mutex},
 };
 
@@ -13,25 +17,70 @@ use smithay_client_toolkit::reexports::{
     },
     protocols_wlr::{
         export_dmabuf::v1::client::zwlr_export_dmabuf_manager_v1::ZwlrExportDmabufManagerV1,
+        layer_shell::v1::client::zwlr_layer_shell_v1::ZwlrLayerShellV1,
+        output_management::v1::client::{
+            zwlr_output_head_v1::{self, ZwlrOutputHeadV1},
+            zwlr_output_manager_v1::{self, ZwlrOutputManagerV1},
+            zwlr_output_mode_v1::{self, ZwlrOutputModeV1},
+        },
+        output_power_management::v1::client::{
+            zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1,
+            zwlr_output_power_v1::{self, ZwlrOutputPowerV1},
+        },
         screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
     },
 };
 
 pub use wayland_client;
 use wayland_client::{
-    backend::WaylandError,
+    backend::{protocol::ProtocolError, ObjectId, WaylandError},
+    event_created_child,
     globals::{registry_queue_init, GlobalList, GlobalListContents},
     protocol::{
+        wl_buffer::WlBuffer,
+        wl_compositor::WlCompositor,
         wl_output::{self, Transform, WlOutput},
         wl_registry::{self, WlRegistry},
         wl_seat::WlSeat,
         wl_shm::WlShm,
     },
-    Connection, Dispatch, EventQueue, Proxy, QueueHandle,
+    Connection, Dispatch, DispatchError, EventQueue, Proxy, QueueHandle,
+};
+use wayland_protocols::{
+    ext::{
+        foreign_toplevel_list::v1::client::{
+            ext_foreign_toplevel_handle_v1::{self, ExtForeignToplevelHandleV1},
+            ext_foreign_toplevel_list_v1::{self, ExtForeignToplevelListV1},
+        },
+        image_capture_source::v1::client::{
+            ext_foreign_toplevel_image_capture_source_manager_v1::ExtForeignToplevelImageCaptureSourceManagerV1,
+            ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1,
+        },
+        image_copy_capture::v1::client::ext_image_copy_capture_manager_v1::ExtImageCopyCaptureManagerV1,
+    },
+    wp::{
+        idle_inhibit::zv1::client::zwp_idle_inhibit_manager_v1::ZwpIdleInhibitManagerV1,
+        linux_dmabuf::zv1::client::{
+            zwp_linux_buffer_params_v1, zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+        },
+        viewporter::client::wp_viewporter::WpViewporter,
+    },
+    xdg::shell::client::xdg_wm_base::{self, XdgWmBase},
+};
+
+use crate::{
+    frame::{Activity, DmabufFrame},
+    output::{scale_of, OutputInfo},
+    protocol_log::ProtocolRecorder,
+};
+#[cfg(feature = "wl-drm")]
+use crate::{
+    frame::{DRM_FORMAT_MOD_INVALID, DRM_FORMAT_MOD_LINEAR},
+    wl_drm::client::wl_drm::{self, WlDrm},
 };
 
 pub enum OutputChangeEvent {
-    /// New output has been created.
+    /// New output has been created and all of its properties are known.
     Create(u32),
     /// Logical position or size has changed, but no changes required in terms of rendering.
     Logical(u32),
@@ -39,6 +88,23 @@ pub enum OutputChangeEvent {
     Physical(u32),
     /// Output has been destroyed.
     Destroy(u32),
+    /// Logical position or scale changed while the resolution stayed the same,
+    /// so a running capture can continue and only its placement needs updating.
+    /// Sent after the `Logical` events of the same change.
+    GeometryChanged(u32, OutputGeometry),
+    /// The output was powered on (true) or off (false), e.g. by DPMS after the session
+    /// went idle. Captures of a powered off output may keep delivering its last content.
+    /// Only sent after `WlxClient::watch_output_power`.
+    PowerChanged(u32, bool),
+}
+
+/// Placement of an output on the desktop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputGeometry {
+    pub logical_pos: (i32, i32),
+    pub logical_size: (i32, i32),
+    /// Pixels per logical pixel.
+    pub scale: f32,
 }
 
 pub struct WlxOutput {
@@ -48,12 +114,217 @@ pub struct WlxOutput {
     pub make: Arc<str>,
     pub model: Arc<str>,
     pub size: (i32, i32),
+    /// Refresh rate of the current mode in mHz, or 0 if unknown.
+    pub refresh: i32,
+    /// Physical width and height in millimeters, or 0 if unknown (e.g. projectors).
+    pub physical_size: (i32, i32),
     pub logical_pos: (i32, i32),
     pub logical_size: (i32, i32),
     pub transform: Transform,
+    /// Serial number of the monitor, if known. Requires wlr-output-management v2.
+    pub serial: Option<Arc<str>>,
+    /// Modes supported by the monitor. Empty without wlr-output-management.
+    pub modes: Vec<OutputMode>,
+    /// Whether the output is powered on. None until known, see `WlxClient::watch_output_power`.
+    pub powered: Option<bool>,
+    power: Option<ZwlrOutputPowerV1>,
     done: bool,
+    /// The logical size has arrived from xdg-output; (0, 0) is no sign of it missing.
+    logical_known: bool,
+    /// Logical position or size changed since the last wl_output.done.
+    geometry_dirty: bool,
+    /// Resolution changed since the last wl_output.done.
+    resized: bool,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputMode {
+    pub size: (i32, i32),
+    /// Refresh rate in mHz, or 0 if unknown.
+    pub refresh: i32,
+    pub preferred: bool,
+    pub current: bool,
+}
+
+impl WlxOutput {
+    /// All properties of the output have arrived, i.e. `OutputChangeEvent::Create` was sent.
+    pub(crate) fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Pixels per inch along the output's width, from the current mode and physical size.
+    /// None if the compositor does not know the physical size.
+    pub fn dpi(&self) -> Option<f32> {
+        if self.physical_size.0 <= 0 || self.size.0 <= 0 {
+            return None;
+        }
+        Some(self.size.0 as f32 * 25.4 / self.physical_size.0 as f32)
+    }
+
+    pub fn geometry(&self) -> OutputGeometry {
+        OutputGeometry {
+            logical_pos: self.logical_pos,
+            logical_size: self.logical_size,
+            scale: scale_of(
+                self.size,
+                self.logical_size,
+                wl_transform_to_frame_transform(self.transform),
+            ),
+        }
+    }
+
+    /// Label for showing to users, such as "DELL U2720Q (DP-1)".
+    /// Falls back to the connector name if make and model are unknown.
+    pub fn label(&self) -> String {
+        let known = |s: &str| !s.is_empty() && s != "Unknown";
+        match (known(&self.make), known(&self.model)) {
+            (true, true) => format!("{} {} ({})", self.make, self.model, self.name),
+            (false, true) => format!("{} ({})", self.model, self.name),
+            _ => self.name.to_string(),
+        }
+    }
+}
+
+/// A window of any client, as listed by ext-foreign-toplevel-list.
+pub struct WlxToplevel {
+    pub handle: ExtForeignToplevelHandleV1,
+    /// Unique among the toplevels of the session and never reused for another window.
+    pub identifier: Arc<str>,
+    pub title: Arc<str>,
+    pub app_id: Arc<str>,
+    /// All properties have been received at least once.
+    pub done: bool,
+}
+
+/// Monitor details announced through wlr-output-management, matched to a
+/// `WlxOutput` by connector name.
+struct OutputHead {
+    head: ZwlrOutputHeadV1,
+    name: Option<Arc<str>>,
+    make: Option<Arc<str>>,
+    model: Option<Arc<str>>,
+    serial: Option<Arc<str>>,
+    modes: Vec<ZwlrOutputModeV1>,
+    current_mode: Option<ObjectId>,
+}
+
+impl From<&WlxOutput> for OutputInfo {
+    fn from(output: &WlxOutput) -> Self {
+        let transform = wl_transform_to_frame_transform(output.transform);
+        OutputInfo {
+            id: output.id,
+            name: output.name.clone(),
+            logical_pos: output.logical_pos,
+            logical_size: output.logical_size,
+            size: output.size,
+            transform,
+            scale: scale_of(output.size, output.logical_size, transform),
+            refresh: output.refresh,
+        }
+    }
+}
+
+/// Result of binding a Wayland global, for diagnosing compositor compatibility.
+#[derive(Debug, Clone)]
+pub struct ProtocolStatus {
+    pub interface: &'static str,
+    /// Highest version advertised by the compositor, or None if the global is absent.
+    pub offered: Option<u32>,
+    pub required: RangeInclusive<u32>,
+    /// Version that was bound, or None if binding failed.
+    pub bound: Option<u32>,
+}
+
+/// Why the Wayland connection stopped working. Once set, the connection is dead
+/// and no further frames will be delivered.
+#[derive(Debug, Clone)]
+pub enum ConnectionError {
+    /// The compositor killed the connection because a request violated the protocol.
+    Protocol {
+        /// Interface of the object the error was posted on, e.g. `zwlr_screencopy_frame_v1`.
+        interface: String,
+        object_id: u32,
+        /// Interface-specific error code, see the protocol XML.
+        code: u32,
+        /// Human readable message supplied by the compositor.
+        message: String,
+    },
+    /// The compositor sent an event that does not match the protocol.
+    BadMessage {
+        interface: &'static str,
+        sender_id: u32,
+        opcode: u16,
+    },
+    /// Reading or writing the socket failed, e.g. because the compositor exited.
+    Io(std::io::ErrorKind),
+}
+
+impl From<ProtocolError> for ConnectionError {
+    fn from(err: ProtocolError) -> Self {
+        ConnectionError::Protocol {
+            interface: err.object_interface,
+            object_id: err.object_id,
+            code: err.code,
+            message: err.message,
+        }
+    }
+}
+
+impl From<WaylandError> for ConnectionError {
+    fn from(err: WaylandError) -> Self {
+        match err {
+            WaylandError::Protocol(err) => err.into(),
+            WaylandError::Io(err) => ConnectionError::Io(err.kind()),
+        }
+    }
+}
+
+impl From<DispatchError> for ConnectionError {
+    fn from(err: DispatchError) -> Self {
+        match err {
+            DispatchError::BadMessage {
+                sender_id,
+                interface,
+                opcode,
+            } => ConnectionError::BadMessage {
+                interface,
+                sender_id: sender_id.protocol_id(),
+                opcode,
+            },
+            DispatchError::Backend(err) => err.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::Protocol {
+                interface,
+                object_id,
+                code,
+                message,
+            } => write!(
+                f,
+                "protocol error {} on {}@{}: {}",
+                code, interface, object_id, message
+            ),
+            ConnectionError::BadMessage {
+                interface,
+                sender_id,
+                opcode,
+            } => write!(
+                f,
+                "malformed event {} from {}@{}",
+                opcode, interface, sender_id
+            ),
+            ConnectionError::Io(kind) => write!(f, "connection lost: {:?}", kind),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
 pub struct WlxClient {
     pub connection: Arc<Connection>,
     pub xdg_output_mgr: ZxdgOutputManagerV1,
@@ -61,12 +332,60 @@ pub struct WlxClient {
     pub maybe_wlr_screencopy_mgr: Option<ZwlrScreencopyManagerV1>,
     pub wl_seat: WlSeat,
     pub wl_shm: WlShm,
+    /// Globals used by `preview::PreviewSurface`; not required for capturing.
+    pub maybe_wl_compositor: Option<WlCompositor>,
+    pub maybe_xdg_wm_base: Option<XdgWmBase>,
+    pub maybe_viewporter: Option<WpViewporter>,
+    pub maybe_linux_dmabuf: Option<ZwpLinuxDmabufV1>,
+    /// Provides make, model, serial and modes of outputs; not required for capturing.
+    pub maybe_wlr_output_mgr: Option<ZwlrOutputManagerV1>,
+    /// Used by `idle::IdleInhibitor`; not required for capturing.
+    pub maybe_idle_inhibit_mgr: Option<ZwpIdleInhibitManagerV1>,
+    /// Reports outputs powering down; not required for capturing.
+    pub maybe_wlr_output_power_mgr: Option<ZwlrOutputPowerManagerV1>,
+    /// Used by `repaint::RepaintCapture`; not required for capturing.
+    pub maybe_layer_shell: Option<ZwlrLayerShellV1>,
+    /// Lists the windows in `toplevels`, for capturing them with `ext_image_copy`.
+    pub maybe_toplevel_list: Option<ExtForeignToplevelListV1>,
+    pub maybe_toplevel_source_mgr: Option<ExtForeignToplevelImageCaptureSourceManagerV1>,
+    pub maybe_image_copy_mgr: Option<ExtImageCopyCaptureManagerV1>,
+    /// Captures outputs with `ext_image_copy`, for compositors without wlr-screencopy.
+    pub maybe_output_source_mgr: Option<ExtOutputImageCaptureSourceManagerV1>,
+    /// Lists COSMIC's workspaces in `workspaces`, for capturing them with `ext_image_copy`.
+    #[cfg(feature = "cosmic")]
+    pub maybe_cosmic_workspace_mgr: Option<crate::cosmic::ZcosmicWorkspaceManagerV1>,
+    #[cfg(feature = "cosmic")]
+    pub maybe_cosmic_workspace_source_mgr:
+        Option<crate::cosmic::ZcosmicWorkspaceImageCaptureSourceManagerV1>,
+    watch_power: bool,
+    /// Captures outputs with `weston`, on Weston which has no other capture protocol.
+    #[cfg(feature = "weston")]
+    pub maybe_weston_capture: Option<crate::weston::client::weston_capture_v1::WestonCaptureV1>,
+    /// Legacy GPU buffer sharing, used by `create_dmabuf_buffer` without linux-dmabuf.
+    #[cfg(feature = "wl-drm")]
+    pub maybe_wl_drm: Option<WlDrm>,
+    #[cfg(feature = "wl-drm")]
+    wl_drm_prime: bool,
     pub outputs: IdMap<u32, WlxOutput>,
+    /// Windows that are currently open. Empty without ext-foreign-toplevel-list.
+    pub toplevels: Vec<WlxToplevel>,
+    /// COSMIC's workspaces. Empty on other compositors.
+    #[cfg(feature = "cosmic")]
+    pub workspaces: Vec<crate::cosmic::WlxWorkspace>,
+    #[cfg(feature = "cosmic")]
+    pub(crate) workspace_groups: Vec<crate::cosmic::WorkspaceGroup>,
     pub queue: Arc<Mutex<EventQueue<Self>>>,
     pub globals: GlobalList,
     pub queue_handle: QueueHandle<Self>,
     default_output_name: Arc<str>,
     events: VecDeque<OutputChangeEvent>,
+    protocols: Vec<ProtocolStatus>,
+    error: Option<ConnectionError>,
+    heads: Vec<OutputHead>,
+    modes: HashMap<ObjectId, OutputMode>,
+    pub(crate) recorder: Option<ProtocolRecorder>,
+    /// Touched whenever the client dispatches, for the `last_activity` of its capture.
+    pub(crate) activity: Activity,
 }
 
 impl WlxClient {
@@ -75,23 +394,87 @@ impl WlxClient {
         let (globals, queue) = registry_queue_init::<Self>(&connection).ok()?;
         let qh = queue.handle();
 
+        let mut protocols = Vec::new();
+        let maybe_xdg_output_mgr = bind_global(&globals, &qh, 2..=3, &mut protocols);
+        let maybe_wl_seat = bind_global(&globals, &qh, 4..=9, &mut protocols);
+        let maybe_wl_shm = bind_global(&globals, &qh, 1..=1, &mut protocols);
+        let maybe_wlr_dmabuf_mgr = bind_global(&globals, &qh, 1..=1, &mut protocols);
+        let maybe_wlr_screencopy_mgr = bind_global(&globals, &qh, 2..=3, &mut protocols);
+        let maybe_wl_compositor = bind_global(&globals, &qh, 4..=6, &mut protocols);
+        let maybe_xdg_wm_base = bind_global(&globals, &qh, 1..=6, &mut protocols);
+        let maybe_viewporter = bind_global(&globals, &qh, 1..=1, &mut protocols);
+        let maybe_linux_dmabuf = bind_global(&globals, &qh, 3..=4, &mut protocols);
+        let maybe_wlr_output_mgr = bind_global(&globals, &qh, 1..=4, &mut protocols);
+        let maybe_idle_inhibit_mgr = bind_global(&globals, &qh, 1..=1, &mut protocols);
+        let maybe_wlr_output_power_mgr = bind_global(&globals, &qh, 1..=1, &mut protocols);
+        let maybe_layer_shell = bind_global(&globals, &qh, 1..=4, &mut protocols);
+        let maybe_toplevel_list = bind_global(&globals, &qh, 1..=1, &mut protocols);
+        let maybe_toplevel_source_mgr = bind_global(&globals, &qh, 1..=1, &mut protocols);
+        let maybe_image_copy_mgr = bind_global(&globals, &qh, 1..=1, &mut protocols);
+        let maybe_output_source_mgr = bind_global(&globals, &qh, 1..=1, &mut protocols);
+        #[cfg(feature = "cosmic")]
+        let maybe_cosmic_workspace_mgr = bind_global(&globals, &qh, 1..=1, &mut protocols);
+        #[cfg(feature = "cosmic")]
+        let maybe_cosmic_workspace_source_mgr = bind_global(&globals, &qh, 1..=1, &mut protocols);
+        #[cfg(feature = "weston")]
+        let maybe_weston_capture = bind_global(&globals, &qh, 1..=1, &mut protocols);
+        #[cfg(feature = "wl-drm")]
+        let maybe_wl_drm = bind_global(&globals, &qh, 2..=2, &mut protocols);
+
+        let (Some(xdg_output_mgr), Some(wl_seat), Some(wl_shm)) =
+            (maybe_xdg_output_mgr, maybe_wl_seat, maybe_wl_shm)
+        else {
+            log::error!("Compositor is missing required Wayland protocols");
+            return None;
+        };
+
         let mut state = Self {
             connection: Arc::new(connection),
-            xdg_output_mgr: globals
-                .bind(&qh, 2..=3, ())
-                .expect(ZxdgOutputManagerV1::interface().name),
-            wl_seat: globals
-                .bind(&qh, 4..=9, ())
-                .expect(WlSeat::interface().name),
-            wl_shm: globals.bind(&qh, 1..=1, ()).expect(WlShm::interface().name),
-            maybe_wlr_dmabuf_mgr: globals.bind(&qh, 1..=1, ()).ok(),
-            maybe_wlr_screencopy_mgr: globals.bind(&qh, 2..=2, ()).ok(),
+            xdg_output_mgr,
+            wl_seat,
+            wl_shm,
+            maybe_wlr_dmabuf_mgr,
+            maybe_wlr_screencopy_mgr,
+            maybe_wl_compositor,
+            maybe_xdg_wm_base,
+            maybe_viewporter,
+            maybe_linux_dmabuf,
+            maybe_wlr_output_mgr,
+            maybe_idle_inhibit_mgr,
+            maybe_wlr_output_power_mgr,
+            maybe_layer_shell,
+            maybe_toplevel_list,
+            maybe_toplevel_source_mgr,
+            maybe_image_copy_mgr,
+            maybe_output_source_mgr,
+            #[cfg(feature = "cosmic")]
+            maybe_cosmic_workspace_mgr,
+            #[cfg(feature = "cosmic")]
+            maybe_cosmic_workspace_source_mgr,
+            watch_power: false,
+            #[cfg(feature = "weston")]
+            maybe_weston_capture,
+            #[cfg(feature = "wl-drm")]
+            maybe_wl_drm,
+            #[cfg(feature = "wl-drm")]
+            wl_drm_prime: false,
             outputs: IdMap::new(),
+            toplevels: Vec::new(),
+            #[cfg(feature = "cosmic")]
+            workspaces: Vec::new(),
+            #[cfg(feature = "cosmic")]
+            workspace_groups: Vec::new(),
             queue: Arc::new(Mutex::new(queue)),
             globals,
             queue_handle: qh,
             default_output_name: "Unknown".into(),
             events: VecDeque::new(),
+            protocols,
+            error: None,
+            heads: Vec::new(),
+            modes: HashMap::new(),
+            recorder: None,
+            activity: Activity::default(),
         };
 
         for o in state.globals.contents().clone_list().iter() {
@@ -102,6 +485,16 @@ impl WlxClient {
 
         state.dispatch();
 
+        #[cfg(feature = "wl-drm")]
+        if state.maybe_wl_drm.is_some() {
+            // capabilities are sent in reply to the bind
+            if let Ok(mut queue) = state.queue.clone().lock() {
+                if let Err(err) = queue.roundtrip(&mut state) {
+                    state.set_error(err);
+                }
+            }
+        }
+
         Some(state)
     }
 
@@ -119,13 +512,25 @@ impl WlxClient {
             make: self.default_output_name.clone(),
             model: self.default_output_name.clone(),
             size: (0, 0),
+            refresh: 0,
+            physical_size: (0, 0),
             logical_pos: (0, 0),
             logical_size: (0, 0),
             transform: Transform::Normal,
+            serial: None,
+            modes: Vec::new(),
+            powered: None,
+            power: None,
             done: false,
+            logical_known: false,
+            geometry_dirty: false,
+            resized: false,
         };
 
         self.outputs.insert(name, output);
+        if self.watch_power {
+            self.watch_output_power();
+        }
     }
 
     pub fn get_desktop_origin(&self) -> (i32, i32) {
@@ -147,15 +552,217 @@ impl WlxClient {
         extent
     }
 
+    /// Copy what wlr-output-management knows about each monitor into the matching output.
+    fn apply_output_heads(&mut self) {
+        for head in self.heads.iter() {
+            let Some(name) = head.name.as_ref() else {
+                continue;
+            };
+            let Some(output) = self.outputs.values_mut().find(|o| &o.name == name) else {
+                continue;
+            };
+            if let Some(make) = head.make.as_ref() {
+                output.make = make.clone();
+            }
+            if let Some(model) = head.model.as_ref() {
+                output.model = model.clone();
+            }
+            output.serial = head.serial.clone();
+            output.modes = head
+                .modes
+                .iter()
+                .filter_map(|m| {
+                    let mut mode = *self.modes.get(&m.id())?;
+                    mode.current = head.current_mode.as_ref() == Some(&m.id());
+                    Some(mode)
+                })
+                .collect();
+        }
+    }
+
+    /// The open window with the given `WlxToplevel::identifier`.
+    pub fn toplevel(&self, identifier: &str) -> Option<&WlxToplevel> {
+        self.toplevels.iter().find(|t| &*t.identifier == identifier)
+    }
+
+    /// The outputs in the backend-agnostic form shared with X11.
+    pub fn output_infos(&self) -> Vec<OutputInfo> {
+        self.outputs.values().map(OutputInfo::from).collect()
+    }
+
+    /// Which protocols were bound at startup, with the versions offered and required.
+    pub fn protocol_report(&self) -> &[ProtocolStatus] {
+        &self.protocols
+    }
+
+    /// Wrap the planes of a dmabuf in a wl_buffer. The compositor imports the planes
+    /// when it receives the request; the fds remain owned by the caller.
+    /// Without linux-dmabuf, wl_drm is used if the `wl-drm` feature is enabled.
+    pub(crate) fn create_dmabuf_buffer<U>(&self, frame: &DmabufFrame, udata: U) -> Option<WlBuffer>
+    where
+        U: Send + Sync + 'static,
+        Self: Dispatch<WlBuffer, U>,
+    {
+        let Some(linux_dmabuf) = self.maybe_linux_dmabuf.as_ref() else {
+            #[cfg(feature = "wl-drm")]
+            return self.create_wl_drm_buffer(frame, udata);
+            #[cfg(not(feature = "wl-drm"))]
+            return None;
+        };
+        let planes = frame.planes.get(..frame.num_planes)?;
+        if planes.iter().any(|p| p.fd.is_none()) {
+            return None;
+        }
+
+        let params = linux_dmabuf.create_params(&self.queue_handle, ());
+        for (i, plane) in planes.iter().enumerate() {
+            let fd = plane.fd?; // checked above
+            params.add(
+                unsafe { BorrowedFd::borrow_raw(fd) },
+                i as _,
+                plane.offset,
+                plane.stride as _,
+                frame.format.get_mod_hi(),
+                frame.format.get_mod_lo(),
+            );
+        }
+        let buffer = params.create_immed(
+            frame.format.width as _,
+            frame.format.height as _,
+            frame.format.fourcc.value,
+            zwp_linux_buffer_params_v1::Flags::empty(),
+            &self.queue_handle,
+            udata,
+        );
+        params.destroy();
+        Some(buffer)
+    }
+
+    /// Wrap a dmabuf in a wl_buffer through wl_drm. wl_drm cannot pass modifiers or
+    /// more than one fd, so only linear or implicitly tiled buffers whose planes
+    /// share one fd can be shared, and the compositor must support PRIME.
+    #[cfg(feature = "wl-drm")]
+    fn create_wl_drm_buffer<U>(&self, frame: &DmabufFrame, udata: U) -> Option<WlBuffer>
+    where
+        U: Send + Sync + 'static,
+        Self: Dispatch<WlBuffer, U>,
+    {
+        let wl_drm = self.maybe_wl_drm.as_ref()?;
+        if !self.wl_drm_prime {
+            debug!("wl_drm: PRIME is not supported");
+            return None;
+        }
+        if !matches!(
+            frame.format.modifier,
+            DRM_FORMAT_MOD_LINEAR | DRM_FORMAT_MOD_INVALID
+        ) {
+            debug!("wl_drm: cannot share buffers with explicit modifiers");
+            return None;
+        }
+        let planes = frame.planes.get(..frame.num_planes)?;
+        let fd = planes.first()?.fd?;
+        if planes.len() > 3 || planes.iter().any(|p| p.fd != Some(fd)) {
+            debug!("wl_drm: cannot share buffers with separate plane fds");
+            return None;
+        }
+
+        let plane = |i: usize| {
+            planes
+                .get(i)
+                .map_or((0, 0), |p| (p.offset as i32, p.stride))
+        };
+        let (offset0, stride0) = plane(0);
+        let (offset1, stride1) = plane(1);
+        let (offset2, stride2) = plane(2);
+        Some(wl_drm.create_prime_buffer(
+            unsafe { BorrowedFd::borrow_raw(fd) },
+            frame.format.width as _,
+            frame.format.height as _,
+            frame.format.fourcc.value,
+            offset0,
+            stride0,
+            offset1,
+            stride1,
+            offset2,
+            stride2,
+            &self.queue_handle,
+            udata,
+        ))
+    }
+
+    /// Write every screencopy and export-dmabuf frame event received from now on
+    /// to the given file, see `protocol_log`. Replaces a previous recording.
+    pub fn record_protocol(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        self.recorder = Some(ProtocolRecorder::create(path.as_ref())?);
+        Ok(())
+    }
+
+    /// The error that killed the connection, if any.
+    pub fn error(&self) -> Option<&ConnectionError> {
+        self.error.as_ref()
+    }
+
+    fn set_error(&mut self, err: impl Into<ConnectionError>) {
+        if self.error.is_some() {
+            return;
+        }
+        // the compositor's own message is more useful than the generic dispatch error
+        let err = match self.connection.protocol_error() {
+            Some(protocol_error) => protocol_error.into(),
+            None => err.into(),
+        };
+        log::error!("Wayland connection failed: {}", err);
+        self.error = Some(err);
+    }
+
+    /// Drain pending events, returning the latest geometry change of the given output.
+    pub(crate) fn take_geometry_change(&mut self, output_id: u32) -> Option<OutputGeometry> {
+        self.events
+            .drain(..)
+            .filter_map(|event| match event {
+                OutputChangeEvent::GeometryChanged(id, geometry) if id == output_id => {
+                    Some(geometry)
+                }
+                _ => None,
+            })
+            .next_back() // the drain removes the remaining events when dropped
+    }
+
+    /// Track the power state of all outputs, including ones added later, in
+    /// `WlxOutput::powered` and with `OutputChangeEvent::PowerChanged`.
+    /// Requires wlr-output-power-management. Some compositors only allow one client
+    /// to do this per output; if another client already does, `powered` stays None.
+    pub fn watch_output_power(&mut self) {
+        let Some(manager) = self.maybe_wlr_output_power_mgr.as_ref() else {
+            return;
+        };
+        self.watch_power = true;
+        for output in self.outputs.values_mut() {
+            if output.power.is_none() {
+                output.power = Some(manager.get_output_power(
+                    &output.wl_output,
+                    &self.queue_handle,
+                    output.id,
+                ));
+            }
+        }
+    }
+
+    /// Output changes seen by previous dispatches. Hotplugged outputs are added to
+    /// `outputs` right away, but only announced with `Create` once their properties arrive.
     pub fn iter_events(&mut self) -> impl Iterator<Item = OutputChangeEvent> + '_ {
         self.events.drain(..)
     }
 
     /// Dispatch pending events and block until finished.
     pub fn dispatch(&mut self) {
+        self.activity.touch();
         if let Ok(mut queue_mut) = self.queue.clone().lock() {
-            let _ = queue_mut.blocking_dispatch(self);
+            if let Err(err) = queue_mut.blocking_dispatch(self) {
+                self.set_error(err);
+            }
         }
+        self.activity.touch();
     }
 
     /// Dispatch pending events without blocking.
@@ -169,6 +776,7 @@ impl WlxClient {
                         }
                         Err(err) => {
                             log::warn!("Error while dispatching {} pending events: {:?}", n, err);
+                            self.set_error(err);
                         }
                     },
                     Err(err) => {
@@ -178,15 +786,64 @@ impl WlxClient {
                             }
                         }
                         log::warn!("Error while reading from event queue: {:?}", err);
+                        self.set_error(err);
                     }
                 }
-            } else {
-                let _ = queue_mut.dispatch_pending(self);
+            } else if let Err(err) = queue_mut.dispatch_pending(self) {
+                self.set_error(err);
             }
         }
     }
 }
 
+/// Bind a global within the given version range, recording the outcome.
+fn bind_global<I>(
+    globals: &GlobalList,
+    qh: &QueueHandle<WlxClient>,
+    required: RangeInclusive<u32>,
+    protocols: &mut Vec<ProtocolStatus>,
+) -> Option<I>
+where
+    I: Proxy + 'static,
+    WlxClient: Dispatch<I, ()>,
+{
+    let interface = I::interface().name;
+    let offered = globals.contents().with_list(|list| {
+        list.iter()
+            .filter(|g| g.interface == interface)
+            .map(|g| g.version)
+            .max()
+    });
+
+    let bound: Option<I> = globals.bind(qh, required.clone(), ()).ok();
+    match (&bound, offered) {
+        (Some(proxy), _) => debug!("{}: bound v{}", interface, proxy.version()),
+        (None, Some(offered)) => log::warn!(
+            "{}: compositor offers v{}, but v{}..={} is required",
+            interface,
+            offered,
+            required.start(),
+            required.end()
+        ),
+        (None, None) => debug!("{}: not offered by compositor", interface),
+    }
+
+    protocols.push(ProtocolStatus {
+        interface,
+        offered,
+        required,
+        bound: bound.as_ref().map(|p| p.version()),
+    });
+    bound
+}
+
+/// Name of the given output, or empty if it does not exist.
+pub(crate) fn output_name(wl: &WlxClient, output_id: u32) -> Arc<str> {
+    wl.outputs
+        .get(output_id)
+        .map_or_else(|| "".into(), |o| o.name.clone())
+}
+
 pub(crate) fn wl_transform_to_frame_transform(transform: Transform) -> crate::frame::Transform {
     match transform {
         Transform::Normal => crate::frame::Transform::Normal,
@@ -201,53 +858,105 @@ pub(crate) fn wl_transform_to_frame_transform(transform: Transform) -> crate::fr
     }
 }
 
+pub(crate) fn frame_transform_to_wl_transform(transform: crate::frame::Transform) -> Transform {
+    match transform {
+        crate::frame::Transform::Rotated90 => Transform::_90,
+        crate::frame::Transform::Rotated180 => Transform::_180,
+        crate::frame::Transform::Rotated270 => Transform::_270,
+        crate::frame::Transform::Flipped => Transform::Flipped,
+        crate::frame::Transform::Flipped90 => Transform::Flipped90,
+        crate::frame::Transform::Flipped180 => Transform::Flipped180,
+        crate::frame::Transform::Flipped270 => Transform::Flipped270,
+        _ => Transform::Normal,
+    }
+}
+
+fn finalize_output(output: &mut WlxOutput) {
+    if output.logical_size.0 < 0 {
+        output.logical_pos.0 += output.logical_size.0;
+        output.logical_size.0 *= -1;
+    }
+    if output.logical_size.1 < 0 {
+        output.logical_pos.1 += output.logical_size.1;
+        output.logical_size.1 *= -1;
+    }
+    if !output.done {
+        output.done = true;
+        debug!(
+            "Discovered WlOutput {}; Size: {:?}; Logical Size: {:?}; Pos: {:?}",
+            output.name, output.size, output.logical_size, output.logical_pos
+        );
+    }
+}
+
+/// Send `Create` for an output once all its properties are known, or report changes
+/// to its geometry, on wl_output.done or, for xdg-output before v3, on zxdg_output.done.
+fn complete_output(output: &mut WlxOutput, id: u32, events: &mut VecDeque<OutputChangeEvent>) {
+    if !output.done {
+        if output.logical_known {
+            finalize_output(output);
+            events.push_back(OutputChangeEvent::Create(id));
+        }
+    } else if output.geometry_dirty && !output.resized {
+        log::info!(
+            "{}: Geometry changed to {:?}",
+            output.name,
+            output.geometry()
+        );
+        events.push_back(OutputChangeEvent::GeometryChanged(id, output.geometry()));
+    }
+    output.geometry_dirty = false;
+}
+
+/// Logical changes are only complete with the next wl_output.done or zxdg_output.done,
+/// except on wl_output v1, which has no such event.
+fn mark_geometry_changed(
+    output: &mut WlxOutput,
+    id: u32,
+    events: &mut VecDeque<OutputChangeEvent>,
+) {
+    if output.wl_output.version() < 2 {
+        events.push_back(OutputChangeEvent::GeometryChanged(id, output.geometry()));
+    } else {
+        output.geometry_dirty = true;
+    }
+}
+
 impl Dispatch<ZxdgOutputV1, u32> for WlxClient {
     fn event(
         state: &mut Self,
-        _proxy: &ZxdgOutputV1,
+        proxy: &ZxdgOutputV1,
         event: <ZxdgOutputV1 as Proxy>::Event,
         data: &u32,
         _conn: &Connection,
         _qhandle: &QueueHandle<Self>,
     ) {
-        fn finalize_output(output: &mut WlxOutput) {
-            if output.logical_size.0 < 0 {
-                output.logical_pos.0 += output.logical_size.0;
-                output.logical_size.0 *= -1;
-            }
-            if output.logical_size.1 < 0 {
-                output.logical_pos.1 += output.logical_size.1;
-                output.logical_size.1 *= -1;
-            }
-            if !output.done {
-                output.done = true;
-                debug!(
-                    "Discovered WlOutput {}; Size: {:?}; Logical Size: {:?}; Pos: {:?}",
-                    output.name, output.size, output.logical_size, output.logical_pos
-                );
-            }
+        // wl_output v2+ and xdg-output signal completion with their own done events;
+        // the position comes before the size
+        fn complete_without_done(output: &WlxOutput) -> bool {
+            output.wl_output.version() < 2 && output.logical_known
         }
         match event {
             zxdg_output_v1::Event::Name { name } => {
                 if let Some(output) = state.outputs.get_mut(*data) {
                     output.name = name.into();
                 }
+                state.apply_output_heads();
             }
             zxdg_output_v1::Event::LogicalPosition { x, y } => {
                 if let Some(output) = state.outputs.get_mut(*data) {
                     output.logical_pos = (x, y);
-                    let was_done = output.done;
-                    if output.logical_size != (0, 0) {
+                    if output.done {
                         finalize_output(output);
-                    }
-                    if was_done {
                         log::info!(
                             "{}: Logical pos changed to {:?}",
                             output.name,
                             output.logical_pos,
                         );
                         state.events.push_back(OutputChangeEvent::Logical(*data));
-                    } else {
+                        mark_geometry_changed(output, *data, &mut state.events);
+                    } else if complete_without_done(output) {
+                        finalize_output(output);
                         state.events.push_back(OutputChangeEvent::Create(*data));
                     }
                 }
@@ -255,22 +964,28 @@ impl Dispatch<ZxdgOutputV1, u32> for WlxClient {
             zxdg_output_v1::Event::LogicalSize { width, height } => {
                 if let Some(output) = state.outputs.get_mut(*data) {
                     output.logical_size = (width, height);
-                    let was_done = output.done;
-                    if output.logical_pos != (0, 0) {
+                    output.logical_known = true;
+                    if output.done {
                         finalize_output(output);
-                    }
-                    if was_done {
                         log::info!(
                             "{}: Logical size changed to {:?}",
                             output.name,
                             output.logical_size,
                         );
                         state.events.push_back(OutputChangeEvent::Logical(*data));
-                    } else {
+                        mark_geometry_changed(output, *data, &mut state.events);
+                    } else if complete_without_done(output) {
+                        finalize_output(output);
                         state.events.push_back(OutputChangeEvent::Create(*data));
                     }
                 }
             }
+            // deprecated in v3 in favor of wl_output.done, and not sent from then on
+            zxdg_output_v1::Event::Done if proxy.version() < 3 => {
+                if let Some(output) = state.outputs.get_mut(*data) {
+                    complete_output(output, *data, &mut state.events);
+                }
+            }
             _ => {}
         }
     }
@@ -286,9 +1001,16 @@ impl Dispatch<WlOutput, u32> for WlxClient {
         _qhandle: &QueueHandle<Self>,
     ) {
         match event {
-            wl_output::Event::Mode { width, height, .. } => {
+            wl_output::Event::Mode {
+                width,
+                height,
+                refresh,
+                ..
+            } => {
                 if let Some(output) = state.outputs.get_mut(*data) {
+                    output.resized |= output.done && output.size != (width, height);
                     output.size = (width, height);
+                    output.refresh = refresh;
                     if output.done {
                         log::info!(
                             "{}: Resolution changed {:?} -> {:?}",
@@ -304,9 +1026,12 @@ impl Dispatch<WlOutput, u32> for WlxClient {
                 make,
                 model,
                 transform,
+                physical_width,
+                physical_height,
                 ..
             } => {
                 if let Some(output) = state.outputs.get_mut(*data) {
+                    output.physical_size = (physical_width, physical_height);
                     let transform = transform.into_result().unwrap_or(Transform::Normal);
                     let old_transform = output.transform;
                     output.transform = transform;
@@ -323,6 +1048,14 @@ impl Dispatch<WlOutput, u32> for WlxClient {
                     output.make = make.into();
                     output.model = model.into();
                 }
+                // wlr-output-management has the untruncated make and model
+                state.apply_output_heads();
+            }
+            wl_output::Event::Done => {
+                if let Some(output) = state.outputs.get_mut(*data) {
+                    complete_output(output, *data, &mut state.events);
+                    output.resized = false;
+                }
             }
             _ => {}
         }
@@ -335,7 +1068,7 @@ impl Dispatch<WlRegistry, GlobalListContents> for WlxClient {
         _proxy: &WlRegistry,
         event: <WlRegistry as Proxy>::Event,
         _data: &GlobalListContents,
-        conn: &Connection,
+        _conn: &Connection,
         _qhandle: &QueueHandle<Self>,
     ) {
         match event {
@@ -345,13 +1078,16 @@ impl Dispatch<WlRegistry, GlobalListContents> for WlxClient {
                 version,
             } => {
                 if interface == WlOutput::interface().name {
+                    // details arrive with later dispatches; Create is emitted once they are complete
                     state.add_output(name, version);
-                    let _ = conn.roundtrip();
                 }
             }
             wl_registry::Event::GlobalRemove { name } => {
                 if let Some(output) = state.outputs.remove(name) {
                     log::info!("{}: Device removed", output.name);
+                    if let Some(power) = output.power {
+                        power.destroy();
+                    }
                     state.events.push_back(OutputChangeEvent::Destroy(name));
                 }
             }
@@ -360,6 +1096,233 @@ impl Dispatch<WlRegistry, GlobalListContents> for WlxClient {
     }
 }
 
+impl Dispatch<ZwlrOutputManagerV1, ()> for WlxClient {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrOutputManagerV1,
+        event: <ZwlrOutputManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_output_manager_v1::Event::Head { head } => {
+                state.heads.push(OutputHead {
+                    head,
+                    name: None,
+                    make: None,
+                    model: None,
+                    serial: None,
+                    modes: Vec::new(),
+                    current_mode: None,
+                });
+            }
+            zwlr_output_manager_v1::Event::Done { .. } => {
+                state.apply_output_heads();
+            }
+            zwlr_output_manager_v1::Event::Finished => {
+                state.maybe_wlr_output_mgr = None;
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(WlxClient, ZwlrOutputManagerV1, [
+        zwlr_output_manager_v1::EVT_HEAD_OPCODE => (ZwlrOutputHeadV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputHeadV1, ()> for WlxClient {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputHeadV1,
+        event: <ZwlrOutputHeadV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let zwlr_output_head_v1::Event::Finished = event {
+            state.heads.retain(|h| &h.head != proxy);
+            if proxy.version() >= 3 {
+                proxy.release();
+            }
+            return;
+        }
+        let Some(head) = state.heads.iter_mut().find(|h| &h.head == proxy) else {
+            return;
+        };
+        match event {
+            zwlr_output_head_v1::Event::Name { name } => head.name = Some(name.into()),
+            zwlr_output_head_v1::Event::Make { make } => head.make = Some(make.into()),
+            zwlr_output_head_v1::Event::Model { model } => head.model = Some(model.into()),
+            zwlr_output_head_v1::Event::SerialNumber { serial_number } => {
+                head.serial = Some(serial_number.into())
+            }
+            zwlr_output_head_v1::Event::Mode { mode } => {
+                state.modes.insert(
+                    mode.id(),
+                    OutputMode {
+                        size: (0, 0),
+                        refresh: 0,
+                        preferred: false,
+                        current: false,
+                    },
+                );
+                head.modes.push(mode);
+            }
+            zwlr_output_head_v1::Event::CurrentMode { mode } => {
+                head.current_mode = Some(mode.id());
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(WlxClient, ZwlrOutputHeadV1, [
+        zwlr_output_head_v1::EVT_MODE_OPCODE => (ZwlrOutputModeV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputPowerManagerV1, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwlrOutputPowerManagerV1,
+        _event: <ZwlrOutputPowerManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrOutputPowerV1, u32> for WlxClient {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputPowerV1,
+        event: <ZwlrOutputPowerV1 as Proxy>::Event,
+        data: &u32,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let Some(output) = state.outputs.get_mut(*data) else {
+            return;
+        };
+        match event {
+            zwlr_output_power_v1::Event::Mode { mode } => {
+                let on = mode.into_result().ok() != Some(zwlr_output_power_v1::Mode::Off);
+                // the first mode only tells the initial state
+                if output.powered.is_some_and(|powered| powered != on) {
+                    log::info!("{}: Powered {}", output.name, if on { "on" } else { "off" });
+                    state
+                        .events
+                        .push_back(OutputChangeEvent::PowerChanged(*data, on));
+                }
+                output.powered = Some(on);
+            }
+            zwlr_output_power_v1::Event::Failed => {
+                debug!("{}: Power state not available", output.name);
+                output.power = None;
+                output.powered = None;
+                proxy.destroy();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtForeignToplevelListV1, ()> for WlxClient {
+    fn event(
+        state: &mut Self,
+        proxy: &ExtForeignToplevelListV1,
+        event: <ExtForeignToplevelListV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_foreign_toplevel_list_v1::Event::Toplevel { toplevel } => {
+                state.toplevels.push(WlxToplevel {
+                    handle: toplevel,
+                    identifier: "".into(),
+                    title: "".into(),
+                    app_id: "".into(),
+                    done: false,
+                });
+            }
+            ext_foreign_toplevel_list_v1::Event::Finished => {
+                proxy.destroy();
+                state.maybe_toplevel_list = None;
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(WlxClient, ExtForeignToplevelListV1, [
+        ext_foreign_toplevel_list_v1::EVT_TOPLEVEL_OPCODE => (ExtForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ExtForeignToplevelHandleV1, ()> for WlxClient {
+    fn event(
+        state: &mut Self,
+        proxy: &ExtForeignToplevelHandleV1,
+        event: <ExtForeignToplevelHandleV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let ext_foreign_toplevel_handle_v1::Event::Closed = event {
+            state.toplevels.retain(|t| &t.handle != proxy);
+            proxy.destroy();
+            return;
+        }
+        let Some(toplevel) = state.toplevels.iter_mut().find(|t| &t.handle == proxy) else {
+            return;
+        };
+        match event {
+            ext_foreign_toplevel_handle_v1::Event::Identifier { identifier } => {
+                toplevel.identifier = identifier.into()
+            }
+            ext_foreign_toplevel_handle_v1::Event::Title { title } => toplevel.title = title.into(),
+            ext_foreign_toplevel_handle_v1::Event::AppId { app_id } => {
+                toplevel.app_id = app_id.into()
+            }
+            ext_foreign_toplevel_handle_v1::Event::Done => toplevel.done = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwlrOutputModeV1, ()> for WlxClient {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputModeV1,
+        event: <ZwlrOutputModeV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let zwlr_output_mode_v1::Event::Finished = event {
+            state.modes.remove(&proxy.id());
+            for head in state.heads.iter_mut() {
+                head.modes.retain(|m| m != proxy);
+            }
+            if proxy.version() >= 3 {
+                proxy.release();
+            }
+            return;
+        }
+        let Some(mode) = state.modes.get_mut(&proxy.id()) else {
+            return;
+        };
+        match event {
+            zwlr_output_mode_v1::Event::Size { width, height } => mode.size = (width, height),
+            zwlr_output_mode_v1::Event::Refresh { refresh } => mode.refresh = refresh,
+            zwlr_output_mode_v1::Event::Preferred => mode.preferred = true,
+            _ => {}
+        }
+    }
+}
+
 // Plumbing below
 
 impl Dispatch<ZxdgOutputManagerV1, ()> for WlxClient {
@@ -386,6 +1349,18 @@ impl Dispatch<ZwlrExportDmabufManagerV1, ()> for WlxClient {
     }
 }
 
+impl Dispatch<ZwlrLayerShellV1, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwlrLayerShellV1,
+        _event: <ZwlrLayerShellV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
 impl Dispatch<ZwlrScreencopyManagerV1, ()> for WlxClient {
     fn event(
         _state: &mut Self,
@@ -398,6 +1373,42 @@ impl Dispatch<ZwlrScreencopyManagerV1, ()> for WlxClient {
     }
 }
 
+impl Dispatch<ExtForeignToplevelImageCaptureSourceManagerV1, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtForeignToplevelImageCaptureSourceManagerV1,
+        _event: <ExtForeignToplevelImageCaptureSourceManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtOutputImageCaptureSourceManagerV1, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtOutputImageCaptureSourceManagerV1,
+        _event: <ExtOutputImageCaptureSourceManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureManagerV1, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtImageCopyCaptureManagerV1,
+        _event: <ExtImageCopyCaptureManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
 impl Dispatch<WlSeat, ()> for WlxClient {
     fn event(
         _state: &mut Self,
@@ -410,6 +1421,85 @@ impl Dispatch<WlSeat, ()> for WlxClient {
     }
 }
 
+impl Dispatch<WlCompositor, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlCompositor,
+        _event: <WlCompositor as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<XdgWmBase, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        proxy: &XdgWmBase,
+        event: <XdgWmBase as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let xdg_wm_base::Event::Ping { serial } = event {
+            proxy.pong(serial);
+        }
+    }
+}
+
+impl Dispatch<ZwpIdleInhibitManagerV1, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpIdleInhibitManagerV1,
+        _event: <ZwpIdleInhibitManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WpViewporter, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WpViewporter,
+        _event: <WpViewporter as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwpLinuxDmabufV1, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpLinuxDmabufV1,
+        _event: <ZwpLinuxDmabufV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+#[cfg(feature = "wl-drm")]
+impl Dispatch<WlDrm, ()> for WlxClient {
+    fn event(
+        state: &mut Self,
+        _proxy: &WlDrm,
+        event: <WlDrm as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let wl_drm::Event::Capabilities { value } = event {
+            state.wl_drm_prime = value & wl_drm::Capability::Prime as u32 != 0;
+        }
+    }
+}
+
 impl Dispatch<WlShm, ()> for WlxClient {
     fn event(
         _state: &mut Self,
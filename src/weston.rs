@@ -0,0 +1,393 @@
+//! Capture of outputs on Weston, which implements neither wlr-screencopy nor
+//! ext-image-copy-capture, through its weston-output-capture protocol.
+//!
+//! Weston only offers the protocol to privileged clients: start it with `--debug`, or
+//! allow the client in the configuration of the kiosk or embedded shell in use.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use wayland_client::{protocol::wl_shm::Format, Connection, Dispatch, Proxy, QueueHandle};
+
+use crate::{
+    frame::{
+        monotonic_ns, Activity, DrmFormat, FrameFormat, FrameMeta, FramePlane, LastFrame,
+        MemFdFrame, WlxFrame, DRM_FORMAT_ARGB8888, DRM_FORMAT_XRGB8888,
+    },
+    wayland::{output_name, WlxClient},
+    wlr_screencopy::{create_shm_buffer, fourcc_from_wlshm, BufData},
+    FrameCallback, WlxCapture,
+};
+
+use self::client::{
+    weston_capture_source_v1::{self, WestonCaptureSourceV1},
+    weston_capture_v1::{self, WestonCaptureV1},
+};
+
+#[allow(dead_code, non_camel_case_types, unused_unsafe, unused_variables)]
+#[allow(non_upper_case_globals, non_snake_case, unused_imports)]
+#[allow(missing_docs, clippy::all)]
+pub mod client {
+    use wayland_client;
+    use wayland_client::protocol::*;
+
+    pub mod __interfaces {
+        use wayland_client::backend as wayland_backend;
+        use wayland_client::protocol::__interfaces::*;
+        wayland_scanner::generate_interfaces!("protocol/weston-output-capture.xml");
+    }
+    use self::__interfaces::*;
+
+    wayland_scanner::generate_client_code!("protocol/weston-output-capture.xml");
+}
+
+/// How often a capture is attempted again after the compositor asked for a new buffer.
+const MAX_RETRIES: usize = 3;
+
+/// Buffer requirements announced by the compositor, and the outcome of the last capture.
+#[derive(Default)]
+struct SourceState {
+    /// DRM fourcc code.
+    drm_format: Option<u32>,
+    size: (u32, u32),
+    result: Option<CaptureResult>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CaptureResult {
+    Complete,
+    Retry,
+    Failed,
+}
+
+type SharedSource = Arc<Mutex<SourceState>>;
+
+struct Source {
+    proxy: WestonCaptureSourceV1,
+    state: SharedSource,
+}
+
+/// Captures one output with weston-output-capture, for Weston-based compositors.
+/// Frames are delivered as `WlxFrame::MemFd`, copied from the output's framebuffer on
+/// its next repaint. The cursor is included if Weston draws it into the framebuffer,
+/// i.e. when it is not on a hardware plane.
+pub struct WestonCapture {
+    output_id: u32,
+    name: Arc<str>,
+    wl: Option<Box<WlxClient>>,
+    handle: Option<JoinHandle<Box<WlxClient>>>,
+    sender: Option<Sender<(WlxFrame, BufData)>>,
+    receiver: Option<mpsc::Receiver<(WlxFrame, BufData)>>,
+    buffers: VecDeque<BufData>,
+    last_frame: LastFrame,
+    frame_callback: Option<Arc<Mutex<FrameCallback>>>,
+    source: Option<Source>,
+    activity: Activity,
+}
+
+impl WestonCapture {
+    pub fn new(wl: WlxClient, output_id: u32) -> Self {
+        Self {
+            name: output_name(&wl, output_id),
+            output_id,
+            activity: wl.activity.clone(),
+            wl: Some(Box::new(wl)),
+            handle: None,
+            sender: None,
+            receiver: None,
+            buffers: VecDeque::with_capacity(2),
+            last_frame: LastFrame::default(),
+            frame_callback: None,
+            source: None,
+        }
+    }
+
+    fn create_source(&self, wl: &WlxClient) -> Option<Source> {
+        let Some(capture) = wl.maybe_weston_capture.as_ref() else {
+            log::warn!("Compositor does not support weston-output-capture");
+            return None;
+        };
+        let Some(output) = wl.outputs.get(self.output_id) else {
+            log::warn!("{}: output not found", self.name);
+            return None;
+        };
+        let state = SharedSource::default();
+        let proxy = capture.create(
+            &output.wl_output,
+            weston_capture_v1::Source::Framebuffer,
+            &wl.queue_handle,
+            state.clone(),
+        );
+        Some(Source { proxy, state })
+    }
+}
+
+impl Drop for WestonCapture {
+    fn drop(&mut self) {
+        if let Some(source) = self.source.take() {
+            source.proxy.destroy();
+        }
+    }
+}
+
+impl WlxCapture for WestonCapture {
+    fn init(&mut self, _: &[DrmFormat]) {
+        debug_assert!(self.wl.is_some());
+
+        let (tx, rx) = mpsc::channel();
+        self.sender = Some(tx);
+        self.receiver = Some(rx);
+    }
+    fn is_ready(&self) -> bool {
+        self.receiver.is_some()
+    }
+    fn supports_dmbuf(&self) -> bool {
+        false
+    }
+    fn receive(&mut self) -> Option<WlxFrame> {
+        if self.last_frame.frozen {
+            return self.last_frame.duplicate();
+        }
+        if let Some(rx) = self.receiver.as_ref() {
+            if let Some((frame, data)) = rx.try_iter().last() {
+                while self.buffers.len() >= 2 {
+                    self.buffers.pop_front();
+                }
+                self.buffers.push_back(data);
+                return self.last_frame.update(Some(frame));
+            }
+        }
+        self.last_frame.take_stale()
+    }
+    fn pause(&mut self) {}
+    fn resume(&mut self) {
+        if self.sender.is_none() {
+            return;
+        }
+        self.receive(); // clear old frames
+        self.last_frame.mark_resumed();
+        while self.buffers.len() > 1 {
+            self.buffers.pop_front();
+        }
+        self.request_new_frame();
+    }
+    fn request_new_frame(&mut self) {
+        if self.last_frame.frozen {
+            return;
+        }
+        if let Some(handle) = self.handle.take() {
+            if handle.is_finished() {
+                self.wl = Some(handle.join().unwrap()); // safe to unwrap because we checked is_finished
+            } else {
+                self.handle = Some(handle);
+                return;
+            }
+        }
+
+        let Some(wl) = self.wl.take() else {
+            return;
+        };
+        if wl.error().is_some() {
+            self.wl = Some(wl);
+            return;
+        }
+
+        if self.source.is_none() {
+            self.source = self.create_source(&wl);
+        }
+        let Some(source) = self.source.as_ref() else {
+            self.wl = Some(wl);
+            return;
+        };
+
+        self.handle = Some(std::thread::spawn({
+            let sender = self
+                .sender
+                .clone()
+                .expect("must call init once before request_new_frame");
+            let name = self.name.clone();
+            let proxy = source.proxy.clone();
+            let state = source.state.clone();
+            let callback = self.frame_callback.clone();
+            move || request_frame(wl, name, (proxy, state), sender, callback)
+        }));
+    }
+    fn last_activity(&self) -> Option<u64> {
+        self.activity.get()
+    }
+    fn freeze(&mut self) {
+        self.last_frame.freeze();
+    }
+    fn unfreeze(&mut self) {
+        self.last_frame.frozen = false;
+        self.request_new_frame();
+    }
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        self.frame_callback = Some(Arc::new(Mutex::new(callback)));
+        Ok(())
+    }
+}
+
+/// The wl_shm format with the given DRM fourcc code. They are the same,
+/// except for the two formats that every compositor supports.
+fn wlshm_from_fourcc(drm_format: u32) -> Option<Format> {
+    match drm_format {
+        DRM_FORMAT_ARGB8888 => Some(Format::Argb8888),
+        DRM_FORMAT_XRGB8888 => Some(Format::Xrgb8888),
+        other => Format::try_from(other).ok(),
+    }
+}
+
+/// Copy the output into a new shm buffer, in the format and size the compositor asks for.
+fn request_frame(
+    client: Box<WlxClient>,
+    name: Arc<str>,
+    (proxy, state): (WestonCaptureSourceV1, SharedSource),
+    sender: Sender<(WlxFrame, BufData)>,
+    callback: Option<Arc<Mutex<FrameCallback>>>,
+) -> Box<WlxClient> {
+    let mut client = client;
+
+    for _ in 0..MAX_RETRIES {
+        // format and size follow the creation of the source, and precede a retry
+        let (size, drm_format) = loop {
+            if let Ok(mut s) = state.lock() {
+                if s.result == Some(CaptureResult::Failed) {
+                    return client;
+                }
+                if let Some(drm_format) = s.drm_format.filter(|_| s.size != (0, 0)) {
+                    s.result = None;
+                    break (s.size, drm_format);
+                }
+            }
+            client.dispatch();
+            if client.error().is_some() {
+                return client;
+            }
+        };
+        let Some((shm_format, fourcc)) =
+            wlshm_from_fourcc(drm_format).and_then(|f| Some((f, fourcc_from_wlshm(f)?)))
+        else {
+            log::warn!("{}: unsupported format {:#x}", name, drm_format);
+            return client;
+        };
+
+        let (width, height) = size;
+        let stride = width * 4;
+        let Some(data) = create_shm_buffer(&client, shm_format, size, stride, &client.queue_handle)
+        else {
+            log::warn!("{}: failed to create shared memory", name);
+            return client;
+        };
+        proxy.capture(&data.wl_buffer);
+
+        // the image is written on the next repaint of the output
+        let result = loop {
+            client.dispatch();
+            if client.error().is_some() {
+                return client;
+            }
+            if let Some(result) = state.lock().ok().and_then(|s| s.result) {
+                break result;
+            }
+        };
+
+        match result {
+            CaptureResult::Complete => {
+                log::trace!("{}: Frame ready", name);
+                let frame = WlxFrame::MemFd(MemFdFrame {
+                    format: FrameFormat {
+                        width,
+                        height,
+                        fourcc,
+                        ..Default::default()
+                    },
+                    plane: FramePlane {
+                        fd: Some(data.fd),
+                        offset: 0,
+                        stride: stride as _,
+                        size: stride * height,
+                    },
+                    meta: FrameMeta {
+                        timestamp: monotonic_ns(),
+                        ..Default::default()
+                    },
+                });
+                if let Some(callback) = callback.as_ref() {
+                    // the buffer is released once the callback returns
+                    if let Ok(mut callback) = callback.lock() {
+                        callback(frame);
+                    }
+                } else {
+                    let _ = sender.send((frame, data));
+                }
+                return client;
+            }
+            CaptureResult::Retry => {
+                log::debug!("{}: buffer no longer matches, retrying", name);
+            }
+            CaptureResult::Failed => {
+                // failures are permanent, e.g. the client is not authorized
+                return client;
+            }
+        }
+    }
+    log::warn!("{}: gave up after {} retries", name, MAX_RETRIES);
+    client
+}
+
+impl Dispatch<WestonCaptureSourceV1, SharedSource> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WestonCaptureSourceV1,
+        event: <WestonCaptureSourceV1 as Proxy>::Event,
+        data: &SharedSource,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let Ok(mut state) = data.lock() else {
+            return;
+        };
+        match event {
+            weston_capture_source_v1::Event::Format { drm_format } => {
+                state.drm_format = Some(drm_format);
+            }
+            weston_capture_source_v1::Event::Size { width, height } => {
+                state.size = (width.max(0) as _, height.max(0) as _);
+            }
+            weston_capture_source_v1::Event::Complete => {
+                state.result = Some(CaptureResult::Complete);
+            }
+            weston_capture_source_v1::Event::Retry => {
+                state.result = Some(CaptureResult::Retry);
+            }
+            weston_capture_source_v1::Event::Failed { msg } => {
+                log::warn!(
+                    "Weston output capture failed: {}",
+                    msg.as_deref().unwrap_or("no reason given")
+                );
+                state.result = Some(CaptureResult::Failed);
+            }
+        }
+    }
+}
+
+// Plumbing below
+
+impl Dispatch<WestonCaptureV1, ()> for WlxClient {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WestonCaptureV1,
+        _event: <WestonCaptureV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
@@ -0,0 +1,23 @@
+//! Client bindings for Mesa's legacy wl_drm protocol.
+//!
+//! Compositors that predate linux-dmabuf only accept GPU buffers through
+//! `wl_drm::create_prime_buffer`, so `WlxClient::create_dmabuf_buffer` falls back to it
+//! when linux-dmabuf is not offered.
+
+#![allow(dead_code, non_camel_case_types, unused_unsafe, unused_variables)]
+#![allow(non_upper_case_globals, non_snake_case, unused_imports)]
+#![allow(missing_docs, clippy::all)]
+
+pub mod client {
+    use wayland_client;
+    use wayland_client::protocol::*;
+
+    pub mod __interfaces {
+        use wayland_client::backend as wayland_backend;
+        use wayland_client::protocol::__interfaces::*;
+        wayland_scanner::generate_interfaces!("protocol/wayland-drm.xml");
+    }
+    use self::__interfaces::*;
+
+    wayland_scanner::generate_client_code!("protocol/wayland-drm.xml");
+}
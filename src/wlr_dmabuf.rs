@@ -1,41 +1,87 @@
 use std::{
     collections::VecDeque,
     os::fd::{FromRawFd, IntoRawFd, OwnedFd, RawFd},
-    sync::mpsc,
+    sync::{mpsc, Arc, Mutex},
     thread::JoinHandle,
 };
 
 use smithay_client_toolkit::reexports::protocols_wlr::export_dmabuf::v1::client::zwlr_export_dmabuf_frame_v1::{self, ZwlrExportDmabufFrameV1};
-use wayland_client::{Connection, QueueHandle, Dispatch, Proxy};
+use wayland_client::{Connection, QueueHandle, Dispatch, Proxy, WEnum};
 
 use crate::{
-    frame::{DmabufFrame, DrmFormat, FramePlane, WlxFrame},
-    wayland::{wl_transform_to_frame_transform, WlxClient},
-    WlxCapture,
+    factory::CaptureDescriptor,
+    fault::{self, Fault},
+    fd_debug,
+    frame::{
+        monotonic_ns, refresh_interval, Activity, BufferKey, DmabufFrame, DrmFormat, FramePlane,
+        LastFrame, RowOrder, WlxFrame,
+    },
+    wayland::{
+        output_name, wl_transform_to_frame_transform, ConnectionError, OutputGeometry, WlxClient,
+    },
+    BackendKind, CaptureCaps, CopyCost, FrameCallback, WlxCapture,
 };
 
 use log::{debug, warn};
 
+/// Delivers `WlxFrame::Dmabuf` frames of an output with wlr-export-dmabuf.
+///
+/// The frames are the compositor's own buffers, which it keeps rendering into, so they can
+/// neither be frozen nor delivered again after a pause: `freeze` does nothing.
 pub struct WlrDmabufCapture {
     output_id: u32,
+    output_name: Arc<str>,
+    /// Refresh rate of the output in mHz, as of the last frame.
+    refresh: i32,
     wl: Option<Box<WlxClient>>,
     handle: Option<JoinHandle<Box<WlxClient>>>,
     sender: Option<mpsc::SyncSender<WlxFrame>>,
     receiver: Option<mpsc::Receiver<WlxFrame>>,
     fds: VecDeque<RawFd>,
+    last_frame: LastFrame,
+    frame_callback: Option<Arc<Mutex<FrameCallback>>>,
+    geometry: Option<OutputGeometry>,
+    overlay_cursor: bool,
+    activity: Activity,
 }
 
 impl WlrDmabufCapture {
     pub fn new(wl: WlxClient, output_id: u32) -> Self {
         Self {
             output_id,
+            output_name: output_name(&wl, output_id),
+            refresh: wl.outputs.get(output_id).map_or(0, |o| o.refresh),
+            activity: wl.activity.clone(),
             wl: Some(Box::new(wl)),
             handle: None,
             sender: None,
             receiver: None,
             fds: VecDeque::new(),
+            last_frame: LastFrame::default(),
+            frame_callback: None,
+            geometry: None,
+            overlay_cursor: true,
         }
     }
+
+    /// The state needed to recreate this capture elsewhere,
+    /// e.g. in a process started with `handoff::spawn_capture_process`.
+    pub fn descriptor(&self) -> CaptureDescriptor {
+        CaptureDescriptor::new(BackendKind::WlrDmabuf, &self.output_name)
+    }
+
+    /// New placement of the output, if it moved or was rescaled since the last call.
+    /// Capturing continues unaffected; only the presentation needs to follow.
+    /// Only updated between frame requests.
+    pub fn geometry_changed(&mut self) -> Option<OutputGeometry> {
+        self.geometry.take()
+    }
+
+    /// The error that killed the Wayland connection, if any. Capturing stops once set.
+    /// Only updated between frame requests.
+    pub fn connection_error(&self) -> Option<&ConnectionError> {
+        self.wl.as_ref().and_then(|wl| wl.error())
+    }
 }
 
 impl WlxCapture for WlrDmabufCapture {
@@ -52,38 +98,64 @@ impl WlxCapture for WlrDmabufCapture {
     fn supports_dmbuf(&self) -> bool {
         true
     }
+    fn caps(&self) -> CaptureCaps {
+        // overlay_cursor draws the cursors of all seats, there is no way to pick one
+        CaptureCaps {
+            dmabuf: true,
+            cost: CopyCost::ZeroCopy,
+            embedded_cursor: self.overlay_cursor,
+            per_seat_cursor: false,
+        }
+    }
     fn receive(&mut self) -> Option<WlxFrame> {
         if let Some(rx) = self.receiver.as_ref() {
-            if let Some(WlxFrame::Dmabuf(last)) = rx.try_iter().last() {
+            let mut last = None;
+            for frame in rx.try_iter() {
+                if let Some(WlxFrame::Dmabuf(skipped)) = last.replace(frame) {
+                    close_planes(&skipped);
+                }
+            }
+            if let Some(WlxFrame::Dmabuf(last)) = last {
                 // this is the only protocol that requires us to manually close the FD
                 while self.fds.len() > 6 * last.num_planes {
                     // safe unwrap
-                    let _ = unsafe { OwnedFd::from_raw_fd(self.fds.pop_back().unwrap()) };
+                    close_fd(self.fds.pop_back().unwrap());
                 }
                 for p in 0..last.num_planes {
                     if let Some(fd) = last.planes[p].fd {
                         self.fds.push_front(fd);
                     }
                 }
-                return Some(WlxFrame::Dmabuf(last));
+                return self.last_frame.update(Some(WlxFrame::Dmabuf(last)));
             }
         }
-        None
+        self.last_frame.take_stale()
     }
     fn pause(&mut self) {}
     fn resume(&mut self) {
         self.receive(); // clear old frames
+        self.last_frame.mark_resumed();
     }
     fn request_new_frame(&mut self) {
         if let Some(handle) = self.handle.take() {
             if handle.is_finished() {
-                self.wl = Some(handle.join().unwrap()); // safe to unwrap because we checked is_finished
+                let mut wl = handle.join().unwrap(); // safe to unwrap because we checked is_finished
+                if let Some(geometry) = wl.take_geometry_change(self.output_id) {
+                    self.geometry = Some(geometry);
+                }
+                if let Some(output) = wl.outputs.get(self.output_id) {
+                    self.refresh = output.refresh;
+                }
+                self.wl = Some(wl);
             } else {
                 self.handle = Some(handle);
                 return;
             }
         }
 
+        if self.connection_error().is_some() {
+            return;
+        }
         let Some(wl) = self.wl.take() else {
             return;
         };
@@ -94,16 +166,64 @@ impl WlxCapture for WlrDmabufCapture {
                 .clone()
                 .expect("must call init once before request_new_frame");
             let output_id = self.output_id;
-            move || request_dmabuf_frame(wl, output_id, sender)
+            let callback = self.frame_callback.clone();
+            let overlay_cursor = self.overlay_cursor;
+            move || request_dmabuf_frame(wl, output_id, overlay_cursor, sender, callback)
         }));
     }
+    fn set_embedded_cursor(&mut self, embedded: bool) -> bool {
+        self.overlay_cursor = embedded;
+        true
+    }
+    fn last_activity(&self) -> Option<u64> {
+        self.activity.get()
+    }
+    fn next_frame_eta(&self) -> Option<u64> {
+        self.last_frame.eta(refresh_interval(self.refresh)?)
+    }
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        self.frame_callback = Some(Arc::new(Mutex::new(callback)));
+        Ok(())
+    }
+}
+
+impl Drop for WlrDmabufCapture {
+    fn drop(&mut self) {
+        for fd in self.fds.drain(..) {
+            close_fd(fd);
+        }
+        if let Some(rx) = self.receiver.as_ref() {
+            for frame in rx.try_iter() {
+                if let WlxFrame::Dmabuf(frame) = frame {
+                    close_planes(&frame);
+                }
+            }
+        }
+        fd_debug::report("wlr-dmabuf capture dropped");
+    }
+}
+
+fn close_fd(fd: RawFd) {
+    fd_debug::untrack(fd);
+    let _ = unsafe { OwnedFd::from_raw_fd(fd) };
+}
+
+/// Close the fds of a frame that will not be delivered.
+fn close_planes(frame: &DmabufFrame) {
+    for plane in frame.planes[..frame.num_planes].iter() {
+        if let Some(fd) = plane.fd {
+            close_fd(fd);
+        }
+    }
 }
 
 /// Request a new DMA-Buf frame using the wlr-export-dmabuf protocol.
 fn request_dmabuf_frame(
     client: Box<WlxClient>,
     output_id: u32,
+    overlay_cursor: bool,
     sender: mpsc::SyncSender<WlxFrame>,
+    callback: Option<Arc<Mutex<FrameCallback>>>,
 ) -> Box<WlxClient> {
     let Some(dmabuf_manager) = client.maybe_wlr_dmabuf_mgr.as_ref() else {
         return client;
@@ -118,7 +238,12 @@ fn request_dmabuf_frame(
     let (tx, rx) = mpsc::sync_channel::<zwlr_export_dmabuf_frame_v1::Event>(16);
     let name = output.name.clone();
 
-    let _ = dmabuf_manager.capture_output(1, &output.wl_output, &client.queue_handle, tx.clone());
+    let _ = dmabuf_manager.capture_output(
+        overlay_cursor as _,
+        &output.wl_output,
+        &client.queue_handle,
+        tx.clone(),
+    );
 
     let mut client = client;
     client.dispatch();
@@ -132,7 +257,6 @@ fn request_dmabuf_frame(
             format,
             mod_high,
             mod_low,
-            num_objects,
             ..
         } => {
             let mut new_frame = DmabufFrame::default();
@@ -141,41 +265,75 @@ fn request_dmabuf_frame(
             new_frame.format.fourcc.value = format;
             new_frame.format.set_mod(mod_high, mod_low);
             new_frame.format.transform = transform;
-            new_frame.num_planes = num_objects as _;
+            new_frame.format.origin = RowOrder::TopDown;
+            // planes are counted as their objects arrive
             frame = Some(new_frame);
         }
         zwlr_export_dmabuf_frame_v1::Event::Object {
             index,
             fd,
+            size,
             offset,
             stride,
-            ..
+            plane_index,
         } => {
             let Some(ref mut frame) = frame else {
                 return;
             };
-            frame.planes[index as usize] = FramePlane {
-                fd: Some(fd.into_raw_fd()),
+            // objects are not in plane order, e.g. when planes share an object
+            let plane = plane_index as usize;
+            if frame.planes.get(plane).is_none_or(|p| p.fd.is_some()) {
+                warn!(
+                    "{}: ignoring object {} for invalid or repeated plane {}",
+                    &name, index, plane_index
+                );
+                return;
+            }
+            let fd = fd.into_raw_fd();
+            fd_debug::track(fd, "export-dmabuf plane");
+            frame.planes[plane] = FramePlane {
+                fd: Some(fd),
                 offset,
                 stride: stride as _,
+                size: size.saturating_sub(offset),
             };
+            frame.num_planes = frame.num_planes.max(plane + 1);
         }
         zwlr_export_dmabuf_frame_v1::Event::Ready { .. } => {
-            let Some(frame) = frame.take() else {
+            let Some(mut frame) = frame.take() else {
                 return;
             };
+            frame.meta.timestamp = monotonic_ns();
+            frame.buffer_key = frame.planes[0].fd.and_then(BufferKey::from_fd);
             debug!("DMA-Buf frame captured");
+
+            if let Some(callback) = callback.as_ref() {
+                if let Ok(mut callback) = callback.lock() {
+                    callback(WlxFrame::Dmabuf(frame.clone()));
+                }
+                // the consumer had to import the frame during the callback
+                close_planes(&frame);
+                return;
+            }
+
             let frame = WlxFrame::Dmabuf(frame);
-            match sender.try_send(frame) {
+            match fault::try_send(&sender, frame) {
                 Ok(_) => (),
-                Err(mpsc::TrySendError::Full(_)) => (),
-                Err(mpsc::TrySendError::Disconnected(_)) => {
+                Err(mpsc::TrySendError::Full(WlxFrame::Dmabuf(frame))) => {
+                    close_planes(&frame);
+                }
+                Err(mpsc::TrySendError::Disconnected(WlxFrame::Dmabuf(frame))) => {
                     log::warn!("{}: disconnected", &name);
+                    close_planes(&frame);
                 }
+                Err(_) => (),
             }
         }
         zwlr_export_dmabuf_frame_v1::Event::Cancel { .. } => {
             warn!("DMA-Buf frame capture cancelled");
+            if let Some(frame) = frame.take() {
+                close_planes(&frame);
+            }
         }
         _ => {}
     });
@@ -187,13 +345,28 @@ impl Dispatch<ZwlrExportDmabufFrameV1, mpsc::SyncSender<zwlr_export_dmabuf_frame
     for WlxClient
 {
     fn event(
-        _state: &mut Self,
+        state: &mut Self,
         proxy: &ZwlrExportDmabufFrameV1,
         event: <ZwlrExportDmabufFrameV1 as Proxy>::Event,
         data: &mpsc::SyncSender<zwlr_export_dmabuf_frame_v1::Event>,
         _conn: &Connection,
         _qhandle: &QueueHandle<Self>,
     ) {
+        if let Some(recorder) = state.recorder.as_mut() {
+            recorder.export_dmabuf(proxy.id().protocol_id(), &event);
+        }
+        let event = match event {
+            zwlr_export_dmabuf_frame_v1::Event::Ready { .. } => {
+                fault::delay_ready();
+                match fault::take(|f| *f == Fault::FrameFailed) {
+                    Some(_) => zwlr_export_dmabuf_frame_v1::Event::Cancel {
+                        reason: WEnum::Value(zwlr_export_dmabuf_frame_v1::CancelReason::Temporary),
+                    },
+                    None => event,
+                }
+            }
+            event => event,
+        };
         match event {
             zwlr_export_dmabuf_frame_v1::Event::Ready { .. }
             | zwlr_export_dmabuf_frame_v1::Event::Cancel { .. } => {
@@ -4,37 +4,53 @@ use std::{
     ffi::CString,
     os::fd::{BorrowedFd, RawFd},
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         mpsc::{self, Sender, SyncSender},
+        Arc, Mutex,
     },
     thread::JoinHandle,
 };
 use wayland_client::{
-    protocol::{wl_buffer::WlBuffer, wl_shm::Format, wl_shm_pool::WlShmPool},
+    protocol::{wl_buffer::WlBuffer, wl_output::WlOutput, wl_shm::Format, wl_shm_pool::WlShmPool},
     Connection, Dispatch, Proxy, QueueHandle, WEnum,
 };
 
-use smithay_client_toolkit::reexports::protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::{ZwlrScreencopyFrameV1, self};
+use smithay_client_toolkit::reexports::protocols_wlr::screencopy::v1::client::{
+    zwlr_screencopy_frame_v1::{self, ZwlrScreencopyFrameV1},
+    zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+};
 
 use crate::{
+    copy::copy_rows,
+    factory::CaptureDescriptor,
+    fault::{self, Fault},
+    fd_debug,
     frame::{
-        DrmFormat, FourCC, FrameFormat, FramePlane, MemFdFrame, WlxFrame, DRM_FORMAT_ARGB8888,
+        monotonic_ns, refresh_interval, Activity, BufferKey, DmabufFrame, DrmFormat, FourCC,
+        FrameFormat, FramePlane, LastFrame, MemFdFrame, MemPtrFrame, RowOrder, SourceKind,
+        Transform, WlxFrame, DRM_FORMAT_ABGR2101010, DRM_FORMAT_ABGR8888, DRM_FORMAT_ARGB2101010,
+        DRM_FORMAT_ARGB8888, DRM_FORMAT_BGRA8888, DRM_FORMAT_BGRX8888, DRM_FORMAT_RGBA8888,
+        DRM_FORMAT_RGBX8888, DRM_FORMAT_XBGR2101010, DRM_FORMAT_XBGR8888, DRM_FORMAT_XRGB2101010,
         DRM_FORMAT_XRGB8888,
     },
-    wayland::{wl_transform_to_frame_transform, WlxClient},
-    WlxCapture,
+    wayland::{
+        output_name, wl_transform_to_frame_transform, ConnectionError, OutputGeometry, WlxClient,
+    },
+    BackendKind, CaptureCaps, CopyCost, FrameCallback, MemoryLimitExceeded, WlxCapture,
 };
 
-struct BufData {
-    wl_buffer: WlBuffer,
+pub(crate) struct BufData {
+    pub(crate) wl_buffer: WlBuffer,
     wl_pool: WlShmPool,
-    fd: RawFd,
+    pub(crate) fd: RawFd,
+    pub(crate) size: usize,
 }
 
 impl Drop for BufData {
     fn drop(&mut self) {
         self.wl_buffer.destroy();
         self.wl_pool.destroy();
+        fd_debug::untrack(self.fd);
         unsafe {
             libc::close(self.fd);
         }
@@ -43,35 +59,219 @@ impl Drop for BufData {
 
 enum ScreenCopyEvent {
     Buffer {
-        data: BufData,
+        shm_format: Format,
         fourcc: FourCC,
         width: u32,
         height: u32,
         stride: u32,
     },
-    Ready,
+    Flags {
+        y_invert: bool,
+    },
+    /// Presentation time of the copied content, in CLOCK_MONOTONIC nanoseconds.
+    Ready {
+        timestamp: u64,
+    },
+    Failed,
+}
+
+/// Events of a copy into a consumer-provided buffer (screencopy v3).
+enum TargetCopyEvent {
+    /// Size of the shm buffer the compositor would copy into; before v3 the only
+    /// description of the frame.
+    Buffer {
+        width: u32,
+        height: u32,
+    },
+    LinuxDmabuf {
+        fourcc: FourCC,
+        width: u32,
+        height: u32,
+    },
+    BufferDone,
+    Flags {
+        y_invert: bool,
+    },
+    /// Presentation time of the copied content, in CLOCK_MONOTONIC nanoseconds.
+    Ready {
+        timestamp: u64,
+    },
     Failed,
 }
 
 pub struct WlrScreencopyCapture {
     output_id: u32,
+    output_name: Arc<str>,
+    /// Refresh rate of the output in mHz, as of the last frame.
+    refresh: i32,
     wl: Option<Box<WlxClient>>,
     handle: Option<JoinHandle<Box<WlxClient>>>,
-    sender: Option<mpsc::Sender<(WlxFrame, BufData)>>,
-    receiver: Option<mpsc::Receiver<(WlxFrame, BufData)>>,
-    buffers: VecDeque<BufData>,
+    sender: Option<mpsc::Sender<(WlxFrame, FrameBacking)>>,
+    receiver: Option<mpsc::Receiver<(WlxFrame, FrameBacking)>>,
+    buffers: VecDeque<FrameBacking>,
+    last_frame: LastFrame,
+    frame_callback: Option<Arc<Mutex<FrameCallback>>>,
+    memory_limit: Option<usize>,
+    /// How many frames fit in the memory limit, once the frame size is known.
+    buffer_capacity: Option<usize>,
+    /// Set by the capture thread if a frame would not fit, before allocating it.
+    memory_error: Arc<Mutex<Option<MemoryLimitExceeded>>>,
+    compact: bool,
+    targets: Vec<DmabufFrame>,
+    /// wl_buffers wrapping `targets`, created on init.
+    target_buffers: Vec<WlBuffer>,
+    next_target: usize,
+    /// Set by the capture thread if the targets no longer match the output.
+    targets_mismatched: Arc<AtomicBool>,
+    geometry: Option<OutputGeometry>,
+    region: Option<Region>,
+    overlay_cursor: bool,
+    activity: Activity,
+}
+
+/// x, y, width and height in logical coordinates relative to the output.
+pub(crate) type Region = (i32, i32, i32, i32);
+
+/// The part of the output to copy, and whether to draw the cursor into it.
+pub(crate) type CopySource = (Option<Region>, bool);
+
+fn source_kind((region, _): CopySource) -> SourceKind {
+    match region {
+        Some(_) => SourceKind::Region,
+        None => SourceKind::Monitor,
+    }
+}
+
+/// Keeps the memory of a delivered frame alive.
+enum FrameBacking {
+    Shm(BufData),
+    /// Rows copied out of the shm buffer with padding removed.
+    Compact(Vec<u8>),
+    /// A consumer-provided buffer, owned by the consumer.
+    Target,
+}
+
+impl FrameBacking {
+    /// Shared memory held by this capture.
+    fn size(&self) -> usize {
+        match self {
+            FrameBacking::Shm(data) => data.size,
+            FrameBacking::Compact(pixels) => pixels.len(),
+            FrameBacking::Target => 0,
+        }
+    }
 }
 
 impl WlrScreencopyCapture {
     pub fn new(wl: WlxClient, output_id: u32) -> Self {
         Self {
             output_id,
+            output_name: output_name(&wl, output_id),
+            refresh: wl.outputs.get(output_id).map_or(0, |o| o.refresh),
+            activity: wl.activity.clone(),
             wl: Some(Box::new(wl)),
             handle: None,
             sender: None,
             receiver: None,
             buffers: VecDeque::with_capacity(2),
+            last_frame: LastFrame::default(),
+            frame_callback: None,
+            memory_limit: None,
+            buffer_capacity: None,
+            memory_error: Arc::new(Mutex::new(None)),
+            compact: false,
+            targets: Vec::new(),
+            target_buffers: Vec::new(),
+            next_target: 0,
+            targets_mismatched: Arc::new(AtomicBool::new(false)),
+            geometry: None,
+            region: None,
+            overlay_cursor: true,
+        }
+    }
+
+    /// Copy only the given rectangle of the output, in logical coordinates relative
+    /// to the output. The compositor crops, so less memory is copied and transferred.
+    /// Target buffers must match the size of the region instead of the output.
+    pub fn with_region(mut self, x: i32, y: i32, width: i32, height: i32) -> Self {
+        self.region = Some((x, y, width, height));
+        self
+    }
+
+    /// Copy into the given dmabufs instead of shared memory, so that frames land
+    /// in buffers the consumer already has imported, such as its own textures.
+    /// Frames are delivered as `WlxFrame::Dmabuf` describing one of these buffers,
+    /// used in turn; a buffer is overwritten once all the others have been used,
+    /// so provide enough of them to cover the frames still being read.
+    ///
+    /// The buffers must match the output's size and one of the formats the compositor
+    /// offers for it, and their fds must stay open for the lifetime of the capture.
+    /// Requires wlr-screencopy v3 and linux-dmabuf, or with the `wl-drm` feature,
+    /// wl_drm on compositors without linux-dmabuf, where only the size of the buffers
+    /// is checked before wlr-screencopy v3. Falls back to shared memory otherwise,
+    /// and for good once the buffers stop matching the output, e.g. after a mode change;
+    /// see `uses_target_buffers`. Must be called before `init`.
+    pub fn with_target_buffers(mut self, targets: Vec<DmabufFrame>) -> Self {
+        self.targets = targets;
+        self
+    }
+
+    /// Deliver `WlxFrame::MemPtr` frames whose stride is exactly `width * 4`,
+    /// for consumers such as encoders that need tightly packed rows.
+    /// Rows are copied out of the shared buffer on the capture thread if the compositor
+    /// chose a padded stride; otherwise the copy is still made so the frame type is stable.
+    pub fn with_compact_stride(mut self) -> Self {
+        self.compact = true;
+        self
+    }
+
+    /// Cap the shared memory held by this capture, including the buffer being copied into.
+    /// Fewer past frames are kept alive to fit; if the limit only fits one frame,
+    /// the previous frame's buffer is released as soon as the next one is requested.
+    /// If not even one frame fits, capturing stops and `memory_error` is set.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// The state needed to recreate this capture elsewhere,
+    /// e.g. in a process started with `handoff::spawn_capture_process`.
+    /// Target buffers are not part of it.
+    pub fn descriptor(&self) -> CaptureDescriptor {
+        let mut descriptor = CaptureDescriptor::new(BackendKind::WlrScreencopy, &self.output_name);
+        if let Some((x, y, width, height)) = self.region {
+            descriptor.set_option("region", format!("{},{},{},{}", x, y, width, height));
+        }
+        if self.compact {
+            descriptor.set_option("compact", true);
         }
+        if let Some(bytes) = self.memory_limit {
+            descriptor.set_option("memory_limit", bytes);
+        }
+        descriptor
+    }
+
+    pub fn memory_error(&self) -> Option<MemoryLimitExceeded> {
+        self.memory_error.lock().ok().and_then(|e| *e)
+    }
+
+    /// Whether frames are copied into the buffers from `with_target_buffers`,
+    /// rather than into shared memory.
+    pub fn uses_target_buffers(&self) -> bool {
+        !self.target_buffers.is_empty()
+    }
+
+    /// New placement of the output, if it moved or was rescaled since the last call.
+    /// Capturing continues unaffected; only the presentation needs to follow.
+    /// Only updated between frame requests.
+    pub fn geometry_changed(&mut self) -> Option<OutputGeometry> {
+        self.geometry.take()
+    }
+
+    /// The error that killed the Wayland connection, if any. Capturing stops once set.
+    /// Only updated between frame requests.
+    pub fn connection_error(&self) -> Option<&ConnectionError> {
+        self.wl.as_ref().and_then(|wl| wl.error())
     }
 }
 
@@ -82,6 +282,30 @@ impl WlxCapture for WlrScreencopyCapture {
         let (tx, rx) = mpsc::channel();
         self.sender = Some(tx);
         self.receiver = Some(rx);
+
+        if let (false, Some(wl)) = (self.targets.is_empty(), self.wl.as_ref()) {
+            let version = wl
+                .maybe_wlr_screencopy_mgr
+                .as_ref()
+                .map_or(0, |mgr| mgr.version());
+            let buffers: Option<Vec<_>> = self
+                .targets
+                .iter()
+                .map(|target| wl.create_dmabuf_buffer(target, ()))
+                .collect();
+            // wl_drm buffers are only created without linux-dmabuf
+            let wl_drm = wl.maybe_linux_dmabuf.is_none();
+            match buffers {
+                Some(buffers) if version >= 3 || (version > 0 && wl_drm) => {
+                    self.target_buffers = buffers
+                }
+                buffers => {
+                    log::warn!("Target buffers are not supported, using shared memory");
+                    buffers.into_iter().flatten().for_each(|b| b.destroy());
+                    self.targets.clear();
+                }
+            }
+        }
     }
     fn is_ready(&self) -> bool {
         self.receiver.is_some()
@@ -89,17 +313,62 @@ impl WlxCapture for WlrScreencopyCapture {
     fn supports_dmbuf(&self) -> bool {
         false // screencopy v1
     }
+    fn caps(&self) -> CaptureCaps {
+        // overlay_cursor draws the cursors of all seats, there is no way to pick one
+        CaptureCaps {
+            dmabuf: !self.target_buffers.is_empty(),
+            cost: if self.compact {
+                CopyCost::DoubleCopy
+            } else {
+                CopyCost::SingleCopy
+            },
+            embedded_cursor: self.overlay_cursor,
+            per_seat_cursor: false,
+        }
+    }
+    fn source_kind(&self) -> SourceKind {
+        source_kind((self.region, self.overlay_cursor))
+    }
     fn receive(&mut self) -> Option<WlxFrame> {
+        if self.last_frame.frozen {
+            return self.last_frame.duplicate();
+        }
         if let Some(rx) = self.receiver.as_ref() {
             if let Some((frame, data)) = rx.try_iter().last() {
-                if self.buffers.len() > 1 {
+                let mut keep = 2;
+                let size = match &frame {
+                    WlxFrame::MemFd(memfd) => Some(memfd.map_range().len()),
+                    WlxFrame::MemPtr(memptr) => Some(memptr.size),
+                    _ => None,
+                };
+                if let (Some(limit), Some(size)) = (self.memory_limit, size) {
+                    if size > limit {
+                        log::error!(
+                            "a {} byte frame exceeds the memory limit of {} bytes",
+                            size,
+                            limit
+                        );
+                        if let Ok(mut error) = self.memory_error.lock() {
+                            *error = Some(MemoryLimitExceeded {
+                                required: size,
+                                limit,
+                            });
+                        }
+                        return None;
+                    }
+                    let capacity = limit / size.max(1);
+                    self.buffer_capacity = Some(capacity);
+                    // one buffer is reserved for the next copy
+                    keep = (capacity - 1).clamp(1, 2);
+                }
+                while self.buffers.len() >= keep {
                     self.buffers.pop_front();
                 }
                 self.buffers.push_back(data);
-                return Some(frame);
+                return self.last_frame.update(Some(frame));
             }
         }
-        None
+        self.last_frame.take_stale()
     }
     fn pause(&mut self) {}
     fn resume(&mut self) {
@@ -107,114 +376,596 @@ impl WlxCapture for WlrScreencopyCapture {
             return;
         }
         self.receive(); // clear old frames
-        self.buffers.clear();
+
+        // the stale frame keeps a copy of the newest buffer, which the memory limit
+        // may not leave room for
+        if self.buffer_capacity != Some(1) {
+            self.last_frame.mark_resumed();
+        }
+        while self.buffers.len() > 1 {
+            self.buffers.pop_front();
+        }
         self.request_new_frame();
     }
     fn request_new_frame(&mut self) {
+        if self.last_frame.frozen || self.memory_error().is_some() {
+            return;
+        }
         let mut wait_for_damage = false;
         if let Some(handle) = self.handle.take() {
             if handle.is_finished() {
                 wait_for_damage = true;
-                self.wl = Some(handle.join().unwrap()); // safe to unwrap because we checked is_finished
+                let mut wl = handle.join().unwrap(); // safe to unwrap because we checked is_finished
+                if let Some(geometry) = wl.take_geometry_change(self.output_id) {
+                    self.geometry = Some(geometry);
+                }
+                if let Some(output) = wl.outputs.get(self.output_id) {
+                    self.refresh = output.refresh;
+                }
+                self.wl = Some(wl);
             } else {
                 self.handle = Some(handle);
                 return;
             }
         }
 
+        if self.connection_error().is_some() {
+            return;
+        }
         let Some(wl) = self.wl.take() else {
             return;
         };
 
+        if let Some(limit) = self.memory_limit {
+            // leave room for the next copy, which is likely the size of the last one
+            let next = self.buffers.back().map_or(0, FrameBacking::size);
+            while !self.buffers.is_empty()
+                && self.buffers.iter().map(FrameBacking::size).sum::<usize>() + next > limit
+            {
+                self.buffers.pop_front();
+            }
+        }
+
+        if self.targets_mismatched.swap(false, Ordering::Relaxed) {
+            log::warn!("Target buffers do not match the output, using shared memory");
+            self.target_buffers.drain(..).for_each(|b| b.destroy());
+            self.targets.clear();
+        }
+
+        if !self.target_buffers.is_empty() {
+            let idx = self.next_target % self.target_buffers.len();
+            self.next_target = idx + 1;
+            self.handle = Some(std::thread::spawn({
+                let sender = self
+                    .sender
+                    .clone()
+                    .expect("must call init once before request_new_frame");
+                let output_id = self.output_id;
+                let callback = self.frame_callback.clone();
+                let target = self.targets[idx].clone();
+                let wl_buffer = self.target_buffers[idx].clone();
+                let source = (self.region, self.overlay_cursor);
+                let mismatched = self.targets_mismatched.clone();
+                move || {
+                    request_target_frame(
+                        wl,
+                        output_id,
+                        source,
+                        (target, wl_buffer, mismatched),
+                        sender,
+                        callback,
+                        wait_for_damage,
+                    )
+                }
+            }));
+            return;
+        }
+
         self.handle = Some(std::thread::spawn({
             let sender = self
                 .sender
                 .clone()
                 .expect("must call init once before request_new_frame");
             let output_id = self.output_id;
-            move || request_screencopy_frame(wl, output_id, sender, wait_for_damage)
+            let callback = self.frame_callback.clone();
+            let compact = self.compact;
+            let source = (self.region, self.overlay_cursor);
+            let memory = (self.memory_limit, self.memory_error.clone());
+            move || {
+                request_screencopy_frame(
+                    wl,
+                    output_id,
+                    source,
+                    sender,
+                    callback,
+                    compact,
+                    wait_for_damage,
+                    memory,
+                )
+            }
         }));
     }
+    fn set_embedded_cursor(&mut self, embedded: bool) -> bool {
+        self.overlay_cursor = embedded;
+        true
+    }
+    fn last_activity(&self) -> Option<u64> {
+        self.activity.get()
+    }
+    fn next_frame_eta(&self) -> Option<u64> {
+        self.last_frame.eta(refresh_interval(self.refresh)?)
+    }
+    fn freeze(&mut self) {
+        self.last_frame.freeze();
+    }
+    fn unfreeze(&mut self) {
+        self.last_frame.frozen = false;
+        self.request_new_frame();
+    }
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        self.frame_callback = Some(Arc::new(Mutex::new(callback)));
+        Ok(())
+    }
 }
 
-/// Request a new DMA-Buf frame using the wlr-screencopy protocol.
+/// Captures several outputs over a single connection.
+/// All outputs are requested together and dispatched at once,
+/// so that every frame in a batch is sampled at the same point in time.
+pub struct WlrScreencopyGroup {
+    output_ids: Vec<u32>,
+    wl: Option<Box<WlxClient>>,
+    handle: Option<JoinHandle<Box<WlxClient>>>,
+    sender: Option<mpsc::Sender<Vec<(u32, WlxFrame, BufData)>>>,
+    receiver: Option<mpsc::Receiver<Vec<(u32, WlxFrame, BufData)>>>,
+    buffers: VecDeque<Vec<BufData>>,
+}
+
+impl WlrScreencopyGroup {
+    pub fn new(wl: WlxClient, output_ids: Vec<u32>) -> Self {
+        Self {
+            output_ids,
+            wl: Some(Box::new(wl)),
+            handle: None,
+            sender: None,
+            receiver: None,
+            buffers: VecDeque::with_capacity(2),
+        }
+    }
+
+    pub fn init(&mut self) {
+        debug_assert!(self.wl.is_some());
+
+        let (tx, rx) = mpsc::channel();
+        self.sender = Some(tx);
+        self.receiver = Some(rx);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.receiver.is_some()
+    }
+
+    /// The error that killed the Wayland connection, if any. Capturing stops once set.
+    pub fn connection_error(&self) -> Option<&ConnectionError> {
+        self.wl.as_ref().and_then(|wl| wl.error())
+    }
+
+    /// Returns the latest batch of frames, as pairs of output id and frame.
+    pub fn receive(&mut self) -> Option<Vec<(u32, WlxFrame)>> {
+        if let Some(rx) = self.receiver.as_ref() {
+            if let Some(batch) = rx.try_iter().last() {
+                if self.buffers.len() > 1 {
+                    self.buffers.pop_front();
+                }
+                let mut datas = Vec::with_capacity(batch.len());
+                let frames = batch
+                    .into_iter()
+                    .map(|(output_id, frame, data)| {
+                        datas.push(data);
+                        (output_id, frame)
+                    })
+                    .collect();
+                self.buffers.push_back(datas);
+                return Some(frames);
+            }
+        }
+        None
+    }
+
+    pub fn request_new_frames(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            if handle.is_finished() {
+                self.wl = Some(handle.join().unwrap()); // safe to unwrap because we checked is_finished
+            } else {
+                self.handle = Some(handle);
+                return;
+            }
+        }
+
+        if self.connection_error().is_some() {
+            return;
+        }
+        let Some(mut wl) = self.wl.take() else {
+            return;
+        };
+
+        self.handle = Some(std::thread::spawn({
+            let sender = self
+                .sender
+                .clone()
+                .expect("must call init once before request_new_frames");
+            let output_ids = self.output_ids.clone();
+            move || {
+                // damage is not waited on, as that would desynchronize the outputs
+                let batch =
+                    request_screencopy_frames(&mut wl, &output_ids, (None, true), false, None)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|(output_id, frame, data)| (output_id, WlxFrame::MemFd(frame), data))
+                        .collect();
+                let _ = sender.send(batch);
+                wl
+            }
+        }));
+    }
+}
+
+/// Request a new frame using the wlr-screencopy protocol.
+/// A frame larger than the memory limit is not allocated; the error is stored instead.
+#[allow(clippy::too_many_arguments)]
 fn request_screencopy_frame(
     client: Box<WlxClient>,
     output_id: u32,
-    sender: Sender<(WlxFrame, BufData)>,
+    source: CopySource,
+    sender: Sender<(WlxFrame, FrameBacking)>,
+    callback: Option<Arc<Mutex<FrameCallback>>>,
+    compact: bool,
     wait_for_damage: bool,
+    (memory_limit, memory_error): (Option<usize>, Arc<Mutex<Option<MemoryLimitExceeded>>>),
 ) -> Box<WlxClient> {
+    let mut client = client;
+    let frames = match request_screencopy_frames(
+        &mut client,
+        &[output_id],
+        source,
+        wait_for_damage,
+        memory_limit,
+    ) {
+        Ok(frames) => frames,
+        Err(e) => {
+            log::error!(
+                "a {} byte frame exceeds the memory limit of {} bytes",
+                e.required,
+                e.limit
+            );
+            if let Ok(mut error) = memory_error.lock() {
+                *error = Some(e);
+            }
+            return client;
+        }
+    };
+    for (_, frame, data) in frames {
+        let (frame, backing) = if compact {
+            let Some(pixels) = compact_rows(&frame) else {
+                log::warn!("Failed to map screencopy buffer for compacting");
+                continue;
+            };
+            let memptr = MemPtrFrame {
+                format: frame.format,
+                meta: frame.meta,
+                ptr: pixels.as_ptr() as _,
+                size: pixels.len(),
+                mouse: None,
+            };
+            (WlxFrame::MemPtr(memptr), FrameBacking::Compact(pixels))
+        } else {
+            (WlxFrame::MemFd(frame), FrameBacking::Shm(data))
+        };
+
+        if let Some(callback) = callback.as_ref() {
+            // the buffer is released once the callback returns
+            if let Ok(mut callback) = callback.lock() {
+                callback(frame);
+            }
+            continue;
+        }
+        let _ = sender.send((frame, backing));
+    }
+    client
+}
+
+/// Request a new frame using the wlr-screencopy protocol, copied into a consumer-provided dmabuf.
+/// Sets `mismatched` instead if the compositor wants a buffer unlike `target`.
+fn request_target_frame(
+    client: Box<WlxClient>,
+    output_id: u32,
+    source: CopySource,
+    (target, wl_buffer, mismatched): (DmabufFrame, WlBuffer, Arc<AtomicBool>),
+    sender: Sender<(WlxFrame, FrameBacking)>,
+    callback: Option<Arc<Mutex<FrameCallback>>>,
+    wait_for_damage: bool,
+) -> Box<WlxClient> {
+    let mut client = client;
     let Some(screencopy_manager) = client.maybe_wlr_screencopy_mgr.as_ref() else {
         return client;
     };
-
     let Some(output) = client.outputs.get(output_id) else {
         return client;
     };
 
-    let transform = wl_transform_to_frame_transform(output.transform);
-
-    let (tx, rx) = mpsc::sync_channel::<ScreenCopyEvent>(16);
-
-    let proxy =
-        screencopy_manager.capture_output(1, &output.wl_output, &client.queue_handle, tx.clone());
-
+    let (tx, rx) = mpsc::sync_channel::<TargetCopyEvent>(16);
     let name = output.name.clone();
+    let proxy = capture_output(
+        screencopy_manager,
+        &output.wl_output,
+        source,
+        &client.queue_handle,
+        tx,
+    );
 
-    let mut client = client;
-    client.dispatch();
+    let mut frame = target;
+    frame.format.transform = wl_transform_to_frame_transform(output.transform);
+    frame.buffer_key = frame.planes[0].fd.and_then(BufferKey::from_fd);
 
-    let mut frame_buffer = None;
+    let mut matched = false;
+    let describes_dmabuf = proxy.version() >= 3;
+    loop {
+        client.dispatch();
+        if client.error().is_some() {
+            return client;
+        }
 
-    'receiver: loop {
         for event in rx.try_iter() {
             match event {
-                ScreenCopyEvent::Buffer {
-                    data,
+                TargetCopyEvent::Buffer { width, height } if !describes_dmabuf => {
+                    if width != frame.format.width || height != frame.format.height {
+                        log::debug!("{}: target buffers do not match the output", name);
+                        mismatched.store(true, Ordering::Relaxed);
+                        proxy.destroy();
+                        return client;
+                    }
+                    log::trace!("{}: Copying into target buffer", name);
+                    if wait_for_damage {
+                        proxy.copy_with_damage(&wl_buffer);
+                    } else {
+                        proxy.copy(&wl_buffer);
+                    }
+                }
+                TargetCopyEvent::Buffer { .. } => {}
+                TargetCopyEvent::LinuxDmabuf {
                     fourcc,
                     width,
                     height,
-                    stride,
                 } => {
-                    let frame = MemFdFrame {
-                        format: FrameFormat {
-                            width,
-                            height,
-                            fourcc,
-                            transform,
-                            ..Default::default()
-                        },
-                        plane: FramePlane {
-                            fd: Some(data.fd),
-                            offset: 0,
-                            stride: stride as _,
-                        },
-                    };
-                    log::trace!("{}: Received screencopy buffer, copying", name.as_ref());
+                    matched |= fourcc == frame.format.fourcc
+                        && width == frame.format.width
+                        && height == frame.format.height;
+                }
+                TargetCopyEvent::BufferDone => {
+                    if !matched {
+                        log::debug!("{}: target buffers do not match the output", name);
+                        mismatched.store(true, Ordering::Relaxed);
+                        proxy.destroy();
+                        return client;
+                    }
+                    log::trace!("{}: Copying into target buffer", name);
                     if wait_for_damage {
-                        proxy.copy_with_damage(&data.wl_buffer);
+                        proxy.copy_with_damage(&wl_buffer);
                     } else {
-                        proxy.copy(&data.wl_buffer);
+                        proxy.copy(&wl_buffer);
                     }
-                    frame_buffer = Some((frame, data));
-                    client.dispatch();
                 }
-                ScreenCopyEvent::Ready => {
-                    if let Some((frame, data)) = frame_buffer {
-                        let _ = sender.send((WlxFrame::MemFd(frame), data));
-                        log::trace!("{}: Frame ready", name.as_ref());
+                TargetCopyEvent::Flags { y_invert } => {
+                    frame.format.origin = if y_invert {
+                        RowOrder::BottomUp
+                    } else {
+                        RowOrder::TopDown
+                    };
+                }
+                TargetCopyEvent::Ready { timestamp } => {
+                    frame.meta.timestamp = timestamp;
+                    frame.meta.source = source_kind(source);
+                    log::trace!("{}: Frame ready", name);
+                    let frame = WlxFrame::Dmabuf(frame);
+                    if let Some(callback) = callback.as_ref() {
+                        if let Ok(mut callback) = callback.lock() {
+                            callback(frame);
+                        }
+                    } else {
+                        let _ = sender.send((frame, FrameBacking::Target));
                     }
-                    break 'receiver;
+                    return client;
                 }
-                ScreenCopyEvent::Failed => {
-                    log::trace!("{}: Frame failed", name.as_ref());
-                    break 'receiver;
+                TargetCopyEvent::Failed => {
+                    log::trace!("{}: Frame failed", name);
+                    return client;
                 }
-            };
+            }
         }
     }
+}
 
-    client
+/// Copy the frame's rows into a buffer with a stride of exactly `width * 4`.
+fn compact_rows(frame: &MemFdFrame) -> Option<Vec<u8>> {
+    let stride = frame.stride();
+    let row_len = frame.format.width as usize * 4;
+    if stride < row_len {
+        return None;
+    }
+
+    let height = frame.format.height as usize;
+    frame
+        .with_mapped(|src| {
+            let mut pixels = vec![0; row_len * height];
+            copy_rows(src, stride, row_len, height, &mut pixels).then_some(pixels)
+        })
+        .flatten()
+}
+
+struct PendingCopy {
+    output_id: u32,
+    name: Arc<str>,
+    transform: Transform,
+    proxy: ZwlrScreencopyFrameV1,
+    rx: mpsc::Receiver<ScreenCopyEvent>,
+    frame_buffer: Option<(MemFdFrame, BufData)>,
+    done: bool,
+}
+
+/// Request a new frame from each of the given outputs using the wlr-screencopy protocol.
+/// All captures are issued before dispatching, so that the outputs are sampled together.
+/// The region, if any, applies to each output.
+/// Fails without allocating if a buffer would be larger than `memory_limit`.
+pub(crate) fn request_screencopy_frames(
+    client: &mut WlxClient,
+    output_ids: &[u32],
+    source: CopySource,
+    wait_for_damage: bool,
+    memory_limit: Option<usize>,
+) -> Result<Vec<(u32, MemFdFrame, BufData)>, MemoryLimitExceeded> {
+    let Some(screencopy_manager) = client.maybe_wlr_screencopy_mgr.as_ref() else {
+        return Ok(Vec::new());
+    };
+
+    let mut pending: Vec<PendingCopy> = output_ids
+        .iter()
+        .filter_map(|output_id| {
+            let output = client.outputs.get(*output_id)?;
+            let (tx, rx) = mpsc::sync_channel::<ScreenCopyEvent>(16);
+            Some(PendingCopy {
+                output_id: *output_id,
+                name: output.name.clone(),
+                transform: wl_transform_to_frame_transform(output.transform),
+                proxy: capture_output(
+                    screencopy_manager,
+                    &output.wl_output,
+                    source,
+                    &client.queue_handle,
+                    tx,
+                ),
+                rx,
+                frame_buffer: None,
+                done: false,
+            })
+        })
+        .collect();
+
+    let mut frames = Vec::with_capacity(pending.len());
+    let mut exceeded = None;
+
+    while pending.iter().any(|p| !p.done) {
+        client.dispatch();
+        if client.error().is_some() {
+            break;
+        }
+
+        for p in pending.iter_mut().filter(|p| !p.done) {
+            for event in p.rx.try_iter() {
+                match event {
+                    ScreenCopyEvent::Buffer {
+                        shm_format,
+                        fourcc,
+                        width,
+                        height,
+                        stride,
+                    } => {
+                        let size = stride as usize * height as usize;
+                        if let Some(limit) = memory_limit.filter(|limit| size > *limit) {
+                            exceeded = Some(MemoryLimitExceeded {
+                                required: size,
+                                limit,
+                            });
+                            p.proxy.destroy();
+                            p.done = true;
+                            break;
+                        }
+                        let Some(data) = create_shm_buffer(
+                            client,
+                            shm_format,
+                            (width, height),
+                            stride,
+                            &client.queue_handle,
+                        ) else {
+                            log::warn!("Failed to create shared memory for screencopy");
+                            p.proxy.destroy();
+                            p.done = true;
+                            break;
+                        };
+                        let frame = MemFdFrame {
+                            format: FrameFormat {
+                                width,
+                                height,
+                                fourcc,
+                                transform: p.transform,
+                                ..Default::default()
+                            },
+                            plane: FramePlane {
+                                fd: Some(data.fd),
+                                offset: 0,
+                                stride: stride as _,
+                                size: stride * height,
+                            },
+                            ..Default::default()
+                        };
+                        log::trace!("{}: Received screencopy buffer, copying", p.name.as_ref());
+                        if wait_for_damage {
+                            p.proxy.copy_with_damage(&data.wl_buffer);
+                        } else {
+                            p.proxy.copy(&data.wl_buffer);
+                        }
+                        p.frame_buffer = Some((frame, data));
+                    }
+                    ScreenCopyEvent::Flags { y_invert } => {
+                        if let Some((frame, _)) = p.frame_buffer.as_mut() {
+                            frame.format.origin = if y_invert {
+                                RowOrder::BottomUp
+                            } else {
+                                RowOrder::TopDown
+                            };
+                        }
+                    }
+                    ScreenCopyEvent::Ready { timestamp } => {
+                        if let Some((mut frame, data)) = p.frame_buffer.take() {
+                            frame.meta.timestamp = timestamp;
+                            frame.meta.source = source_kind(source);
+                            frames.push((p.output_id, frame, data));
+                            log::trace!("{}: Frame ready", p.name.as_ref());
+                        }
+                        p.done = true;
+                    }
+                    ScreenCopyEvent::Failed => {
+                        log::trace!("{}: Frame failed", p.name.as_ref());
+                        p.done = true;
+                    }
+                };
+            }
+        }
+    }
+
+    match exceeded {
+        Some(e) => Err(e),
+        None => Ok(frames),
+    }
+}
+
+/// Start copying the whole output, or only the given region of it.
+fn capture_output<U>(
+    manager: &ZwlrScreencopyManagerV1,
+    output: &WlOutput,
+    (region, overlay_cursor): CopySource,
+    qh: &QueueHandle<WlxClient>,
+    udata: U,
+) -> ZwlrScreencopyFrameV1
+where
+    U: Send + Sync + 'static,
+    WlxClient: Dispatch<ZwlrScreencopyFrameV1, U>,
+{
+    let overlay_cursor = overlay_cursor as i32;
+    match region {
+        Some((x, y, width, height)) => {
+            manager.capture_output_region(overlay_cursor, output, x, y, width, height, qh, udata)
+        }
+        None => manager.capture_output(overlay_cursor, output, qh, udata),
+    }
 }
 
 static FD_COUNTER: AtomicUsize = AtomicUsize::new(0);
@@ -226,8 +977,11 @@ impl Dispatch<ZwlrScreencopyFrameV1, SyncSender<ScreenCopyEvent>> for WlxClient
         event: <ZwlrScreencopyFrameV1 as Proxy>::Event,
         data: &SyncSender<ScreenCopyEvent>,
         _conn: &Connection,
-        qhandle: &QueueHandle<Self>,
+        _qhandle: &QueueHandle<Self>,
     ) {
+        if let Some(recorder) = state.recorder.as_mut() {
+            recorder.screencopy(proxy.id().protocol_id(), &event);
+        }
         match event {
             zwlr_screencopy_frame_v1::Event::Failed => {
                 let _ = data.send(ScreenCopyEvent::Failed);
@@ -253,46 +1007,33 @@ impl Dispatch<ZwlrScreencopyFrameV1, SyncSender<ScreenCopyEvent>> for WlxClient
                     return;
                 };
 
-                let fd_num = FD_COUNTER.fetch_add(1, Ordering::Relaxed);
-                let name = CString::new(format!("wlx-{}", fd_num)).unwrap(); // safe
-                let size = stride * height;
-                let fd = unsafe {
-                    let fd = libc::shm_open(name.as_ptr(), O_CREAT | O_RDWR, S_IRUSR | S_IWUSR);
-                    libc::shm_unlink(name.as_ptr());
-                    libc::ftruncate(fd, size as _);
-                    fd
-                };
-
-                let borrowed_fd = unsafe { BorrowedFd::borrow_raw(fd) };
-
-                let wl_pool = state
-                    .wl_shm
-                    .create_pool(borrowed_fd, size as _, qhandle, ());
-
-                let wl_buffer = wl_pool.create_buffer(
-                    0,
-                    width as _,
-                    height as _,
-                    stride as _,
-                    shm_format,
-                    qhandle,
-                    (),
-                );
-
+                // allocated by the requester, which checks the memory limit first
                 let _ = data.send(ScreenCopyEvent::Buffer {
-                    data: BufData {
-                        wl_buffer,
-                        wl_pool,
-                        fd,
-                    },
+                    shm_format,
                     fourcc,
                     width,
                     height,
                     stride,
                 });
             }
-            zwlr_screencopy_frame_v1::Event::Ready { .. } => {
-                let _ = data.send(ScreenCopyEvent::Ready);
+            zwlr_screencopy_frame_v1::Event::Flags { flags } => {
+                let y_invert = flags
+                    .into_result()
+                    .is_ok_and(|f| f.contains(zwlr_screencopy_frame_v1::Flags::YInvert));
+                let _ = data.send(ScreenCopyEvent::Flags { y_invert });
+            }
+            zwlr_screencopy_frame_v1::Event::Ready {
+                tv_sec_hi,
+                tv_sec_lo,
+                tv_nsec,
+            } => {
+                fault::delay_ready();
+                if fault::take(|f| *f == Fault::FrameFailed).is_some() {
+                    let _ = data.send(ScreenCopyEvent::Failed);
+                } else {
+                    let timestamp = ready_timestamp(tv_sec_hi, tv_sec_lo, tv_nsec);
+                    let _ = data.send(ScreenCopyEvent::Ready { timestamp });
+                }
                 proxy.destroy();
             }
             _ => {}
@@ -300,16 +1041,143 @@ impl Dispatch<ZwlrScreencopyFrameV1, SyncSender<ScreenCopyEvent>> for WlxClient
     }
 }
 
-fn fourcc_from_wlshm(shm_format: Format) -> Option<FourCC> {
-    match shm_format {
-        Format::Argb8888 => Some(FourCC::from(DRM_FORMAT_ARGB8888)),
-        Format::Xrgb8888 => Some(FourCC::from(DRM_FORMAT_XRGB8888)),
-        Format::Abgr8888 => Some(FourCC::from(DRM_FORMAT_ARGB8888)),
-        Format::Xbgr8888 => Some(FourCC::from(DRM_FORMAT_XRGB8888)),
-        _ => None,
+impl Dispatch<ZwlrScreencopyFrameV1, SyncSender<TargetCopyEvent>> for WlxClient {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrScreencopyFrameV1,
+        event: <ZwlrScreencopyFrameV1 as Proxy>::Event,
+        data: &SyncSender<TargetCopyEvent>,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let Some(recorder) = state.recorder.as_mut() {
+            recorder.screencopy(proxy.id().protocol_id(), &event);
+        }
+        match event {
+            zwlr_screencopy_frame_v1::Event::Buffer { width, height, .. } => {
+                let _ = data.send(TargetCopyEvent::Buffer { width, height });
+            }
+            zwlr_screencopy_frame_v1::Event::LinuxDmabuf {
+                format,
+                width,
+                height,
+            } => {
+                let _ = data.send(TargetCopyEvent::LinuxDmabuf {
+                    fourcc: FourCC::from(format),
+                    width,
+                    height,
+                });
+            }
+            zwlr_screencopy_frame_v1::Event::BufferDone => {
+                let _ = data.send(TargetCopyEvent::BufferDone);
+            }
+            zwlr_screencopy_frame_v1::Event::Flags { flags } => {
+                let y_invert = flags
+                    .into_result()
+                    .is_ok_and(|f| f.contains(zwlr_screencopy_frame_v1::Flags::YInvert));
+                let _ = data.send(TargetCopyEvent::Flags { y_invert });
+            }
+            zwlr_screencopy_frame_v1::Event::Ready {
+                tv_sec_hi,
+                tv_sec_lo,
+                tv_nsec,
+            } => {
+                fault::delay_ready();
+                if fault::take(|f| *f == Fault::FrameFailed).is_some() {
+                    let _ = data.send(TargetCopyEvent::Failed);
+                } else {
+                    let timestamp = ready_timestamp(tv_sec_hi, tv_sec_lo, tv_nsec);
+                    let _ = data.send(TargetCopyEvent::Ready { timestamp });
+                }
+                proxy.destroy();
+            }
+            zwlr_screencopy_frame_v1::Event::Failed => {
+                let _ = data.send(TargetCopyEvent::Failed);
+                proxy.destroy();
+            }
+            _ => {}
+        }
     }
 }
 
+/// Allocate shared memory for one frame and wrap it in a wl_buffer.
+pub(crate) fn create_shm_buffer(
+    state: &WlxClient,
+    shm_format: Format,
+    (width, height): (u32, u32),
+    stride: u32,
+    qhandle: &QueueHandle<WlxClient>,
+) -> Option<BufData> {
+    let fd_num = FD_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let name = CString::new(format!("wlx-{}", fd_num)).unwrap(); // safe
+    let size = stride * height;
+    let fd = match fault::take(|f| *f == Fault::FdExhausted) {
+        Some(_) => -1,
+        None => unsafe { libc::shm_open(name.as_ptr(), O_CREAT | O_RDWR, S_IRUSR | S_IWUSR) },
+    };
+    if fd < 0 {
+        return None;
+    }
+    unsafe {
+        libc::shm_unlink(name.as_ptr());
+        libc::ftruncate(fd, size as _);
+    }
+    fd_debug::track(fd, "screencopy shm");
+
+    let borrowed_fd = unsafe { BorrowedFd::borrow_raw(fd) };
+
+    let wl_pool = state
+        .wl_shm
+        .create_pool(borrowed_fd, size as _, qhandle, ());
+
+    let wl_buffer = wl_pool.create_buffer(
+        0,
+        width as _,
+        height as _,
+        stride as _,
+        shm_format,
+        qhandle,
+        (),
+    );
+
+    Some(BufData {
+        wl_buffer,
+        wl_pool,
+        fd,
+        size: size as _,
+    })
+}
+
+/// Timestamp of a `ready` event, which uses the presentation clock (CLOCK_MONOTONIC).
+/// Falls back to the current time if the compositor sends zero.
+pub(crate) fn ready_timestamp(tv_sec_hi: u32, tv_sec_lo: u32, tv_nsec: u32) -> u64 {
+    let secs = ((tv_sec_hi as u64) << 32) | tv_sec_lo as u64;
+    match secs * 1_000_000_000 + tv_nsec as u64 {
+        0 => monotonic_ns(),
+        ns => ns,
+    }
+}
+
+/// The DRM fourcc of a wl_shm format, for the 32-bit formats this crate handles.
+pub(crate) fn fourcc_from_wlshm(shm_format: Format) -> Option<FourCC> {
+    let fourcc = match shm_format {
+        Format::Argb8888 => DRM_FORMAT_ARGB8888,
+        Format::Xrgb8888 => DRM_FORMAT_XRGB8888,
+        Format::Abgr8888 => DRM_FORMAT_ABGR8888,
+        Format::Xbgr8888 => DRM_FORMAT_XBGR8888,
+        Format::Rgba8888 => DRM_FORMAT_RGBA8888,
+        Format::Rgbx8888 => DRM_FORMAT_RGBX8888,
+        Format::Bgra8888 => DRM_FORMAT_BGRA8888,
+        Format::Bgrx8888 => DRM_FORMAT_BGRX8888,
+        Format::Argb2101010 => DRM_FORMAT_ARGB2101010,
+        Format::Xrgb2101010 => DRM_FORMAT_XRGB2101010,
+        Format::Abgr2101010 => DRM_FORMAT_ABGR2101010,
+        Format::Xbgr2101010 => DRM_FORMAT_XBGR2101010,
+        _ => return None,
+    };
+    Some(FourCC::from(fourcc))
+}
+
 // Plumbing below
 
 impl Dispatch<WlShmPool, ()> for WlxClient {
@@ -0,0 +1,549 @@
+//! Capture of single X11 windows with the Composite extension. The window is redirected
+//! offscreen, so that it is captured whole even when covered by other windows, and its
+//! pixmap is read back through MIT-SHM like `xshm` reads monitors.
+//!
+//! Windows are listed with `XCompositeCapture::get_windows`. Minimized windows have no
+//! contents to capture; no frames arrive until they are shown again.
+
+use std::{
+    env,
+    error::Error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+};
+
+use xcb::{composite, shm, x, Xid, XidNew};
+
+use crate::{
+    fault,
+    frame::{
+        monotonic_ns, Activity, DrmFormat, FrameFormat, FrameMeta, LastFrame, MemPtrFrame,
+        MouseMeta, RowOrder, SourceKind, Transform, WlxFrame, DRM_FORMAT_ARGB8888,
+        DRM_FORMAT_XRGB8888, HEARTBEAT_INTERVAL,
+    },
+    FrameCallback, WlxCapture,
+};
+
+/// A top-level window, as listed by the window manager.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct X11Window {
+    /// The window's XID.
+    pub id: u32,
+    pub title: String,
+    /// The class part of `WM_CLASS`, which serves as the app id on X11.
+    pub app_id: String,
+    /// Position on the root window.
+    pub pos: (i32, i32),
+    pub size: (i32, i32),
+}
+
+/// Delivers `WlxFrame::MemPtr` frames of one window, tightly packed like those of
+/// `XshmCapture`. Frames change size with the window, and stay valid until the next frame
+/// is received.
+pub struct XCompositeCapture {
+    pub window: X11Window,
+    sender: Option<mpsc::SyncSender<()>>,
+    receiver: Option<mpsc::Receiver<SegmentFrame>>,
+    /// The segment of the last received frame, which must not be written to or unmapped.
+    held: Option<Arc<ShmSegment>>,
+    last_frame: LastFrame,
+    frame_callback: Option<FrameCallback>,
+    stopped: Arc<AtomicBool>,
+    activity: Activity,
+}
+
+impl XCompositeCapture {
+    pub fn new(window: X11Window) -> Self {
+        Self {
+            window,
+            sender: None,
+            receiver: None,
+            held: None,
+            last_frame: LastFrame::default(),
+            frame_callback: None,
+            stopped: Arc::new(AtomicBool::new(false)),
+            activity: Activity::default(),
+        }
+    }
+
+    /// The windows managed by the window manager, in its stacking order if it keeps one.
+    /// Without an EWMH window manager, the mapped children of the root window.
+    pub fn get_windows() -> Result<Vec<X11Window>, Box<dyn Error>> {
+        let (conn, root) = connect()?;
+        let atoms = Atoms::intern(&conn)?;
+
+        let cookie = conn.send_request(&x::GetProperty {
+            delete: false,
+            window: root,
+            property: atoms.net_client_list,
+            r#type: x::ATOM_WINDOW,
+            long_offset: 0,
+            long_length: u32::MAX,
+        });
+        let clients = conn.wait_for_reply(cookie)?;
+        let mut windows: Vec<x::Window> = clients.value().to_vec();
+        if clients.r#type() != x::ATOM_WINDOW {
+            let cookie = conn.send_request(&x::QueryTree { window: root });
+            windows = conn.wait_for_reply(cookie)?.children().to_vec();
+            windows.retain(|&window| {
+                let cookie = conn.send_request(&x::GetWindowAttributes { window });
+                conn.wait_for_reply(cookie).is_ok_and(|attrs| {
+                    attrs.map_state() == x::MapState::Viewable && !attrs.override_redirect()
+                })
+            });
+        }
+
+        Ok(windows
+            .into_iter()
+            .filter_map(|window| {
+                describe_window(&conn, root, &atoms, window)
+                    .map_err(|e| log::debug!("X11: skipping window {:?}: {}", window, e))
+                    .ok()
+            })
+            .collect())
+    }
+
+    /// The window is gone. No more frames will arrive.
+    pub fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+}
+
+impl WlxCapture for XCompositeCapture {
+    fn init(&mut self, _: &[DrmFormat]) {
+        let (tx_frame, rx_frame) = mpsc::sync_channel(4);
+        let (tx_cmd, rx_cmd) = mpsc::sync_channel(2);
+        self.sender = Some(tx_cmd);
+        self.receiver = Some(rx_frame);
+
+        std::thread::spawn({
+            let id = self.window.id;
+            let name = self.window.title.clone();
+            let mut frame_callback = self.frame_callback.take();
+            let stopped = self.stopped.clone();
+            let activity = self.activity.clone();
+            move || {
+                let mut capturer = match WindowCapturer::new(id) {
+                    Ok(capturer) => capturer,
+                    Err(e) => {
+                        log::error!("{}: failed to redirect window: {}", name, e);
+                        stopped.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                };
+
+                loop {
+                    activity.touch();
+                    match rx_cmd.recv_timeout(HEARTBEAT_INTERVAL) {
+                        Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                        Ok(_) => {
+                            let (frame, segment) = match capturer.capture() {
+                                Ok(Some(captured)) => captured,
+                                Ok(None) => continue,
+                                Err(e) => {
+                                    log::info!("{}: window is gone: {}", name, e);
+                                    stopped.store(true, Ordering::Relaxed);
+                                    break;
+                                }
+                            };
+                            log::trace!("{}: captured frame", name);
+
+                            if let Some(callback) = frame_callback.as_mut() {
+                                // the segment is written again once the callback returns
+                                callback(frame);
+                                continue;
+                            }
+                            match fault::try_send(&tx_frame, (frame, segment)) {
+                                Ok(_) => (),
+                                Err(mpsc::TrySendError::Full(_)) => {
+                                    log::debug!("{}: channel full", name);
+                                }
+                                Err(mpsc::TrySendError::Disconnected(_)) => {
+                                    log::warn!("{}: capture thread channel closed (send)", name);
+                                    break;
+                                }
+                            }
+                        }
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
+                            log::debug!("{}: capture thread channel closed (recv)", name);
+                            break;
+                        }
+                    }
+                }
+                log::debug!("{}: capture thread stopped", name);
+            }
+        });
+    }
+    fn is_ready(&self) -> bool {
+        self.receiver.is_some()
+    }
+    fn supports_dmbuf(&self) -> bool {
+        false
+    }
+    fn source_kind(&self) -> SourceKind {
+        SourceKind::Window
+    }
+    fn receive(&mut self) -> Option<WlxFrame> {
+        if self.last_frame.frozen {
+            return self.last_frame.duplicate();
+        }
+        if let Some((frame, segment)) = self.receiver.as_ref().and_then(|rx| rx.try_iter().last()) {
+            self.held = Some(segment);
+            return self.last_frame.update(Some(frame));
+        }
+        self.last_frame.take_stale()
+    }
+    fn pause(&mut self) {}
+    fn resume(&mut self) {
+        self.receive(); // clear old frames
+        self.last_frame.mark_resumed();
+        self.request_new_frame();
+    }
+    fn request_new_frame(&mut self) {
+        if self.last_frame.frozen || self.stopped() {
+            return;
+        }
+        if let Some(sender) = &self.sender {
+            if let Err(mpsc::TrySendError::Disconnected(_)) = sender.try_send(()) {
+                log::debug!("{}: capture thread is gone", self.window.title);
+            }
+        }
+    }
+    fn last_activity(&self) -> Option<u64> {
+        self.activity.get()
+    }
+    fn freeze(&mut self) {
+        self.last_frame.freeze();
+    }
+    fn unfreeze(&mut self) {
+        self.last_frame.frozen = false;
+        self.request_new_frame();
+    }
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        self.frame_callback = Some(callback);
+        Ok(())
+    }
+}
+
+struct Atoms {
+    net_client_list: x::Atom,
+    net_wm_name: x::Atom,
+    utf8_string: x::Atom,
+}
+
+impl Atoms {
+    fn intern(conn: &xcb::Connection) -> xcb::Result<Self> {
+        let intern = |name: &[u8]| {
+            conn.send_request(&x::InternAtom {
+                only_if_exists: false,
+                name,
+            })
+        };
+        let cookies = [
+            intern(b"_NET_CLIENT_LIST"),
+            intern(b"_NET_WM_NAME"),
+            intern(b"UTF8_STRING"),
+        ];
+        let [net_client_list, net_wm_name, utf8_string] =
+            cookies.map(|cookie| conn.wait_for_reply(cookie).map(|r| r.atom()));
+        Ok(Self {
+            net_client_list: net_client_list?,
+            net_wm_name: net_wm_name?,
+            utf8_string: utf8_string?,
+        })
+    }
+}
+
+fn connect() -> Result<(xcb::Connection, x::Window), Box<dyn Error>> {
+    let display = env::var("DISPLAY")?;
+    let (conn, screen_num) = xcb::Connection::connect_with_extensions(
+        Some(&display),
+        &[xcb::Extension::Composite, xcb::Extension::Shm],
+        &[],
+    )?;
+    let root = conn
+        .get_setup()
+        .roots()
+        .nth(screen_num as usize)
+        .ok_or("X11: screen not found")?
+        .root();
+    Ok((conn, root))
+}
+
+fn string_property(
+    conn: &xcb::Connection,
+    window: x::Window,
+    property: x::Atom,
+    r#type: x::Atom,
+) -> xcb::Result<String> {
+    let cookie = conn.send_request(&x::GetProperty {
+        delete: false,
+        window,
+        property,
+        r#type,
+        long_offset: 0,
+        long_length: 1024,
+    });
+    let reply = conn.wait_for_reply(cookie)?;
+    if reply.format() != 8 {
+        return Ok(String::new());
+    }
+    Ok(String::from_utf8_lossy(reply.value::<u8>()).into_owned())
+}
+
+fn describe_window(
+    conn: &xcb::Connection,
+    root: x::Window,
+    atoms: &Atoms,
+    window: x::Window,
+) -> xcb::Result<X11Window> {
+    let mut title = string_property(conn, window, atoms.net_wm_name, atoms.utf8_string)?;
+    if title.is_empty() {
+        title = string_property(conn, window, x::ATOM_WM_NAME, x::ATOM_STRING)?;
+    }
+    // instance and class, each terminated by a nul byte
+    let class = string_property(conn, window, x::ATOM_WM_CLASS, x::ATOM_STRING)?;
+    let app_id = class.split('\0').nth(1).unwrap_or_default().to_string();
+
+    let cookie = conn.send_request(&x::GetGeometry {
+        drawable: x::Drawable::Window(window),
+    });
+    let geometry = conn.wait_for_reply(cookie)?;
+    let cookie = conn.send_request(&x::TranslateCoordinates {
+        src_window: window,
+        dst_window: root,
+        src_x: 0,
+        src_y: 0,
+    });
+    let pos = conn.wait_for_reply(cookie)?;
+
+    Ok(X11Window {
+        id: window.resource_id(),
+        title,
+        app_id,
+        pos: (pos.dst_x() as _, pos.dst_y() as _),
+        size: (geometry.width() as _, geometry.height() as _),
+    })
+}
+
+/// How many segments may exist at once: one for each frame in the channel, one for the
+/// frame held by the consumer and one to capture into.
+const MAX_SEGMENTS: usize = 6;
+
+/// A frame and the segment its pixels are in.
+type SegmentFrame = (WlxFrame, Arc<ShmSegment>);
+
+/// A SysV shared memory segment, attached to this process and the X server.
+/// Unmapped from this process when the last frame pointing into it is gone.
+struct ShmSegment {
+    seg: shm::Seg,
+    ptr: *mut u8,
+    size: usize,
+}
+
+// only written by the X server while no frame points into it
+unsafe impl Send for ShmSegment {}
+unsafe impl Sync for ShmSegment {}
+
+impl ShmSegment {
+    fn new(conn: &xcb::Connection, size: usize) -> Result<Self, Box<dyn Error>> {
+        let shmid = unsafe { libc::shmget(libc::IPC_PRIVATE, size, libc::IPC_CREAT | 0o600) };
+        if shmid < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let ptr = unsafe { libc::shmat(shmid, std::ptr::null(), 0) };
+        // removed once both sides have detached
+        unsafe { libc::shmctl(shmid, libc::IPC_RMID, std::ptr::null_mut()) };
+        if ptr as isize == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let segment = Self {
+            seg: conn.generate_id(),
+            ptr: ptr as *mut u8,
+            size,
+        };
+        conn.send_and_check_request(&shm::Attach {
+            shmseg: segment.seg,
+            shmid: shmid as u32,
+            read_only: false,
+        })?;
+        Ok(segment)
+    }
+
+    /// Detach the segment from the X server, which will not write to it anymore.
+    fn detach_server(&self, conn: &xcb::Connection) {
+        conn.send_request(&shm::Detach { shmseg: self.seg });
+    }
+}
+
+impl Drop for ShmSegment {
+    fn drop(&mut self) {
+        unsafe { libc::shmdt(self.ptr as *const _) };
+    }
+}
+
+/// Owns the connection and redirection for one window, on the capture thread.
+struct WindowCapturer {
+    conn: xcb::Connection,
+    window: x::Window,
+    /// Segments that frames may point into. A segment only referenced from here is free.
+    segments: Vec<Arc<ShmSegment>>,
+}
+
+impl WindowCapturer {
+    fn new(id: u32) -> Result<Self, Box<dyn Error>> {
+        let (conn, _) = connect()?;
+        let window = x::Window::new(id);
+        // automatic redirection coexists with a compositing window manager
+        conn.send_and_check_request(&composite::RedirectWindow {
+            window,
+            update: composite::Redirect::Automatic,
+        })?;
+        Ok(Self {
+            conn,
+            window,
+            segments: Vec::new(),
+        })
+    }
+
+    /// A segment of at least `size` bytes that no frame points into.
+    fn free_segment(&mut self, size: usize) -> Result<Option<Arc<ShmSegment>>, Box<dyn Error>> {
+        // segments from before a resize are dropped once they are free
+        let conn = &self.conn;
+        self.segments.retain(|s| {
+            let keep = s.size >= size || Arc::strong_count(s) > 1;
+            if !keep {
+                s.detach_server(conn);
+            }
+            keep
+        });
+        let free = self
+            .segments
+            .iter()
+            .find(|s| s.size >= size && Arc::strong_count(s) == 1);
+        if let Some(segment) = free {
+            return Ok(Some(segment.clone()));
+        }
+        if self.segments.len() >= MAX_SEGMENTS {
+            return Ok(None);
+        }
+        let segment = Arc::new(ShmSegment::new(&self.conn, size)?);
+        self.segments.push(segment.clone());
+        Ok(Some(segment))
+    }
+
+    /// None if the window has no contents right now, e.g. while it is minimized, or if all
+    /// segments are still in use. An error means that the window is gone.
+    /// The frame points into the returned segment.
+    fn capture(&mut self) -> Result<Option<SegmentFrame>, Box<dyn Error>> {
+        let cookie = self.conn.send_request(&x::GetGeometry {
+            drawable: x::Drawable::Window(self.window),
+        });
+        let geometry = self.conn.wait_for_reply(cookie)?;
+        let (width, height) = (geometry.width(), geometry.height());
+        let size = width as usize * height as usize * 4;
+        if size == 0 {
+            return Ok(None);
+        }
+
+        let Some(segment) = self.free_segment(size)? else {
+            log::debug!(
+                "X11: window {} has no free segment, skipping frame",
+                self.window.resource_id()
+            );
+            return Ok(None);
+        };
+
+        // the pixmap is replaced whenever the window is resized, so name it every time
+        let pixmap: x::Pixmap = self.conn.generate_id();
+        if let Err(e) = self
+            .conn
+            .send_and_check_request(&composite::NameWindowPixmap {
+                window: self.window,
+                pixmap,
+            })
+        {
+            // unmapped windows have no pixmap
+            log::trace!(
+                "X11: window {} has no pixmap: {}",
+                self.window.resource_id(),
+                e
+            );
+            return Ok(None);
+        }
+        let cookie = self.conn.send_request(&shm::GetImage {
+            drawable: x::Drawable::Pixmap(pixmap),
+            x: 0,
+            y: 0,
+            width,
+            height,
+            plane_mask: u32::MAX,
+            format: x::ImageFormat::ZPixmap as u8,
+            shmseg: segment.seg,
+            offset: 0,
+        });
+        let image = self.conn.wait_for_reply(cookie);
+        self.conn.send_request(&x::FreePixmap { pixmap });
+        let image = match image {
+            Ok(image) => image,
+            Err(e) => {
+                log::debug!("X11: ShmGetImage failed: {}", e);
+                return Ok(None);
+            }
+        };
+
+        let cookie = self.conn.send_request(&x::QueryPointer {
+            window: self.window,
+        });
+        let mouse = self
+            .conn
+            .wait_for_reply(cookie)
+            .ok()
+            .filter(|p| p.same_screen())
+            .map(|p| MouseMeta {
+                x: p.win_x() as f32 / width as f32,
+                y: p.win_y() as f32 / height as f32,
+            });
+
+        let frame = WlxFrame::MemPtr(MemPtrFrame {
+            format: FrameFormat {
+                width: width as _,
+                height: height as _,
+                // windows with an alpha channel use a 32 bit visual
+                fourcc: if image.depth() == 32 {
+                    DRM_FORMAT_ARGB8888.into()
+                } else {
+                    DRM_FORMAT_XRGB8888.into()
+                },
+                transform: Transform::Normal,
+                origin: RowOrder::TopDown,
+                ..Default::default()
+            },
+            meta: FrameMeta {
+                timestamp: monotonic_ns(),
+                source: SourceKind::Window,
+                ..Default::default()
+            },
+            ptr: segment.ptr as _,
+            size,
+            mouse,
+        });
+        Ok(Some((frame, segment)))
+    }
+}
+
+impl Drop for WindowCapturer {
+    fn drop(&mut self) {
+        self.conn.send_request(&composite::UnredirectWindow {
+            window: self.window,
+            update: composite::Redirect::Automatic,
+        });
+        // frames that are still held keep their segment mapped in this process
+        for segment in self.segments.drain(..) {
+            segment.detach_server(&self.conn);
+        }
+        let _ = self.conn.flush();
+    }
+}
@@ -1,28 +1,107 @@
 use std::{
+    collections::HashMap,
     env,
     error::Error,
+    os::fd::AsRawFd,
     sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
         mpsc::{self},
         Arc,
     },
+    time::Duration,
 };
 
 use rxscreen::monitor::Monitor;
+use xcb::{damage, dpms, randr, x, xfixes, Xid};
 
 use crate::{
-    frame::{DrmFormat, FrameFormat, MemPtrFrame, MouseMeta, WlxFrame, DRM_FORMAT_XRGB8888},
-    WlxCapture,
+    factory::CaptureDescriptor,
+    fault,
+    frame::{
+        monotonic_ns, refresh_interval, Activity, DrmFormat, FrameFormat, FrameMeta, LastFrame,
+        MemPtrFrame, MouseMeta, RowOrder, Transform, WlxFrame, DRM_FORMAT_XRGB8888,
+        HEARTBEAT_INTERVAL,
+    },
+    output::{is_rotated, OutputInfo},
+    BackendKind, FrameCallback, WlxCapture,
 };
 
+/// Fields after `monitor` mirror those of `WlxOutput`.
 pub struct XshmScreen {
+    /// Index of the monitor in the list returned by `XshmCapture::get_monitors`.
+    pub id: u32,
     pub name: Arc<str>,
     pub monitor: Monitor,
+    /// Resolution before rotation.
+    pub size: (i32, i32),
+    /// Refresh rate of the current mode in mHz, or 0 if unknown.
+    pub refresh: i32,
+    /// Physical width and height in millimeters, or 0 if unknown.
+    pub physical_size: (i32, i32),
+    /// Position on the root window.
+    pub logical_pos: (i32, i32),
+    /// Size on the root window. X11 does not scale outputs, so this only
+    /// differs from `size` by rotation.
+    pub logical_size: (i32, i32),
+    /// Rotation of the monitor. Captured frames are already upright.
+    pub transform: Transform,
+    pub primary: bool,
+}
+
+impl XshmScreen {
+    /// Pixels per inch along the monitor's width, from its size and physical size.
+    /// None if the X server does not know the physical size.
+    pub fn dpi(&self) -> Option<f32> {
+        if self.physical_size.0 <= 0 || self.size.0 <= 0 {
+            return None;
+        }
+        Some(self.size.0 as f32 * 25.4 / self.physical_size.0 as f32)
+    }
+}
+
+/// Properties of a monitor as reported by XRandR.
+struct RandrMonitor {
+    pos: (i32, i32),
+    size: (i32, i32),
+    physical_size: (i32, i32),
+    primary: bool,
+    transform: Transform,
+    refresh: i32,
+}
+
+impl From<&XshmScreen> for OutputInfo {
+    fn from(screen: &XshmScreen) -> Self {
+        OutputInfo {
+            id: screen.id,
+            name: screen.name.clone(),
+            logical_pos: screen.logical_pos,
+            logical_size: screen.logical_size,
+            size: screen.size,
+            transform: screen.transform,
+            scale: 1.0,
+            refresh: screen.refresh,
+        }
+    }
 }
 
+/// Delivers `WlxFrame::MemPtr` frames, which are always tightly packed (stride == width * 4).
 pub struct XshmCapture {
     pub screen: Arc<XshmScreen>,
     sender: Option<mpsc::SyncSender<()>>,
     receiver: Option<mpsc::Receiver<WlxFrame>>,
+    last_frame: LastFrame,
+    mouse_poll: Option<Duration>,
+    mouse_receiver: Option<mpsc::Receiver<MouseMeta>>,
+    mouse_stop: Arc<AtomicBool>,
+    frame_callback: Option<FrameCallback>,
+    activity: Activity,
+    /// Capture when XDamage reports a change instead of on request.
+    push: bool,
+    /// Paused or frozen; a pushing capture thread stops capturing while set.
+    held: Arc<AtomicBool>,
+    /// A pushed frame points into the shared image, which must not be captured into
+    /// until the consumer is done with the frame, i.e. receives again.
+    in_use: Arc<AtomicBool>,
 }
 
 impl XshmCapture {
@@ -31,25 +110,263 @@ impl XshmCapture {
             screen,
             sender: None,
             receiver: None,
+            last_frame: LastFrame::default(),
+            mouse_poll: None,
+            mouse_receiver: None,
+            mouse_stop: Arc::new(AtomicBool::new(false)),
+            frame_callback: None,
+            activity: Activity::default(),
+            push: false,
+            held: Arc::new(AtomicBool::new(false)),
+            in_use: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// The state needed to recreate this capture elsewhere,
+    /// e.g. in a process started with `handoff::spawn_capture_process`.
+    pub fn descriptor(&self) -> CaptureDescriptor {
+        let mut descriptor = CaptureDescriptor::new(BackendKind::Xshm, &self.screen.name);
+        if self.push {
+            descriptor.set_option("damage", true);
+        }
+        if let Some(interval) = self.mouse_poll {
+            descriptor.set_option("mouse_poll_ms", interval.as_millis());
+        }
+        descriptor
+    }
+
+    /// Poll the pointer at the given interval, independently of the capture rate.
+    /// Position changes between frames are delivered as `WlxFrame::Mouse`.
+    /// Must be called before `init`.
+    pub fn with_mouse_poll(mut self, interval: Duration) -> Self {
+        self.mouse_poll = Some(interval);
+        self
+    }
+
+    /// Capture whenever the monitor's contents change, as reported by XDamage, instead of
+    /// on `request_new_frame`, which then does nothing. A static screen costs no captures.
+    /// Cursor movement is not a change; see `with_mouse_poll`. Must be called before `init`.
+    pub fn with_damage(mut self) -> Self {
+        self.push = true;
+        self
+    }
+
+    /// Let a pushing capture thread capture into the shared image again.
+    fn release(&self) {
+        if self.in_use.swap(false, Ordering::AcqRel) {
+            self.wake();
+        }
+    }
+
+    fn wake(&self) {
+        if let Some(sender) = &self.sender {
+            if let Err(e) = sender.try_send(()) {
+                log::debug!("Failed to send frame request: {}", e);
+            }
         }
     }
 
     pub fn get_monitors() -> Result<Vec<Arc<XshmScreen>>, Box<dyn Error>> {
         let display = env::var("DISPLAY")?;
-        let Ok(d) = rxscreen::Display::new(display) else {
+        let Ok(d) = rxscreen::Display::new(&display) else {
             return Err("X11: Failed to open display".into());
         };
+        let mut randr_monitors = randr_monitors(&display).unwrap_or_else(|e| {
+            log::debug!("X11: could not query RandR monitors: {}", e);
+            HashMap::new()
+        });
         Ok(d.monitors()
             .into_iter()
             .enumerate()
-            .map(|x| {
+            .map(|(id, monitor)| {
+                let randr = randr_monitors
+                    .remove(monitor.name())
+                    .unwrap_or_else(|| RandrMonitor {
+                        pos: (monitor.x(), monitor.y()),
+                        size: (monitor.width() as _, monitor.height() as _),
+                        physical_size: (0, 0),
+                        primary: monitor.primary(),
+                        transform: Transform::Normal,
+                        refresh: 0,
+                    });
+                let size = if is_rotated(randr.transform) {
+                    (randr.size.1, randr.size.0)
+                } else {
+                    randr.size
+                };
                 Arc::new(XshmScreen {
-                    name: x.1.name().replace("DisplayPort", "DP").into(),
-                    monitor: x.1,
+                    id: id as _,
+                    name: monitor.name().replace("DisplayPort", "DP").into(),
+                    monitor,
+                    size,
+                    refresh: randr.refresh,
+                    physical_size: randr.physical_size,
+                    logical_pos: randr.pos,
+                    logical_size: randr.size,
+                    transform: randr.transform,
+                    primary: randr.primary,
                 })
             })
             .collect())
     }
+
+    /// The monitors in the backend-agnostic form shared with Wayland.
+    pub fn get_output_infos() -> Result<Vec<OutputInfo>, Box<dyn Error>> {
+        Ok(Self::get_monitors()?
+            .iter()
+            .map(|screen| OutputInfo::from(screen.as_ref()))
+            .collect())
+    }
+}
+
+/// Reports the X server turning the monitors off through DPMS.
+/// X11 powers all monitors down together.
+pub struct DpmsWatcher {
+    conn: xcb::Connection,
+    powered: Option<bool>,
+}
+
+impl DpmsWatcher {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let display = env::var("DISPLAY")?;
+        let (conn, _) =
+            xcb::Connection::connect_with_extensions(Some(&display), &[xcb::Extension::Dpms], &[])?;
+        Ok(Self {
+            conn,
+            powered: None,
+        })
+    }
+
+    /// Whether the monitors are on. Monitors in standby or suspend count as off.
+    pub fn is_powered(&mut self) -> Result<bool, Box<dyn Error>> {
+        let cookie = self.conn.send_request(&dpms::Info {});
+        let info = self.conn.wait_for_reply(cookie)?;
+        Ok(!info.state() || info.power_level() == dpms::DpmsMode::On)
+    }
+
+    /// The new power state if it changed since the last call. Meant to be called
+    /// periodically, e.g. once per frame; the first call only records the state.
+    pub fn poll(&mut self) -> Option<bool> {
+        let powered = self
+            .is_powered()
+            .map_err(|e| log::debug!("X11: could not query DPMS: {}", e))
+            .ok()?;
+        let changed = self.powered.is_some_and(|p| p != powered);
+        self.powered = Some(powered);
+        changed.then_some(powered)
+    }
+}
+
+/// The active RandR monitors, keyed by monitor name.
+fn randr_monitors(display: &str) -> Result<HashMap<String, RandrMonitor>, Box<dyn Error>> {
+    let (conn, screen_num) =
+        xcb::Connection::connect_with_extensions(Some(display), &[xcb::Extension::RandR], &[])?;
+    let root = conn
+        .get_setup()
+        .roots()
+        .nth(screen_num as usize)
+        .ok_or("X11: screen not found")?
+        .root();
+
+    let cookie = conn.send_request(&randr::GetMonitors {
+        window: root,
+        get_active: true,
+    });
+    let reply = conn.wait_for_reply(cookie)?;
+
+    let cookie = conn.send_request(&randr::GetScreenResourcesCurrent { window: root });
+    let resources = conn.wait_for_reply(cookie)?;
+
+    let mut monitors = HashMap::new();
+    for m in reply.monitors() {
+        let cookie = conn.send_request(&x::GetAtomName { atom: m.name() });
+        let name = conn.wait_for_reply(cookie)?.name().to_utf8().into_owned();
+
+        // a monitor may span several outputs; they share a crtc unless it is a tiled display
+        let mut transform = Transform::Normal;
+        let mut refresh = 0;
+        if let Some(&output) = m.outputs().first() {
+            let cookie = conn.send_request(&randr::GetOutputInfo {
+                output,
+                config_timestamp: x::CURRENT_TIME,
+            });
+            let crtc = conn.wait_for_reply(cookie)?.crtc();
+            if !crtc.is_none() {
+                let cookie = conn.send_request(&randr::GetCrtcInfo {
+                    crtc,
+                    config_timestamp: x::CURRENT_TIME,
+                });
+                let crtc_info = conn.wait_for_reply(cookie)?;
+                transform = randr_rotation_to_transform(crtc_info.rotation());
+                refresh = resources
+                    .modes()
+                    .iter()
+                    .find(|mode| mode.id == crtc_info.mode().resource_id())
+                    .map(mode_refresh)
+                    .unwrap_or(0);
+            }
+        }
+
+        monitors.insert(
+            name,
+            RandrMonitor {
+                pos: (m.x() as _, m.y() as _),
+                size: (m.width() as _, m.height() as _),
+                physical_size: (
+                    m.width_in_millimeters() as _,
+                    m.height_in_millimeters() as _,
+                ),
+                primary: m.primary(),
+                transform,
+                refresh,
+            },
+        );
+    }
+    Ok(monitors)
+}
+
+/// Refresh rate of a mode in mHz, from its pixel clock and timings.
+fn mode_refresh(mode: &randr::ModeInfo) -> i32 {
+    let mut vtotal = mode.vtotal as u64;
+    if mode.mode_flags.contains(randr::ModeFlag::DOUBLE_SCAN) {
+        vtotal *= 2;
+    }
+    if mode.mode_flags.contains(randr::ModeFlag::INTERLACE) {
+        vtotal /= 2;
+    }
+    let pixels = mode.htotal as u64 * vtotal;
+    if pixels == 0 {
+        return 0;
+    }
+    (mode.dot_clock as u64 * 1000 / pixels) as i32
+}
+
+fn randr_rotation_to_transform(rotation: randr::Rotation) -> Transform {
+    let mut quarters = if rotation.contains(randr::Rotation::ROTATE_90) {
+        1
+    } else if rotation.contains(randr::Rotation::ROTATE_180) {
+        2
+    } else if rotation.contains(randr::Rotation::ROTATE_270) {
+        3
+    } else {
+        0
+    };
+    let mut flipped = rotation.contains(randr::Rotation::REFLECT_X);
+    // reflecting along y is reflecting along x, then rotating by 180°
+    if rotation.contains(randr::Rotation::REFLECT_Y) {
+        flipped = !flipped;
+        quarters = (quarters + 2) % 4;
+    }
+    match (flipped, quarters) {
+        (false, 1) => Transform::Rotated90,
+        (false, 2) => Transform::Rotated180,
+        (false, 3) => Transform::Rotated270,
+        (false, _) => Transform::Normal,
+        (true, 1) => Transform::Flipped90,
+        (true, 2) => Transform::Flipped180,
+        (true, 3) => Transform::Flipped270,
+        (true, _) => Transform::Flipped,
+    }
 }
 
 impl WlxCapture for XshmCapture {
@@ -59,10 +376,38 @@ impl WlxCapture for XshmCapture {
         self.sender = Some(tx_cmd);
         self.receiver = Some(rx_frame);
 
+        // width and height of the last captured image, packed into one value
+        let image_size = Arc::new(AtomicU64::new(0));
+
+        if let Some(interval) = self.mouse_poll {
+            let (tx_mouse, rx_mouse) = mpsc::sync_channel(16);
+            self.mouse_receiver = Some(rx_mouse);
+
+            std::thread::spawn({
+                let monitor = self.screen.monitor.clone();
+                let image_size = image_size.clone();
+                let stop = self.mouse_stop.clone();
+                move || poll_mouse(monitor, interval, image_size, stop, tx_mouse)
+            });
+        }
+
         std::thread::spawn({
             let monitor = self.screen.monitor.clone();
+            let mut frame_callback = self.frame_callback.take();
+            let activity = self.activity.clone();
+            let push = self.push;
+            let held = self.held.clone();
+            let in_use = self.in_use.clone();
             move || {
                 let display = env::var("DISPLAY").expect("DISPLAY not set");
+                let mut damage = match push.then(|| DamageWatcher::new(&display, &monitor)) {
+                    Some(Ok(damage)) => Some(damage),
+                    Some(Err(e)) => {
+                        log::error!("{}: failed to watch for damage: {}", monitor.name(), e);
+                        return;
+                    }
+                    None => None,
+                };
                 let Ok(d) = rxscreen::Display::new(display) else {
                     log::error!("{}: failed to open display", monitor.name());
                     return;
@@ -73,15 +418,26 @@ impl WlxCapture for XshmCapture {
                 };
 
                 loop {
-                    match rx_cmd.recv() {
+                    activity.touch();
+                    match next_capture(&rx_cmd, damage.as_mut(), &held, &in_use) {
+                        Err(mpsc::RecvTimeoutError::Timeout) => continue,
                         Ok(_) => {
                             if let Ok(image) = shm.capture() {
                                 let size = unsafe { image.as_bytes().len() };
+                                image_size.store(
+                                    ((image.width() as u64) << 32) | image.height() as u64,
+                                    Ordering::Relaxed,
+                                );
                                 let memptr_frame = MemPtrFrame {
                                     format: FrameFormat {
                                         width: image.width() as _,
                                         height: image.height() as _,
                                         fourcc: DRM_FORMAT_XRGB8888.into(),
+                                        origin: RowOrder::TopDown,
+                                        ..Default::default()
+                                    },
+                                    meta: FrameMeta {
+                                        timestamp: monotonic_ns(),
                                         ..Default::default()
                                     },
                                     ptr: unsafe { image.as_ptr() as _ },
@@ -101,10 +457,16 @@ impl WlxCapture for XshmCapture {
                                 log::trace!("{}: captured frame", &monitor.name());
 
                                 let frame = WlxFrame::MemPtr(memptr_frame);
-                                match tx_frame.try_send(frame) {
+                                if let Some(callback) = frame_callback.as_mut() {
+                                    callback(frame);
+                                    continue;
+                                }
+                                in_use.store(push, Ordering::Release);
+                                match fault::try_send(&tx_frame, frame) {
                                     Ok(_) => (),
                                     Err(mpsc::TrySendError::Full(_)) => {
                                         log::debug!("{}: channel full", &monitor.name());
+                                        in_use.store(false, Ordering::Release);
                                     }
                                     Err(mpsc::TrySendError::Disconnected(_)) => {
                                         log::warn!(
@@ -118,7 +480,7 @@ impl WlxCapture for XshmCapture {
                                 log::debug!("{}: XShmGetImage failed", &monitor.name());
                             }
                         }
-                        Err(_) => {
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
                             log::warn!("{}: capture thread channel closed (recv)", monitor.name());
                             break;
                         }
@@ -135,21 +497,275 @@ impl WlxCapture for XshmCapture {
         false
     }
     fn receive(&mut self) -> Option<WlxFrame> {
+        if self.last_frame.frozen {
+            return self.last_frame.duplicate();
+        }
+        let mouse = self
+            .mouse_receiver
+            .as_ref()
+            .and_then(|rx| rx.try_iter().last());
         if let Some(rx) = self.receiver.as_ref() {
-            return rx.try_iter().last();
+            if let Some(mut frame) = rx.try_iter().last() {
+                if let (WlxFrame::MemPtr(memptr), Some(mouse)) = (&mut frame, mouse) {
+                    memptr.mouse = Some(mouse);
+                }
+                return self.last_frame.update(Some(frame));
+            }
         }
-        None
+        // nothing new, so the consumer is done with the last frame
+        self.release();
+        if let Some(frame) = self.last_frame.take_stale() {
+            return Some(frame);
+        }
+        mouse.map(WlxFrame::Mouse)
+    }
+    fn pause(&mut self) {
+        // kept for `resume`, since the shared memory is captured into again
+        self.last_frame.retain();
+        self.held.store(true, Ordering::Relaxed);
+        self.in_use.store(false, Ordering::Release);
     }
-    fn pause(&mut self) {}
     fn resume(&mut self) {
         self.receive(); // clear old frames
-        self.request_new_frame();
+        self.last_frame.mark_resumed();
+        self.held.store(self.last_frame.frozen, Ordering::Relaxed);
+        if self.push {
+            self.wake();
+        } else {
+            self.request_new_frame();
+        }
     }
     fn request_new_frame(&mut self) {
+        if self.last_frame.frozen || self.push {
+            return;
+        }
         if let Some(sender) = &self.sender {
             if let Err(e) = sender.send(()) {
                 log::debug!("Failed to send frame request: {}", e);
             }
         }
     }
+    fn last_activity(&self) -> Option<u64> {
+        self.activity.get()
+    }
+    fn next_frame_eta(&self) -> Option<u64> {
+        self.last_frame.eta(refresh_interval(self.screen.refresh)?)
+    }
+    fn freeze(&mut self) {
+        // frozen frames are delivered from a copy
+        self.last_frame.freeze();
+        self.held.store(true, Ordering::Relaxed);
+        self.in_use.store(false, Ordering::Release);
+    }
+    fn unfreeze(&mut self) {
+        self.last_frame.frozen = false;
+        self.held.store(false, Ordering::Relaxed);
+        if self.push {
+            self.wake();
+        } else {
+            self.request_new_frame();
+        }
+    }
+    fn set_frame_callback(&mut self, callback: FrameCallback) -> Result<(), FrameCallback> {
+        self.frame_callback = Some(callback);
+        Ok(())
+    }
+}
+
+impl Drop for XshmCapture {
+    fn drop(&mut self) {
+        self.mouse_stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Wait for the next frame to capture, like `recv_timeout` on the request channel.
+/// With XDamage, frames are captured when the monitor changes, or right away when a
+/// request wakes the thread after it was held. While the last pushed frame is in use,
+/// nothing is captured and changes pile up until it is released.
+fn next_capture(
+    rx_cmd: &mpsc::Receiver<()>,
+    damage: Option<&mut DamageWatcher>,
+    held: &AtomicBool,
+    in_use: &AtomicBool,
+) -> Result<(), mpsc::RecvTimeoutError> {
+    let Some(damage) = damage else {
+        return rx_cmd.recv_timeout(HEARTBEAT_INTERVAL);
+    };
+    if in_use.load(Ordering::Acquire) {
+        rx_cmd.recv_timeout(HEARTBEAT_INTERVAL)?;
+        return Err(mpsc::RecvTimeoutError::Timeout);
+    }
+    match rx_cmd.try_recv() {
+        Ok(_) => {
+            damage.clear();
+            return Ok(());
+        }
+        Err(mpsc::TryRecvError::Disconnected) => return Err(mpsc::RecvTimeoutError::Disconnected),
+        Err(mpsc::TryRecvError::Empty) => {}
+    }
+    if held.load(Ordering::Relaxed) {
+        // changes pile up until the capture is woken again
+        rx_cmd.recv_timeout(HEARTBEAT_INTERVAL)?;
+        damage.clear();
+        return Ok(());
+    }
+    if damage.wait(HEARTBEAT_INTERVAL) {
+        Ok(())
+    } else {
+        Err(mpsc::RecvTimeoutError::Timeout)
+    }
+}
+
+/// Reports changes to the contents of one monitor through XDamage.
+struct DamageWatcher {
+    conn: xcb::Connection,
+    damage: damage::Damage,
+    /// The monitor's position and size on the root window.
+    rect: (i32, i32, i32, i32),
+    /// Changed since the damage was last cleared. Starts out set, for the first frame.
+    changed: bool,
+}
+
+impl DamageWatcher {
+    fn new(display: &str, monitor: &Monitor) -> Result<Self, Box<dyn Error>> {
+        let (conn, screen_num) = xcb::Connection::connect_with_extensions(
+            Some(display),
+            &[xcb::Extension::Damage, xcb::Extension::XFixes],
+            &[],
+        )?;
+        let root = conn
+            .get_setup()
+            .roots()
+            .nth(screen_num as usize)
+            .ok_or("X11: screen not found")?
+            .root();
+
+        // both extensions must be told the client's version before use
+        let cookie = conn.send_request(&xfixes::QueryVersion {
+            client_major_version: 5,
+            client_minor_version: 0,
+        });
+        conn.wait_for_reply(cookie)?;
+        let cookie = conn.send_request(&damage::QueryVersion {
+            client_major_version: 1,
+            client_minor_version: 1,
+        });
+        conn.wait_for_reply(cookie)?;
+
+        let damage = conn.generate_id();
+        conn.send_and_check_request(&damage::Create {
+            damage,
+            drawable: x::Drawable::Window(root),
+            level: damage::ReportLevel::BoundingBox,
+        })?;
+        Ok(Self {
+            conn,
+            damage,
+            rect: (
+                monitor.x(),
+                monitor.y(),
+                monitor.width() as _,
+                monitor.height() as _,
+            ),
+            changed: true,
+        })
+    }
+
+    /// Wait up to `timeout` for the monitor to change. If it did, the damage is cleared
+    /// before returning true, so that changes during the capture are reported next time.
+    fn wait(&mut self, timeout: Duration) -> bool {
+        self.drain();
+        if !self.changed {
+            let mut fd = libc::pollfd {
+                fd: self.conn.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as _) };
+            self.drain();
+        }
+        if !self.changed {
+            return false;
+        }
+        self.clear();
+        true
+    }
+
+    /// Forget the changes so far.
+    fn clear(&mut self) {
+        self.drain();
+        self.changed = false;
+        self.conn.send_request(&damage::Subtract {
+            damage: self.damage,
+            repair: xfixes::Region::none(),
+            parts: xfixes::Region::none(),
+        });
+        if let Err(e) = self.conn.flush() {
+            log::debug!("X11: failed to flush damage subtraction: {}", e);
+        }
+    }
+
+    /// Take in the events received so far.
+    fn drain(&mut self) {
+        let (x, y, width, height) = self.rect;
+        while let Ok(Some(event)) = self.conn.poll_for_event() {
+            let xcb::Event::Damage(damage::Event::Notify(notify)) = event else {
+                continue;
+            };
+            let area = notify.area();
+            let (ax, ay) = (area.x as i32, area.y as i32);
+            let (aw, ah) = (area.width as i32, area.height as i32);
+            if ax < x + width && ax + aw > x && ay < y + height && ay + ah > y {
+                self.changed = true;
+            }
+        }
+    }
+}
+
+fn poll_mouse(
+    monitor: Monitor,
+    interval: Duration,
+    image_size: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    sender: mpsc::SyncSender<MouseMeta>,
+) {
+    let display = env::var("DISPLAY").expect("DISPLAY not set");
+    let Ok(d) = rxscreen::Display::new(display) else {
+        log::error!(
+            "{}: failed to open display for mouse polling",
+            monitor.name()
+        );
+        return;
+    };
+
+    let mut last_pos = None;
+    while !stop.load(Ordering::Relaxed) {
+        std::thread::sleep(interval);
+
+        let packed = image_size.load(Ordering::Relaxed);
+        let (width, height) = ((packed >> 32) as f32, (packed & 0xFFFFFFFF) as f32);
+        if width == 0. || height == 0. {
+            continue; // no frame captured yet
+        }
+
+        let pos = d
+            .root_mouse_position()
+            .and_then(|root_pos| monitor.mouse_to_local(root_pos));
+        if pos == last_pos {
+            continue;
+        }
+        last_pos = pos;
+
+        let Some((x, y)) = pos else {
+            continue;
+        };
+        let mouse = MouseMeta {
+            x: (x as f32) / width,
+            y: (y as f32) / height,
+        };
+        if let Err(mpsc::TrySendError::Disconnected(_)) = sender.try_send(mouse) {
+            break;
+        }
+    }
+    log::debug!("{}: mouse polling stopped", monitor.name());
 }